@@ -0,0 +1,38 @@
+//! `--plugin` で読み込める式のプラグインの例 (Tricorn: `conj(z)^2 + c`)
+//!
+//! ```bash
+//! $ cargo build --release --example plugin_formula
+//! $ target/release/mandelbrot-rewrite /tmp/tricorn.png 1200x1200 -2.2,1.8 1.4,-1.8 \
+//!       --plugin target/release/examples/libplugin_formula.so
+//! ```
+//!
+//! ABI は `src/plugin.rs` に書いたもの。本体のクレートには依存せず、`#[repr(C)]` の構造体を自分で持つ。
+
+use std::os::raw::c_char;
+
+#[repr(C)]
+pub struct PluginDescriptor {
+    pub name: *const c_char,
+    pub bailout: f64
+}
+
+#[no_mangle]
+pub extern "C" fn plugin_abi_version() -> u32 {
+    2
+}
+
+#[no_mangle]
+pub extern "C" fn plugin_descriptor() -> PluginDescriptor {
+    PluginDescriptor {
+        name: b"tricorn\0".as_ptr() as *const c_char,
+        bailout: 2.0
+    }
+}
+
+/// # Safety
+/// `out` は2つの `f64` を書ける領域を指していること
+#[no_mangle]
+pub unsafe extern "C" fn iterate(z_re: f64, z_im: f64, c_re: f64, c_im: f64, out: *mut f64) {
+    *out = z_re * z_re - z_im * z_im + c_re;
+    *out.add(1) = -2.0 * z_re * z_im + c_im;
+}
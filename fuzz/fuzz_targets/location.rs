@@ -0,0 +1,17 @@
+//! `.kfr`・`.upr`・`.par` の位置ファイルが壊れた入力でパニックしないかを調べる
+
+#![no_main]
+
+use std::str::FromStr;
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let text = match std::str::from_utf8(data) {
+        Ok(text) => text,
+        Err(_) => return
+    };
+    let _ = mandelbrot::kfr::Location::from_str(text);
+    let _ = mandelbrot::paramfile::parse_upr(text);
+    let _ = mandelbrot::paramfile::parse_par(text);
+});
@@ -0,0 +1,18 @@
+//! 壊れた入力から読めた注記を、小さな画像に描く `Overlay::draw` がパニックしないかを調べる
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use mandelbrot::Region;
+use num::Complex;
+
+fuzz_target!(|data: &[u8]| {
+    let text = match std::str::from_utf8(data) {
+        Ok(text) => text,
+        Err(_) => return
+    };
+    if let Ok(overlay) = mandelbrot::overlay::parse_annotations(text) {
+        let region = Region::new((16, 12), Complex { re: -2.0, im: 1.5 }, Complex { re: 1.0, im: -1.5 });
+        overlay.draw(&mut [0; 16 * 12], region);
+    }
+});
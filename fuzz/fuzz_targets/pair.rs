@@ -0,0 +1,15 @@
+//! `WxH` と `RE,IM` の組が壊れた入力でパニックしないかを調べる
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let text = match std::str::from_utf8(data) {
+        Ok(text) => text,
+        Err(_) => return
+    };
+    let _ = mandelbrot::parse_pair::<usize>(text, 'x');
+    let _ = mandelbrot::parse_pair::<f64>(text, ',');
+    let _ = mandelbrot::parse_complex(text);
+});
@@ -0,0 +1,14 @@
+//! 色とパレットの TOML が壊れた入力でパニックしないかを調べる
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let text = match std::str::from_utf8(data) {
+        Ok(text) => text,
+        Err(_) => return
+    };
+    let _ = mandelbrot::color::parse_color(text);
+    let _ = mandelbrot::color::Palette::parse(text);
+});
@@ -0,0 +1,14 @@
+//! `--cores` の番号の範囲が壊れた入力でパニックしないかを調べる
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let text = match std::str::from_utf8(data) {
+        Ok(text) => text,
+        Err(_) => return
+    };
+    let _ = mandelbrot::parse_ranges(text);
+    let _ = mandelbrot::parse_list::<u32>(text, ',');
+});
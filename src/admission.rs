@@ -0,0 +1,254 @@
+//! `serve-api` に来た描画を受け付けるかを決める、順番待ちの列とクライアント毎の頻度の上限
+//!
+//! 同時に描画するのは `--max-concurrent` 件までで、埋まっているときは `--max-queue` 件まで空きを待たせる。
+//! 列も埋まっていれば待たせずに断る。待つ間もリクエストの期限は進み、期限までに空かなければ諦める。
+//!
+//! `--rate-limit N` を指定すると、クライアントの IP アドレス毎に1分あたり N 件までしか描画しない。
+//! トークンバケットで、N 件までは続けて受け付け、その後は 60/N 秒に1件ずつ回復する。
+//! IPv6 は /64 毎に数える。覚えるクライアントは 10,000 件までで、超えたら最後に来たのが古いものから忘れる。
+//!
+//! どちらも描画だけを数えるので、その前に、開いている接続の数を `--max-connections` 件までに抑える。
+//! 超えた接続にはスレッドを立てずにすぐ断るので、接続が殺到してもスレッドの数は増えない。
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// 描画を受け付けなかった理由
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Rejection {
+    /// 順番待ちの列が埋まっている
+    QueueFull,
+    /// 期限までに描画の枠が空かなかった
+    TimedOut,
+    /// クライアントの頻度の上限を超えた。次に受け付けられるまでの時間を持つ
+    RateLimited(Duration)
+}
+
+#[derive(Debug, Default)]
+struct QueueState {
+    active: usize,
+    waiting: usize
+}
+
+/// 同時に描画する数の枠と、枠の空きを待つ列
+#[derive(Debug)]
+pub struct RenderQueue {
+    max_concurrent: usize,
+    max_waiting: usize,
+    state: Mutex<QueueState>,
+    released: Condvar
+}
+
+/// 描画の枠。スコープを抜けると枠を返し、待っているリクエストを1つ起こす
+pub struct Slot<'a> {
+    queue: &'a RenderQueue
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        self.queue.state.lock().unwrap().active -= 1;
+        self.queue.released.notify_one();
+    }
+}
+
+impl RenderQueue {
+    pub fn new(max_concurrent: usize, max_waiting: usize) -> RenderQueue {
+        RenderQueue { max_concurrent, max_waiting, state: Mutex::default(), released: Condvar::new() }
+    }
+
+    /// 描画の枠を1つ確保する。埋まっていれば `deadline` まで空きを待つ
+    pub fn acquire(&self, deadline: Instant) -> Result<Slot, Rejection> {
+        let mut state = self.state.lock().unwrap();
+        if state.active < self.max_concurrent {
+            state.active += 1;
+            return Ok(Slot { queue: self });
+        }
+        if state.waiting >= self.max_waiting {
+            return Err(Rejection::QueueFull);
+        }
+        state.waiting += 1;
+        while state.active >= self.max_concurrent {
+            let now = Instant::now();
+            if now >= deadline {
+                state.waiting -= 1;
+                return Err(Rejection::TimedOut);
+            }
+            state = self.released.wait_timeout(state, deadline - now).unwrap().0;
+        }
+        state.waiting -= 1;
+        state.active += 1;
+        Ok(Slot { queue: self })
+    }
+
+    /// 描画中と、枠の空きを待っているリクエストの数
+    pub fn load(&self) -> (usize, usize) {
+        let state = self.state.lock().unwrap();
+        (state.active, state.waiting)
+    }
+}
+
+#[test]
+fn test_render_queue() {
+    let queue = std::sync::Arc::new(RenderQueue::new(1, 1));
+    let soon = || Instant::now() + Duration::from_secs(5);
+    let slot = queue.acquire(soon()).unwrap();
+    assert_eq!(queue.acquire(Instant::now()).err(), Some(Rejection::TimedOut));
+
+    // 待っている間に2件目は列が埋まっていて断られ、1件目の枠を返すと待っていた方が描画に進む
+    let waiter = {
+        let queue = queue.clone();
+        std::thread::spawn(move || queue.acquire(soon()).map(|_| ()))
+    };
+    while queue.load() != (1, 1) {
+        std::thread::yield_now();
+    }
+    assert_eq!(queue.acquire(soon()).err(), Some(Rejection::QueueFull));
+    drop(slot);
+    assert_eq!(waiter.join().unwrap(), Ok(()));
+    assert_eq!(queue.load(), (0, 0));
+}
+
+/// 覚えておくクライアントの数の上限
+const MAX_CLIENTS: usize = 10_000;
+/// 上限に達したら、最後に来たのが古いものから忘れてこの数まで減らす
+const RETAINED_CLIENTS: usize = MAX_CLIENTS * 3 / 4;
+
+/// バケットを分ける単位。IPv6 は1つの利用者が /64 を丸ごと持つことが多いので /64 毎に数える
+fn client_key(client: IpAddr) -> IpAddr {
+    match client {
+        IpAddr::V4(_) => client,
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => {
+                let mut segments = v6.segments();
+                segments[4 ..].fill(0);
+                IpAddr::V6(segments.into())
+            }
+        }
+    }
+}
+
+/// クライアント毎のトークンバケット
+#[derive(Debug)]
+pub struct RateLimiter {
+    per_minute: u32,
+    buckets: Mutex<HashMap<IpAddr, (f64, Instant)>>
+}
+
+impl RateLimiter {
+    pub fn new(per_minute: u32) -> RateLimiter {
+        RateLimiter { per_minute, buckets: Mutex::default() }
+    }
+
+    /// 時刻 `now` に `client` から来た描画を1件数える
+    pub fn check(&self, client: IpAddr, now: Instant) -> Result<(), Rejection> {
+        let capacity = self.per_minute as f64;
+        let refill = |tokens: f64, since: Instant| {
+            tokens + now.saturating_duration_since(since).as_secs_f64() * capacity / 60.0
+        };
+        let client = client_key(client);
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_CLIENTS && !buckets.contains_key(&client) {
+            // まとめて減らすので、並べ替えはおよそ MAX_CLIENTS / 4 件に1回で済む
+            let mut seen: Vec<_> = buckets.iter().map(|(&key, &(_, last))| (last, key)).collect();
+            seen.sort_unstable_by_key(|&(last, _)| last);
+            for (_, key) in &seen[.. seen.len() - RETAINED_CLIENTS] {
+                buckets.remove(key);
+            }
+        }
+        let (tokens, last) = buckets.entry(client).or_insert((capacity, now));
+        *tokens = refill(*tokens, *last).min(capacity);
+        *last = now;
+        if *tokens < 1.0 {
+            return Err(Rejection::RateLimited(Duration::from_secs_f64((1.0 - *tokens) * 60.0 / capacity)));
+        }
+        *tokens -= 1.0;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_rate_limiter() {
+    let limiter = RateLimiter::new(2);
+    let (client, other) = ([127, 0, 0, 1].into(), [10, 0, 0, 1].into());
+    let start = Instant::now();
+    assert!(limiter.check(client, start).is_ok());
+    assert!(limiter.check(client, start).is_ok());
+    // 2件/分なので、使い切ると 30 秒で1件分回復する
+    assert_eq!(limiter.check(client, start), Err(Rejection::RateLimited(Duration::from_secs(30))));
+    assert!(limiter.check(other, start).is_ok());
+    assert!(limiter.check(client, start + Duration::from_secs(20)).is_err());
+    assert!(limiter.check(client, start + Duration::from_secs(40)).is_ok());
+    assert!(limiter.check(client, start + Duration::from_secs(40)).is_err());
+}
+
+#[test]
+fn test_rate_limiter_clients() {
+    // 同じ /64 の IPv6 アドレスは1つのバケットを分け合う
+    let limiter = RateLimiter::new(1);
+    let start = Instant::now();
+    let v6 = |last: u16| IpAddr::from([0x2001, 0xdb8, 0, 1, 0, 0, 0, last]);
+    assert!(limiter.check(v6(1), start).is_ok());
+    assert!(limiter.check(v6(2), start).is_err());
+    assert!(limiter.check(IpAddr::from([0x2001, 0xdb8, 0, 2, 0, 0, 0, 1]), start).is_ok());
+    assert!(limiter.check([127, 0, 0, 1].into(), start).is_ok());
+    assert!(limiter.check("::ffff:127.0.0.1".parse().unwrap(), start).is_err());
+
+    // 覚えるクライアントの数は上限を超えず、最後に来たのが古いものから忘れる
+    let limiter = RateLimiter::new(1);
+    let client = |n: usize| IpAddr::from([10, (n >> 16) as u8, (n >> 8) as u8, n as u8]);
+    for n in 0 ..= MAX_CLIENTS {
+        assert!(limiter.check(client(n), start + Duration::from_millis(n as u64)).is_ok());
+        assert!(limiter.buckets.lock().unwrap().len() <= MAX_CLIENTS);
+    }
+    let buckets = limiter.buckets.lock().unwrap();
+    assert_eq!(buckets.len(), RETAINED_CLIENTS + 1);
+    assert!(!buckets.contains_key(&client(0)) && buckets.contains_key(&client(MAX_CLIENTS)));
+}
+
+/// 開いている接続の数の上限
+#[derive(Debug)]
+pub struct ConnectionLimit {
+    max: usize,
+    open: AtomicUsize
+}
+
+/// 開いている接続の枠。スコープを抜けると枠を返す
+pub struct OpenConnection {
+    limit: Arc<ConnectionLimit>
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.limit.open.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl ConnectionLimit {
+    pub fn new(max: usize) -> ConnectionLimit {
+        ConnectionLimit { max, open: AtomicUsize::new(0) }
+    }
+
+    /// 接続の枠を1つ確保する。埋まっていれば待たずに `None` を返す
+    pub fn try_open(self: &Arc<Self>) -> Option<OpenConnection> {
+        self.open.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |open| Some(open + 1).filter(|&open| open <= self.max))
+            .ok()
+            .map(|_| OpenConnection { limit: self.clone() })
+    }
+}
+
+#[test]
+fn test_connection_limit() {
+    let limit = Arc::new(ConnectionLimit::new(2));
+    let first = limit.try_open();
+    let second = limit.try_open();
+    assert!(first.is_some() && second.is_some());
+    assert!(limit.try_open().is_none());
+    drop(first);
+    assert!(limit.try_open().is_some());
+    drop(second);
+    assert_eq!(limit.open.load(Ordering::SeqCst), 0);
+}
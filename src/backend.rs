@@ -0,0 +1,630 @@
+//! 帯に分けたピクセルバッファを並列に描画するバックエンド
+//!
+//! rayon はワークスティーリングで帯を動的に割り振り、crossbeam は書籍の実装と同じく
+//! 画像をスレッド数の帯に静的に分けて1本ずつスレッドに任せる。
+//! atomic は rayon を使わず、共有のカウンタを `fetch_add` して次の帯を取り合う最小限の動的スケジューラ。
+//! `--pin-threads` を指定すると、どのバックエンドでもワーカースレッドを順にコアへ固定する。
+//! rayon と tokio は固定したスレッドのプールを固定先毎に一度だけ作って使い回すので、`--mmap-buffer` の
+//! ストリップ毎や描画毎にスレッドを立て直して測定がばらつくことはない。
+//! `--numa-local` では各スレッドが自分で確保したバッファに帯を描く。Linux はページを最初に書き込んだ
+//! スレッドの NUMA ノードに割り当てる (first-touch) ので、描画中の書き込みは全てノード内に収まり、
+//! ノードをまたぐコピーは最後の組み立ての1回だけになる。
+//!
+//! tokio (`async` フィーチャ) は帯毎のタスクを `spawn_blocking` のスレッドプールに投げ、
+//! 描画済みの帯をチャネルで受け取って組み立てる。CPU 律速の処理で async のオーバーヘッドを測る実験用。
+
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, ThreadId};
+use std::time::{Duration, Instant};
+
+use num::Complex;
+use rayon::prelude::*;
+
+use super::color::MISSING;
+use super::{precision, projection};
+use super::{pixel_to_point, render, render_projected, RenderParams, Scheduling};
+
+/// 並列化の方法
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Backend {
+    Rayon,
+    Crossbeam,
+    Atomic,
+    #[cfg(feature = "async")]
+    Tokio
+}
+
+impl Backend {
+    /// ベンチマークで比べる全てのバックエンド
+    pub const ALL: &'static [Backend] = &[
+        Backend::Rayon,
+        Backend::Crossbeam,
+        Backend::Atomic,
+        #[cfg(feature = "async")]
+        Backend::Tokio
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Backend::Rayon => "rayon",
+            Backend::Crossbeam => "crossbeam",
+            Backend::Atomic => "atomic",
+            #[cfg(feature = "async")]
+            Backend::Tokio => "tokio"
+        }
+    }
+
+    /// `scheduling` で描画するときに帯を描くワーカースレッドの数
+    pub fn workers(self, scheduling: &Scheduling) -> usize {
+        match self {
+            Backend::Rayon if scheduling.pin_threads => pinning_cores(scheduling).len(),
+            Backend::Rayon => rayon::current_num_threads(),
+            Backend::Crossbeam => thread_count(scheduling.bands),
+            Backend::Atomic => thread_count(scheduling.threads),
+            #[cfg(feature = "async")]
+            Backend::Tokio => thread_count(scheduling.threads)
+        }
+    }
+}
+
+/// スレッド数の指定がなければ CPU 数を使う
+fn thread_count(threads: Option<usize>) -> usize {
+    threads.unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()))
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Backend, String> {
+        Backend::ALL.iter().cloned()
+            .find(|backend| backend.name() == s)
+            .ok_or_else(|| format!("unknown backend {}", s))
+    }
+}
+
+/// スレッドを固定する先のコア。`--cores` がなければ物理コア毎に1つずつ選ぶ
+fn pinning_cores(scheduling: &Scheduling) -> Vec<usize> {
+    if !scheduling.cores.is_empty() {
+        return scheduling.cores.clone();
+    }
+    let logical: Vec<usize> = core_affinity::get_core_ids()
+        .unwrap_or_default()
+        .into_iter()
+        .map(|core| core.id)
+        .collect();
+    let physical = physical_cores(&logical);
+    if physical.is_empty() { logical } else { physical }
+}
+
+/// Linux ではトポロジ情報を読み、ハイパースレッドの兄弟を除いて物理コア毎の最初の論理 CPU を返す
+fn physical_cores(logical: &[usize]) -> Vec<usize> {
+    let mut seen = vec![];
+    let mut cores = vec![];
+    for &cpu in logical {
+        let topology = format!("/sys/devices/system/cpu/cpu{}/topology", cpu);
+        let read = |name: &str| std::fs::read_to_string(format!("{}/{}", topology, name))
+            .ok().map(|s| s.trim().to_string());
+        let key = match (read("physical_package_id"), read("core_id")) {
+            (Some(package), Some(core)) => (package, core),
+            _ => return vec![]
+        };
+        if !seen.contains(&key) {
+            seen.push(key);
+            cores.push(cpu);
+        }
+    }
+    cores
+}
+
+/// `index` 番目のワーカースレッドを `cores` のいずれかに固定する
+fn pin_current_thread(cores: &[usize], index: usize) {
+    if cores.is_empty() {
+        return;
+    }
+    let core = core_affinity::CoreId { id: cores[index % cores.len()] };
+    if !core_affinity::set_for_current(core) {
+        eprintln!("cannot pin thread {} to core {}", index, core.id);
+    }
+}
+
+/// `--pin-threads` の rayon のプール。`--cores` の指定 (空なら物理コア毎) 毎に一度だけ作る
+static PINNED_POOLS: Mutex<Vec<(Vec<usize>, Arc<rayon::ThreadPool>)>> = Mutex::new(Vec::new());
+
+/// `scheduling` の固定先にスレッドを固定した rayon のプール。同じ固定先なら前に作ったものを返す
+fn pinned_pool(scheduling: &Scheduling) -> Result<Arc<rayon::ThreadPool>, rayon::ThreadPoolBuildError> {
+    let mut pools = PINNED_POOLS.lock().unwrap();
+    if let Some((_, pool)) = pools.iter().find(|(cores, _)| *cores == scheduling.cores) {
+        return Ok(pool.clone());
+    }
+    let cores = pinning_cores(scheduling);
+    let pool = Arc::new(rayon::ThreadPoolBuilder::new()
+        .num_threads(cores.len())
+        .start_handler(move |index| pin_current_thread(&cores, index))
+        .build()?);
+    pools.push((scheduling.cores.clone(), pool.clone()));
+    Ok(pool)
+}
+
+/// ランタイムを使い回す単位。スレッド数の指定と、`--pin-threads` なら `--cores` の指定
+#[cfg(feature = "async")]
+type RuntimeKey = (Option<usize>, Option<Vec<usize>>);
+
+/// `tokio` の描画に使うランタイム。`RuntimeKey` 毎に一度だけ作る
+#[cfg(feature = "async")]
+static RUNTIMES: Mutex<Vec<(RuntimeKey, Arc<tokio::runtime::Runtime>)>> = Mutex::new(Vec::new());
+
+/// `scheduling` のスレッド数で、`--pin-threads` ならスレッドを固定したランタイム。同じ設定なら前に作ったものを返す
+#[cfg(feature = "async")]
+fn runtime(scheduling: &Scheduling) -> std::io::Result<Arc<tokio::runtime::Runtime>> {
+    let key = (scheduling.threads, scheduling.pin_threads.then(|| scheduling.cores.clone()));
+    let mut runtimes = RUNTIMES.lock().unwrap();
+    if let Some((_, runtime)) = runtimes.iter().find(|(existing, _)| *existing == key) {
+        return Ok(runtime.clone());
+    }
+    let cores = if scheduling.pin_threads { pinning_cores(scheduling) } else { vec![] };
+    let started = AtomicUsize::new(0);
+    let runtime = Arc::new(tokio::runtime::Builder::new_multi_thread()
+        .max_blocking_threads(thread_count(scheduling.threads))
+        .on_thread_start(move || pin_current_thread(&cores, started.fetch_add(1, Ordering::Relaxed)))
+        .build()?);
+    runtimes.push((key, runtime.clone()));
+    Ok(runtime)
+}
+
+/// 1つのワーカースレッドが行った仕事
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ThreadWork {
+    /// 描画した帯の数
+    pub bands: usize,
+    /// 実際に行った反復の回数
+    pub iterations: u64,
+    /// 帯の描画に費やした時間
+    pub busy: Duration
+}
+
+/// 描画した1本の帯と、それを描いたスレッド
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BandOwner {
+    /// 画像の何行目から始まる帯か
+    pub top: usize,
+    pub rows: usize,
+    /// `WorkLog::threads` での番号
+    pub thread: usize
+}
+
+/// ワーカースレッド毎の仕事量の記録。`render_rows_logged` に渡すと帯を描く度に足し込む
+#[derive(Debug, Default)]
+pub struct WorkLog {
+    log: Mutex<Entries>
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    threads: Vec<(ThreadId, ThreadWork)>,
+    bands: Vec<BandOwner>
+}
+
+impl WorkLog {
+    fn record(&self, thread: ThreadId, top: usize, rows: usize, iterations: u64, busy: Duration) {
+        let mut log = self.log.lock().unwrap();
+        let Entries { threads, bands } = &mut *log;
+        let index = match threads.iter().position(|&(id, _)| id == thread) {
+            Some(index) => index,
+            None => {
+                threads.push((thread, ThreadWork::default()));
+                threads.len() - 1
+            }
+        };
+        let work = &mut threads[index].1;
+        work.bands += 1;
+        work.iterations += iterations;
+        work.busy += busy;
+        bands.push(BandOwner { top, rows, thread: index });
+    }
+
+    /// スレッド毎の仕事量。最初に帯を描き始めたスレッドから順に並ぶ
+    pub fn threads(&self) -> Vec<ThreadWork> {
+        self.log.lock().unwrap().threads.iter().map(|&(_, work)| work).collect()
+    }
+
+    /// 描画した帯とそれを描いたスレッド。描き終えた順に並ぶ
+    pub fn bands(&self) -> Vec<BandOwner> {
+        self.log.lock().unwrap().bands.clone()
+    }
+
+    /// 全てのスレッドの反復の合計
+    pub fn iterations(&self) -> u64 {
+        self.threads().iter().map(|work| work.iterations).sum()
+    }
+}
+
+/// 1回の描画で行う反復の合計の上限。帯を描き終える度に使った分を足し込み、
+/// 使い切ったらまだ描画していない帯を諦める。描画中の帯の分だけ上限を超えることがある
+#[derive(Debug)]
+pub struct IterationBudget {
+    limit: u64,
+    spent: AtomicU64
+}
+
+impl IterationBudget {
+    pub fn new(limit: u64) -> IterationBudget {
+        IterationBudget { limit, spent: AtomicU64::new(0) }
+    }
+
+    fn spend(&self, iterations: u64) {
+        self.spent.fetch_add(iterations, Ordering::Relaxed);
+    }
+
+    pub fn exhausted(&self) -> bool {
+        self.spent.load(Ordering::Relaxed) >= self.limit
+    }
+}
+
+impl PartialEq for IterationBudget {
+    fn eq(&self, other: &IterationBudget) -> bool {
+        self.limit == other.limit && self.spent.load(Ordering::Relaxed) == other.spent.load(Ordering::Relaxed)
+    }
+}
+
+/// 画像全体の座標系で帯を描画するための共通の情報
+struct Bands<'a> {
+    bounds: (usize, usize),
+    upper_left: Complex<f64>,
+    lower_right: Complex<f64>,
+    params: &'a RenderParams,
+    work: Option<&'a WorkLog>,
+    /// ワーカースレッドには呼び出し元のスパンが引き継がれないので、帯のスパンの親として持ち回る
+    span: tracing::Span,
+    complete: AtomicBool
+}
+
+impl Bands<'_> {
+    /// 画像の `top` 行目から始まる帯 `band` を描画する。期限を過ぎたか反復の上限を使い切っていれば描画せずに諦める
+    fn render(&self, top: usize, band: &mut [u8]) {
+        let started = Instant::now();
+        let iterations = self.render_untimed(top, band);
+        if let Some(work) = self.work {
+            let rows = band.len() / self.bounds.0;
+            work.record(thread::current().id(), top, rows, iterations, started.elapsed());
+        }
+    }
+
+    /// 仕事量を記録せずに帯を描画し、行った反復の回数を返す
+    fn render_untimed(&self, top: usize, band: &mut [u8]) -> u64 {
+        let _span = tracing::info_span!(parent: &self.span, "band").entered();
+        if self.params.deadline.is_some_and(|deadline| Instant::now() >= deadline)
+            || self.params.budget.as_ref().is_some_and(|budget| budget.exhausted()) {
+            self.complete.store(false, Ordering::Relaxed);
+            if self.params.mark_missing {
+                band.fill(MISSING);
+            }
+            return 0;
+        }
+        let iterations = self.render_band_pixels(top, band);
+        if self.params.mark_missing {
+            band.iter_mut().for_each(|pixel| *pixel = (*pixel).min(MISSING - 1));
+        }
+        if let Some(budget) = &self.params.budget {
+            budget.spend(iterations);
+        }
+        iterations
+    }
+
+    fn render_band_pixels(&self, top: usize, band: &mut [u8]) -> u64 {
+        if !projection::is_plane(self.params) {
+            let projection = projection::for_params(self.params, self.upper_left, self.lower_right);
+            return render_projected(band, self.bounds, top, &*projection, self.params);
+        }
+        let rows = band.len() / self.bounds.0;
+        // f64 では刻みが足りないタイルのピクセルは、f64 で描いた後で倍々精度で描き直す
+        let spans = if precision::supports(self.params) {
+            precision::double_spans(self.bounds, top, rows, self.upper_left, self.lower_right)
+        } else {
+            vec![]
+        };
+        let mut iterations = 0;
+        if spans.iter().map(|span| span.len()).sum::<usize>() < band.len() {
+            let band_bounds = (self.bounds.0, rows);
+            let band_upper_left = pixel_to_point(self.bounds, (0, top),
+                                                 self.upper_left, self.lower_right);
+            let band_lower_right = pixel_to_point(self.bounds, (self.bounds.0, top + rows),
+                                                  self.upper_left, self.lower_right);
+            iterations += render(band, band_bounds, band_upper_left, band_lower_right,
+                                 self.params);
+        }
+        iterations + precision::render_spans(band, &spans, self.bounds, top, self.upper_left, self.lower_right,
+                                             self.params)
+    }
+
+    /// `render` と同じだが、`--numa-local` のときはこのスレッドで確保したバッファに描いて
+    /// 書き込み先の帯と一緒に返す。組み立ては `assemble` で最後に行う
+    fn render_band<'p>(&self, top: usize, band: &'p mut [u8]) -> Option<(&'p mut [u8], Vec<u8>)> {
+        if !self.params.scheduling.local_buffers {
+            self.render(top, band);
+            return None;
+        }
+        // vec![0; n] はゼロページを割り当てるだけなので、実際のページは描画時にこのスレッドが確保する
+        let mut local = vec![0; band.len()];
+        self.render(top, &mut local);
+        Some((band, local))
+    }
+}
+
+/// スレッド毎のバッファに描いた帯を画像のバッファにコピーする
+fn assemble(finished: Vec<(&mut [u8], Vec<u8>)>) {
+    for (band, local) in finished {
+        band.copy_from_slice(&local);
+    }
+}
+
+/// 大きさ `bounds` の画像のうち `top` 行目から始まる行を `pixels` に並列に描画する。
+/// `params.deadline` を過ぎたか `params.budget` を使い切って描画しきれなかった行があれば `false` を返す。
+pub fn render_rows(pixels: &mut [u8],
+                   bounds: (usize, usize),
+                   top: usize,
+                   upper_left: Complex<f64>,
+                   lower_right: Complex<f64>,
+                   params: &RenderParams)
+    -> bool
+{
+    render_rows_logged(pixels, bounds, top, upper_left, lower_right, params, None)
+}
+
+/// `render_rows` と同じだが、`work` にワーカースレッド毎の仕事量を記録する
+pub fn render_rows_logged(pixels: &mut [u8],
+                          bounds: (usize, usize),
+                          top: usize,
+                          upper_left: Complex<f64>,
+                          lower_right: Complex<f64>,
+                          params: &RenderParams,
+                          work: Option<&WorkLog>)
+    -> bool
+{
+    let bands = Bands {
+        bounds,
+        upper_left,
+        lower_right,
+        params,
+        work,
+        span: tracing::Span::current(),
+        complete: AtomicBool::new(true)
+    };
+    match params.scheduling.backend {
+        Backend::Rayon => render_rayon(pixels, top, &bands),
+        Backend::Crossbeam => render_crossbeam(pixels, top, &bands),
+        Backend::Atomic => render_atomic(pixels, top, &bands),
+        #[cfg(feature = "async")]
+        Backend::Tokio => render_tokio(pixels, top, &bands)
+    }
+    bands.complete.into_inner()
+}
+
+fn render_rayon<'p>(pixels: &'p mut [u8], top: usize, bands: &Bands) {
+    let band_height = bands.params.scheduling.band_height;
+    let tasks: Vec<(usize, &mut [u8])> = pixels
+        .chunks_mut(bands.bounds.0 * band_height)
+        .enumerate()
+        .map(|(i, band)| (top + i * band_height, band))
+        .collect();
+    let render_band = |(top, band): (usize, &'p mut [u8])| bands.render_band(top, band);
+
+    // 用意したタスクを並列イテレータに変換して実行する。
+    // 既定では .with_max_len(1) で帯1本ずつまで分割し、CPUを重く消費するタスクを細かく分配する
+    // (rayon 0.4 の .weight_max() に相当)
+    let run = || -> Vec<_> {
+        let tasks = tasks.into_par_iter();
+        match bands.params.scheduling.min_len {
+            1 => tasks.with_max_len(1).filter_map(render_band).collect(),
+            min_len => tasks.with_min_len(min_len).filter_map(render_band).collect()
+        }
+    };
+
+    if !bands.params.scheduling.pin_threads {
+        return assemble(run());
+    }
+    // グローバルなスレッドプールは固定できないので、起動時に固定する専用のプールで実行する
+    let finished = match pinned_pool(&bands.params.scheduling) {
+        Ok(pool) => pool.install(run),
+        Err(e) => {
+            eprintln!("cannot build pinned thread pool: {}", e);
+            run()
+        }
+    };
+    assemble(finished);
+}
+
+fn render_crossbeam(pixels: &mut [u8], top: usize, bands: &Bands) {
+    let scheduling = &bands.params.scheduling;
+    let threads = thread_count(scheduling.bands);
+    let cores = if scheduling.pin_threads { pinning_cores(scheduling) } else { vec![] };
+    let cores = &cores;
+    let rows = pixels.len() / bands.bounds.0;
+    let rows_per_band = rows.div_ceil(threads).max(1);
+
+    // 書籍と同じく、帯の数だけスレッドを立てて各スレッドに1本ずつ任せる
+    let finished = crossbeam::scope(|spawner| {
+        let handles: Vec<_> = pixels.chunks_mut(rows_per_band * bands.bounds.0).enumerate()
+            .map(|(i, band)| spawner.spawn(move || {
+                pin_current_thread(cores, i);
+                let band_top = top + i * rows_per_band;
+                let chunk = bands.bounds.0 * scheduling.band_height;
+                band.chunks_mut(chunk).enumerate()
+                    .filter_map(|(j, rows)| bands.render_band(band_top + j * scheduling.band_height, rows))
+                    .collect::<Vec<_>>()
+            }))
+            .collect();
+        handles.into_iter().flat_map(|handle| handle.join().unwrap()).collect()
+    });
+    assemble(finished);
+}
+
+fn render_atomic(pixels: &mut [u8], top: usize, bands: &Bands) {
+    let scheduling = &bands.params.scheduling;
+    // 各帯の Mutex はカウンタで番号を引き当てたスレッドが一度だけロックするので競合しない
+    let tasks: Vec<Mutex<Option<&mut [u8]>>> = pixels
+        .chunks_mut(bands.bounds.0 * scheduling.band_height)
+        .map(|band| Mutex::new(Some(band)))
+        .collect();
+    let next = AtomicUsize::new(0);
+    let cores = if scheduling.pin_threads { pinning_cores(scheduling) } else { vec![] };
+
+    let finished = thread::scope(|scope| {
+        let handles: Vec<_> = (0 .. thread_count(scheduling.threads).min(tasks.len()))
+            .map(|worker| {
+                let (tasks, next, cores) = (&tasks, &next, &cores);
+                scope.spawn(move || {
+                    pin_current_thread(cores, worker);
+                    let mut finished = vec![];
+                    loop {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+                        let band = match tasks.get(index) {
+                            Some(task) => task.lock().unwrap().take().unwrap(),
+                            None => break
+                        };
+                        finished.extend(bands.render_band(top + index * scheduling.band_height, band));
+                    }
+                    finished
+                })
+            })
+            .collect();
+        handles.into_iter().flat_map(|handle| handle.join().unwrap()).collect()
+    });
+    assemble(finished);
+}
+
+#[cfg(feature = "async")]
+fn render_tokio(pixels: &mut [u8], top: usize, bands: &Bands) {
+    use tokio::sync::mpsc;
+
+    let runtime = match runtime(&bands.params.scheduling) {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            eprintln!("cannot start tokio runtime: {}", e);
+            bands.complete.store(false, Ordering::Relaxed);
+            return;
+        }
+    };
+    // spawn_blocking のタスクは 'static でなければならないので、共有する値は Arc に移す
+    let params = Arc::new(bands.params.clone());
+    let (bounds, upper_left, lower_right) = (bands.bounds, bands.upper_left, bands.lower_right);
+    let chunk = bounds.0 * params.scheduling.band_height;
+    let band_height = params.scheduling.band_height;
+    let count = pixels.len().div_ceil(chunk);
+
+    runtime.block_on(async {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        for index in 0 .. count {
+            let params = params.clone();
+            let sender = sender.clone();
+            let span = bands.span.clone();
+            let len = chunk.min(pixels.len() - index * chunk);
+            tokio::task::spawn_blocking(move || {
+                let band_top = top + index * band_height;
+                let bands = Bands {
+                    bounds,
+                    upper_left,
+                    lower_right,
+                    params: &params,
+                    work: None,
+                    span,
+                    complete: AtomicBool::new(true)
+                };
+                let mut band = vec![0; len];
+                let started = Instant::now();
+                let iterations = bands.render_untimed(band_top, &mut band);
+                let work = (thread::current().id(), band_top, iterations, started.elapsed());
+                let _ = sender.send((index, band, bands.complete.into_inner(), work));
+            });
+        }
+        drop(sender);
+
+        while let Some((index, band, complete, (thread, band_top, iterations, busy))) = receiver.recv().await {
+            pixels[index * chunk .. index * chunk + band.len()].copy_from_slice(&band);
+            if !complete {
+                bands.complete.store(false, Ordering::Relaxed);
+            }
+            if let Some(work) = bands.work {
+                work.record(thread, band_top, band.len() / bounds.0, iterations, busy);
+            }
+        }
+    });
+}
+
+#[test]
+fn test_mark_missing() {
+    let bounds = (32, 24);
+    let (upper_left, lower_right) = (Complex { re: -2.0, im: 1.0 }, Complex { re: 1.0, im: -1.0 });
+    let marked = RenderParams { mark_missing: true, ..RenderParams::default() };
+    let mut pixels = vec![0; bounds.0 * bounds.1];
+    assert!(render_rows(&mut pixels, bounds, 0, upper_left, lower_right, &marked));
+    // すぐに発散する点も MISSING より暗くし、内部の点は黒のまま
+    assert!(pixels.iter().all(|&pixel| pixel < MISSING));
+    assert!(pixels.contains(&(MISSING - 1)) && pixels.contains(&0));
+
+    // 期限を過ぎていれば全ての行を諦め、内部と区別できるよう MISSING で埋める
+    let expired = RenderParams { deadline: Some(Instant::now()), ..marked };
+    assert!(!render_rows(&mut pixels, bounds, 0, upper_left, lower_right, &expired));
+    assert!(pixels.iter().all(|&pixel| pixel == MISSING));
+}
+
+#[test]
+fn test_work_log() {
+    let bounds = (32, 24);
+    let upper_left = Complex { re: -1.20, im: 0.35 };
+    let lower_right = Complex { re: -1.0, im: 0.20 };
+    let params = RenderParams::default();
+    let (_, expected) = super::escape_counts(bounds, upper_left, lower_right, &params);
+    for &backend in Backend::ALL {
+        let mut params = params.clone();
+        params.scheduling.backend = backend;
+        let work = WorkLog::default();
+        let mut pixels = vec![0; bounds.0 * bounds.1];
+        assert!(render_rows_logged(&mut pixels, bounds, 0, upper_left, lower_right, &params, Some(&work)));
+        assert_eq!(work.iterations(), expected);
+        assert_eq!(work.threads().iter().map(|thread| thread.bands).sum::<usize>(), bounds.1);
+        let mut rows: Vec<usize> = work.bands().iter()
+            .flat_map(|band| band.top .. band.top + band.rows)
+            .collect();
+        rows.sort();
+        assert_eq!(rows, (0 .. bounds.1).collect::<Vec<_>>());
+    }
+}
+
+#[test]
+fn test_pinning_cores() {
+    let scheduling = Scheduling { pin_threads: true, cores: vec![2, 3], ..Scheduling::default() };
+    assert_eq!(pinning_cores(&scheduling), vec![2, 3]);
+    let cores = pinning_cores(&Scheduling { pin_threads: true, ..Scheduling::default() });
+    assert!(!cores.is_empty());
+    // 物理コア毎に1つなので同じ CPU は二度選ばれない
+    let mut unique = cores.clone();
+    unique.dedup();
+    assert_eq!(unique, cores);
+}
+
+#[test]
+fn test_pinned_pool_reused() {
+    // 同じ固定先ならストリップ毎や描画毎に呼んでも同じプールを使い、固定先が違えば別のプールになる
+    let scheduling = Scheduling { pin_threads: true, cores: vec![0], ..Scheduling::default() };
+    let pool = pinned_pool(&scheduling).unwrap();
+    assert!(Arc::ptr_eq(&pool, &pinned_pool(&scheduling).unwrap()));
+    assert_eq!(pool.current_num_threads(), 1);
+    let other = Scheduling { cores: vec![0, 0], ..scheduling };
+    assert!(!Arc::ptr_eq(&pool, &pinned_pool(&other).unwrap()));
+    #[cfg(feature = "async")]
+    {
+        let scheduling = Scheduling { threads: Some(2), ..Scheduling::default() };
+        assert!(Arc::ptr_eq(&runtime(&scheduling).unwrap(), &runtime(&scheduling).unwrap()));
+        assert!(!Arc::ptr_eq(&runtime(&scheduling).unwrap(), &runtime(&Scheduling::default()).unwrap()));
+    }
+}
+
+#[test]
+fn test_backend_from_str() {
+    for &backend in Backend::ALL {
+        assert_eq!(Backend::from_str(backend.name()), Ok(backend));
+    }
+    assert!(Backend::from_str("openmp").is_err());
+}
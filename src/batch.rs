@@ -0,0 +1,366 @@
+//! ジョブファイルに並べた複数の描画をまとめて実行する `render-batch` サブコマンド
+//!
+//! ジョブファイルは次のような TOML で、各ジョブのオプションは通常の描画と同じものを並べる。
+//!
+//! ```toml
+//! concurrency = 2
+//!
+//! [[job]]
+//! output = "mandel.png"
+//! pixels = "1000x750"
+//! upper_left = "-1.20,0.35"
+//! lower_right = "-1,0.20"
+//! options = ["--passes", "256,1024"]
+//! ```
+//!
+//! 各ジョブは通常の描画と同じ `render_file` で描くので、`--palette` などのオプションも使え、
+//! `output` の拡張子で書き出す形式が決まる。
+//! 失敗したジョブがあっても残りのジョブは続け、最後に結果の一覧を表示する。
+//! `--watch` を付けるとジョブファイルの更新を監視し、保存される度に描画し直す。
+//!
+//! `--jobs -` ではジョブファイルの代わりに、標準入力の1行毎の JSON を1つのジョブとして順に描画し、
+//! 終わる度に結果を1行の JSON で標準出力に書く。他のプログラムが子プロセスとして起動したままにして、
+//! 描画毎のプロセスの起動を省ける。
+//!
+//! ```text
+//! > {"id": 1, "output": "a.png", "pixels": "400x300", "upper_left": "-2,1", "lower_right": "1,-1"}
+//! < {"elapsed":0.01,"id":1,"output":"a.png","status":"ok"}
+//! ```
+
+use std::fs;
+use std::io::{BufRead, Write};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+use serde_json::json;
+
+use super::{parse_complex, parse_pair, render_file, Failure};
+
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct JobFile {
+    /// 同時に実行するジョブ数。コマンドラインの `--jobs` が優先する
+    concurrency: Option<usize>,
+    #[serde(default)]
+    job: Vec<BatchJob>
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+struct BatchJob {
+    output: String,
+    pixels: String,
+    upper_left: String,
+    lower_right: String,
+    #[serde(default)]
+    options: Vec<String>
+}
+
+impl BatchJob {
+    fn run(&self) -> Result<(), String> {
+        let bounds = parse_pair(&self.pixels, 'x').filter(|&(width, height)| width > 0 && height > 0)
+            .ok_or_else(|| format!("pixels expects WxH with positive sizes: {}", self.pixels))?;
+        let upper_left = parse_complex(&self.upper_left)
+            .ok_or("error parsing upper left corner point")?;
+        let lower_right = parse_complex(&self.lower_right)
+            .ok_or("error parsing lower right corner point")?;
+        render_file(&self.output, bounds, upper_left, lower_right, &self.options, false).map_err(Failure::message)
+    }
+}
+
+pub fn parse_job_file(text: &str) -> Result<JobFile, String> {
+    toml::from_str(text).map_err(|e| e.to_string())
+}
+
+#[test]
+fn test_parse_job_file() {
+    let jobs = parse_job_file(r#"
+        concurrency = 2
+
+        [[job]]
+        output = "a.png"
+        pixels = "100x75"
+        upper_left = "-1.20,0.35"
+        lower_right = "-1,0.20"
+        options = ["--passes", "256,1024"]
+
+        [[job]]
+        output = "b.png"
+        pixels = "10x10"
+        upper_left = "-2,1"
+        lower_right = "1,-1"
+    "#).unwrap();
+    assert_eq!(jobs.concurrency, Some(2));
+    assert_eq!(jobs.job.len(), 2);
+    assert_eq!(jobs.job[0].options, vec!["--passes", "256,1024"]);
+    assert!(jobs.job[1].options.is_empty());
+
+    assert_eq!(parse_job_file("").map(|jobs| jobs.job), Ok(vec![]));
+    assert!(parse_job_file("[[job]]\noutput = \"a.png\"").is_err());
+}
+
+/// 1つのジョブの実行結果
+struct Outcome {
+    index: usize,
+    output: String,
+    elapsed: Duration,
+    result: Result<(), String>
+}
+
+/// `jobs` を `concurrency` 本のスレッドで順に取り出して実行し、ジョブの順に結果を返す
+fn run_jobs(jobs: &[BatchJob], concurrency: usize) -> Vec<Outcome> {
+    let next = AtomicUsize::new(0);
+    let outcomes = Mutex::new(Vec::new());
+
+    thread::scope(|scope| {
+        for _ in 0 .. concurrency.min(jobs.len()) {
+            scope.spawn(|| {
+                loop {
+                    let index = next.fetch_add(1, Ordering::SeqCst);
+                    let job = match jobs.get(index) {
+                        Some(job) => job,
+                        None => break
+                    };
+                    let started = Instant::now();
+                    let result = job.run();
+                    let outcome = Outcome {
+                        index,
+                        output: job.output.clone(),
+                        elapsed: started.elapsed(),
+                        result
+                    };
+                    outcomes.lock().unwrap().push(outcome);
+                }
+            });
+        }
+    });
+
+    let mut outcomes = outcomes.into_inner().unwrap();
+    outcomes.sort_by_key(|outcome| outcome.index);
+    outcomes
+}
+
+/// `--watch` でジョブファイルの更新日時を確かめる間隔
+const WATCH_INTERVAL: Duration = Duration::from_millis(500);
+
+/// `render-batch` の引数
+#[derive(Debug, PartialEq)]
+struct BatchArgs {
+    path: String,
+    concurrency: Option<usize>,
+    watch: bool,
+    /// `--jobs -`: ジョブファイルの代わりに標準入力から1行ずつジョブを読む
+    stream: bool
+}
+
+fn parse_batch_args(args: &[String]) -> Result<BatchArgs, String> {
+    let usage = "render-batch expects FILE [--jobs N] [--watch] or --jobs -";
+    let mut args = args.iter().peekable();
+    let path = match args.peek() {
+        Some(path) if !path.starts_with("--") => args.next().unwrap().clone(),
+        _ => String::new()
+    };
+    let mut batch = BatchArgs { path, concurrency: None, watch: false, stream: false };
+
+    while let Some(name) = args.next() {
+        match name.as_str() {
+            "--jobs" if args.peek().map(|n| n.as_str()) == Some("-") => {
+                args.next();
+                batch.stream = true;
+            }
+            "--jobs" => {
+                let n = args.next().and_then(|n| usize::from_str(n).ok()).filter(|&n| n > 0)
+                    .ok_or("--jobs expects a positive integer or -")?;
+                batch.concurrency = Some(n);
+            }
+            "--watch" => batch.watch = true,
+            _ => return Err(format!("unknown option {}", name))
+        }
+    }
+
+    // ジョブファイルか標準入力のどちらか一方だけを読み、標準入力は監視も並列化もしない
+    let file = !batch.path.is_empty();
+    if file == batch.stream || (batch.stream && (batch.watch || batch.concurrency.is_some())) {
+        return Err(usage.to_string());
+    }
+    Ok(batch)
+}
+
+#[test]
+fn test_parse_batch_args() {
+    let args = |s: &str| -> Vec<String> {
+        s.split_whitespace().map(String::from).collect()
+    };
+    assert_eq!(parse_batch_args(&args("jobs.toml")),
+               Ok(BatchArgs { path: "jobs.toml".to_string(), concurrency: None, watch: false, stream: false }));
+    assert_eq!(parse_batch_args(&args("jobs.toml --watch --jobs 3")),
+               Ok(BatchArgs { path: "jobs.toml".to_string(), concurrency: Some(3), watch: true, stream: false }));
+    assert_eq!(parse_batch_args(&args("--jobs -")),
+               Ok(BatchArgs { path: String::new(), concurrency: None, watch: false, stream: true }));
+    assert!(parse_batch_args(&args("")).is_err());
+    assert!(parse_batch_args(&args("jobs.toml --jobs 0")).is_err());
+    assert!(parse_batch_args(&args("jobs.toml --verbose")).is_err());
+    assert!(parse_batch_args(&args("jobs.toml --jobs -")).is_err());
+    assert!(parse_batch_args(&args("--jobs - --watch")).is_err());
+}
+
+/// `render-batch FILE [--jobs N] [--watch]` か `render-batch --jobs -` サブコマンド
+pub fn run_batch(args: &[String]) -> Result<(), Failure> {
+    let batch = parse_batch_args(args).map_err(Failure::Usage)?;
+    if batch.stream {
+        let stdout = std::io::stdout();
+        return run_stream(std::io::stdin().lock(), stdout.lock()).map_err(Failure::Runtime);
+    }
+    if !batch.watch {
+        return run_file(&batch.path, batch.concurrency).map_err(Failure::Runtime);
+    }
+
+    eprintln!("watching {} for changes", batch.path);
+    let mut last_modified = None;
+    loop {
+        let modified = fs::metadata(&batch.path).and_then(|m| m.modified()).ok();
+        if modified.is_some() && modified != last_modified {
+            last_modified = modified;
+            // 失敗しても監視は続け、次の保存で描画し直す
+            if let Err(message) = run_file(&batch.path, batch.concurrency) {
+                eprintln!("error: {}", message);
+            }
+        }
+        thread::sleep(WATCH_INTERVAL);
+    }
+}
+
+/// `--jobs -` で標準入力から読む1行のジョブ。`id` はそのまま結果の行に付けて返す
+#[derive(Debug, Deserialize)]
+struct StreamJob {
+    #[serde(default)]
+    id: Option<serde_json::Value>,
+    #[serde(flatten)]
+    job: BatchJob
+}
+
+/// 1行に1つの JSON のジョブを `input` から読んでは描画し、結果を1行の JSON で `output` に書く。
+/// 読めない行や失敗したジョブは `"status": "error"` の行を返して次の行に進み、入力が終わると戻る
+fn run_stream<R: BufRead, W: Write>(input: R, mut output: W) -> Result<(), String> {
+    for line in input.lines() {
+        let line = line.map_err(|e| format!("cannot read stdin: {}", e))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let started = Instant::now();
+        let result = match serde_json::from_str::<StreamJob>(&line) {
+            Ok(stream) => {
+                let result = stream.job.run();
+                let mut reply = json!({ "id": stream.id, "output": stream.job.output });
+                match result {
+                    Ok(()) => {
+                        reply["status"] = json!("ok");
+                        reply["elapsed"] = json!(started.elapsed().as_secs_f64());
+                    }
+                    Err(message) => {
+                        reply["status"] = json!("error");
+                        reply["error"] = json!(message);
+                    }
+                }
+                reply
+            }
+            Err(e) => json!({ "id": null, "status": "error", "error": format!("invalid job: {}", e) })
+        };
+        writeln!(output, "{}", result).and_then(|_| output.flush())
+            .map_err(|e| format!("cannot write stdout: {}", e))?;
+    }
+    Ok(())
+}
+
+#[test]
+fn test_run_stream() {
+    let dir = std::env::temp_dir().join(format!("mandelbrot-stream-test-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let output = dir.join("a.png").to_string_lossy().replace('\\', "/");
+    let input = format!("{{\"id\": 1, \"output\": \"{}\", \"pixels\": \"16x12\", \"upper_left\": \"-2,1\", \
+                         \"lower_right\": \"1,-1\", \"options\": [\"--passes\", \"64\"]}}\n\
+                         \n\
+                         not json\n\
+                         {{\"id\": \"b\", \"output\": \"b.png\", \"pixels\": \"16\", \"upper_left\": \"-2,1\", \
+                         \"lower_right\": \"1,-1\"}}\n", output);
+    let mut written = Vec::new();
+    run_stream(input.as_bytes(), &mut written).unwrap();
+
+    let replies: Vec<serde_json::Value> = String::from_utf8(written).unwrap().lines()
+        .map(|line| serde_json::from_str(line).unwrap()).collect();
+    assert_eq!(replies.len(), 3);
+    assert_eq!((&replies[0]["id"], &replies[0]["status"], &replies[0]["output"]), (&json!(1), &json!("ok"), &json!(output)));
+    assert!(replies[0]["elapsed"].is_number());
+    assert_eq!(replies[1]["status"], "error");
+    assert!(replies[1]["error"].as_str().unwrap().starts_with("invalid job"));
+    assert_eq!((&replies[2]["id"], &replies[2]["status"]), (&json!("b"), &json!("error")));
+    assert!(dir.join("a.png").exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// ジョブファイル `path` を読み込んで全てのジョブを実行する
+fn run_file(path: &str, concurrency: Option<usize>) -> Result<(), String> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("cannot read {}: {}", path, e))?;
+    let jobs = parse_job_file(&text)
+        .map_err(|e| format!("error parsing {}: {}", path, e))?;
+    let concurrency = concurrency.or(jobs.concurrency).unwrap_or(1).max(1);
+
+    let outcomes = run_jobs(&jobs.job, concurrency);
+    let failed = outcomes.iter().filter(|outcome| outcome.result.is_err()).count();
+    for outcome in &outcomes {
+        match outcome.result {
+            Ok(()) => println!("ok      {} ({:.2}s)", outcome.output, outcome.elapsed.as_secs_f64()),
+            Err(ref message) => println!("FAILED  {}: {}", outcome.output, message)
+        }
+    }
+    println!("{} succeeded, {} failed", outcomes.len() - failed, failed);
+
+    if failed > 0 {
+        return Err(format!("{} of {} jobs failed", failed, outcomes.len()));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_run_jobs_continues_past_failures() {
+    let dir = std::env::temp_dir().join(format!("mandelbrot-batch-test-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let job = |name: &str, pixels: &str| BatchJob {
+        output: dir.join(name).to_string_lossy().into_owned(),
+        pixels: pixels.to_string(),
+        upper_left: "-2,1".to_string(),
+        lower_right: "1,-1".to_string(),
+        options: vec![]
+    };
+    let jobs = vec![job("a.png", "16x12"), job("b.png", "16"), job("c.png", "8x8"), job("d.png", "0x12")];
+
+    let outcomes = run_jobs(&jobs, 2);
+    assert_eq!(outcomes.len(), 4);
+    assert!(outcomes[0].result.is_ok());
+    assert!(outcomes[1].result.is_err());
+    assert!(outcomes[2].result.is_ok());
+    // 大きさが 0 のジョブはパニックせずにそのジョブだけ失敗する
+    assert!(outcomes[3].result.as_ref().is_err_and(|message| message.contains("positive")));
+    assert!(!dir.join("d.png").exists());
+
+    // 通常の描画のオプションが使え、拡張子の形式で書き出す
+    let passes = BatchJob { options: vec!["--pass-stop".to_string(), "0".to_string()], ..job("e.png", "16x12") };
+    assert!(run_jobs(&[passes], 1)[0].result.is_ok());
+    let colored = BatchJob { options: vec!["--palette".to_string(), "fire".to_string()], ..job("e.png", "16x12") };
+    let tiff = BatchJob { options: vec!["--sidecar".to_string()], ..job("f.tif", "16x12") };
+    let outcomes = run_jobs(&[colored, tiff], 1);
+    assert!(outcomes.iter().all(|outcome| outcome.result.is_ok()));
+    assert_eq!(image::open(dir.join("e.png")).unwrap().color(), image::ColorType::RGB(8));
+    assert!(fs::read(dir.join("f.tif")).unwrap().starts_with(b"II+\0"));
+    assert!(dir.join("f.json").exists());
+    let interactive = BatchJob { options: vec!["--dry-run".to_string()], ..job("g.png", "16x12") };
+    assert!(run_jobs(&[interactive], 1)[0].result.is_err());
+    assert!(dir.join("a.png").exists());
+    assert!(dir.join("c.png").exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,476 @@
+//! 同じ範囲を全てのバックエンドで描画して所要時間を比べる `bench` サブコマンド
+//!
+//! `--history FILE` を指定すると結果を CSV に追記し、同じ場面とバックエンドの前回の記録より
+//! 中央値が `--threshold` 割合を超えて遅くなっていれば劣化として知らせる。
+//! `--scaling N` はスレッド数を変えて描画し、スレッド数に対する速度向上率を CSV と SVG のグラフにする。
+//! `--coordinates` は描画せずに全てのピクセルの点を求め、ピクセル毎に `pixel_to_point` で補間するときと、
+//! `render` が使う行毎の増分で求めるときの時間を比べる。
+
+use std::fs::OpenOptions;
+use std::hint::black_box;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use super::backend::{render_rows_logged, Backend, WorkLog};
+use super::{image_points, parse_complex, parse_pair, parse_params, pixel_to_point, Failure, RenderParams};
+
+/// 1つのバックエンドを `runs` 回描画した結果
+#[derive(Debug)]
+struct Measurement {
+    backend: Backend,
+    durations: Vec<Duration>,
+    /// 1回の描画で行った反復の回数
+    iterations: u64,
+    /// 仕事をしたワーカースレッドの数
+    threads: usize
+}
+
+impl Measurement {
+    fn min(&self) -> Duration {
+        *self.durations.iter().min().unwrap()
+    }
+
+    fn median(&self) -> Duration {
+        median(&self.durations)
+    }
+
+    /// 中央値の時間で割った1秒あたりの反復の回数 (百万回単位)
+    fn mega_iterations_per_sec(&self) -> f64 {
+        self.iterations as f64 / self.median().as_secs_f64().max(1e-9) / 1e6
+    }
+}
+
+/// 履歴ファイルの1行
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct Record {
+    /// 記録した時刻 (UNIX 時間の秒)
+    timestamp: u64,
+    /// 描画した範囲とオプション
+    scene: String,
+    backend: String,
+    threads: usize,
+    median_secs: f64,
+    min_secs: f64,
+    version: String,
+    revision: String
+}
+
+impl Record {
+    fn new(scene: &str, measurement: &Measurement, params: &RenderParams) -> Record {
+        let mut scheduling = params.scheduling.clone();
+        scheduling.backend = measurement.backend;
+        Record {
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()),
+            scene: scene.to_string(),
+            backend: measurement.backend.name().to_string(),
+            threads: measurement.backend.workers(&scheduling),
+            median_secs: measurement.median().as_secs_f64(),
+            min_secs: measurement.min().as_secs_f64(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            revision: env!("GIT_REVISION").to_string()
+        }
+    }
+
+    /// `previous` より中央値が `threshold` の割合を超えて遅ければ、遅くなった割合を返す
+    fn regression(&self, previous: &Record, threshold: f64) -> Option<f64> {
+        let slowdown = self.median_secs / previous.median_secs - 1.0;
+        if slowdown > threshold { Some(slowdown) } else { None }
+    }
+}
+
+/// 履歴ファイルを読む。ファイルがなければ空の履歴とする
+fn read_history(path: &Path) -> Result<Vec<Record>, String> {
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    let mut reader = csv::Reader::from_path(path)
+        .map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+    reader.deserialize()
+        .collect::<Result<_, _>>()
+        .map_err(|e| format!("cannot parse {}: {}", path.display(), e))
+}
+
+/// 履歴ファイルに `records` を追記する。新しいファイルにはヘッダ行を書く
+fn append_history(path: &Path, records: &[Record]) -> Result<(), String> {
+    let error = |e: &dyn std::fmt::Display| format!("cannot write {}: {}", path.display(), e);
+    let is_new = !path.exists();
+    let file = OpenOptions::new().create(true).append(true).open(path).map_err(|e| error(&e))?;
+    let mut writer = csv::WriterBuilder::new().has_headers(is_new).from_writer(file);
+    for record in records {
+        writer.serialize(record).map_err(|e| error(&e))?;
+    }
+    writer.flush().map_err(|e| error(&e))
+}
+
+#[test]
+fn test_history() {
+    let path = std::env::temp_dir().join(format!("mandelbrot-bench-{}.csv", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let record = |backend: &str, median_secs| Record {
+        timestamp: 1,
+        scene: "16x12 -2,1 1,-1 --passes 64,256".to_string(),
+        backend: backend.to_string(),
+        threads: 4,
+        median_secs,
+        min_secs: median_secs,
+        version: "0.1.0".to_string(),
+        revision: "abc1234".to_string()
+    };
+    assert_eq!(read_history(&path), Ok(vec![]));
+    append_history(&path, &[record("rayon", 1.0)]).unwrap();
+    append_history(&path, &[record("atomic", 2.0), record("rayon", 1.5)]).unwrap();
+    let history = read_history(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(history, vec![record("rayon", 1.0), record("atomic", 2.0), record("rayon", 1.5)]);
+
+    assert_eq!(record("rayon", 1.05).regression(&record("rayon", 1.0), 0.1), None);
+    assert!(record("rayon", 1.2).regression(&record("rayon", 1.0), 0.1).is_some());
+}
+
+/// `bench` の引数
+struct BenchArgs<'a> {
+    bounds: (usize, usize),
+    upper_left: num::Complex<f64>,
+    lower_right: num::Complex<f64>,
+    runs: usize,
+    history: Option<&'a Path>,
+    threshold: f64,
+    scaling: Option<usize>,
+    svg: Option<&'a String>,
+    coordinates: bool,
+    /// 描画のオプション
+    options: Vec<String>,
+    params: RenderParams
+}
+
+fn parse_bench_args(args: &[String]) -> Result<BenchArgs, String> {
+    if args.len() < 3 {
+        return Err("bench expects PIXELS UPPERLEFT LOWERRIGHT".to_string());
+    }
+    let bounds = parse_pair(&args[0], 'x').filter(|&(width, height)| width > 0 && height > 0)
+        .ok_or("PIXELS expects WxH with positive sizes")?;
+    let upper_left = parse_complex(&args[1])
+        .ok_or("error parsing upper left corner point")?;
+    let lower_right = parse_complex(&args[2])
+        .ok_or("error parsing lower right corner point")?;
+
+    let mut runs = 3;
+    let mut history = None;
+    let mut threshold = 0.1;
+    let mut scaling = None;
+    let mut svg = None;
+    let mut coordinates = false;
+    let mut options = vec![];
+    let mut rest = args[3..].iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--runs" => {
+                runs = rest.next().and_then(|n| usize::from_str(n).ok()).filter(|&n| n > 0)
+                    .ok_or("--runs expects a positive integer")?;
+            }
+            "--history" => {
+                history = Some(Path::new(rest.next().ok_or("--history expects a file")?));
+            }
+            "--threshold" => {
+                threshold = rest.next().and_then(|n| f64::from_str(n).ok())
+                    .filter(|n| n.is_finite() && *n >= 0.0)
+                    .ok_or("--threshold expects a non-negative percentage")? / 100.0;
+            }
+            "--scaling" => {
+                scaling = Some(rest.next().and_then(|n| usize::from_str(n).ok()).filter(|&n| n > 0)
+                    .ok_or("--scaling expects a positive number of threads")?);
+            }
+            "--svg" => svg = Some(rest.next().ok_or("--svg expects a file")?),
+            "--coordinates" => coordinates = true,
+            _ => options.push(arg.clone())
+        }
+    }
+    if svg.is_some() && scaling.is_none() && !coordinates {
+        return Err("--svg requires --scaling".to_string());
+    }
+    let params = parse_params(&options)?;
+    Ok(BenchArgs { bounds, upper_left, lower_right, runs, history, threshold, scaling, svg, coordinates, options, params })
+}
+
+/// `bench PIXELS UPPERLEFT LOWERRIGHT [--runs N] [--history FILE [--threshold PCT]] [OPTIONS]` サブコマンド。
+/// `--scaling N [--svg FILE]` ではスレッド数を 1 から N まで変えた測定を CSV で出力する
+pub fn run_bench(args: &[String]) -> Result<(), Failure> {
+    let BenchArgs { bounds, upper_left, lower_right, runs, history, threshold, scaling, svg, coordinates, options, params } =
+        parse_bench_args(args).map_err(Failure::Usage)?;
+
+    if coordinates {
+        let (per_pixel, stepped) = measure_coordinates(bounds, upper_left, lower_right, runs);
+        println!("{:<16} {:>10} {:>10}", "coordinates", "median", "min");
+        for (name, durations) in [("pixel_to_point", &per_pixel), ("row steps", &stepped)] {
+            println!("{:<16} {:>9.3}s {:>9.3}s", name, median(durations).as_secs_f64(),
+                     durations.iter().min().unwrap().as_secs_f64());
+        }
+        println!("speedup {:.2}x", median(&per_pixel).as_secs_f64() / median(&stepped).as_secs_f64().max(1e-9));
+        return Ok(());
+    }
+    if let Some(max_threads) = scaling {
+        let points = measure_scaling(bounds, upper_left, lower_right, &params, runs, max_threads);
+        print!("{}", scaling_csv(&points));
+        if let Some(path) = svg {
+            std::fs::write(path, scaling_svg(&points))
+                .map_err(|e| Failure::Runtime(format!("cannot write {}: {}", path, e)))?;
+        }
+        return Ok(());
+    }
+    let scene = args[..3].iter().chain(&options).cloned().collect::<Vec<_>>().join(" ");
+    let previous = match history {
+        Some(path) => read_history(path).map_err(Failure::Runtime)?,
+        None => vec![]
+    };
+
+    println!("{:<10} {:>10} {:>10} {:>10} {:>14}", "backend", "median", "min", "M iter/s", "M iter/s/thr");
+    let mut records = vec![];
+    for measurement in measure(bounds, upper_left, lower_right, &params, runs) {
+        let record = Record::new(&scene, &measurement, &params);
+        let regression = previous.iter()
+            .rev()
+            .find(|previous| previous.scene == record.scene && previous.backend == record.backend)
+            .and_then(|previous| record.regression(previous, threshold));
+        let rate = measurement.mega_iterations_per_sec();
+        println!("{:<10} {:>9.3}s {:>9.3}s {:>10.1} {:>14.1}{}", record.backend,
+                 record.median_secs, record.min_secs, rate, rate / measurement.threads.max(1) as f64,
+                 match regression {
+                     Some(slowdown) => format!("  REGRESSION +{:.1}% vs previous run", slowdown * 100.0),
+                     None => String::new()
+                 });
+        records.push(record);
+    }
+    if let Some(path) = history {
+        append_history(path, &records).map_err(Failure::Runtime)?;
+    }
+    Ok(())
+}
+
+/// `params` のバックエンドを差し替えながら、全てのバックエンドで `runs` 回ずつ描画する
+fn measure(bounds: (usize, usize),
+           upper_left: num::Complex<f64>,
+           lower_right: num::Complex<f64>,
+           params: &RenderParams,
+           runs: usize)
+    -> Vec<Measurement>
+{
+    let mut pixels = vec![0; bounds.0 * bounds.1];
+    Backend::ALL.iter().map(|&backend| {
+        measure_backend(&mut pixels, bounds, upper_left, lower_right, params, backend, None, runs)
+    }).collect()
+}
+
+/// `backend` で `runs` 回描画する。`threads` を指定するとワーカースレッドの数をその数にする
+#[allow(clippy::too_many_arguments)]
+fn measure_backend(pixels: &mut [u8],
+                   bounds: (usize, usize),
+                   upper_left: num::Complex<f64>,
+                   lower_right: num::Complex<f64>,
+                   params: &RenderParams,
+                   backend: Backend,
+                   threads: Option<usize>,
+                   runs: usize)
+    -> Measurement
+{
+    let mut params = RenderParams { deadline: None, ..params.clone() };
+    params.scheduling.backend = backend;
+    if threads.is_some() {
+        params.scheduling.bands = threads;
+        params.scheduling.threads = threads;
+    }
+    let work = WorkLog::default();
+    let mut durations = || (0 .. runs).map(|run| {
+        let started = Instant::now();
+        // 反復の回数は毎回同じなので、最初の1回だけ記録する
+        render_rows_logged(pixels, bounds, 0, upper_left, lower_right, &params,
+                           Some(&work).filter(|_| run == 0));
+        started.elapsed()
+    }).collect();
+    // rayon はスケジューリングのオプションでは大きさを変えられないので、その数のスレッドプールで実行する
+    let durations = match (backend, threads) {
+        (Backend::Rayon, Some(threads)) => rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .map(|pool| pool.install(&mut durations))
+            .unwrap_or_else(|_| durations()),
+        _ => durations()
+    };
+    Measurement { backend, durations, iterations: work.iterations(), threads: work.threads().len() }
+}
+
+#[test]
+fn test_measure() {
+    let measurements = measure((16, 12), num::Complex { re: -2.0, im: 1.0 },
+                               num::Complex { re: 1.0, im: -1.0 },
+                               &RenderParams::default(), 3);
+    assert_eq!(measurements.len(), Backend::ALL.len());
+    for measurement in &measurements {
+        assert_eq!(measurement.durations.len(), 3);
+        assert!(measurement.min() <= measurement.median());
+        assert!(measurement.iterations >= 16 * 12);
+        assert!(measurement.threads >= 1);
+    }
+    // 大きさが 0 の画像は描かずに断る
+    let args: Vec<String> = ["0x10", "-2,1", "1,-1"].iter().map(|s| s.to_string()).collect();
+    assert!(run_bench(&args).is_err());
+}
+
+/// `durations` の中央値
+fn median(durations: &[Duration]) -> Duration {
+    let mut durations = durations.to_vec();
+    durations.sort();
+    durations[durations.len() / 2]
+}
+
+/// 大きさ `bounds` の画像の全ての点を、ピクセル毎の `pixel_to_point` と `image_points` でそれぞれ
+/// `runs` 回求めた時間
+fn measure_coordinates(bounds: (usize, usize),
+                       upper_left: num::Complex<f64>,
+                       lower_right: num::Complex<f64>,
+                       runs: usize)
+    -> (Vec<Duration>, Vec<Duration>)
+{
+    // 点を捨てると計算ごと消されるので、全ての点を足して black_box に渡す
+    let time = |points: &dyn Fn() -> num::Complex<f64>| -> Vec<Duration> {
+        (0 .. runs).map(|_| {
+            let started = Instant::now();
+            black_box(points());
+            started.elapsed()
+        }).collect()
+    };
+    let zero = num::Complex { re: 0.0, im: 0.0 };
+    let per_pixel = time(&|| {
+        (0 .. bounds.1).flat_map(|row| (0 .. bounds.0).map(move |column| (column, row)))
+            .map(|pixel| pixel_to_point(black_box(bounds), pixel, upper_left, lower_right))
+            .fold(zero, |sum, point| sum + point)
+    });
+    let stepped = time(&|| image_points(black_box(bounds), upper_left, lower_right).fold(zero, |sum, point| sum + point));
+    (per_pixel, stepped)
+}
+
+#[test]
+fn test_measure_coordinates() {
+    let (per_pixel, stepped) = measure_coordinates((16, 12), num::Complex { re: -2.0, im: 1.0 },
+                                                   num::Complex { re: 1.0, im: -1.0 }, 3);
+    assert_eq!((per_pixel.len(), stepped.len()), (3, 3));
+    assert!(median(&stepped) <= *stepped.iter().max().unwrap());
+}
+
+/// スケーリングの測定で描画した1点
+#[derive(Clone, Debug, PartialEq)]
+struct ScalingPoint {
+    backend: Backend,
+    threads: usize,
+    median_secs: f64
+}
+
+/// 全てのバックエンドを 1 から `max_threads` までのスレッド数で描画する
+fn measure_scaling(bounds: (usize, usize),
+                   upper_left: num::Complex<f64>,
+                   lower_right: num::Complex<f64>,
+                   params: &RenderParams,
+                   runs: usize,
+                   max_threads: usize)
+    -> Vec<ScalingPoint>
+{
+    let mut pixels = vec![0; bounds.0 * bounds.1];
+    let mut points = vec![];
+    for &backend in Backend::ALL {
+        for threads in 1 ..= max_threads {
+            let measurement = measure_backend(&mut pixels, bounds, upper_left, lower_right,
+                                              params, backend, Some(threads), runs);
+            points.push(ScalingPoint {
+                backend,
+                threads,
+                median_secs: measurement.median().as_secs_f64()
+            });
+        }
+    }
+    points
+}
+
+/// 1スレッドの時間に対する速度向上率
+fn speedup(points: &[ScalingPoint], point: &ScalingPoint) -> f64 {
+    points.iter()
+        .find(|base| base.backend == point.backend && base.threads == 1)
+        .map_or(1.0, |base| base.median_secs / point.median_secs)
+}
+
+/// `backend,threads,median_secs,speedup,efficiency` の CSV にする
+fn scaling_csv(points: &[ScalingPoint]) -> String {
+    let mut csv = "backend,threads,median_secs,speedup,efficiency\n".to_string();
+    for point in points {
+        let speedup = speedup(points, point);
+        csv += &format!("{},{},{:.6},{:.3},{:.3}\n", point.backend.name(), point.threads,
+                        point.median_secs, speedup, speedup / point.threads as f64);
+    }
+    csv
+}
+
+/// スレッド数と速度向上率の折れ線グラフを SVG で描く。破線は理想的な線形のスケーリング
+fn scaling_svg(points: &[ScalingPoint]) -> String {
+    const COLORS: [&str; 4] = ["#1f77b4", "#ff7f0e", "#2ca02c", "#d62728"];
+    let (width, height, margin) = (640.0, 480.0, 60.0);
+    let max_threads = points.iter().map(|p| p.threads).max().unwrap_or(1).max(2) as f64;
+    let max_speedup = points.iter().map(|p| speedup(points, p)).fold(max_threads, f64::max).ceil();
+    let x = |threads: f64| margin + (threads - 1.0) / (max_threads - 1.0) * (width - 2.0 * margin);
+    let y = |speedup: f64| height - margin - speedup / max_speedup * (height - 2.0 * margin);
+
+    let mut svg = format!("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" \
+                           font-family=\"sans-serif\" font-size=\"12\">\n", width, height);
+    svg += "<rect width=\"100%\" height=\"100%\" fill=\"white\"/>\n";
+    svg += &format!("<path d=\"M{0},{1} H{2} M{0},{1} V{3}\" stroke=\"black\"/>\n",
+                    margin, height - margin, width - margin, margin);
+    for threads in 1 ..= max_threads as usize {
+        svg += &format!("<text x=\"{}\" y=\"{}\" text-anchor=\"middle\">{}</text>\n",
+                        x(threads as f64), height - margin + 16.0, threads);
+    }
+    for speedup in 0 ..= max_speedup as usize {
+        svg += &format!("<text x=\"{}\" y=\"{}\" text-anchor=\"end\">{}</text>\n",
+                        margin - 6.0, y(speedup as f64) + 4.0, speedup);
+    }
+    svg += &format!("<text x=\"{}\" y=\"{}\" text-anchor=\"middle\">threads</text>\n",
+                    width / 2.0, height - 20.0);
+    svg += &format!("<text x=\"16\" y=\"{}\" transform=\"rotate(-90 16 {0})\" \
+                     text-anchor=\"middle\">speedup</text>\n", height / 2.0);
+    svg += &format!("<line x1=\"{}\" y1=\"{}\" x2=\"{}\" y2=\"{}\" stroke=\"gray\" \
+                     stroke-dasharray=\"4\"/>\n", x(1.0), y(1.0), x(max_threads), y(max_threads));
+
+    for (i, &backend) in Backend::ALL.iter().enumerate() {
+        let color = COLORS[i % COLORS.len()];
+        let line: Vec<String> = points.iter()
+            .filter(|p| p.backend == backend)
+            .map(|p| format!("{:.1},{:.1}", x(p.threads as f64), y(speedup(points, p))))
+            .collect();
+        svg += &format!("<polyline points=\"{}\" fill=\"none\" stroke=\"{}\" stroke-width=\"2\"/>\n",
+                        line.join(" "), color);
+        svg += &format!("<text x=\"{}\" y=\"{}\" fill=\"{}\">{}</text>\n",
+                        margin + 10.0, margin + 16.0 * (i as f64 + 1.0), color, backend.name());
+    }
+    svg + "</svg>\n"
+}
+
+#[test]
+fn test_scaling_report() {
+    let points: Vec<ScalingPoint> = Backend::ALL.iter().flat_map(|&backend| {
+        (1 ..= 4).map(move |threads| ScalingPoint {
+            backend,
+            threads,
+            median_secs: 1.0 / threads as f64
+        })
+    }).collect();
+    let csv = scaling_csv(&points);
+    assert_eq!(csv.lines().count(), points.len() + 1);
+    assert!(csv.contains("\nrayon,4,0.250000,4.000,1.000\n"));
+    let svg = scaling_svg(&points);
+    assert!(svg.starts_with("<svg"));
+    assert_eq!(svg.matches("<polyline").count(), Backend::ALL.len());
+
+    let measured = measure_scaling((16, 12), num::Complex { re: -2.0, im: 1.0 },
+                                   num::Complex { re: 1.0, im: -1.0 },
+                                   &RenderParams::default(), 1, 2);
+    assert_eq!(measured.len(), Backend::ALL.len() * 2);
+}
@@ -0,0 +1,106 @@
+//! 集合の縁だけを線で描く `--render boundary`
+//!
+//! 境界までの距離の見積もりが `--boundary-width W` ピクセル (既定値: 1) 以内の点を黒、それ以外を白にした線画を描く。
+//! 外部の点は発散したときの `c` に関する微分から、内部の点は `--interior de` と同じくサイクルでの微分から
+//! 距離を見積もるので、線は縁を外と内の両側から挟む。サイクルが見つからない点は境界にごく近いので線に含める。
+//! 白は明るさ 255 なので、`--alpha exterior` で透明にすれば線だけを残せる。Mandelbrot 集合だけ。
+
+use std::str::FromStr;
+
+use mandelbrot::interior_distance;
+use num::Complex;
+use rayon::prelude::*;
+
+use super::{channels, coloring, projection, FractalKind, RenderParams};
+
+/// `--boundary-width` を指定しないときの線の太さのピクセル数
+pub const DEFAULT_WIDTH: f64 = 1.0;
+
+/// `--render` で選ぶ描き方
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Mode {
+    /// 全ての点を色付けする
+    #[default]
+    Full,
+    /// 境界の近くの点だけを黒く描く
+    Boundary
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Mode, String> {
+        match s {
+            "full" => Ok(Mode::Full),
+            "boundary" => Ok(Mode::Boundary),
+            _ => Err(format!("unknown render mode '{}', expected full or boundary", s))
+        }
+    }
+}
+
+impl Mode {
+    /// `params` で描けるか確かめる
+    pub fn check(self, params: &RenderParams) -> Result<(), String> {
+        if self == Mode::Boundary && params.fractal != FractalKind::Mandelbrot {
+            return Err("--render boundary needs --fractal mandelbrot".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// 線の太さ。正の有限の数でなければならない
+pub fn parse_width(s: &str) -> Result<f64, String> {
+    f64::from_str(s).ok().filter(|width| *width > 0.0 && width.is_finite())
+        .ok_or(format!("--boundary-width expects a positive number of pixels: {}", s))
+}
+
+/// 点 `c` から境界までの、大きさ `pixel_size` のピクセルで数えた距離の見積もり。サイクルが見つからなければ 0
+fn distance_pixels(c: Complex<f64>, params: &RenderParams, pixel_size: f64) -> f64 {
+    match channels::escape(c, params, true, pixel_size) {
+        Some(escape) => escape.de * channels::DE_PIXELS,
+        None => interior_distance(c, params.limit().max(coloring::INTERIOR_MIN_ITERATIONS))
+            .map_or(0.0, |distance| distance / pixel_size)
+    }
+}
+
+/// 範囲の境界から `width` ピクセル以内を黒、それ以外を白にして `pixels` に描く
+pub fn render(pixels: &mut [u8],
+              bounds: (usize, usize),
+              upper_left: Complex<f64>,
+              lower_right: Complex<f64>,
+              params: &RenderParams,
+              width: f64)
+{
+    let pixel_size = (lower_right.re - upper_left.re) / bounds.0 as f64;
+    pixels.par_chunks_mut(bounds.0).enumerate().for_each(|(row, line)| {
+        let projection = projection::for_params(params, upper_left, lower_right);
+        for (column, pixel) in line.iter_mut().enumerate() {
+            let point = projection.point(bounds, (column, row));
+            *pixel = if distance_pixels(point, params, pixel_size) <= width { 0 } else { 255 };
+        }
+    });
+}
+
+#[test]
+fn test_render_boundary() {
+    assert_eq!(Mode::from_str("boundary"), Ok(Mode::Boundary));
+    assert!(Mode::from_str("outline").is_err());
+    assert!(Mode::Boundary.check(&RenderParams { fractal: FractalKind::Celtic, ..RenderParams::default() }).is_err());
+    assert_eq!(parse_width("2.5"), Ok(2.5));
+    assert!(parse_width("-1").is_err());
+
+    // 実軸に沿った1行。右端 1/4 の近くは線になり、周期2の円板の中心と遠い外部は白
+    let bounds = (450, 1);
+    let (upper_left, lower_right) = (Complex { re: -2.5, im: 0.0 }, Complex { re: 2.0, im: 0.0 });
+    let mut pixels = vec![0; bounds.0];
+    render(&mut pixels, bounds, upper_left, lower_right, &RenderParams::default(), 2.0);
+    let column = |re: f64| ((re + 2.5) / 0.01) as usize;
+    assert_eq!(pixels[column(0.25)], 0);
+    assert_eq!(pixels[column(-1.0)], 255);
+    assert_eq!(pixels[column(1.5)], 255);
+    // 太くすると線の点が増える
+    let mut wide = vec![0; bounds.0];
+    render(&mut wide, bounds, upper_left, lower_right, &RenderParams::default(), 8.0);
+    let lines = |pixels: &[u8]| pixels.iter().filter(|&&pixel| pixel == 0).count();
+    assert!(lines(&wide) > lines(&pixels));
+}
@@ -0,0 +1,95 @@
+//! 描画結果を入れるピクセルバッファ
+//!
+//! 通常はヒープ上の `Vec<u8>` を使う。`--mmap-buffer` では一時ファイルをメモリにマップしたバッファを使い、
+//! 物理メモリに収まらない大きさの画像でもページを OS に書き出させながら描画できるようにする。
+
+use std::fs::OpenOptions;
+use std::io;
+use std::ops::{Deref, DerefMut};
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use memmap2::MmapMut;
+
+/// マップしたバッファを1度に描画する大きさ。描き終えた分から OS に書き出させる
+const STRIP_BYTES: usize = 64 << 20;
+
+pub enum PixelBuffer {
+    Heap(Vec<u8>),
+    Mapped(MmapMut)
+}
+
+impl PixelBuffer {
+    /// `len` バイトのバッファを確保する。`mapped` なら一時ファイルにマップする
+    pub fn new(len: usize, mapped: bool) -> io::Result<PixelBuffer> {
+        if !mapped {
+            // マクロ呼び出しvec![v; n]で長さnのベクタを作り、vで初期化
+            return Ok(PixelBuffer::Heap(vec![0; len]));
+        }
+        let path = temp_path();
+        let file = OpenOptions::new().read(true).write(true).create_new(true).open(&path)?;
+        // マップした後はファイル名が不要なので、異常終了しても残らないよう先に消しておく
+        // (Windows では開いているファイルを消せないので、描画後に残る)
+        let _ = std::fs::remove_file(&path);
+        file.set_len(len as u64)?;
+        let map = unsafe { MmapMut::map_mut(&file)? };
+        Ok(PixelBuffer::Mapped(map))
+    }
+
+    /// 一度に描画する行数。マップしたバッファでは `STRIP_BYTES` ずつ描画して書き出す
+    pub fn strip_rows(&self, width: usize) -> usize {
+        match self {
+            PixelBuffer::Heap(pixels) => pixels.len() / width.max(1),
+            PixelBuffer::Mapped(_) => (STRIP_BYTES / width.max(1)).max(1)
+        }
+    }
+
+    /// `range` の描画を終えたことを知らせる。マップしたバッファでは OS にページの書き出しを促す
+    pub fn finish(&self, range: std::ops::Range<usize>) -> io::Result<()> {
+        match self {
+            PixelBuffer::Heap(_) => Ok(()),
+            PixelBuffer::Mapped(map) => map.flush_async_range(range.start, range.len())
+        }
+    }
+}
+
+/// 同じプロセスで同時に複数のバッファを作っても重ならない一時ファイル名
+fn temp_path() -> PathBuf {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let serial = NEXT.fetch_add(1, Ordering::Relaxed);
+    std::env::temp_dir().join(format!("mandelbrot-{}-{}.pixels", process::id(), serial))
+}
+
+impl Deref for PixelBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            PixelBuffer::Heap(pixels) => pixels,
+            PixelBuffer::Mapped(map) => map
+        }
+    }
+}
+
+impl DerefMut for PixelBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        match self {
+            PixelBuffer::Heap(pixels) => pixels,
+            PixelBuffer::Mapped(map) => map
+        }
+    }
+}
+
+#[test]
+fn test_pixel_buffer() {
+    for &mapped in &[false, true] {
+        let mut buffer = PixelBuffer::new(300, mapped).unwrap();
+        assert_eq!(buffer.len(), 300);
+        assert!(buffer.iter().all(|&p| p == 0));
+        buffer[299] = 7;
+        buffer.finish(0 .. 300).unwrap();
+        assert_eq!(buffer[299], 7);
+        assert!(buffer.strip_rows(10) >= 1);
+    }
+}
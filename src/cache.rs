@@ -0,0 +1,180 @@
+//! 描画済みの画像をディレクトリに保存しておくディスクキャッシュ
+//!
+//! ファイル名は描画条件を表す文字列の SHA-1 で、同じ条件の要求には保存済みのファイルをそのまま返す。
+//! 合計サイズが上限を超えたら最後に使われてから最も時間の経ったものから削除する。
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use sha1_smol::Sha1;
+
+/// キャッシュしたファイルの大きさと最後に使われた順番
+#[derive(Debug)]
+struct Entry {
+    size: u64,
+    last_used: u64
+}
+
+#[derive(Debug, Default)]
+struct Index {
+    entries: HashMap<String, Entry>,
+    total: u64,
+    /// 使われる度に増やす論理時刻
+    clock: u64
+}
+
+impl Index {
+    fn touch(&mut self, key: &str) -> bool {
+        self.clock += 1;
+        match self.entries.get_mut(key) {
+            Some(entry) => { entry.last_used = self.clock; true }
+            None => false
+        }
+    }
+}
+
+/// 一時ファイルの名前を書き込み毎に変えるための通し番号
+static TEMPORARY_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+pub struct TileCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    index: Mutex<Index>
+}
+
+impl TileCache {
+    /// `dir` を使うキャッシュを開く。既に保存されているファイルは更新日時の古い順に並べて引き継ぐ
+    pub fn open(dir: &Path, max_bytes: u64) -> io::Result<TileCache> {
+        fs::create_dir_all(dir)?;
+        let mut files = vec![];
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if let Some(key) = name.strip_suffix(".png") {
+                let metadata = entry.metadata()?;
+                files.push((metadata.modified().ok(), key.to_string(), metadata.len()));
+            }
+        }
+        files.sort();
+
+        let mut index = Index::default();
+        for (_, key, size) in files {
+            index.clock += 1;
+            index.total += size;
+            index.entries.insert(key, Entry { size, last_used: index.clock });
+        }
+        let cache = TileCache { dir: dir.to_path_buf(), max_bytes, index: Mutex::new(index) };
+        cache.evict(&mut cache.index.lock().unwrap());
+        Ok(cache)
+    }
+
+    /// 描画条件を表す文字列からキャッシュの鍵を求める
+    pub fn key(description: &str) -> String {
+        let mut sha1 = Sha1::new();
+        sha1.update(description.as_bytes());
+        sha1.digest().to_string()
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.png", key))
+    }
+
+    fn temporary_path(&self, key: &str) -> PathBuf {
+        let count = TEMPORARY_COUNTER.fetch_add(1, Ordering::Relaxed);
+        self.dir.join(format!("{}.{}.{}.tmp", key, std::process::id(), count))
+    }
+
+    pub fn get(&self, key: &str) -> Option<Vec<u8>> {
+        let mut index = self.index.lock().unwrap();
+        if !index.touch(key) {
+            return None;
+        }
+        match fs::read(self.path(key)) {
+            Ok(bytes) => Some(bytes),
+            Err(_) => {
+                // 外から消されていたら索引からも外す
+                if let Some(entry) = index.entries.remove(key) {
+                    index.total -= entry.size;
+                }
+                None
+            }
+        }
+    }
+
+    pub fn put(&self, key: &str, bytes: &[u8]) -> io::Result<()> {
+        let size = bytes.len() as u64;
+        if size > self.max_bytes {
+            return Ok(());
+        }
+        // 書きかけのファイルを読まれないよう、一時ファイルに書いてから名前を変える。
+        // 同じ鍵を同時に書くスレッドやプロセスと一時ファイルを取り合わないよう、名前は書き込み毎に変える
+        let temporary = self.temporary_path(key);
+        fs::write(&temporary, bytes)?;
+        if let Err(error) = fs::rename(&temporary, self.path(key)) {
+            let _ = fs::remove_file(&temporary);
+            return Err(error);
+        }
+
+        let mut index = self.index.lock().unwrap();
+        index.clock += 1;
+        let last_used = index.clock;
+        if let Some(old) = index.entries.insert(key.to_string(), Entry { size, last_used }) {
+            index.total -= old.size;
+        }
+        index.total += size;
+        self.evict(&mut index);
+        Ok(())
+    }
+
+    fn evict(&self, index: &mut Index) {
+        while index.total > self.max_bytes {
+            let oldest = index.entries.iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone());
+            let key = match oldest {
+                Some(key) => key,
+                None => break
+            };
+            let entry = index.entries.remove(&key).unwrap();
+            index.total -= entry.size;
+            let _ = fs::remove_file(self.path(&key));
+        }
+    }
+}
+
+#[test]
+fn test_tile_cache_lru() {
+    let dir = std::env::temp_dir().join(format!("mandelbrot-cache-test-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+
+    let cache = TileCache::open(&dir, 10).unwrap();
+    let (a, b, c) = (TileCache::key("a"), TileCache::key("b"), TileCache::key("c"));
+    assert_eq!(a.len(), 40);
+    assert_ne!(a, b);
+
+    cache.put(&a, b"aaaa").unwrap();
+    cache.put(&b, b"bbbb").unwrap();
+    assert_eq!(cache.get(&a), Some(b"aaaa".to_vec()));
+    // a を使ったばかりなので、上限を超えたら b が消える
+    cache.put(&c, b"cccc").unwrap();
+    assert_eq!(cache.get(&b), None);
+    assert_eq!(cache.get(&a), Some(b"aaaa".to_vec()));
+    assert_eq!(cache.get(&c), Some(b"cccc".to_vec()));
+    assert!(!cache.path(&b).exists());
+
+    // 開き直しても保存済みのファイルを引き継ぐ
+    drop(cache);
+    let cache = TileCache::open(&dir, 10).unwrap();
+    assert_eq!(cache.get(&c), Some(b"cccc".to_vec()));
+    cache.put(&b, &[0; 11]).unwrap();
+    assert_eq!(cache.get(&b), None);
+    // 一時ファイルの名前は書き込み毎に違い、書き終えたら残らない
+    assert_ne!(cache.temporary_path(&a), cache.temporary_path(&a));
+    assert!(fs::read_dir(&dir).unwrap().all(|entry| !entry.unwrap().file_name().to_string_lossy().ends_with(".tmp")));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,246 @@
+//! 反復の結果から求めた3つの量を R、G、B にそれぞれ割り当てる `--channels`
+//!
+//! 灰色の明るさを1つ決める `--coloring` と違い、各点から3つの量を求めてそれぞれの成分にする。
+//! 量は連続化した発散までの回数 `count` (反復の上限で 1)、距離推定 `de` (`DE_PIXELS` ピクセルで 1)、
+//! 脱出角 `angle` (一周で 1) のどれかで、成分毎に `scale=S` を掛けて 0 から 1 に切り詰めてから、
+//! 伝達曲線 `linear` (既定値)、`sqrt`、`log`、`pow=G` を通し、`invert` なら反転する。内部の点は黒にする。
+//!
+//! `--channels count:log,de:sqrt:scale=2,angle` のように R、G、B の順に `量[:曲線や倍率]` をカンマで区切る。
+//! 各ピクセルを1度だけ反復して3つの量を求め、RGB の PNG に書き出す。`de` は z^2 + c の微分を使うので
+//! `--fractal mandelbrot` でだけ使える。
+
+use std::str::FromStr;
+
+use num::Complex;
+use rayon::prelude::*;
+
+use super::{coloring, projection, FractalKind, RenderParams};
+
+/// 距離推定のこのピクセル数を 1 にする
+pub const DE_PIXELS: f64 = 16.0;
+
+/// 成分にする量
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Source {
+    Count,
+    De,
+    Angle
+}
+
+/// 0 から 1 の値を 0 から 1 に移す伝達曲線
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Curve {
+    Linear,
+    Sqrt,
+    /// 小さい値を持ち上げる対数 `ln(1 + 255 v) / ln 256`
+    Log,
+    Pow(f64)
+}
+
+impl Curve {
+    pub fn apply(self, v: f64) -> f64 {
+        match self {
+            Curve::Linear => v,
+            Curve::Sqrt => v.sqrt(),
+            Curve::Log => (1.0 + 255.0 * v).ln() / 256f64.ln(),
+            Curve::Pow(gamma) => v.powf(gamma)
+        }
+    }
+}
+
+/// 1つの成分の決め方
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Channel {
+    pub source: Source,
+    pub scale: f64,
+    pub curve: Curve,
+    pub invert: bool
+}
+
+impl FromStr for Channel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Channel, String> {
+        let mut parts = s.split(':');
+        let source = match parts.next() {
+            Some("count") => Source::Count,
+            Some("de") => Source::De,
+            Some("angle") => Source::Angle,
+            _ => return Err(format!("unknown channel source in '{}', expected count, de or angle", s))
+        };
+        let mut channel = Channel { source, scale: 1.0, curve: Curve::Linear, invert: false };
+        for part in parts {
+            let number = |value: &str| f64::from_str(value).ok().filter(|v| *v > 0.0 && v.is_finite());
+            match part.split_once('=') {
+                None if part == "linear" => channel.curve = Curve::Linear,
+                None if part == "sqrt" => channel.curve = Curve::Sqrt,
+                None if part == "log" => channel.curve = Curve::Log,
+                None if part == "invert" => channel.invert = true,
+                Some(("pow", value)) => {
+                    channel.curve = Curve::Pow(number(value).ok_or(format!("pow expects a positive number: {}", s))?);
+                }
+                Some(("scale", value)) => {
+                    channel.scale = number(value).ok_or(format!("scale expects a positive number: {}", s))?;
+                }
+                _ => {
+                    return Err(format!("unknown channel option '{}', expected linear, sqrt, log, pow=G, scale=S or invert",
+                                       part));
+                }
+            }
+        }
+        Ok(channel)
+    }
+}
+
+impl Channel {
+    /// 量の値 `raw` を成分の値にする
+    fn value(&self, raw: f64) -> u8 {
+        let v = self.curve.apply((raw * self.scale).clamp(0.0, 1.0));
+        let v = if self.invert { 1.0 - v } else { v };
+        (v * 255.0).round() as u8
+    }
+}
+
+/// R、G、B の決め方
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChannelMap(pub [Channel; 3]);
+
+impl FromStr for ChannelMap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ChannelMap, String> {
+        let channels = s.split(',').map(Channel::from_str).collect::<Result<Vec<_>, String>>()?;
+        match channels[..] {
+            [r, g, b] => Ok(ChannelMap([r, g, b])),
+            _ => Err(format!("--channels expects three channels for R,G,B: {}", s))
+        }
+    }
+}
+
+impl ChannelMap {
+    fn uses(&self, source: Source) -> bool {
+        self.0.iter().any(|channel| channel.source == source)
+    }
+
+    /// `params` で描けるか確かめる
+    pub fn check(&self, params: &RenderParams) -> Result<(), String> {
+        if self.uses(Source::De) && params.fractal != FractalKind::Mandelbrot {
+            return Err("--channels de needs --fractal mandelbrot".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_parse_channels() {
+    assert_eq!(Channel::from_str("count"),
+               Ok(Channel { source: Source::Count, scale: 1.0, curve: Curve::Linear, invert: false }));
+    assert_eq!(Channel::from_str("de:sqrt:scale=2:invert"),
+               Ok(Channel { source: Source::De, scale: 2.0, curve: Curve::Sqrt, invert: true }));
+    assert_eq!(Channel::from_str("angle:pow=2.2").map(|channel| channel.curve), Ok(Curve::Pow(2.2)));
+    assert!(Channel::from_str("hue").is_err());
+    assert!(Channel::from_str("count:cubic").is_err());
+    assert!(Channel::from_str("count:scale=0").is_err());
+    assert!(ChannelMap::from_str("count:log,de,angle").is_ok());
+    assert!(ChannelMap::from_str("count,de").is_err());
+
+    let de = ChannelMap::from_str("count,de,angle").unwrap();
+    assert!(de.check(&RenderParams::default()).is_ok());
+    assert!(de.check(&RenderParams { fractal: FractalKind::BurningShip, ..RenderParams::default() }).is_err());
+    assert!(ChannelMap::from_str("count,count,angle").unwrap()
+            .check(&RenderParams { fractal: FractalKind::BurningShip, ..RenderParams::default() }).is_ok());
+
+    let log = Channel { curve: Curve::Log, ..Channel::from_str("count").unwrap() };
+    assert_eq!((log.value(0.0), log.value(1.0), log.value(2.0)), (0, 255, 255));
+    assert!(log.value(0.1) > Channel::from_str("count").unwrap().value(0.1));
+}
+
+/// 発散した点の3つの量
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Escape {
+    pub count: f64,
+    pub de: f64,
+    pub angle: f64
+}
+
+/// 点 `c` を反復して3つの量を求める。発散しなければ `None`。
+/// `de` が要るときだけ z^2 + c の `c` に関する微分を積み上げながら反復する
+pub fn escape(c: Complex<f64>, params: &RenderParams, with_de: bool, pixel_size: f64) -> Option<Escape> {
+    let limit = params.limit();
+    let (count, z, derivative) = if with_de {
+        let (mut z, mut derivative) = (Complex { re: 0.0, im: 0.0 }, Complex { re: 0.0, im: 0.0 });
+        let mut escaped = None;
+        for i in 0 .. limit {
+            derivative = z * derivative * 2.0 + 1.0;
+            z = z * z + c;
+            if params.termination.escaped(z) {
+                escaped = Some(i);
+                break;
+            }
+        }
+        (escaped?, z, derivative)
+    } else {
+        let (traced, _) = coloring::trace(c, params);
+        (traced.count?, traced.escaped_z?, Complex { re: 0.0, im: 0.0 })
+    };
+    let radius = params.termination.radius;
+    let smooth = if radius > 1.0 { count as f64 + 1.0 - (z.norm().ln() / radius.ln()).log2() } else { count as f64 };
+    let de = if with_de { z.norm() * z.norm().ln() / derivative.norm() } else { 0.0 };
+    Some(Escape {
+        count: smooth / limit as f64,
+        de: de / pixel_size / DE_PIXELS,
+        angle: z.im.atan2(z.re) / (2.0 * std::f64::consts::PI) + 0.5
+    })
+}
+
+/// 範囲を `channels` で色付けした RGB の画像を描く
+pub fn render(bounds: (usize, usize),
+              upper_left: Complex<f64>,
+              lower_right: Complex<f64>,
+              params: &RenderParams,
+              channels: &ChannelMap)
+    -> Vec<u8>
+{
+    let with_de = channels.uses(Source::De);
+    let pixel_size = (lower_right.re - upper_left.re) / bounds.0 as f64;
+    let mut rgb = vec![0; bounds.0 * bounds.1 * 3];
+    rgb.par_chunks_mut(bounds.0 * 3).enumerate().for_each(|(row, line)| {
+        let projection = projection::for_params(params, upper_left, lower_right);
+        for (column, pixel) in line.chunks_mut(3).enumerate() {
+            let point = projection.point(bounds, (column, row));
+            if let Some(escape) = escape(point, params, with_de, pixel_size) {
+                for (value, channel) in pixel.iter_mut().zip(&channels.0) {
+                    *value = channel.value(match channel.source {
+                        Source::Count => escape.count,
+                        Source::De => escape.de,
+                        Source::Angle => escape.angle
+                    });
+                }
+            }
+        }
+    });
+    rgb
+}
+
+#[test]
+fn test_render_channels() {
+    let params = RenderParams::default();
+    let (upper_left, lower_right) = (Complex { re: -2.0, im: 1.0 }, Complex { re: 1.0, im: -1.0 });
+    let channels = ChannelMap::from_str("count,de,angle:invert").unwrap();
+    let rgb = render((30, 20), upper_left, lower_right, &params, &channels);
+    assert_eq!(rgb.len(), 30 * 20 * 3);
+    // 原点は内部なので黒、左上の角はすぐに発散して集合から遠い
+    let at = |x: usize, y: usize| &rgb[(y * 30 + x) * 3 .. (y * 30 + x) * 3 + 3];
+    assert_eq!(at(20, 10), [0, 0, 0]);
+    assert_eq!(at(0, 0)[1], 255);
+
+    // 距離推定は境界に近いほど小さく、連続化した回数は大きい
+    let pixel_size = 3.0 / 30.0;
+    let far = escape(Complex { re: -2.0, im: 1.0 }, &params, true, pixel_size).unwrap();
+    let near = escape(Complex { re: -0.75, im: 0.1 }, &params, true, pixel_size).unwrap();
+    assert!(near.de < far.de);
+    assert!(near.count > far.count);
+    // 微分を使わない反復でも回数と脱出角は同じ
+    let traced = escape(Complex { re: -0.75, im: 0.1 }, &params, false, pixel_size).unwrap();
+    assert_eq!((traced.count, traced.angle), (near.count, near.angle));
+}
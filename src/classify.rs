@@ -0,0 +1,143 @@
+//! ピクセルを介さずに1つの点を調べる
+//!
+//! `classify(c, max_iter)` は点 `c` を反復して、吸引サイクルに捕まった内部の点か、発散する外部の点かを返す。
+//! 外部の点では連続化した反復回数と集合までの距離の見積もり、内部の点ではサイクルの周期が分かる。
+//! `max_iter` 回で決着が付かない境界の近くの点は `Unknown` になる。
+//! `interior_distance` は内部の点からサイクルの成分の境界までの距離を見積もる。
+
+use num::Complex;
+
+/// 連続化した反復回数を滑らかにするため、描画より大きく取る脱出半径
+const ESCAPE_RADIUS: f64 = 256.0;
+
+/// 軌道が同じ点に戻ったとみなす距離
+const CYCLE_EPSILON: f64 = 1e-9;
+
+/// サイクル上の点を Newton 法で求め直す回数の上限
+const NEWTON_STEPS: usize = 16;
+
+/// 点の分類
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PointClass {
+    /// 周期 `period` の吸引サイクルに収束する、集合の内部の点
+    Interior { period: u32 },
+    /// 発散する点。`smooth_iter` は連続化した反復回数、`de` は集合までの距離の見積もりで、
+    /// 本当の距離は `de / 4` 以上 `de` 以下 (Koebe の 1/4 定理)
+    Exterior { smooth_iter: f64, de: f64 },
+    /// `max_iter` 回では発散もサイクルも見つからなかった
+    Unknown
+}
+
+/// 点 `c` を最大 `max_iter` 回反復して分類する
+pub fn classify(c: Complex<f64>, max_iter: u32) -> PointClass {
+    let mut z = Complex { re: 0.0, im: 0.0 };
+    // c に関する微分 dz/dc
+    let mut dc = Complex { re: 0.0, im: 0.0 };
+    for n in 0 .. max_iter {
+        dc = z * dc * 2.0 + Complex { re: 1.0, im: 0.0 };
+        z = z * z + c;
+        let norm = z.norm();
+        if norm > ESCAPE_RADIUS {
+            let smooth_iter = n as f64 + 1.0 - norm.ln().log2();
+            let de = 2.0 * norm * norm.ln() / dc.norm();
+            return PointClass::Exterior { smooth_iter, de };
+        }
+    }
+
+    // 軌道はもうサイクルの近くにあるはずなので、戻ってくるまでの周期と、その間の z に関する微分を調べる
+    let mut w = z;
+    let mut multiplier = Complex { re: 1.0, im: 0.0 };
+    for period in 1 ..= max_iter {
+        multiplier = multiplier * w * 2.0;
+        w = w * w + c;
+        if (w - z).norm() < CYCLE_EPSILON {
+            return if multiplier.norm() < 1.0 {
+                PointClass::Interior { period }
+            } else {
+                PointClass::Unknown
+            };
+        }
+    }
+    PointClass::Unknown
+}
+
+#[test]
+fn test_classify() {
+    let point = |re, im| Complex { re, im };
+    assert_eq!(classify(point(0.0, 0.0), 1000), PointClass::Interior { period: 1 });
+    assert_eq!(classify(point(-0.1, 0.1), 1000), PointClass::Interior { period: 1 });
+    assert_eq!(classify(point(-1.0, 0.05), 1000), PointClass::Interior { period: 2 });
+    // Douady の兎
+    assert_eq!(classify(point(-0.1225, 0.7448), 1000), PointClass::Interior { period: 3 });
+    // 放物型の点 1/4 は収束が遅すぎて決着しない
+    assert_eq!(classify(point(0.25, 0.0), 1000), PointClass::Unknown);
+
+    // 実軸上の 2 から集合 (右端は 1/4) までの距離は 1.75
+    match classify(point(2.0, 0.0), 1000) {
+        PointClass::Exterior { smooth_iter, de } => {
+            assert!(smooth_iter > 0.0 && smooth_iter < 5.0);
+            assert!(de / 4.0 <= 1.75 && 1.75 <= de);
+        }
+        class => panic!("unexpected {:?}", class)
+    }
+    // 連続化した反復回数は外側ほど小さい
+    let smooth = |c| match classify(c, 1000) {
+        PointClass::Exterior { smooth_iter, .. } => smooth_iter,
+        class => panic!("unexpected {:?}", class)
+    };
+    assert!(smooth(point(0.5, 0.0)) > smooth(point(1.0, 0.0)));
+    assert!(smooth(point(1.0, 0.0)) > smooth(point(4.0, 0.0)));
+}
+
+/// 内部の点 `c` から集合の境界までの距離の見積もり。本当の距離は見積もりの 1/4 以上で、見積もり以下。
+/// 周期 `p` のサイクルの点 `z0` で、`f^p` の `z` と `c` に関する1階と2階の微分から求める。
+/// 内部の点と分からなければ `None`
+pub fn interior_distance(c: Complex<f64>, max_iter: u32) -> Option<f64> {
+    let period = match classify(c, max_iter) {
+        PointClass::Interior { period } => period,
+        _ => return None
+    };
+    let one = Complex { re: 1.0, im: 0.0 };
+    let zero = Complex { re: 0.0, im: 0.0 };
+    let mut z0 = zero;
+    for _ in 0 .. max_iter {
+        z0 = z0 * z0 + c;
+    }
+    // サイクルの近くまで来た点から f^p(z) = z を解き、微分に使うサイクルの点を正確にする
+    for _ in 0 .. NEWTON_STEPS {
+        let (mut z, mut dz) = (z0, one);
+        for _ in 0 .. period {
+            dz = z * dz * 2.0;
+            z = z * z + c;
+        }
+        let step = (z - z0) / (dz - one);
+        z0 -= step;
+        if step.norm() < 1e-15 * z0.norm().max(1.0) {
+            break;
+        }
+    }
+    let (mut z, mut dz, mut dc, mut dzdz, mut dcdz) = (z0, one, zero, zero, zero);
+    for _ in 0 .. period {
+        dcdz = (z * dcdz + dz * dc) * 2.0;
+        dzdz = (z * dzdz + dz * dz) * 2.0;
+        dc = z * dc * 2.0 + one;
+        dz = z * dz * 2.0;
+        z = z * z + c;
+    }
+    let distance = (1.0 - dz.norm_sqr()) / (dcdz + dzdz * dc / (one - dz)).norm();
+    Some(distance).filter(|distance| distance.is_finite() && *distance > 0.0)
+}
+
+#[test]
+fn test_interior_distance() {
+    let point = |re, im| Complex { re, im };
+    // 周期2の円板は中心 -1、半径 1/4 なので、-1 から境界までは 0.25
+    let center = interior_distance(point(-1.0, 0.0), 1000).unwrap();
+    assert!(0.25 <= center && center / 4.0 <= 0.25);
+    // 円板の縁に近いほど近い
+    let near_edge = interior_distance(point(-1.2, 0.0), 1000).unwrap();
+    assert!(near_edge < center && (0.05 / 4.0 ..= 0.05 * 4.0).contains(&near_edge));
+    // 主カージオイドの中の点も求まり、外部の点は求まらない
+    assert!(interior_distance(point(-0.1, 0.1), 1000).is_some());
+    assert_eq!(interior_distance(point(1.0, 1.0), 1000), None);
+}
@@ -0,0 +1,524 @@
+//! 灰色の画像に色を付けるパレットと、書き出す画像に埋め込む色の情報
+//!
+//! `--palette NAME|FILE.toml` は発散までの反復回数から決まる明るさを、色の段階 (stop) を補間したグラデーションで
+//! 色に置き換える。位置 0 が直ちに発散した点、1 が反復の上限に達した点と内部で、`gray` (既定値) はこれまでどおり白から黒になる。
+//! 補間の色空間は `--interp-space srgb|linear|oklab|lch` (既定値: oklab) で選ぶ。Oklab とその極座標の LCh は
+//! 知覚的にほぼ均等なので、sRGB の値をそのまま補間するより明るさの変わり方が揃ったグラデーションになる。
+//! 組み込みの `cividis` と `blue-orange` は赤と緑の差に頼らないので、1型と2型の2色覚でも帯を見分けやすい
+//! (`--check-contrast` で確かめられる)。
+//!
+//! パレットのファイルは次のような TOML で、`space` は `--interp-space` を指定しなかったときに使う。
+//!
+//! ```toml
+//! space = "lch"
+//!
+//! [[stop]]
+//! position = 0.0
+//! color = "#000764"
+//!
+//! [[stop]]
+//! position = 1.0
+//! color = "#ffffff"
+//! ```
+//!
+//! 色を付けた PNG と TIFF には sRGB の ICC プロファイルを埋め込む。`--icc-profile FILE` で別のプロファイルを、
+//! `--icc-profile none` で埋め込まないよう指定できる。灰色の画像は `--icc-profile srgb` を指定したときだけ、
+//! sRGB と同じ階調の灰色のプロファイルを埋め込む。
+//!
+//! `--alpha exterior|interior` は外部か内部の点を透明にした、不透明度の成分の付いた画像を書く。
+//! 内部の点は明るさ 0 (反復の上限まで発散しなかった点) で見分けるので、パレットを通す前の明るさで決まる。
+
+use std::fs;
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+use super::frame::{FrameBuffer, Gray8, Rgb8, Rgba8};
+use super::icc;
+
+/// グラデーションを補間する色空間
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InterpSpace {
+    /// ガンマの掛かった sRGB の値
+    Srgb,
+    /// 線形の sRGB
+    Linear,
+    Oklab,
+    /// Oklab の明るさ・彩度・色相。色相は近い向きに回す
+    Lch
+}
+
+impl InterpSpace {
+    pub const ALL: [InterpSpace; 4] = [InterpSpace::Srgb, InterpSpace::Linear, InterpSpace::Oklab, InterpSpace::Lch];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            InterpSpace::Srgb => "srgb",
+            InterpSpace::Linear => "linear",
+            InterpSpace::Oklab => "oklab",
+            InterpSpace::Lch => "lch"
+        }
+    }
+}
+
+impl FromStr for InterpSpace {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<InterpSpace, String> {
+        match s {
+            "srgb" => Ok(InterpSpace::Srgb),
+            "linear" => Ok(InterpSpace::Linear),
+            "oklab" => Ok(InterpSpace::Oklab),
+            "lch" => Ok(InterpSpace::Lch),
+            _ => Err(format!("unknown interpolation space '{}', expected srgb, linear, oklab or lch", s))
+        }
+    }
+}
+
+pub fn srgb_to_linear(c: f64) -> f64 {
+    if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) }
+}
+
+pub fn linear_to_srgb(c: f64) -> f64 {
+    if c <= 0.0031308 { c * 12.92 } else { 1.055 * c.powf(1.0 / 2.4) - 0.055 }
+}
+
+/// sRGB の値 (0 から 1) を `space` の座標にする
+pub fn to_space(rgb: [f64; 3], space: InterpSpace) -> [f64; 3] {
+    if space == InterpSpace::Srgb {
+        return rgb;
+    }
+    let [r, g, b] = rgb.map(srgb_to_linear);
+    if space == InterpSpace::Linear {
+        return [r, g, b];
+    }
+    let l = (0.4122214708 * r + 0.5363325363 * g + 0.0514459929 * b).cbrt();
+    let m = (0.2119034982 * r + 0.6806995451 * g + 0.1073969566 * b).cbrt();
+    let s = (0.0883024619 * r + 0.2817188376 * g + 0.6299787005 * b).cbrt();
+    let lab = [0.2104542553 * l + 0.7936177850 * m - 0.0040720468 * s,
+               1.9779984951 * l - 2.4285922050 * m + 0.4505937099 * s,
+               0.0259040371 * l + 0.7827717662 * m - 0.8086757660 * s];
+    if space == InterpSpace::Oklab {
+        return lab;
+    }
+    [lab[0], lab[1].hypot(lab[2]), lab[2].atan2(lab[1])]
+}
+
+/// `to_space` の逆。sRGB の色域の外に出た値は 0 から 1 に切り詰める
+fn from_space(coords: [f64; 3], space: InterpSpace) -> [f64; 3] {
+    let linear = match space {
+        InterpSpace::Srgb => return coords.map(|c| c.clamp(0.0, 1.0)),
+        InterpSpace::Linear => coords,
+        InterpSpace::Oklab | InterpSpace::Lch => {
+            let [lightness, a, b] = match space {
+                InterpSpace::Lch => [coords[0], coords[1] * coords[2].cos(), coords[1] * coords[2].sin()],
+                _ => coords
+            };
+            let l = (lightness + 0.3963377774 * a + 0.2158037573 * b).powi(3);
+            let m = (lightness - 0.1055613458 * a - 0.0638541728 * b).powi(3);
+            let s = (lightness - 0.0894841775 * a - 1.2914855480 * b).powi(3);
+            [4.0767416621 * l - 3.3077115913 * m + 0.2309699292 * s,
+             -1.2684380046 * l + 2.6097574011 * m - 0.3413193965 * s,
+             -0.0041960863 * l - 0.7034186147 * m + 1.7076147010 * s]
+        }
+    };
+    linear.map(|c| linear_to_srgb(c.clamp(0.0, 1.0)))
+}
+
+/// `space` の座標 `a` と `b` の間の割合 `t` の点
+fn mix(a: [f64; 3], b: [f64; 3], t: f64, space: InterpSpace) -> [f64; 3] {
+    let lerp = |x: f64, y: f64| x + (y - x) * t;
+    if space != InterpSpace::Lch {
+        return [lerp(a[0], b[0]), lerp(a[1], b[1]), lerp(a[2], b[2])];
+    }
+    // 無彩色の端では色相が決まらないので、もう一方の色相を使う
+    const ACHROMATIC: f64 = 1e-4;
+    let (mut from, mut to) = (a[2], b[2]);
+    if a[1] < ACHROMATIC {
+        from = to;
+    } else if b[1] < ACHROMATIC {
+        to = from;
+    }
+    let turn = std::f64::consts::TAU;
+    let delta = (to - from + turn / 2.0).rem_euclid(turn) - turn / 2.0;
+    [lerp(a[0], b[0]), lerp(a[1], b[1]), from + delta * t]
+}
+
+/// `#rrggbb`
+pub fn parse_color(s: &str) -> Option<[u8; 3]> {
+    let hex = s.strip_prefix('#').filter(|hex| hex.len() == 6)?;
+    let channel = |i: usize| u8::from_str_radix(hex.get(i .. i + 2)?, 16).ok();
+    Some([channel(0)?, channel(2)?, channel(4)?])
+}
+
+#[test]
+fn test_parse_color() {
+    assert_eq!(parse_color("#ff8000"), Some([255, 128, 0]));
+    assert_eq!(parse_color("#FF8000"), Some([255, 128, 0]));
+    assert_eq!(parse_color("ff8000"), None);
+    assert_eq!(parse_color("#ff80"), None);
+    assert_eq!(parse_color("#gg8000"), None);
+}
+
+/// グラデーションの1つの段階
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Stop {
+    /// 0 から 1
+    pub position: f64,
+    pub color: [u8; 3]
+}
+
+/// 位置の昇順に並んだ段階を補間するグラデーション
+#[derive(Clone, Debug, PartialEq)]
+pub struct Palette {
+    pub stops: Vec<Stop>,
+    /// ファイルで指定した補間の色空間
+    pub space: Option<InterpSpace>
+}
+
+const fn stop(position: f64, color: [u8; 3]) -> Stop {
+    Stop { position, color }
+}
+
+/// 組み込みのパレット
+const BUILTIN: &[(&str, &[Stop])] = &[
+    ("gray", &[stop(0.0, [255, 255, 255]), stop(1.0, [0, 0, 0])]),
+    // Ultra Fractal の既定のグラデーションに近いもの
+    ("ultra", &[stop(0.0, [0, 7, 100]), stop(0.16, [32, 107, 203]), stop(0.42, [237, 255, 255]),
+                stop(0.6425, [255, 170, 0]), stop(0.8575, [0, 2, 0]), stop(1.0, [0, 0, 0])]),
+    ("fire", &[stop(0.0, [0, 0, 0]), stop(0.35, [160, 0, 0]), stop(0.7, [255, 144, 0]),
+               stop(0.95, [255, 240, 160]), stop(1.0, [0, 0, 0])]),
+    ("ocean", &[stop(0.0, [0, 16, 48]), stop(0.5, [0, 128, 192]), stop(0.9, [224, 255, 255]),
+                stop(1.0, [0, 0, 0])]),
+    // 1型と2型の2色覚でも明るさと青から黄の差で見分けられるもの。内部だけ黒にする
+    // matplotlib の cividis に近いもの
+    ("cividis", &[stop(0.0, [0, 34, 78]), stop(0.25, [65, 77, 107]), stop(0.5, [124, 123, 120]),
+                  stop(0.75, [188, 175, 111]), stop(254.0 / 255.0, [254, 232, 56]), stop(1.0, [0, 0, 0])]),
+    // 赤と緑を使わない青から橙
+    ("blue-orange", &[stop(0.0, [10, 31, 68]), stop(0.3, [47, 111, 179]), stop(0.55, [247, 247, 247]),
+                      stop(0.8, [230, 159, 0]), stop(254.0 / 255.0, [122, 59, 0]), stop(1.0, [0, 0, 0])])
+];
+
+/// `--palette` に指定できる組み込みのパレットの名前
+pub fn palette_names() -> Vec<&'static str> {
+    BUILTIN.iter().map(|&(name, _)| name).collect()
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PaletteFile {
+    space: Option<String>,
+    stop: Vec<StopEntry>
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StopEntry {
+    position: f64,
+    color: String
+}
+
+impl Palette {
+    pub fn builtin(name: &str) -> Option<Palette> {
+        BUILTIN.iter().find(|&&(builtin, _)| builtin == name)
+            .map(|&(_, stops)| Palette { stops: stops.to_vec(), space: None })
+    }
+
+    /// 組み込みのパレットの名前か、パレットのファイル
+    pub fn load(spec: &str) -> Result<Palette, String> {
+        if let Some(palette) = Palette::builtin(spec) {
+            return Ok(palette);
+        }
+        if !spec.ends_with(".toml") {
+            return Err(format!("unknown palette {}, expected {} or a .toml file", spec, palette_names().join(", ")));
+        }
+        let text = fs::read_to_string(spec).map_err(|e| format!("cannot read {}: {}", spec, e))?;
+        Palette::parse(&text).map_err(|e| format!("error parsing {}: {}", spec, e))
+    }
+
+    pub fn parse(text: &str) -> Result<Palette, String> {
+        let file: PaletteFile = toml::from_str(text).map_err(|e| e.to_string())?;
+        let stops = file.stop.iter()
+            .map(|entry| {
+                let color = parse_color(&entry.color).ok_or(format!("color expects #rrggbb: {}", entry.color))?;
+                Ok(Stop { position: entry.position, color })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        let palette = Palette { stops, space: file.space.as_deref().map(InterpSpace::from_str).transpose()? };
+        palette.check()?;
+        Ok(palette)
+    }
+
+    /// `parse` で読めるパレットのファイルの中身
+    pub fn to_toml(&self) -> String {
+        let mut text = String::new();
+        if let Some(space) = self.space {
+            text.push_str(&format!("space = \"{}\"\n", space.name()));
+        }
+        for stop in &self.stops {
+            let [r, g, b] = stop.color;
+            // `{:?}` は 0 も 0.0 と書くので TOML の浮動小数点数になる
+            text.push_str(&format!("\n[[stop]]\nposition = {:?}\ncolor = \"#{:02x}{:02x}{:02x}\"\n", stop.position, r, g, b));
+        }
+        text
+    }
+
+    fn check(&self) -> Result<(), String> {
+        if self.stops.len() < 2 {
+            return Err("a palette needs at least two stops".to_string());
+        }
+        if self.stops.iter().any(|stop| !(0.0 ..= 1.0).contains(&stop.position)) {
+            return Err("stop positions must be between 0 and 1".to_string());
+        }
+        if self.stops.windows(2).any(|pair| pair[0].position > pair[1].position) {
+            return Err("stop positions must be in ascending order".to_string());
+        }
+        Ok(())
+    }
+
+    /// 位置 `t` (0 から 1) の色
+    pub fn sample(&self, t: f64, space: InterpSpace) -> [u8; 3] {
+        let to_unit = |color: [u8; 3]| color.map(|c| c as f64 / 255.0);
+        let upper = self.stops.iter().position(|stop| stop.position >= t).unwrap_or(self.stops.len() - 1);
+        let (a, b) = (self.stops[upper.saturating_sub(1)], self.stops[upper]);
+        let span = b.position - a.position;
+        let ratio = if span > 0.0 { ((t - a.position) / span).clamp(0.0, 1.0) } else { 1.0 };
+        let mixed = mix(to_space(to_unit(a.color), space), to_space(to_unit(b.color), space), ratio, space);
+        from_space(mixed, space).map(|c| (c * 255.0).round() as u8)
+    }
+
+    /// 明るさ 0 から 255 の灰色を置き換える色の表
+    pub fn lut(&self, space: InterpSpace) -> Lut {
+        Lut((0 ..= 255).map(|gray| self.sample(1.0 - gray as f64 / 255.0, space)).collect())
+    }
+}
+
+#[test]
+fn test_palette() {
+    let gray = Palette::builtin("gray").unwrap();
+    let lut = gray.lut(InterpSpace::Srgb);
+    assert!((0 ..= 255).all(|gray| lut.0[gray] == [gray as u8; 3]));
+    // Oklab の中間は明るさ L が半分で、sRGB の中間 (128) より暗い
+    assert_eq!(gray.sample(0.5, InterpSpace::Oklab), [99, 99, 99]);
+    assert_eq!(gray.sample(0.5, InterpSpace::Linear), [188, 188, 188]);
+    assert_eq!(gray.sample(0.0, InterpSpace::Lch), [255, 255, 255]);
+
+    let ultra = Palette::builtin("ultra").unwrap();
+    for &space in &[InterpSpace::Srgb, InterpSpace::Linear, InterpSpace::Oklab, InterpSpace::Lch] {
+        assert_eq!(ultra.sample(0.16, space), [32, 107, 203]);
+        assert_eq!(ultra.sample(1.0, space), [0, 0, 0]);
+    }
+
+    // 赤から青へ LCh で回ると、色相の近い紫を通る
+    let red_blue = Palette { stops: vec![stop(0.0, [255, 0, 0]), stop(1.0, [0, 0, 255])], space: None };
+    let [r, g, b] = red_blue.sample(0.5, InterpSpace::Lch);
+    assert!(r > 100 && b > 100 && g < 60, "{:?}", (r, g, b));
+}
+
+#[test]
+fn test_parse_palette() {
+    let palette = Palette::parse(r##"
+        space = "lch"
+
+        [[stop]]
+        position = 0.0
+        color = "#000764"
+
+        [[stop]]
+        position = 1.0
+        color = "#ffffff"
+    "##).unwrap();
+    assert_eq!(palette, Palette { stops: vec![stop(0.0, [0, 7, 100]), stop(1.0, [255, 255, 255])],
+                                  space: Some(InterpSpace::Lch) });
+    let stops = |positions: &[f64]| -> String {
+        positions.iter().map(|p| format!("[[stop]]\nposition = {}\ncolor = \"#000000\"\n", p)).collect::<Vec<_>>().concat()
+    };
+    assert!(Palette::parse(&stops(&[0.0])).is_err());
+    assert!(Palette::parse(&stops(&[0.0, 1.5])).is_err());
+    assert!(Palette::parse(&stops(&[0.5, 0.2])).is_err());
+    assert!(Palette::parse(&stops(&[0.0, 1.0]).replace("#000000", "black")).is_err());
+    assert!(Palette::parse(&format!("space = \"hsv\"\n{}", stops(&[0.0, 1.0]))).is_err());
+    assert!(Palette::load("rainbow").is_err());
+    for name in palette_names() {
+        let palette = Palette { space: Some(InterpSpace::Lch), ..Palette::builtin(name).unwrap() };
+        assert_eq!(Palette::parse(&palette.to_toml()), Ok(palette));
+    }
+}
+
+/// 描画を諦めた点の明るさ。`--missing-color` のときだけ予約し、描いた点はこれより暗くする
+pub const MISSING: u8 = 255;
+
+/// 灰色の明るさ毎の色
+#[derive(Clone, Debug, PartialEq)]
+pub struct Lut(pub Vec<[u8; 3]>);
+
+impl Lut {
+    /// 灰色をそのままにする表
+    pub fn gray() -> Lut {
+        Lut((0 ..= 255).map(|gray| [gray; 3]).collect())
+    }
+
+    /// 灰色の行を RGB の行にして `output` に足す
+    pub fn extend_rgb(&self, gray: &[u8], output: &mut Vec<u8>) {
+        output.extend(gray.iter().flat_map(|&value| self.0[value as usize]));
+    }
+}
+
+/// RGB の画像に埋め込む、`--icc-profile` で指定したプロファイル。既定値は sRGB
+pub fn rgb_profile(icc_profile: Option<&str>) -> Result<Option<Vec<u8>>, String> {
+    match icc_profile {
+        Some("none") => Ok(None),
+        None | Some("srgb") => Ok(Some(icc::srgb())),
+        Some(path) => icc::load(path, true).map(Some)
+    }
+}
+
+/// `--alpha` で透明にする点
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Alpha {
+    Exterior,
+    Interior
+}
+
+impl FromStr for Alpha {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Alpha, String> {
+        match s {
+            "exterior" => Ok(Alpha::Exterior),
+            "interior" => Ok(Alpha::Interior),
+            _ => Err(format!("unknown alpha '{}', expected exterior or interior", s))
+        }
+    }
+}
+
+impl Alpha {
+    /// 明るさ `gray` の点の不透明度
+    pub fn opacity(self, gray: u8) -> u8 {
+        if (gray == 0) == (self == Alpha::Interior) { 0 } else { 255 }
+    }
+}
+
+/// 書き出す画像の色
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Colors {
+    /// 灰色を置き換える色。`None` なら灰色のまま書く
+    pub lut: Option<Lut>,
+    /// 埋め込む ICC プロファイル
+    pub icc: Option<Vec<u8>>,
+    /// 透明にする点。`None` なら不透明度の成分を書かない
+    pub alpha: Option<Alpha>
+}
+
+impl Colors {
+    /// `--palette`、`--interp-space`、`--icc-profile` の指定から決める
+    pub fn resolve(palette: Option<&str>, space: Option<InterpSpace>, icc_profile: Option<&str>)
+        -> Result<Colors, String>
+    {
+        let lut = match palette {
+            None | Some("gray") => None,
+            Some(spec) => {
+                let palette = Palette::load(spec)?;
+                Some(palette.lut(space.or(palette.space).unwrap_or(InterpSpace::Oklab)))
+            }
+        };
+        let icc = match (icc_profile, lut.is_some()) {
+            (Some("none"), _) | (None, false) => None,
+            (Some("srgb"), false) => Some(icc::srgb_gray()),
+            (Some(path), false) => Some(icc::load(path, false)?),
+            (icc_profile, true) => rgb_profile(icc_profile)?
+        };
+        Ok(Colors { lut, icc, alpha: None })
+    }
+
+    /// 明るさ `MISSING` の点を `color` で書く。灰色のままなら灰色の表にして、`icc_profile` の RGB のプロファイルを付ける
+    pub fn with_missing(self, color: [u8; 3], icc_profile: Option<&str>) -> Result<Colors, String> {
+        let (mut lut, icc) = match self.lut {
+            Some(lut) => (lut, self.icc),
+            None => (Lut::gray(), rgb_profile(icc_profile)?)
+        };
+        lut.0[MISSING as usize] = color;
+        Ok(Colors { lut: Some(lut), icc, ..self })
+    }
+
+    /// 灰色の画像を灰色のまま、プロファイルも付けずに書くか
+    pub fn is_plain(&self) -> bool {
+        self.lut.is_none() && self.icc.is_none() && self.alpha.is_none()
+    }
+
+    /// 灰色の画像 `frame` の各点の色。パレットが無ければ灰色のまま
+    pub fn rgb(&self, frame: &FrameBuffer<Gray8>) -> FrameBuffer<Rgb8> {
+        let gray = Lut::gray();
+        let lut = self.lut.as_ref().unwrap_or(&gray);
+        frame.map(|Gray8(value)| Rgb8(lut.0[value as usize]))
+    }
+
+    /// `rgb` に不透明度を足したもの。透明にする点が無ければ全て不透明
+    pub fn rgba(&self, frame: &FrameBuffer<Gray8>) -> FrameBuffer<Rgba8> {
+        let gray = Lut::gray();
+        let lut = self.lut.as_ref().unwrap_or(&gray);
+        frame.map(|Gray8(value)| {
+            let [r, g, b] = lut.0[value as usize];
+            Rgba8([r, g, b, self.alpha.map_or(255, |alpha| alpha.opacity(value))])
+        })
+    }
+
+    /// 1ピクセルのサンプルの数。灰色か RGB に、透明にするなら不透明度が続く
+    pub fn channels(&self) -> usize {
+        (if self.lut.is_some() { 3 } else { 1 }) + self.alpha.is_some() as usize
+    }
+
+    /// 灰色の画像 `pixels` を書き出す形式のサンプルにする。灰色で不透明ならそのまま
+    pub fn samples<'a>(&self, pixels: &'a [u8]) -> std::borrow::Cow<'a, [u8]> {
+        match (&self.lut, self.alpha) {
+            (Some(lut), None) => {
+                let mut rgb = Vec::with_capacity(pixels.len() * 3);
+                lut.extend_rgb(pixels, &mut rgb);
+                rgb.into()
+            }
+            (Some(lut), Some(alpha)) => {
+                pixels.iter().flat_map(|&gray| {
+                    let [r, g, b] = lut.0[gray as usize];
+                    [r, g, b, alpha.opacity(gray)]
+                }).collect::<Vec<u8>>().into()
+            }
+            (None, Some(alpha)) => pixels.iter().flat_map(|&gray| [gray, alpha.opacity(gray)]).collect::<Vec<u8>>().into(),
+            (None, None) => pixels.into()
+        }
+    }
+}
+
+#[test]
+fn test_resolve_colors() {
+    assert_eq!(Colors::resolve(None, None, None), Ok(Colors::default()));
+    assert_eq!(Colors::resolve(Some("gray"), Some(InterpSpace::Lch), None), Ok(Colors::default()));
+    let fire = Colors::resolve(Some("fire"), None, None).unwrap();
+    assert_eq!(fire.lut, Some(Palette::builtin("fire").unwrap().lut(InterpSpace::Oklab)));
+    assert_eq!(fire.icc, Some(icc::srgb()));
+    assert_eq!(Colors::resolve(Some("fire"), None, Some("none")).unwrap().icc, None);
+    assert_eq!(Colors::resolve(None, None, Some("srgb")).unwrap().icc, Some(icc::srgb_gray()));
+    assert!(Colors::resolve(Some("fire"), None, Some("/nonexistent.icc")).is_err());
+    assert_eq!(fire.samples(&[255, 0]).len(), 6);
+
+    assert!(Alpha::from_str("background").is_err());
+    let transparent = |alpha, lut| Colors { lut, alpha: Some(alpha), ..Colors::default() };
+    // 内部は明るさ 0 の点
+    assert_eq!(transparent(Alpha::Interior, None).samples(&[200, 0]).into_owned(), vec![200, 255, 0, 0]);
+    assert_eq!(transparent(Alpha::Exterior, None).samples(&[200, 0]).into_owned(), vec![200, 0, 0, 255]);
+    let rgba = transparent(Alpha::Exterior, fire.lut.clone());
+    assert_eq!(rgba.channels(), 4);
+    assert_eq!(rgba.samples(&[0]).into_owned(), vec![0, 0, 0, 255]);
+    assert!(!rgba.is_plain());
+    let frame = FrameBuffer::gray((2, 1), &[0, 255]);
+    // samples と同じ色と不透明度になる
+    assert_eq!(rgba.rgba(&frame).pixels.iter().flat_map(|&Rgba8(rgba)| rgba).collect::<Vec<u8>>(),
+               rgba.samples(&[0, 255]).into_owned());
+    assert_eq!(Colors::default().rgb(&frame).pixels, vec![Rgb8([0; 3]), Rgb8([255; 3])]);
+    assert_eq!(fire.rgb(&frame).pixels[1], Rgb8(fire.lut.as_ref().unwrap().0[255]));
+
+    // 灰色のままでも RGB にして、描画を諦めた点だけ色を変える
+    let missing = Colors::default().with_missing([255, 0, 255], None).unwrap();
+    assert_eq!(missing.samples(&[254, MISSING]).into_owned(), vec![254, 254, 254, 255, 0, 255]);
+    assert_eq!(missing.icc, Some(icc::srgb()));
+    assert_eq!(fire.clone().with_missing([0, 255, 0], Some("none")).unwrap().icc, fire.icc);
+}
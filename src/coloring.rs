@@ -0,0 +1,272 @@
+//! 発散までの回数以外の軌道の性質を使う色付け
+//!
+//! 既定の `escape` は発散までの回数だけで明るさを決めるので、軌道の途中の値を残さない。
+//! それ以外の色付けでは `Orbit::tracked` で反復中の値を `OrbitStats` に集めながら反復し、
+//! 軌道全体を見てから明るさを決める。
+//!
+//! 内部の点は既定では黒く塗るが、`--interior de` では境界までの距離の見積もりで塗り、境界に近いほど明るくする。
+//! 明るさは距離をピクセルの大きさで割って決めるので、拡大しても同じように見える。
+
+use std::str::FromStr;
+
+use mandelbrot::{interior_distance, shade};
+use num::Complex;
+
+use super::layers;
+use super::{Orbit, RenderParams};
+
+/// Pickover の茎 (stalks) と見なす、軸からの距離の幅
+const STALK_WIDTH: f64 = 0.05;
+
+/// `--interior de` で、境界からこのピクセル数だけ離れた内部の点を黒にする
+const INTERIOR_FADE_PIXELS: f64 = 64.0;
+
+/// 内部の点のサイクルを探す反復回数の下限。境界の近くではサイクルへの収束が遅い
+pub const INTERIOR_MIN_ITERATIONS: u32 = 1000;
+
+/// `--coloring` で選ぶ色付けの方法
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Coloring {
+    /// 発散までの回数
+    Escape,
+    /// 軌道が実軸と虚軸に最も近づいた距離 (Pickover stalks)
+    Stalks,
+    /// `|z|` が最小になった反復の番号 (atom domains)。番号は双曲成分の周期に対応する
+    AtomDomains,
+    /// 発散したときの `z` の虚部の符号と反復回数の偶奇 (binary decomposition)
+    Binary
+}
+
+impl FromStr for Coloring {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Coloring, String> {
+        match s {
+            "escape" => Ok(Coloring::Escape),
+            "stalks" => Ok(Coloring::Stalks),
+            "atom-domains" => Ok(Coloring::AtomDomains),
+            "binary" => Ok(Coloring::Binary),
+            _ => Err(format!("unknown coloring '{}', expected escape, stalks, atom-domains or binary", s))
+        }
+    }
+}
+
+/// `--interior` で選ぶ内部の点の塗り方
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Interior {
+    /// 黒
+    #[default]
+    Black,
+    /// 境界までの距離の見積もり。Mandelbrot 集合だけ
+    Distance
+}
+
+impl FromStr for Interior {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Interior, String> {
+        match s {
+            "black" => Ok(Interior::Black),
+            "de" => Ok(Interior::Distance),
+            _ => Err(format!("unknown interior shading '{}', expected black or de", s))
+        }
+    }
+}
+
+/// 内部の点 `c` を、大きさ `pixel_size` のピクセルで境界までの距離に応じて塗った明るさ
+fn interior_shade(c: Complex<f64>, limit: u32, pixel_size: f64) -> u8 {
+    match interior_distance(c, limit.max(INTERIOR_MIN_ITERATIONS)) {
+        Some(distance) => {
+            let t = (distance / (pixel_size * INTERIOR_FADE_PIXELS)).min(1.0);
+            (255.0 * (1.0 - t.sqrt())).round() as u8
+        }
+        None => 0
+    }
+}
+
+/// 反復中に集める軌道の統計
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OrbitStats {
+    /// `z_1` 以降の `z` が実軸か虚軸に最も近づいた距離
+    pub axis_distance: f64,
+    /// `z_1` 以降の `|z|^2` の最小値
+    pub min_norm_sqr: f64,
+    /// `|z|` が最小になった `z_n` の `n`
+    pub min_norm_iteration: u32
+}
+
+impl Default for OrbitStats {
+    fn default() -> OrbitStats {
+        OrbitStats {
+            axis_distance: f64::INFINITY,
+            min_norm_sqr: f64::INFINITY,
+            min_norm_iteration: 0
+        }
+    }
+}
+
+impl OrbitStats {
+    /// 反復で得た `z_n` を1つ取り込む
+    pub fn observe(&mut self, z: Complex<f64>, n: u32) {
+        self.axis_distance = self.axis_distance.min(z.re.abs().min(z.im.abs()));
+        let norm_sqr = z.norm_sqr();
+        if norm_sqr < self.min_norm_sqr {
+            self.min_norm_sqr = norm_sqr;
+            self.min_norm_iteration = n;
+        }
+    }
+}
+
+/// 色付けに必要な分だけ残した、反復を終えた軌道
+pub struct Traced {
+    /// 発散までの回数。発散しなかったら `None`
+    pub count: Option<u32>,
+    /// 発散した点では、脱出半径を越えた直後の `z`
+    pub escaped_z: Option<Complex<f64>>,
+    pub stats: OrbitStats
+}
+
+/// 点 `c` を全てのパスの上限まで反復し、行った反復の回数と一緒に返す
+pub fn trace(c: Complex<f64>, params: &RenderParams) -> (Traced, u64) {
+    let mut orbit = Orbit::tracked(c);
+    let mut count = None;
+    for &limit in &params.limits {
+        count = orbit.advance(limit, params.fractal, &params.termination);
+        if count.is_some() || orbit.interior {
+            break;
+        }
+    }
+    let traced = Traced {
+        count,
+        escaped_z: count.map(|_| orbit.z),
+        stats: orbit.stats.unwrap()
+    };
+    (traced, orbit.iteration as u64)
+}
+
+impl Traced {
+    /// `coloring` で決めた明るさ
+    pub fn value(&self, coloring: Coloring, limit: u32) -> u8 {
+        match coloring {
+            Coloring::Escape => shade(self.count, limit),
+            Coloring::Stalks => {
+                // 軸に近づいた軌道ほど明るい。茎は集合の内側にも外側にも現れる
+                let closeness = 1.0 - (self.stats.axis_distance / STALK_WIDTH).min(1.0);
+                (closeness * 255.0).round() as u8
+            }
+            Coloring::AtomDomains => {
+                // 黄金比ずつ明るさをずらし、隣り合う周期の領域を見分けやすくする
+                let level = (self.stats.min_norm_iteration as f64 * 0.618_033_988_75).fract();
+                (level * 255.0).round() as u8
+            }
+            Coloring::Binary => match (self.count, self.escaped_z) {
+                // 回数の帯ごとに白黒を入れ替えると、帯の境目で二分木状の模様が繋がる
+                (Some(count), Some(z)) => if (z.im >= 0.0) != (count % 2 == 1) { 255 } else { 0 },
+                _ => 0
+            }
+        }
+    }
+
+    /// 外側に貼るテクスチャの座標。横は脱出角、縦は連続化した反復回数で、帯1本で 1.0 進む。
+    /// 発散しなかった点や、連続化できない半径 1 以下の `radius` では `None`
+    pub fn texture_coordinates(&self, radius: f64) -> Option<(f64, f64)> {
+        let (count, z) = (self.count?, self.escaped_z?);
+        if radius <= 1.0 {
+            return None;
+        }
+        let u = z.im.atan2(z.re) / (2.0 * std::f64::consts::PI) + 0.5;
+        // |z| が半径から半径の2乗まで進む間に、小数部が 1 から 0 へ滑らかに減る
+        let v = count as f64 + 1.0 - (z.norm().ln() / radius.ln()).log2();
+        Some((u, v))
+    }
+}
+
+/// 点 `c` を `params` のテクスチャ、レイヤー、色付けの順に見て色付けし、行った反復の回数と一緒に返す。
+/// `pixel_size` は `c` のピクセルの複素平面での幅で、`--interior de` で使う
+pub fn color_for_params(c: Complex<f64>, params: &RenderParams, pixel_size: f64) -> (u8, u64) {
+    let (traced, iterations) = trace(c, params);
+    if traced.count.is_none() && params.interior == Interior::Distance {
+        return (interior_shade(c, params.limit(), pixel_size), iterations);
+    }
+    if let Some(texture) = &params.exterior_texture {
+        if let Some(coordinates) = traced.texture_coordinates(params.termination.radius) {
+            return (texture.sample(coordinates, params.texture_mode), iterations);
+        }
+    }
+    let value = if params.layers.is_empty() && params.coloring == Coloring::Escape {
+        params.shade(traced.count)
+    } else if params.layers.is_empty() {
+        traced.value(params.coloring, params.limit())
+    } else {
+        layers::blend(&traced, &params.layers, params.limit())
+    };
+    (value, iterations)
+}
+
+#[test]
+fn test_coloring_from_str() {
+    assert_eq!(Coloring::from_str("escape"), Ok(Coloring::Escape));
+    assert_eq!(Coloring::from_str("stalks"), Ok(Coloring::Stalks));
+    assert_eq!(Coloring::from_str("atom-domains"), Ok(Coloring::AtomDomains));
+    assert_eq!(Coloring::from_str("binary"), Ok(Coloring::Binary));
+    assert!(Coloring::from_str("rainbow").is_err());
+    assert_eq!(Interior::from_str("de"), Ok(Interior::Distance));
+    assert!(Interior::from_str("white").is_err());
+}
+
+#[test]
+fn test_interior_shade() {
+    let params = RenderParams { interior: Interior::Distance, ..RenderParams::default() };
+    // 周期2の円板の中で、縁に近い点ほど明るい。中心は64ピクセル以上離れているので黒
+    let brightness = |re| color_for_params(Complex { re, im: 0.0 }, &params, 0.002).0;
+    assert!(brightness(-1.24) > brightness(-1.2));
+    assert!(brightness(-1.2) > 0);
+    assert_eq!(brightness(-1.0), 0);
+    // 外部の点は変わらない
+    let c = Complex { re: 0.5, im: 0.3 };
+    assert_eq!(color_for_params(c, &params, 0.001).0, shade(super::escape_time(c, 255), 255));
+}
+
+#[test]
+fn test_traced_value() {
+    let params = RenderParams::default();
+    let value = |c, coloring| trace(c, &params).0.value(coloring, params.limit());
+    // 実軸上の点は軌道がずっと実軸に乗るので、茎として最も明るくなる
+    assert_eq!(value(Complex { re: -1.5, im: 0.0 }, Coloring::Stalks), 255);
+    // 軸から離れたまま発散する点は暗い
+    assert_eq!(value(Complex { re: 1.0, im: 1.0 }, Coloring::Stalks), 0);
+    // 主カージオイドの中では z_1 = c が最も原点に近く、周期2の円板の中心 -1 では z_2 = 0 になる
+    let atom = |c| {
+        let mut orbit = Orbit::tracked(c);
+        orbit.advance(1000, params.fractal, &params.termination);
+        orbit.stats.unwrap().min_norm_iteration
+    };
+    assert_eq!(atom(Complex { re: 0.1, im: 0.1 }), 1);
+    assert_eq!(atom(Complex { re: -1.0, im: 0.0 }), 2);
+    assert_ne!(value(Complex { re: 0.1, im: 0.1 }, Coloring::AtomDomains),
+               value(Complex { re: -1.0, im: 0.0 }, Coloring::AtomDomains));
+
+    // binary は実軸を挟んで白黒が入れ替わり、内部の点は黒
+    let above = value(Complex { re: 0.5, im: 0.3 }, Coloring::Binary);
+    let below = value(Complex { re: 0.5, im: -0.3 }, Coloring::Binary);
+    assert_eq!(above + below, 255);
+    assert_eq!(value(Complex { re: 0.0, im: 0.0 }, Coloring::Binary), 0);
+
+    // 発散した点だけにテクスチャ座標があり、縦の座標の整数部は発散までの回数に近い
+    let (traced, _) = trace(Complex { re: 0.5, im: 0.3 }, &params);
+    let (u, v) = traced.texture_coordinates(2.0).unwrap();
+    assert!((0.0 ..= 1.0).contains(&u));
+    assert!((v - traced.count.unwrap() as f64).abs() <= 1.0);
+    assert!(trace(Complex { re: 0.0, im: 0.0 }, &params).0.texture_coordinates(2.0).is_none());
+    let textured = RenderParams {
+        exterior_texture: Some(std::sync::Arc::new(super::Texture::new(1, 1, vec![77]))),
+        ..RenderParams::default()
+    };
+    assert_eq!(color_for_params(Complex { re: 0.5, im: 0.3 }, &textured, 0.01).0, 77);
+    assert_eq!(color_for_params(Complex { re: 0.0, im: 0.0 }, &textured, 0.01).0, 0);
+
+    // escape は通常の濃淡と同じになる
+    let c = Complex { re: -0.75, im: 0.1 };
+    assert_eq!(value(c, Coloring::Escape),
+               shade(super::escape_time(c, 255), 255));
+}
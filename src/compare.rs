@@ -0,0 +1,168 @@
+//! 2枚の画像を比べる `diff A B` サブコマンド
+//!
+//! 異なるピクセルの数、差の最大と平均、PSNR と SSIM を表示する。バックエンドを替えても同じ画像になるかの確認や、
+//! アンチエイリアスや精度の設定で画質がどれだけ変わったかを数で比べるためのもの。
+//! `--output FILE` を付けると、差の絶対値を `--amplify N` 倍 (既定値: 10) した画像も書き出す。
+//!
+//! SSIM は 8x8 ピクセルの窓を 4 ピクセルずつずらしながら求めた値の平均。
+
+use std::str::FromStr;
+
+use super::{failed, write_image, Failure};
+
+/// SSIM を求める窓の一辺と、窓をずらす幅
+const SSIM_WINDOW: usize = 8;
+const SSIM_STRIDE: usize = 4;
+
+/// 比べた結果
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Comparison {
+    pub differing: usize,
+    pub max_difference: u8,
+    pub mean_difference: f64,
+    /// 同じ画像なら無限大
+    pub psnr: f64,
+    pub ssim: f64
+}
+
+pub fn compare(a: &[u8], b: &[u8], bounds: (usize, usize)) -> Comparison {
+    let differences = a.iter().zip(b).map(|(&a, &b)| (a as i32 - b as i32).unsigned_abs() as u8);
+    let (mut differing, mut max_difference, mut sum, mut squares) = (0, 0, 0u64, 0u64);
+    for difference in differences {
+        differing += (difference != 0) as usize;
+        max_difference = max_difference.max(difference);
+        sum += difference as u64;
+        squares += difference as u64 * difference as u64;
+    }
+    let len = a.len().max(1) as f64;
+    let mse = squares as f64 / len;
+    Comparison {
+        differing,
+        max_difference,
+        mean_difference: sum as f64 / len,
+        psnr: if mse == 0.0 { f64::INFINITY } else { 10.0 * (255.0 * 255.0 / mse).log10() },
+        ssim: ssim(a, b, bounds)
+    }
+}
+
+/// 窓毎の SSIM の平均。窓より小さい画像は全体を1つの窓にする
+fn ssim(a: &[u8], b: &[u8], bounds: (usize, usize)) -> f64 {
+    let (width, height) = bounds;
+    let (window_width, window_height) = (SSIM_WINDOW.min(width), SSIM_WINDOW.min(height));
+    let c1 = (0.01 * 255.0f64).powi(2);
+    let c2 = (0.03 * 255.0f64).powi(2);
+    let (mut total, mut windows) = (0.0, 0);
+    for top in (0 ..= height - window_height).step_by(SSIM_STRIDE) {
+        for left in (0 ..= width - window_width).step_by(SSIM_STRIDE) {
+            let pixels = (top .. top + window_height)
+                .flat_map(|y| (left .. left + window_width).map(move |x| y * width + x));
+            let (mut sa, mut sb, mut saa, mut sbb, mut sab) = (0.0, 0.0, 0.0, 0.0, 0.0);
+            for i in pixels {
+                let (x, y) = (a[i] as f64, b[i] as f64);
+                sa += x;
+                sb += y;
+                saa += x * x;
+                sbb += y * y;
+                sab += x * y;
+            }
+            let n = (window_width * window_height) as f64;
+            let (mean_a, mean_b) = (sa / n, sb / n);
+            let variance_a = saa / n - mean_a * mean_a;
+            let variance_b = sbb / n - mean_b * mean_b;
+            let covariance = sab / n - mean_a * mean_b;
+            total += (2.0 * mean_a * mean_b + c1) * (2.0 * covariance + c2)
+                / ((mean_a * mean_a + mean_b * mean_b + c1) * (variance_a + variance_b + c2));
+            windows += 1;
+        }
+    }
+    total / windows as f64
+}
+
+/// 差の絶対値を `amplify` 倍して 255 で飽和させた画像
+fn difference_image(a: &[u8], b: &[u8], amplify: u32) -> Vec<u8> {
+    a.iter().zip(b)
+        .map(|(&a, &b)| ((a as i32 - b as i32).unsigned_abs().saturating_mul(amplify)).min(255) as u8)
+        .collect()
+}
+
+#[test]
+fn test_compare() {
+    let bounds = (16, 12);
+    let a: Vec<u8> = (0 .. 16 * 12).map(|i| (i * 7 % 256) as u8).collect();
+    let same = compare(&a, &a, bounds);
+    assert_eq!(same, Comparison { differing: 0, max_difference: 0, mean_difference: 0.0,
+                                  psnr: f64::INFINITY, ssim: 1.0 });
+
+    let mut b = a.clone();
+    b[5] = b[5].wrapping_add(16);
+    b[100] = b[100].wrapping_sub(4);
+    let changed = compare(&a, &b, bounds);
+    assert_eq!((changed.differing, changed.max_difference), (2, 16));
+    assert!((changed.mean_difference - 20.0 / 192.0).abs() < 1e-12);
+    // MSE = (256 + 16) / 192
+    assert!((changed.psnr - 10.0 * (255.0f64 * 255.0 * 192.0 / 272.0).log10()).abs() < 1e-9);
+    assert!(changed.ssim < 1.0 && changed.ssim > 0.9);
+
+    // 明暗が反転した画像は構造が逆なので SSIM が負になる
+    let inverted: Vec<u8> = a.iter().map(|&p| 255 - p).collect();
+    assert!(compare(&a, &inverted, bounds).ssim < 0.0);
+    // 窓より小さい画像
+    assert_eq!(ssim(&[10, 20, 30], &[10, 20, 30], (3, 1)), 1.0);
+
+    assert_eq!(difference_image(&[0, 100, 200], &[3, 90, 200], 10), vec![30, 100, 0]);
+    assert_eq!(difference_image(&[0], &[255], 10), vec![255]);
+}
+
+fn open_luma(path: &str) -> Result<(Vec<u8>, (usize, usize)), String> {
+    let image = image::open(path).map_err(|e| format!("cannot read {}: {}", path, e))?.to_luma();
+    let (width, height) = image.dimensions();
+    Ok((image.into_raw(), (width as usize, height as usize)))
+}
+
+/// `diff` の引数を比べる2つの画像、差分画像の出力先と強調の倍率に分ける
+fn parse_diff_args(args: &[String]) -> Result<(&String, &String, Option<String>, u32), String> {
+    let (paths, options) = args.split_at(args.len().min(2));
+    let [first, second] = paths else {
+        return Err("diff expects two images".to_string());
+    };
+    let (mut output, mut amplify) = (None, 10);
+    let mut options = options.iter();
+    while let Some(arg) = options.next() {
+        let mut value = || options.next().ok_or(format!("{} expects a value", arg));
+        match arg.as_str() {
+            "--output" => output = Some(value()?.clone()),
+            "--amplify" => {
+                amplify = u32::from_str(value()?).ok().filter(|n| *n > 0)
+                    .ok_or("--amplify expects a positive integer")?;
+            }
+            _ => return Err(format!("unknown diff option: {}", arg))
+        }
+    }
+    Ok((first, second, output, amplify))
+}
+
+/// `diff A B [--output FILE] [--amplify N]` サブコマンド
+pub fn run_diff(args: &[String]) -> Result<(), Failure> {
+    let (first, second, output, amplify) = parse_diff_args(args).map_err(Failure::Usage)?;
+    let (a, bounds) = open_luma(first).map_err(Failure::Runtime)?;
+    let (b, other_bounds) = open_luma(second).map_err(Failure::Runtime)?;
+    if bounds != other_bounds {
+        return Err(Failure::Runtime(format!("image sizes differ: {}x{} and {}x{}",
+                                            bounds.0, bounds.1, other_bounds.0, other_bounds.1)));
+    }
+    if a.is_empty() {
+        return Err(Failure::Runtime("images are empty".to_string()));
+    }
+
+    let comparison = compare(&a, &b, bounds);
+    println!("differing pixels: {} of {} ({:.4}%)",
+             comparison.differing, a.len(), 100.0 * comparison.differing as f64 / a.len() as f64);
+    println!("max difference:   {}", comparison.max_difference);
+    println!("mean difference:  {:.4}", comparison.mean_difference);
+    println!("PSNR:             {:.2} dB", comparison.psnr);
+    println!("SSIM:             {:.6}", comparison.ssim);
+    if let Some(path) = output {
+        write_image(&path, &difference_image(&a, &b, amplify), bounds).map_err(failed("error writing PNG file"))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,299 @@
+//! bash・zsh・fish の補完スクリプトを書き出す `completions SHELL` サブコマンド
+//!
+//! サブコマンドとオプションは `usage` の説明から拾うので、説明に書き足せば補完にも現れる。
+//! 値の決まっているオプションには `value_hints` の候補を補う。
+//!
+//! ```bash
+//! $ mandelbrot completions bash > ~/.local/share/bash-completion/completions/mandelbrot
+//! $ mandelbrot completions zsh > ~/.zfunc/_mandelbrot
+//! $ mandelbrot completions fish > ~/.config/fish/completions/mandelbrot.fish
+//! ```
+
+use std::fmt::Write;
+
+use super::backend::Backend;
+use super::{color, usage, wallpaper, Failure};
+
+/// 補完するコマンドの名前
+const COMMANDS: &[&str] = &["mandelbrot", "mandelbrot-rewrite"];
+
+/// 補完スクリプトを書ける shell
+const SHELLS: &[&str] = &["bash", "zsh", "fish"];
+
+const FRACTALS: &[&str] = &["mandelbrot", "burning-ship", "celtic", "buffalo", "perpendicular-burning-ship",
+                            "perpendicular-mandelbrot", "magnet-1", "magnet-2", "nova", "collatz"];
+const COLORINGS: &[&str] = &["escape", "stalks", "atom-domains", "binary"];
+const INTERIORS: &[&str] = &["black", "de"];
+const RENDER_MODES: &[&str] = &["full", "boundary"];
+const NORMS: &[&str] = &["euclidean", "real", "imag", "manhattan"];
+const TEXTURE_MODES: &[&str] = &["wrap", "mirror"];
+const PROJECTIONS: &[&str] = &["plane", "sphere"];
+const INTERP_SPACES: &[&str] = &["srgb", "linear", "oklab", "lch"];
+const ALPHAS: &[&str] = &["exterior", "interior"];
+const DEPTH_SOURCES: &[&str] = &["count", "de"];
+
+/// 値の候補を補うオプション
+fn value_hints() -> Vec<(&'static str, Vec<&'static str>)> {
+    vec![
+        ("--fractal", FRACTALS.to_vec()),
+        ("--coloring", COLORINGS.to_vec()),
+        ("--interior", INTERIORS.to_vec()),
+        ("--bailout-norm", NORMS.to_vec()),
+        ("--texture-mode", TEXTURE_MODES.to_vec()),
+        ("--projection", PROJECTIONS.to_vec()),
+        ("--backend", Backend::ALL.iter().map(|backend| backend.name()).collect()),
+        ("--preset", wallpaper::preset_names()),
+        ("--palette", color::palette_names()),
+        ("--interp-space", INTERP_SPACES.to_vec()),
+        ("--alpha", ALPHAS.to_vec()),
+        ("--depth-source", DEPTH_SOURCES.to_vec()),
+        ("--render", RENDER_MODES.to_vec())
+    ]
+}
+
+#[test]
+fn test_value_hints() {
+    use std::str::FromStr;
+    use super::{Coloring, FractalKind, Norm, ProjectionKind, TextureMode};
+
+    // 候補はどれも実際に解析できる
+    assert!(FRACTALS.iter().all(|name| FractalKind::from_str(name).is_ok()));
+    assert!(COLORINGS.iter().all(|name| Coloring::from_str(name).is_ok()));
+    assert!(INTERIORS.iter().all(|name| super::Interior::from_str(name).is_ok()));
+    assert!(NORMS.iter().all(|name| Norm::from_str(name).is_ok()));
+    assert!(TEXTURE_MODES.iter().all(|name| TextureMode::from_str(name).is_ok()));
+    assert!(PROJECTIONS.iter().all(|name| ProjectionKind::from_str(name).is_ok()));
+    assert!(INTERP_SPACES.iter().all(|name| super::color::InterpSpace::from_str(name).is_ok()));
+    assert!(ALPHAS.iter().all(|name| super::color::Alpha::from_str(name).is_ok()));
+    assert!(DEPTH_SOURCES.iter().all(|name| super::depth::Source::from_str(name).is_ok()));
+    assert!(RENDER_MODES.iter().all(|name| super::boundary::Mode::from_str(name).is_ok()));
+    let options: Vec<String> = options().into_iter().map(|option| option.name).collect();
+    assert!(value_hints().iter().all(|(name, _)| options.iter().any(|option| option == name)));
+}
+
+/// 使い方の説明に書かれたオプション
+#[derive(Debug, PartialEq)]
+struct CliOption {
+    name: String,
+    /// 値を取るならその名前 (`N` や `FILE`)
+    value: Option<String>,
+    description: String
+}
+
+/// 使い方の説明の行 `line` に現れる `--name` とその直後の値の名前
+fn options_in(line: &str) -> Vec<(String, Option<String>)> {
+    let mut found = vec![];
+    let mut rest = line;
+    while let Some(start) = rest.find("--") {
+        let after = &rest[start + 2 ..];
+        let length = after.find(|c: char| !(c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-'))
+            .unwrap_or(after.len());
+        let name = &after[.. length];
+        rest = &after[length ..];
+        if !name.starts_with(|c: char| c.is_ascii_lowercase()) {
+            continue;
+        }
+        // 空白1つを挟んで大文字で始まる語が値の名前。2つ以上空ければ説明が続く
+        let value = rest.strip_prefix(' ')
+            .filter(|value| value.starts_with(|c: char| c.is_ascii_uppercase()))
+            .map(|value| value.split([' ', ']']).next().unwrap().to_string());
+        found.push((format!("--{}", name), value));
+    }
+    found
+}
+
+/// 使い方の説明に書かれた全てのオプション。オプションの一覧の行からは説明も拾う
+fn options() -> Vec<CliOption> {
+    let mut options: Vec<CliOption> = vec![];
+    for line in usage("mandelbrot").lines() {
+        let listed = line.starts_with("    --");
+        for (i, (name, value)) in options_in(line).into_iter().enumerate() {
+            let description = if listed && i == 0 {
+                let skip = name.len() + value.as_ref().map_or(0, |value| value.len() + 1);
+                line.trim_start()[skip ..].trim().to_string()
+            } else {
+                String::new()
+            };
+            match options.iter_mut().find(|option| option.name == name) {
+                Some(option) => {
+                    if option.value.is_none() {
+                        option.value = value;
+                    }
+                    if option.description.is_empty() {
+                        option.description = description;
+                    }
+                }
+                None => options.push(CliOption { name, value, description })
+            }
+        }
+    }
+    options
+}
+
+/// 使い方の説明に書かれたサブコマンド
+fn subcommands() -> Vec<String> {
+    let mut names: Vec<String> = vec![];
+    for line in usage("mandelbrot").lines() {
+        let synopsis = line.strip_prefix("Usage: ").unwrap_or(line).trim_start();
+        let name = synopsis.strip_prefix("mandelbrot ").and_then(|rest| rest.split(' ').next());
+        if let Some(name) = name.filter(|name| name.starts_with(|c: char| c.is_ascii_lowercase())) {
+            if !names.iter().any(|known| known == name) {
+                names.push(name.to_string());
+            }
+        }
+    }
+    names
+}
+
+#[test]
+fn test_usage_entries() {
+    assert_eq!(options_in("    --passes N,N,...    反復回数"), vec![("--passes".to_string(), Some("N,N,...".to_string()))]);
+    assert_eq!(options_in("    --lossless          FILE が .webp"), vec![("--lossless".to_string(), None)]);
+    assert_eq!(options_in("mandelbrot render-batch JOBS.toml [--jobs N] [--watch]"),
+               vec![("--jobs".to_string(), Some("N".to_string())), ("--watch".to_string(), None)]);
+
+    let options = options();
+    let find = |name: &str| options.iter().find(|option| option.name == name).unwrap();
+    assert_eq!(find("--nova-relaxation").value.as_deref(), Some("R"));
+    assert_eq!(find("--nova-relaxation").description, "nova の緩和係数 (既定値: 1.0)");
+    assert_eq!(find("--dry-run").value, None);
+    assert_eq!(find("--count").value.as_deref(), Some("N"));
+    assert_eq!(options.iter().filter(|option| option.name == "--zoom").count(), 1);
+
+    let subcommands = subcommands();
+    for name in ["worker", "serve-api", "render-batch", "wallpaper", "completions"] {
+        assert!(subcommands.iter().any(|known| known == name), "{}", name);
+    }
+    assert!(!subcommands.iter().any(|known| known == "FILE"));
+}
+
+fn bash() -> String {
+    let mut script = String::new();
+    let names = |options: &[CliOption]| options.iter().map(|option| option.name.as_str()).collect::<Vec<_>>().join(" ");
+    let options = options();
+    writeln!(script, "_mandelbrot() {{").unwrap();
+    writeln!(script, "    local cur=\"${{COMP_WORDS[COMP_CWORD]}}\" prev=\"${{COMP_WORDS[COMP_CWORD-1]}}\"").unwrap();
+    writeln!(script, "    COMPREPLY=()").unwrap();
+    writeln!(script, "    case \"$prev\" in").unwrap();
+    for (name, values) in value_hints() {
+        writeln!(script, "        {}) COMPREPLY=($(compgen -W \"{}\" -- \"$cur\")); return ;;", name, values.join(" ")).unwrap();
+    }
+    writeln!(script, "        completions) COMPREPLY=($(compgen -W \"{}\" -- \"$cur\")); return ;;", SHELLS.join(" ")).unwrap();
+    writeln!(script, "    esac").unwrap();
+    writeln!(script, "    if [[ $cur == -* ]]; then").unwrap();
+    writeln!(script, "        COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))", names(&options)).unwrap();
+    writeln!(script, "    elif [[ $COMP_CWORD -eq 1 ]]; then").unwrap();
+    writeln!(script, "        COMPREPLY=($(compgen -W \"{}\" -- \"$cur\") $(compgen -f -- \"$cur\"))",
+             subcommands().join(" ")).unwrap();
+    writeln!(script, "    else").unwrap();
+    writeln!(script, "        COMPREPLY=($(compgen -f -- \"$cur\"))").unwrap();
+    writeln!(script, "    fi").unwrap();
+    writeln!(script, "}}").unwrap();
+    writeln!(script, "complete -o filenames -F _mandelbrot {}", COMMANDS.join(" ")).unwrap();
+    script
+}
+
+/// 説明を `'` で囲んで zsh と fish に渡せるようにする
+fn quote(description: &str) -> String {
+    description.replace('\'', "'\\''")
+}
+
+fn zsh() -> String {
+    let mut script = String::new();
+    writeln!(script, "#compdef {}", COMMANDS.join(" ")).unwrap();
+    writeln!(script).unwrap();
+    writeln!(script, "_mandelbrot() {{").unwrap();
+    writeln!(script, "    local -a options subcommands").unwrap();
+    writeln!(script, "    case $words[CURRENT-1] in").unwrap();
+    for (name, values) in value_hints() {
+        writeln!(script, "        {}) compadd -- {}; return ;;", name, values.join(" ")).unwrap();
+    }
+    writeln!(script, "        completions) compadd -- {}; return ;;", SHELLS.join(" ")).unwrap();
+    writeln!(script, "    esac").unwrap();
+    writeln!(script, "    if [[ $PREFIX == -* ]]; then").unwrap();
+    writeln!(script, "        options=(").unwrap();
+    for option in options() {
+        let description = match option.value {
+            Some(ref value) if option.description.is_empty() => value.clone(),
+            Some(ref value) => format!("{} {}", value, option.description),
+            None => option.description.clone()
+        };
+        writeln!(script, "            '{}:{}'", option.name, quote(&description)).unwrap();
+    }
+    writeln!(script, "        )").unwrap();
+    writeln!(script, "        _describe option options").unwrap();
+    writeln!(script, "    elif (( CURRENT == 2 )); then").unwrap();
+    writeln!(script, "        subcommands=({})", subcommands().join(" ")).unwrap();
+    writeln!(script, "        compadd -- $subcommands").unwrap();
+    writeln!(script, "        _files").unwrap();
+    writeln!(script, "    else").unwrap();
+    writeln!(script, "        _files").unwrap();
+    writeln!(script, "    fi").unwrap();
+    writeln!(script, "}}").unwrap();
+    writeln!(script).unwrap();
+    // fpath から自動で読まれたときは補完を実行し、source されたときは登録する
+    writeln!(script, "if [[ $zsh_eval_context[-1] == loadautofunc ]]; then").unwrap();
+    writeln!(script, "    _mandelbrot \"$@\"").unwrap();
+    writeln!(script, "else").unwrap();
+    writeln!(script, "    compdef _mandelbrot {}", COMMANDS.join(" ")).unwrap();
+    writeln!(script, "fi").unwrap();
+    script
+}
+
+fn fish() -> String {
+    let mut script = String::new();
+    let hints = value_hints();
+    for command in COMMANDS {
+        writeln!(script, "complete -c {} -n __fish_use_subcommand -a '{}'", command, subcommands().join(" ")).unwrap();
+        writeln!(script, "complete -c {} -n '__fish_seen_subcommand_from completions' -x -a '{}'",
+                 command, SHELLS.join(" ")).unwrap();
+        for option in options() {
+            write!(script, "complete -c {} -l {}", command, &option.name[2 ..]).unwrap();
+            if let Some((_, values)) = hints.iter().find(|(name, _)| *name == option.name) {
+                write!(script, " -x -a '{}'", values.join(" ")).unwrap();
+            } else if option.value.is_some() {
+                write!(script, " -r").unwrap();
+            }
+            if !option.description.is_empty() {
+                write!(script, " -d '{}'", quote(&option.description)).unwrap();
+            }
+            writeln!(script).unwrap();
+        }
+    }
+    script
+}
+
+fn script(shell: &str) -> Result<String, String> {
+    match shell {
+        "bash" => Ok(bash()),
+        "zsh" => Ok(zsh()),
+        "fish" => Ok(fish()),
+        _ => Err(format!("unknown shell {}, expected {}", shell, SHELLS.join(", ")))
+    }
+}
+
+#[test]
+fn test_script() {
+    let bash = script("bash").unwrap();
+    assert!(bash.contains("--fractal) COMPREPLY=($(compgen -W \"mandelbrot burning-ship celtic"));
+    assert!(bash.contains("complete -o filenames -F _mandelbrot mandelbrot mandelbrot-rewrite"));
+    let zsh = script("zsh").unwrap();
+    assert!(zsh.starts_with("#compdef mandelbrot mandelbrot-rewrite\n"));
+    assert!(zsh.contains("'--dry-run:描画せずにパラメータとコストの見積もりを表示する'"));
+    assert!(zsh.contains("--preset) compadd -- whole seahorse-valley"));
+    let fish = script("fish").unwrap();
+    assert!(fish.contains("complete -c mandelbrot -l coloring -x -a 'escape stalks atom-domains binary'"));
+    assert!(fish.contains("complete -c mandelbrot-rewrite -l passes -r -d '反復回数の上限を段階的に引き上げて描画する (既定値: 255)'"));
+    assert!(script("powershell").is_err());
+}
+
+/// `completions SHELL` サブコマンド
+pub fn run_completions(args: &[String]) -> Result<(), Failure> {
+    match args {
+        [shell] => {
+            print!("{}", script(shell).map_err(Failure::Usage)?);
+            Ok(())
+        }
+        _ => Err(Failure::Usage(format!("completions expects a shell: {}", SHELLS.join(", "))))
+    }
+}
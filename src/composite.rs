@@ -0,0 +1,127 @@
+//! 描いた画像を背景の画像に重ねる `--composite-over`
+//!
+//! 描いた灰色の画像に `--palette` の色を付け、`--alpha` (既定値: `exterior`) で透明にした点を抜いてから、
+//! 背景の画像の `--composite-offset X,Y` の位置に `--composite-scale S` 倍の大きさで重ねる。
+//! 背景からはみ出した部分は捨てる。重ねた結果は背景と同じ大きさの RGBA の PNG になる。
+//! 倍率を変えるときは最も近いピクセルを取る。
+
+use std::str::FromStr;
+
+use rayon::prelude::*;
+
+use super::color::{Alpha, Colors};
+use super::frame::{FrameBuffer, Gray8, Rgba8};
+
+/// 背景のどこにどの大きさで重ねるか
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Placement {
+    /// 描いた画像の左上を置く背景のピクセル
+    pub offset: (i64, i64),
+    /// 描いた画像の1ピクセルを背景の何ピクセルにするか
+    pub scale: f64
+}
+
+impl Default for Placement {
+    fn default() -> Placement {
+        Placement { offset: (0, 0), scale: 1.0 }
+    }
+}
+
+/// 倍率。正の有限の数でなければならない
+pub fn parse_scale(s: &str) -> Result<f64, String> {
+    f64::from_str(s).ok().filter(|scale| *scale > 0.0 && scale.is_finite())
+        .ok_or(format!("--composite-scale expects a positive number: {}", s))
+}
+
+/// 色を付けて透明にする点を抜いた、重ねる RGBA の画像
+pub fn layer(frame: &FrameBuffer<Gray8>, colors: &Colors) -> FrameBuffer<Rgba8> {
+    Colors { alpha: Some(colors.alpha.unwrap_or(Alpha::Exterior)), ..colors.clone() }.rgba(frame)
+}
+
+/// 重ねる先の RGBA の画像
+#[derive(Debug, PartialEq)]
+pub struct Backdrop {
+    pub frame: FrameBuffer<Rgba8>
+}
+
+impl Backdrop {
+    /// `image` が読める形式の画像を読み込む
+    pub fn open(path: &str) -> Result<Backdrop, String> {
+        let image = image::open(path)
+            .map_err(|e| format!("cannot read background {}: {}", path, e))?
+            .to_rgba();
+        let (width, height) = image.dimensions();
+        if width == 0 || height == 0 {
+            return Err(format!("background {} is empty", path));
+        }
+        Ok(Backdrop { frame: FrameBuffer::from_rgba((width as usize, height as usize), &image.into_raw()) })
+    }
+
+    /// RGBA の画像 `layer` を `placement` に置いて重ねる
+    pub fn over(&mut self, layer: &FrameBuffer<Rgba8>, placement: Placement) {
+        let (offset, scale, bounds) = (placement.offset, placement.scale, layer.bounds);
+        // 背景のピクセルの中心が描いた画像のどのピクセルに入るか
+        let source = |at: usize, offset: i64, len: usize| {
+            let v = ((at as i64 - offset) as f64 + 0.5) / scale;
+            if v >= 0.0 && v < len as f64 { Some(v as usize) } else { None }
+        };
+        let width = self.frame.bounds.0;
+        self.frame.pixels.par_chunks_mut(width).enumerate().for_each(|(y, line)| {
+            let row = match source(y, offset.1, bounds.1) {
+                Some(row) => layer.row(row),
+                None => return
+            };
+            for (x, pixel) in line.iter_mut().enumerate() {
+                if let Some(column) = source(x, offset.0, bounds.0) {
+                    blend(&mut pixel.0, &row[column].0);
+                }
+            }
+        });
+    }
+}
+
+/// `top` を `bottom` の上に重ねる (Porter-Duff の over)
+fn blend(bottom: &mut [u8], top: &[u8]) {
+    let (a, b) = (top[3] as f64 / 255.0, bottom[3] as f64 / 255.0);
+    let alpha = a + b * (1.0 - a);
+    if alpha == 0.0 {
+        return;
+    }
+    for i in 0 .. 3 {
+        let value = (top[i] as f64 * a + bottom[i] as f64 * b * (1.0 - a)) / alpha;
+        bottom[i] = value.round() as u8;
+    }
+    bottom[3] = (alpha * 255.0).round() as u8;
+}
+
+#[test]
+fn test_composite_over() {
+    let mut opaque = [10, 20, 30, 255];
+    blend(&mut opaque, &[200, 100, 0, 0]);
+    assert_eq!(opaque, [10, 20, 30, 255]);
+    blend(&mut opaque, &[200, 100, 0, 255]);
+    assert_eq!(opaque, [200, 100, 0, 255]);
+    // 透明な背景には重ねたものがそのまま残り、半透明どうしは不透明度が増える
+    let mut clear = [0, 0, 0, 0];
+    blend(&mut clear, &[90, 80, 70, 128]);
+    assert_eq!(clear, [90, 80, 70, 128]);
+    blend(&mut clear, &[90, 80, 70, 128]);
+    assert_eq!(clear, [90, 80, 70, 192]);
+
+    // 内部が黒で外部が透明な 2x1 の画像を、4x4 の白い背景の (1, 1) に2倍で重ねる
+    let layer = layer(&FrameBuffer::gray((2, 1), &[0, 200]), &Colors::default());
+    assert_eq!(layer.pixels, vec![Rgba8([0, 0, 0, 255]), Rgba8([200, 200, 200, 0])]);
+    let mut backdrop = Backdrop { frame: FrameBuffer::from_pixels((4, 4), vec![Rgba8([255; 4]); 4 * 4]) };
+    backdrop.over(&layer, Placement { offset: (1, 1), scale: 2.0 });
+    let black: Vec<usize> = (0 .. 16).filter(|&i| backdrop.frame.pixels[i].0[0] == 0).collect();
+    assert_eq!(black, vec![5, 6, 9, 10]);
+    // はみ出した部分は捨てる
+    backdrop.over(&layer, Placement { offset: (3, 3), scale: 1.0 });
+    assert_eq!(backdrop.frame.pixels[15], Rgba8([0, 0, 0, 255]));
+    backdrop.over(&layer, Placement { offset: (-2, -1), scale: 1.0 });
+    assert_eq!(backdrop.frame.pixels.iter().flat_map(|pixel| pixel.0).filter(|&v| v == 0).count(), 5 * 3);
+
+    assert_eq!(parse_scale("0.5"), Ok(0.5));
+    assert!(parse_scale("0").is_err());
+    assert!(Backdrop::open("/nonexistent/background.png").is_err());
+}
@@ -0,0 +1,117 @@
+//! 環境変数による設定
+//!
+//! 設定は 既定値 < 環境変数 < コマンドライン の順に重ねる。オプション `--foo-bar` には
+//! 環境変数 `MANDELBROT_FOO_BAR` が対応し、その値をコマンドラインの前に置いたのと同じになる。
+//! 後に書いたオプションが優先するので、コマンドラインで指定すれば環境変数の値は上書きされる。
+//! フラグのオプションは `1`、`true`、`yes`、`on` のとき有効になる。空の値は設定しないのと同じ。
+//! コンテナでは引数を変えずに環境変数だけで設定できる。
+//!
+//! ```bash
+//! $ MANDELBROT_BACKEND=atomic MANDELBROT_THREADS=4 mandelbrot mandel.png 1000x750 -2,1 1,-1
+//! ```
+
+/// 環境変数で設定できるオプション
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Setting {
+    /// 値を取るオプション
+    Value(&'static str),
+    /// 値を取らないオプション
+    Flag(&'static str)
+}
+
+/// 描画パラメータ (`parse_params`)
+pub const RENDER: &[Setting] = &[
+    Setting::Value("--passes"),
+    Setting::Value("--fractal"),
+    Setting::Value("--coloring"),
+    Setting::Value("--bailout"),
+    Setting::Value("--backend"),
+    Setting::Value("--threads"),
+    Setting::Value("--bands"),
+    Setting::Value("--chunk-size"),
+    Setting::Flag("--pin-threads"),
+    Setting::Value("--cores"),
+    Setting::Flag("--interior-check")
+];
+
+/// `serve-api` のオプション
+pub const SERVER: &[Setting] = &[
+    Setting::Value("--max-connections"),
+    Setting::Value("--max-concurrent"),
+    Setting::Value("--max-queue"),
+    Setting::Value("--rate-limit"),
+    Setting::Value("--timeout"),
+    Setting::Value("--header-timeout"),
+    Setting::Value("--max-size"),
+    Setting::Value("--max-iters"),
+    Setting::Value("--iteration-budget"),
+    Setting::Value("--cache-dir"),
+    Setting::Value("--cache-size")
+];
+
+impl Setting {
+    fn option(self) -> &'static str {
+        match self {
+            Setting::Value(option) | Setting::Flag(option) => option
+        }
+    }
+
+    /// 対応する環境変数の名前
+    pub fn variable(self) -> String {
+        format!("MANDELBROT_{}", self.option()[2 ..].to_uppercase().replace('-', "_"))
+    }
+}
+
+/// 環境変数 `vars` の `settings` をオプションに直し、その後に `args` を続ける
+pub fn layered<I>(settings: &[Setting], args: &[String], vars: I) -> Result<Vec<String>, String>
+    where I: IntoIterator<Item = (String, String)>
+{
+    let vars: Vec<(String, String)> = vars.into_iter().collect();
+    let mut layered = vec![];
+    for &setting in settings {
+        let variable = setting.variable();
+        let value = match vars.iter().find(|(name, _)| *name == variable) {
+            Some((_, value)) if !value.is_empty() => value,
+            _ => continue
+        };
+        match setting {
+            Setting::Value(option) => layered.extend([option.to_string(), value.clone()]),
+            Setting::Flag(option) => match value.to_lowercase().as_str() {
+                "1" | "true" | "yes" | "on" => layered.push(option.to_string()),
+                "0" | "false" | "no" | "off" => {}
+                _ => return Err(format!("{} expects 1 or 0, got {}", variable, value))
+            }
+        }
+    }
+    layered.extend_from_slice(args);
+    Ok(layered)
+}
+
+/// 実際の環境変数で `layered` する
+pub fn from_environment(settings: &[Setting], args: &[String]) -> Result<Vec<String>, String> {
+    layered(settings, args, std::env::vars())
+}
+
+#[test]
+fn test_layered() {
+    let vars = |pairs: &[(&str, &str)]| -> Vec<(String, String)> {
+        pairs.iter().map(|&(name, value)| (name.to_string(), value.to_string())).collect()
+    };
+    let args = |s: &str| -> Vec<String> { s.split_whitespace().map(String::from).collect() };
+    assert_eq!(Setting::Value("--max-concurrent").variable(), "MANDELBROT_MAX_CONCURRENT");
+
+    // 環境変数はコマンドラインの前に置くので、同じオプションならコマンドラインが優先する
+    assert_eq!(layered(RENDER, &args("--threads 8"),
+                       vars(&[("MANDELBROT_THREADS", "2"), ("MANDELBROT_PIN_THREADS", "yes"),
+                              ("MANDELBROT_BANDS", ""), ("MANDELBROT_CACHE_DIR", "/tmp"), ("HOME", "/root")])),
+               Ok(args("--threads 2 --pin-threads --threads 8")));
+    assert_eq!(layered(SERVER, &[], vars(&[("MANDELBROT_CACHE_DIR", "/var/cache/mandelbrot")])),
+               Ok(args("--cache-dir /var/cache/mandelbrot")));
+    assert_eq!(layered(RENDER, &[], vars(&[("MANDELBROT_INTERIOR_CHECK", "0")])), Ok(vec![]));
+    assert!(layered(RENDER, &[], vars(&[("MANDELBROT_PIN_THREADS", "maybe")])).is_err());
+
+    let params = super::parse_params_exact(&layered(RENDER, &args("--passes 64"),
+                                                    vars(&[("MANDELBROT_PASSES", "32"), ("MANDELBROT_THREADS", "3")]))
+                                           .unwrap()).unwrap();
+    assert_eq!((params.limits, params.scheduling.threads), (vec![64], Some(3)));
+}
@@ -0,0 +1,115 @@
+//! 外部の帯を繋がった領域毎に塗り分ける `--contour-map`
+//!
+//! 描いた画像で同じ明るさ (同じ発散までの回数) のピクセルが上下左右に繋がった領域を1つの帯とみなし、
+//! 帯毎に黄金比ずつずらした明るさで塗り直す。隣り合う帯が必ず違う明るさになるので、等高線図のように見える。
+//! 内部の点 (明るさ 0) はそのまま黒で、塗り直した帯は 0 にならない。
+//!
+//! 領域の番号付けは画像を行の帯に分けて並列に行う。帯の中では Union-Find で繋ぎ、帯の境目の行どうしを
+//! 後から繋いでから、各ピクセルの根を並列に求める。根はいつも領域の中で最も前のピクセルなので、
+//! 番号は並列の分け方によらず、左上から見つかった順になる。
+
+use rayon::prelude::*;
+
+/// 並列に番号付けする帯の行数
+const STRIP_ROWS: usize = 64;
+
+/// 親を辿って根を求める。辿った道を半分に縮める
+fn find(parent: &mut [usize], offset: usize, mut i: usize) -> usize {
+    while parent[i - offset] != i {
+        let grandparent = parent[parent[i - offset] - offset];
+        parent[i - offset] = grandparent;
+        i = grandparent;
+    }
+    i
+}
+
+/// `a` と `b` の領域を繋ぐ。前にある根の方を残す
+fn union(parent: &mut [usize], offset: usize, a: usize, b: usize) {
+    let (a, b) = (find(parent, offset, a), find(parent, offset, b));
+    if a != b {
+        parent[a.max(b) - offset] = a.min(b);
+    }
+}
+
+/// 大きさ `bounds` の `pixels` で、同じ明るさで繋がった領域のピクセル毎の根。内部の点は自分自身
+pub fn label(pixels: &[u8], bounds: (usize, usize)) -> Vec<usize> {
+    let (width, len) = (bounds.0, pixels.len());
+    let strip = STRIP_ROWS * width;
+    let mut parent: Vec<usize> = (0 .. len).collect();
+    parent.par_chunks_mut(strip).zip(pixels.par_chunks(strip)).enumerate().for_each(|(index, (parent, pixels))| {
+        let offset = index * strip;
+        for i in 0 .. pixels.len() {
+            if pixels[i] == 0 {
+                continue;
+            }
+            if i % width > 0 && pixels[i - 1] == pixels[i] {
+                union(parent, offset, offset + i, offset + i - 1);
+            }
+            if i >= width && pixels[i - width] == pixels[i] {
+                union(parent, offset, offset + i, offset + i - width);
+            }
+        }
+    });
+    // 帯の最初の行を、前の帯の最後の行と繋ぐ
+    for top in (strip .. len).step_by(strip) {
+        for i in top .. top + width {
+            if pixels[i] != 0 && pixels[i - width] == pixels[i] {
+                union(&mut parent, 0, i, i - width);
+            }
+        }
+    }
+    (0 .. len).into_par_iter().map(|mut i| {
+        while parent[i] != i {
+            i = parent[i];
+        }
+        i
+    }).collect()
+}
+
+/// 帯を繋がった領域毎に塗り直し、領域の数を返す
+pub fn recolor(pixels: &mut [u8], bounds: (usize, usize)) -> usize {
+    let roots = label(pixels, bounds);
+    // 根は領域の最初のピクセルなので、前から数えると見つかった順の番号になる
+    let mut numbers = vec![0; pixels.len()];
+    let mut components = 0;
+    for (i, &root) in roots.iter().enumerate() {
+        if root == i && pixels[i] != 0 {
+            numbers[i] = components;
+            components += 1;
+        }
+    }
+    pixels.par_iter_mut().zip(&roots).for_each(|(pixel, &root)| {
+        if *pixel != 0 {
+            let level = (numbers[root] as f64 * 0.618_033_988_75).fract();
+            *pixel = 1 + (level * 254.0).round() as u8;
+        }
+    });
+    components
+}
+
+#[test]
+fn test_contour_map() {
+    // 同じ明るさでも繋がっていなければ別の領域。内部の点 0 は繋がない
+    let pixels = [
+        5, 5, 7, 5,
+        0, 5, 7, 5,
+        0, 0, 7, 7
+    ];
+    let roots = label(&pixels, (4, 3));
+    assert_eq!(roots, vec![0, 0, 2, 3, 4, 0, 2, 3, 8, 9, 2, 2]);
+    let mut recolored = pixels;
+    assert_eq!(recolor(&mut recolored, (4, 3)), 3);
+    assert_eq!(recolored[4], 0);
+    assert!(recolored[0] != recolored[2] && recolored[2] != recolored[3] && recolored[0] != recolored[3]);
+    assert!(recolored.iter().zip(&pixels).all(|(&after, &before)| (after == 0) == (before == 0)));
+
+    // 帯の境目をまたぐ領域も1つになり、並列に分けない場合と同じ番号になる
+    let bounds = (3, STRIP_ROWS * 2 + 5);
+    let mut pixels = vec![9; bounds.0 * bounds.1];
+    for row in 0 .. bounds.1 {
+        pixels[row * bounds.0 + 1] = 0;
+    }
+    let roots = label(&pixels, bounds);
+    assert!((0 .. bounds.1).all(|row| roots[row * bounds.0] == 0 && roots[row * bounds.0 + 2] == 2));
+    assert_eq!(recolor(&mut pixels, bounds), 2);
+}
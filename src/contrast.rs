@@ -0,0 +1,180 @@
+//! パレットの色が隣り合う反復の帯を見分けられるかを調べる `--check-contrast`
+//!
+//! 反復の上限を `BANDS` 等分した帯毎に、画像に現れたピクセルの色を Oklab で平均し、隣り合う帯の色の差が
+//! 見分けられる差の目安 `JUST_NOTICEABLE` より小さければ知らせる。間の帯が画像に現れなければ、その両側の帯が
+//! 隣り合って見えるので比べる。内部は最後の帯の次の帯として扱う。
+//! 通常の色覚の他に、1型 (protanopia) と 2型 (deuteranopia) の2色覚で見える色も Machado らの
+//! 模擬 (重さ 1.0) で確かめる。画面に現れない帯は見分ける必要がないので比べない。
+//!
+//! ```bash
+//! $ mandelbrot mandel.png 1000x750 -2,1 1,-1 --palette cividis --check-contrast
+//! ```
+
+use super::color::{self, InterpSpace, Lut};
+
+/// 反復の上限を分ける帯の数
+const BANDS: usize = 16;
+
+/// Oklab で見分けられる色の差の目安
+const JUST_NOTICEABLE: f64 = 0.02;
+
+/// 色の見え方
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Vision {
+    Normal,
+    /// 1型2色覚。赤の錐体が無い
+    Protanopia,
+    /// 2型2色覚。緑の錐体が無い
+    Deuteranopia
+}
+
+impl Vision {
+    pub const ALL: [Vision; 3] = [Vision::Normal, Vision::Protanopia, Vision::Deuteranopia];
+
+    fn name(self) -> &'static str {
+        match self {
+            Vision::Normal => "normal vision",
+            Vision::Protanopia => "protanopia",
+            Vision::Deuteranopia => "deuteranopia"
+        }
+    }
+
+    /// 線形の sRGB に掛ける模擬の行列
+    fn matrix(self) -> [[f64; 3]; 3] {
+        match self {
+            Vision::Normal => [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]],
+            Vision::Protanopia => [[0.152286, 1.052583, -0.204868],
+                                   [0.114503, 0.786281, 0.099216],
+                                   [-0.003882, -0.048116, 1.051998]],
+            Vision::Deuteranopia => [[0.367322, 0.860646, -0.227968],
+                                     [0.280085, 0.672501, 0.047413],
+                                     [-0.011820, 0.042940, 0.968881]]
+        }
+    }
+
+    /// `rgb` がこの見え方で見える色の Oklab の座標
+    pub fn oklab(self, rgb: [u8; 3]) -> [f64; 3] {
+        let linear = rgb.map(|c| color::srgb_to_linear(c as f64 / 255.0));
+        let seen = self.matrix().map(|row| (row[0] * linear[0] + row[1] * linear[1] + row[2] * linear[2]).clamp(0.0, 1.0));
+        color::to_space(seen.map(color::linear_to_srgb), InterpSpace::Oklab)
+    }
+}
+
+/// 隣り合う帯が続けて見分けにくい反復回数の範囲
+#[derive(Clone, Debug, PartialEq)]
+pub struct Warning {
+    pub vision: Vision,
+    /// 最初の帯の始まりの反復回数
+    pub from: u32,
+    /// 最後の帯の終わりの反復回数 (含まない)。内部まで続けば `None`
+    pub to: Option<u32>,
+    /// 範囲の中の帯の数
+    pub bands: usize,
+    /// 隣り合う帯の色の差のうち最も大きいもの
+    pub difference: f64
+}
+
+impl std::fmt::Display for Warning {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.to {
+            Some(to) => write!(f, "iterations {}..{}", self.from, to)?,
+            None => write!(f, "iterations from {} and the interior", self.from)?
+        }
+        write!(f, " look alike with {}: {} bands differ by at most {:.3} (noticeable from {})",
+               self.vision.name(), self.bands, self.difference, JUST_NOTICEABLE)
+    }
+}
+
+/// 灰色の明るさが属する帯。内部 (明るさ 0) は `BANDS`
+fn band_of(gray: u8) -> usize {
+    if gray == 0 {
+        return BANDS;
+    }
+    // `shade` の逆で、明るさ 255 が反復 0 回
+    ((255 - gray as usize) * BANDS / 255).min(BANDS - 1)
+}
+
+/// 灰色の画像 `pixels` を `lut` で色に置き換えたときに見分けにくい帯を、反復の上限 `limit` で数えて返す
+pub fn check(pixels: &[u8], lut: &Lut, limit: u32) -> Vec<Warning> {
+    let mut histogram = [0u64; 256];
+    for &gray in pixels {
+        histogram[gray as usize] += 1;
+    }
+    let bound = |band: usize| (band < BANDS).then(|| (band as u64 * limit as u64 / BANDS as u64) as u32);
+
+    let mut warnings: Vec<Warning> = vec![];
+    for vision in Vision::ALL {
+        // 帯毎の Oklab の座標の和とピクセルの数
+        let mut sums = [([0.0; 3], 0u64); BANDS + 1];
+        for (gray, &count) in histogram.iter().enumerate().filter(|(_, &count)| count > 0) {
+            let lab = vision.oklab(lut.0[gray]);
+            let (sum, total) = &mut sums[band_of(gray as u8)];
+            for i in 0 .. 3 {
+                sum[i] += lab[i] * count as f64;
+            }
+            *total += count;
+        }
+        let means: Vec<(usize, [f64; 3])> = sums.iter().enumerate()
+            .filter(|(_, (_, total))| *total > 0)
+            .map(|(band, (sum, total))| (band, sum.map(|s| s / *total as f64)))
+            .collect();
+        // 直前に見分けにくかった帯の組の後の帯
+        let mut previous = None;
+        for pair in means.windows(2) {
+            let ((first, a), (second, b)) = (pair[0], pair[1]);
+            let difference = ((a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2) + (a[2] - b[2]).powi(2)).sqrt();
+            if difference >= JUST_NOTICEABLE {
+                continue;
+            }
+            match warnings.last_mut() {
+                Some(warning) if previous == Some(first) => {
+                    warning.to = bound(second + 1);
+                    warning.bands += 1;
+                    warning.difference = warning.difference.max(difference);
+                }
+                _ => warnings.push(Warning {
+                    vision,
+                    from: bound(first).unwrap_or(limit),
+                    to: bound(second + 1),
+                    bands: 2,
+                    difference
+                })
+            }
+            previous = Some(second);
+        }
+    }
+    warnings
+}
+
+#[test]
+fn test_check() {
+    use super::color::Palette;
+
+    let lut = |name: &str| Palette::builtin(name).unwrap().lut(InterpSpace::Oklab);
+    let ramp: Vec<u8> = (0 ..= 255).collect();
+    assert_eq!(check(&ramp, &lut("cividis"), 1024), vec![]);
+    assert_eq!(check(&ramp, &lut("blue-orange"), 1024), vec![]);
+    // 黒に近づいてから内部の黒になるパレットは、最後の帯と内部が見分けにくい
+    assert_eq!(check(&ramp, &lut("ultra"), 1024).iter().map(|w| (w.from, w.to)).collect::<Vec<_>>(),
+               vec![(960, None); 3]);
+
+    // 赤から緑は2型2色覚では明るさの差しか残らない
+    let red_green = Palette::parse(r##"
+        [[stop]]
+        position = 0.0
+        color = "#ff0000"
+
+        [[stop]]
+        position = 1.0
+        color = "#00ff00"
+    "##).unwrap().lut(InterpSpace::Oklab);
+    let warnings = check(&ramp, &red_green, 1024);
+    assert!(warnings.iter().any(|w| w.vision == Vision::Deuteranopia && w.bands > 10));
+    assert!(warnings.iter().all(|w| w.vision != Vision::Normal || w.bands == 2));
+
+    // 画像に現れた帯だけを比べる。明るさ 240 と 239 は別の帯
+    let warnings = check(&[240, 239, 239], &lut("gray"), 1024);
+    assert_eq!((warnings[0].from, warnings[0].to, warnings[0].bands), (0, Some(128), 2));
+    assert!(warnings[0].to_string().starts_with("iterations 0..128 look alike with normal vision: 2 bands"));
+    assert_eq!(check(&[255, 0], &lut("gray"), 1024), vec![]);
+}
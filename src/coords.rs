@@ -0,0 +1,156 @@
+//! 画像のピクセル、複素平面上の点、中心と倍率の間で座標を変換する `coords` サブコマンド
+//!
+//! 範囲は描画と同じく左上と右下の点か、`--center` と `--zoom` で与える。問い合わせが無ければ
+//! 範囲を両方の形で表示し、`--pixel X,Y` にはそのピクセルで計算する点 (左上の角)、
+//! `--point RE,IM` にはその点を含むピクセルを1行ずつ答える。数は読み直すと同じ値になる桁数で書く。
+//!
+//! ```bash
+//! $ mandelbrot coords 1000x750 -1.20,0.35 -1,0.20 --pixel 500,375 --point -1.1,0.3
+//! ```
+
+use std::str::FromStr;
+
+use mandelbrot::Region;
+use num::Complex;
+
+use super::{parse_complex, parse_pair, region_from_center, Failure};
+
+/// 答える問い合わせ
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Query {
+    Pixel((usize, usize)),
+    Point(Complex<f64>)
+}
+
+#[derive(Debug, PartialEq)]
+struct CoordsArgs {
+    region: Region,
+    queries: Vec<Query>
+}
+
+fn parse_coords_args(args: &[String]) -> Result<CoordsArgs, String> {
+    let bounds = args.first().and_then(|s| parse_pair(s, 'x')).filter(|&(w, h)| w > 0 && h > 0)
+        .ok_or("coords expects PIXELS as WxH with positive sizes")?;
+    let mut corners = None;
+    let mut rest = &args[1 ..];
+    if rest.first().is_some_and(|arg| !arg.starts_with("--")) {
+        let upper_left = parse_complex(&rest[0]).ok_or("error parsing upper left corner point")?;
+        let lower_right = rest.get(1).and_then(|s| parse_complex(s))
+            .ok_or("error parsing lower right corner point")?;
+        corners = Some((upper_left, lower_right));
+        rest = &rest[2 ..];
+    }
+
+    let (mut center, mut zoom) = (None, None);
+    let mut queries = vec![];
+    let mut args = rest.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().map(String::as_str).ok_or(format!("{} expects a value", arg));
+        match arg.as_str() {
+            "--center" => center = Some(parse_complex(value()?).ok_or("--center expects RE,IM")?),
+            "--zoom" => {
+                zoom = Some(f64::from_str(value()?).ok().filter(|zoom| *zoom > 0.0 && zoom.is_finite())
+                    .ok_or("--zoom expects a positive number")?);
+            }
+            "--pixel" => queries.push(Query::Pixel(parse_pair(value()?, ',').ok_or("--pixel expects X,Y")?)),
+            "--point" => queries.push(Query::Point(parse_complex(value()?).ok_or("--point expects RE,IM")?)),
+            _ => return Err(format!("unknown coords option: {}", arg))
+        }
+    }
+
+    let (upper_left, lower_right) = match (corners, center) {
+        (Some(_), Some(_)) => return Err("give either UPPERLEFT LOWERRIGHT or --center, not both".to_string()),
+        (Some(corners), None) if zoom.is_none() => corners,
+        (Some(_), None) => return Err("--zoom needs --center".to_string()),
+        (None, Some(center)) => region_from_center(center, zoom.unwrap_or(1.0), bounds),
+        (None, None) => return Err("coords expects UPPERLEFT LOWERRIGHT or --center RE,IM".to_string())
+    };
+    // 倍率が小さ過ぎると範囲が f64 で表せず、答えが全て NaN になる
+    let size = lower_right - upper_left;
+    if !(size.re.is_finite() && size.im.is_finite() && size.re != 0.0 && size.im != 0.0) {
+        return Err("the region must have a finite, non-zero width and height".to_string());
+    }
+    Ok(CoordsArgs { region: Region::new(bounds, upper_left, lower_right), queries })
+}
+
+#[test]
+fn test_parse_coords_args() {
+    let args = |s: &str| -> Vec<String> { s.split_whitespace().map(String::from).collect() };
+    let region = |ul: (f64, f64), lr: (f64, f64)| Region::new(
+        (400, 200), Complex { re: ul.0, im: ul.1 }, Complex { re: lr.0, im: lr.1 });
+    assert_eq!(parse_coords_args(&args("400x200 -2.5,1 1.5,-1 --pixel 10,20 --point 0,0.5")),
+               Ok(CoordsArgs {
+                   region: region((-2.5, 1.0), (1.5, -1.0)),
+                   queries: vec![Query::Pixel((10, 20)), Query::Point(Complex { re: 0.0, im: 0.5 })]
+               }));
+    assert_eq!(parse_coords_args(&args("400x200 --center -0.5,0")).map(|args| args.region),
+               Ok(region((-2.5, 1.0), (1.5, -1.0))));
+    assert_eq!(parse_coords_args(&args("400x200 --center -0.5,0 --zoom 2")).map(|args| args.region),
+               Ok(region((-1.5, 0.5), (0.5, -0.5))));
+
+    assert!(parse_coords_args(&args("400x200")).is_err());
+    assert!(parse_coords_args(&args("400x200 -2.5,1")).is_err());
+    assert!(parse_coords_args(&args("400x200 -2.5,1 1.5,-1 --center 0,0")).is_err());
+    assert!(parse_coords_args(&args("400x200 -2.5,1 1.5,-1 --zoom 2")).is_err());
+    assert!(parse_coords_args(&args("400x200 --center 0,0 --zoom -1")).is_err());
+    assert!(parse_coords_args(&args("400x200 --center 0,0 --pixel 1")).is_err());
+    assert!(parse_coords_args(&args("400x200 --center 0,0 --frobnicate")).is_err());
+    assert!(parse_coords_args(&args("0x10 --center 0,0")).is_err());
+    assert!(parse_coords_args(&args("400x200 --center 0,0 --zoom 1e-320")).is_err());
+    assert!(parse_coords_args(&args("400x200 -1,1 -1,1")).is_err());
+}
+
+fn format_point(point: Complex<f64>) -> String {
+    format!("{},{}", point.re, point.im)
+}
+
+/// 範囲を左上と右下、中心と倍率の両方の形で書く
+fn describe(region: &Region) -> Vec<String> {
+    let Region { upper_left, lower_right, .. } = *region;
+    let center = (upper_left + lower_right) / 2.0;
+    // 倍率1で実軸方向の幅が4になる `region_from_center` の逆
+    let zoom = 4.0 / (lower_right.re - upper_left.re);
+    vec![
+        format!("upper_left  {}", format_point(upper_left)),
+        format!("lower_right {}", format_point(lower_right)),
+        format!("center      {}", format_point(center)),
+        format!("zoom        {}", zoom)
+    ]
+}
+
+fn answer(region: &Region, query: Query) -> String {
+    match query {
+        Query::Pixel(pixel) => {
+            format!("pixel {},{} -> {}", pixel.0, pixel.1, format_point(region.pixel_to_point(pixel)))
+        }
+        Query::Point(point) => match region.point_to_pixel(point) {
+            Some(pixel) => format!("point {} -> {},{}", format_point(point), pixel.0, pixel.1),
+            None => format!("point {} -> outside", format_point(point))
+        }
+    }
+}
+
+#[test]
+fn test_coords_answers() {
+    let region = Region::new((400, 200), Complex { re: -2.5, im: 1.0 }, Complex { re: 1.5, im: -1.0 });
+    assert_eq!(describe(&region), vec![
+        "upper_left  -2.5,1", "lower_right 1.5,-1", "center      -0.5,0", "zoom        1"
+    ]);
+    assert_eq!(answer(&region, Query::Pixel((100, 50))), "pixel 100,50 -> -1.5,0.5");
+    assert_eq!(answer(&region, Query::Point(Complex { re: -1.495, im: 0.495 })), "point -1.495,0.495 -> 100,50");
+    assert_eq!(answer(&region, Query::Point(Complex { re: 2.0, im: 0.0 })), "point 2,0 -> outside");
+}
+
+/// `coords PIXELS (UPPERLEFT LOWERRIGHT | --center RE,IM [--zoom Z]) [--pixel X,Y]... [--point RE,IM]...`
+pub fn run_coords(args: &[String]) -> Result<(), Failure> {
+    let CoordsArgs { region, queries } = parse_coords_args(args).map_err(Failure::Usage)?;
+    if queries.is_empty() {
+        for line in describe(&region) {
+            println!("{}", line);
+        }
+    }
+    for query in queries {
+        println!("{}", answer(&region, query));
+    }
+    Ok(())
+}
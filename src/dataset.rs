@@ -0,0 +1,282 @@
+//! 無作為に選んだ範囲の画像とその説明を並べて、機械学習のデータセットを書き出す `dataset` サブコマンド
+//!
+//! 中心は集合の境界の近く (発散するが `MIN_ESCAPE` 回以上かかる点) から、倍率は `--min-zoom` から
+//! `--max-zoom` の間から対数で一様に選ぶ。選び方は `--seed` だけで決まる。1枚ずつ並列に描き、
+//! 各画像の中心・倍率・反復の統計を `DIR/manifest.csv` と `DIR/manifest.json` に書く。
+//!
+//! ```bash
+//! $ mandelbrot dataset --count 1000 --size 256x256 --out /tmp/dataset --passes 1024 --seed 7
+//! ```
+
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use num::Complex;
+use rayon::prelude::*;
+use serde::Serialize;
+
+use super::{escape_counts, parse_pair, parse_params, region_from_center, render, write_image};
+use super::{Failure, Orbit, RenderParams};
+
+/// 中心に選ぶ点が発散までにかかる反復回数の下限。これより早く発散する点の周りは平坦な画像になる
+const MIN_ESCAPE: u32 = 16;
+
+/// 中心を選ぶ範囲
+const SAMPLE_AREA: (Complex<f64>, Complex<f64>) = (Complex { re: -2.5, im: 2.0 }, Complex { re: 1.5, im: -2.0 });
+
+/// 1つの中心を選ぶまでに試す点の数の上限
+const MAX_ATTEMPTS: usize = 100_000;
+
+/// SplitMix64 の擬似乱数
+pub struct SplitMix64(pub u64);
+
+impl SplitMix64 {
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// [0, 1) の一様な値
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[test]
+fn test_split_mix() {
+    // 参照実装の seed 0 の最初の値
+    assert_eq!(SplitMix64(0).next_u64(), 0xe220_a839_7b1d_cdaf);
+    let mut rng = SplitMix64(7);
+    assert!((0 .. 1000).map(|_| rng.next_f64()).all(|x| (0.0 .. 1.0).contains(&x)));
+}
+
+#[derive(Debug, PartialEq)]
+struct DatasetArgs {
+    count: usize,
+    bounds: (usize, usize),
+    dir: String,
+    seed: u64,
+    min_zoom: f64,
+    max_zoom: f64,
+    /// 描画パラメータとして `parse_params` に渡す残り
+    options: Vec<String>
+}
+
+fn parse_dataset_args(args: &[String]) -> Result<DatasetArgs, String> {
+    let mut dataset = DatasetArgs {
+        count: 0,
+        bounds: (256, 256),
+        dir: String::new(),
+        seed: 0,
+        min_zoom: 1.0,
+        max_zoom: 1000.0,
+        options: vec![]
+    };
+    let zoom = |value: &str| {
+        f64::from_str(value).ok().filter(|zoom| *zoom > 0.0 && zoom.is_finite())
+            .ok_or("--min-zoom and --max-zoom expect a positive number")
+    };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().map(String::as_str).ok_or(format!("{} expects a value", arg));
+        match arg.as_str() {
+            "--count" => {
+                dataset.count = usize::from_str(value()?).ok().filter(|n| *n > 0)
+                    .ok_or("--count expects a positive integer")?;
+            }
+            "--size" => {
+                dataset.bounds = parse_pair(value()?, 'x').filter(|&(w, h)| w > 0 && h > 0)
+                    .ok_or("--size expects WxH with positive sizes")?;
+            }
+            "--out" => dataset.dir = value()?.to_string(),
+            "--seed" => dataset.seed = u64::from_str(value()?).map_err(|_| "--seed expects an integer")?,
+            "--min-zoom" => dataset.min_zoom = zoom(value()?)?,
+            "--max-zoom" => dataset.max_zoom = zoom(value()?)?,
+            _ => dataset.options.push(arg.clone())
+        }
+    }
+    if dataset.count == 0 || dataset.dir.is_empty() {
+        return Err("dataset expects --count N and --out DIR".to_string());
+    }
+    if dataset.min_zoom > dataset.max_zoom {
+        return Err("--min-zoom must not exceed --max-zoom".to_string());
+    }
+    Ok(dataset)
+}
+
+#[test]
+fn test_parse_dataset_args() {
+    let args = |s: &str| -> Vec<String> { s.split_whitespace().map(String::from).collect() };
+    assert_eq!(parse_dataset_args(&args("--count 3 --size 64x48 --out d --seed 9 --max-zoom 50 --passes 64")),
+               Ok(DatasetArgs {
+                   count: 3,
+                   bounds: (64, 48),
+                   dir: "d".to_string(),
+                   seed: 9,
+                   min_zoom: 1.0,
+                   max_zoom: 50.0,
+                   options: args("--passes 64")
+               }));
+    assert!(parse_dataset_args(&args("--out d")).is_err());
+    assert!(parse_dataset_args(&args("--count 3")).is_err());
+    assert!(parse_dataset_args(&args("--count 0 --out d")).is_err());
+    assert!(parse_dataset_args(&args("--count 3 --out d --min-zoom 10 --max-zoom 5")).is_err());
+    assert!(parse_dataset_args(&args("--count 3 --out d --seed -1")).is_err());
+    assert!(parse_dataset_args(&args("--count 3 --out d --size 0x10")).is_err());
+}
+
+/// 無作為に選んだ範囲
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Sample {
+    center: Complex<f64>,
+    zoom: f64
+}
+
+/// `rng` で境界の近くの中心と倍率を1つ選ぶ
+fn sample(rng: &mut SplitMix64, dataset: &DatasetArgs, params: &RenderParams) -> Result<Sample, String> {
+    let (upper_left, lower_right) = SAMPLE_AREA;
+    for _ in 0 .. MAX_ATTEMPTS {
+        let center = Complex {
+            re: upper_left.re + rng.next_f64() * (lower_right.re - upper_left.re),
+            im: lower_right.im + rng.next_f64() * (upper_left.im - lower_right.im)
+        };
+        let count = Orbit::new(center).advance(params.limit(), params.fractal, &params.termination);
+        if count.is_some_and(|count| count >= MIN_ESCAPE) {
+            let zoom = dataset.min_zoom * (dataset.max_zoom / dataset.min_zoom).powf(rng.next_f64());
+            return Ok(Sample { center, zoom });
+        }
+    }
+    Err(format!("no point near the boundary found in {} attempts", MAX_ATTEMPTS))
+}
+
+/// manifest の1行
+#[derive(Debug, PartialEq, Serialize)]
+struct Label {
+    file: String,
+    center_re: f64,
+    center_im: f64,
+    zoom: f64,
+    width: usize,
+    height: usize,
+    max_iterations: u32,
+    /// 実際に行った反復の合計
+    iterations: u64,
+    /// 発散しなかったピクセルの割合
+    interior_fraction: f64,
+    /// 発散したピクセルの発散までの反復回数の平均と最大
+    mean_escape: f64,
+    max_escape: u32
+}
+
+/// `sample` を描いて `dir` に書き、その説明を返す
+fn render_sample(dir: &Path, index: usize, sample: Sample, bounds: (usize, usize), params: &RenderParams)
+    -> Result<Label, String>
+{
+    let (upper_left, lower_right) = region_from_center(sample.center, sample.zoom, bounds);
+    let (counts, iterations) = escape_counts(bounds, upper_left, lower_right, params);
+    let mut pixels: Vec<u8> = counts.iter().map(|&count| params.shade(count)).collect();
+    if params.tracks_orbits() {
+        render(&mut pixels, bounds, upper_left, lower_right, params);
+    }
+    let file = format!("{:06}.png", index);
+    let path = dir.join(&file);
+    write_image(&path.to_string_lossy(), &pixels, bounds)
+        .map_err(|e| format!("cannot write {}: {}", path.display(), e))?;
+
+    let escaped: Vec<u32> = counts.iter().flatten().copied().collect();
+    Ok(Label {
+        file,
+        center_re: sample.center.re,
+        center_im: sample.center.im,
+        zoom: sample.zoom,
+        width: bounds.0,
+        height: bounds.1,
+        max_iterations: params.limit(),
+        iterations,
+        interior_fraction: (counts.len() - escaped.len()) as f64 / counts.len() as f64,
+        mean_escape: escaped.iter().map(|&count| count as f64).sum::<f64>() / escaped.len().max(1) as f64,
+        max_escape: escaped.iter().copied().max().unwrap_or(0)
+    })
+}
+
+/// `dataset --count N --out DIR [--size WxH] [--seed S] [--min-zoom Z] [--max-zoom Z] [OPTIONS]` サブコマンド
+pub fn run_dataset(args: &[String]) -> Result<(), Failure> {
+    let dataset = parse_dataset_args(args).map_err(Failure::Usage)?;
+    let params = parse_params(&dataset.options).map_err(Failure::Usage)?;
+    write_dataset(&dataset, &params).map_err(Failure::Runtime)
+}
+
+/// `dataset` の範囲を選んで描き、画像と一覧を書き出す
+fn write_dataset(dataset: &DatasetArgs, params: &RenderParams) -> Result<(), String> {
+    let dir = Path::new(&dataset.dir);
+    fs::create_dir_all(dir).map_err(|e| format!("cannot create {}: {}", dir.display(), e))?;
+
+    // 範囲は先に順に選んでおき、並列に描く順序に関わらず同じ seed で同じデータセットになるようにする
+    let mut rng = SplitMix64(dataset.seed);
+    let samples = (0 .. dataset.count).map(|_| sample(&mut rng, dataset, params)).collect::<Result<Vec<_>, _>>()?;
+    eprintln!("rendering {} images of {}x{}", samples.len(), dataset.bounds.0, dataset.bounds.1);
+    let labels = samples.par_iter().enumerate()
+        .map(|(index, &sample)| render_sample(dir, index, sample, dataset.bounds, params))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let path = dir.join("manifest.csv");
+    let error = |e: &dyn std::fmt::Display| format!("cannot write {}: {}", path.display(), e);
+    let mut writer = csv::Writer::from_path(&path).map_err(|e| error(&e))?;
+    for label in &labels {
+        writer.serialize(label).map_err(|e| error(&e))?;
+    }
+    writer.flush().map_err(|e| error(&e))?;
+    let path = dir.join("manifest.json");
+    let json = serde_json::to_string_pretty(&labels).map_err(|e| e.to_string())?;
+    fs::write(&path, json).map_err(|e| format!("cannot write {}: {}", path.display(), e))?;
+    eprintln!("wrote {}", path.display());
+    Ok(())
+}
+
+#[test]
+fn test_run_dataset() {
+    let dir = std::env::temp_dir().join(format!("mandelbrot-dataset-{}", std::process::id()));
+    let args = |seed: &str| -> Vec<String> {
+        format!("--count 3 --size 16x12 --out {} --seed {} --passes 64", dir.display(), seed)
+            .split_whitespace().map(String::from).collect()
+    };
+    run_dataset(&args("5")).unwrap();
+    let manifest = fs::read_to_string(dir.join("manifest.csv")).unwrap();
+    let lines: Vec<&str> = manifest.lines().collect();
+    assert_eq!(lines.len(), 4);
+    assert!(lines[0].starts_with("file,center_re,center_im,zoom,width,height,max_iterations,iterations"));
+    assert!(lines[1].starts_with("000000.png,"));
+    assert!(dir.join("000002.png").exists());
+    let json: serde_json::Value = serde_json::from_str(&fs::read_to_string(dir.join("manifest.json")).unwrap()).unwrap();
+    assert_eq!(json[2]["file"], "000002.png");
+    assert_eq!(json[2]["max_iterations"], 64);
+
+    // 同じ seed なら同じ範囲を選ぶ
+    run_dataset(&args("5")).unwrap();
+    assert_eq!(fs::read_to_string(dir.join("manifest.csv")).unwrap(), manifest);
+    run_dataset(&args("6")).unwrap();
+    assert_ne!(fs::read_to_string(dir.join("manifest.csv")).unwrap(), manifest);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_dataset_thread_counts() {
+    // スレッドの数を変えても、画像も一覧もバイト単位で同じになる
+    let write = |threads: usize| {
+        let dir = std::env::temp_dir().join(format!("mandelbrot-dataset-threads-{}-{}", std::process::id(), threads));
+        let args: Vec<String> = format!("--count 6 --size 16x12 --out {} --seed 3 --passes 64 --backend atomic --threads {}",
+                                        dir.display(), threads)
+            .split_whitespace().map(String::from).collect();
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+        pool.install(|| run_dataset(&args)).unwrap();
+        let mut files = vec![fs::read(dir.join("manifest.csv")).unwrap()];
+        files.extend((0 .. 6).map(|index| fs::read(dir.join(format!("{:06}.png", index))).unwrap()));
+        fs::remove_dir_all(&dir).unwrap();
+        files
+    };
+    assert_eq!(write(1), write(4));
+}
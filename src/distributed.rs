@@ -0,0 +1,528 @@
+//! 複数のマシンで帯を分担して描画する `worker` と `coordinator` サブコマンド
+//!
+//! coordinator は画像を数行ずつのジョブに分け、TCP で接続した worker に配る。
+//! メッセージは4バイトのビッグエンディアンの長さに続けて本体を送る単純な形式で、
+//! 依頼は位置引数とオプションを1行ずつ並べたテキスト、応答は描画済みのピクセル列となる。
+//! 応答を返さずに切断した worker のジョブは他の worker に配り直す。
+//!
+//! 接続に認証は無いので、worker は届いた依頼を信用しない。画像の大きさと帯の大きさに上限を設け、
+//! coordinator の手元のファイルを指す `--plugin` と `--exterior-texture` は断り、`--threads` は
+//! worker のスレッド数までに抑える。開いている接続は `--max-connections` 件までで、超えた接続は
+//! スレッドを立てずに閉じる。依頼のメッセージは数百バイトのテキストなので小さな上限で読み、
+//! 大きな上限は coordinator が帯を受け取るときだけに使う。どちらも届いた分だけ確保する。
+
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::str::FromStr;
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+#[cfg(test)]
+use std::time::Instant;
+
+use num::Complex;
+
+use super::admission::ConnectionLimit;
+use super::backend::render_rows;
+use super::config;
+use super::{failed, parse_complex, parse_list, parse_pair, parse_params_exact, write_image, Failure, RenderParams};
+
+/// 1つのメッセージの大きさの上限
+const MAX_FRAME_LEN: usize = 1 << 30;
+
+/// worker が受け取る依頼のメッセージの大きさの上限
+const MAX_JOB_LEN: usize = 1 << 16;
+
+/// worker が既定で同時に開いておく接続の数
+const DEFAULT_MAX_CONNECTIONS: usize = 16;
+
+/// ジョブの画像の幅と高さの上限
+const MAX_SIDE: usize = 1 << 20;
+
+/// worker が受け付けないオプション。coordinator の手元のファイルを指すので worker では意味が無く、
+/// 接続して来た相手に共有ライブラリや任意のファイルを開かせることにもなる
+const LOCAL_OPTIONS: &[&str] = &["--plugin", "--exterior-texture"];
+
+/// worker に依頼する描画の単位。画像全体のうち `top` 行目から `rows` 行分を描画する
+#[derive(Clone, Debug, PartialEq)]
+pub struct Job {
+    bounds: (usize, usize),
+    upper_left: Complex<f64>,
+    lower_right: Complex<f64>,
+    top: usize,
+    rows: usize,
+    /// worker 側で `parse_params_exact` に渡すオプション
+    options: Vec<String>
+}
+
+impl Job {
+    fn encode(&self) -> Vec<u8> {
+        let mut lines = vec![
+            format!("{}x{}", self.bounds.0, self.bounds.1),
+            format!("{},{}", self.upper_left.re, self.upper_left.im),
+            format!("{},{}", self.lower_right.re, self.lower_right.im),
+            self.top.to_string(),
+            self.rows.to_string()
+        ];
+        lines.extend(self.options.iter().cloned());
+        lines.join("\n").into_bytes()
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Job> {
+        let text = std::str::from_utf8(bytes).ok()?;
+        let mut lines = text.split('\n');
+        let job = Job {
+            bounds: parse_pair(lines.next()?, 'x')?,
+            upper_left: parse_complex(lines.next()?)?,
+            lower_right: parse_complex(lines.next()?)?,
+            top: usize::from_str(lines.next()?).ok()?,
+            rows: usize::from_str(lines.next()?).ok()?,
+            options: lines.map(String::from).collect()
+        };
+        // 応答の帯が1つのメッセージに収まらない大きさや、足すと溢れる行の範囲は受け付けない
+        let (width, height) = job.bounds;
+        let band_len = width.checked_mul(job.rows)?;
+        if width > MAX_SIDE || height > MAX_SIDE || band_len > MAX_FRAME_LEN
+            || job.top.checked_add(job.rows)? > height {
+            return None;
+        }
+        Some(job)
+    }
+}
+
+#[test]
+fn test_job_encode_decode() {
+    let job = Job {
+        bounds: (1000, 750),
+        upper_left: Complex { re: -1.20, im: 0.35 },
+        lower_right: Complex { re: -1.0, im: 0.20 },
+        top: 16,
+        rows: 8,
+        options: vec!["--passes".to_string(), "256,1024".to_string()]
+    };
+    assert_eq!(Job::decode(&job.encode()), Some(job.clone()));
+    let bare = Job { options: vec![], ..job };
+    assert_eq!(Job::decode(&bare.encode()), Some(bare.clone()));
+    assert_eq!(Job::decode(&Job { top: 745, ..job }.encode()), None);
+    assert_eq!(Job::decode(b"1000x750\n-1.2,0.35"), None);
+
+    // 溢れる値や大き過ぎる画像はパニックせずに断る
+    let max = usize::MAX;
+    assert_eq!(Job::decode(&Job { top: max, rows: max, ..bare.clone() }.encode()), None);
+    assert_eq!(Job::decode(&Job { top: 1, rows: max, ..bare.clone() }.encode()), None);
+    assert_eq!(Job::decode(&Job { bounds: (max, max), top: 0, rows: 1, ..bare.clone() }.encode()), None);
+    assert_eq!(Job::decode(&Job { bounds: (max, 2), top: 0, rows: 2, ..bare.clone() }.encode()), None);
+    assert_eq!(Job::decode(&Job { bounds: (MAX_SIDE, MAX_SIDE), top: 0, rows: MAX_SIDE, ..bare }.encode()), None);
+}
+
+/// 接続して来た相手のオプション `options` を読む。`--threads` はこのマシンのスレッド数までに抑える
+fn parse_remote_options(options: &[String]) -> Result<RenderParams, String> {
+    if let Some(option) = options.iter().find(|option| LOCAL_OPTIONS.contains(&option.as_str())) {
+        return Err(format!("{} names a local file and cannot be sent to a worker", option));
+    }
+    let mut params = parse_params_exact(options)?;
+    let available = thread::available_parallelism().map_or(1, |n| n.get());
+    params.scheduling.threads = params.scheduling.threads.map(|threads| threads.min(available));
+    Ok(params)
+}
+
+#[test]
+fn test_parse_remote_options() {
+    let args = |s: &str| -> Vec<String> { s.split_whitespace().map(String::from).collect() };
+    assert!(parse_remote_options(&args("--passes 64,256")).is_ok());
+    assert!(parse_remote_options(&args("--plugin /tmp/evil.so")).is_err());
+    assert!(parse_remote_options(&args("--exterior-texture /etc/passwd")).is_err());
+    let available = thread::available_parallelism().map_or(1, |n| n.get());
+    assert_eq!(parse_remote_options(&args("--threads 1000000")).unwrap().scheduling.threads, Some(available));
+
+    assert!(parse_job(b"8x6\n-2,1\n1,-1\n0\n6\n--passes\n64").is_ok_and(|(job, _)| job.rows == 6));
+    assert!(parse_job(b"8x6\n-2,1\n1,-1\n0\n6\n--plugin\n/tmp/evil.so").is_err());
+    assert!(parse_job(b"8x6\n-2,1").is_err());
+}
+
+/// 接続して来た相手の依頼 `request` を読み、オプションを `parse_remote_options` で確かめる
+///
+/// worker が届いた依頼を読むのに使い、`fuzz-parsers` もこれで外から届く依頼の読み方を調べる。
+pub fn parse_job(request: &[u8]) -> Result<(Job, RenderParams), String> {
+    let job = Job::decode(request).ok_or("malformed job")?;
+    let params = parse_remote_options(&job.options)?;
+    Ok((job, params))
+}
+
+fn write_frame<W: Write>(stream: &mut W, payload: &[u8]) -> io::Result<()> {
+    stream.write_all(&(payload.len() as u32).to_be_bytes())?;
+    stream.write_all(payload)?;
+    stream.flush()
+}
+
+/// `max_len` バイトまでのメッセージを1つ読む。長さの欄を信じて先に確保せず、届いた分だけを溜める
+fn read_frame<R: Read>(stream: &mut R, max_len: usize) -> io::Result<Vec<u8>> {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > max_len {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "frame too large"));
+    }
+    let mut payload = Vec::new();
+    stream.take(len as u64).read_to_end(&mut payload)?;
+    if payload.len() < len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated frame"));
+    }
+    Ok(payload)
+}
+
+#[test]
+fn test_read_frame() {
+    let mut frame = vec![];
+    write_frame(&mut frame, b"8x6").unwrap();
+    assert_eq!(read_frame(&mut frame.as_slice(), MAX_JOB_LEN).unwrap(), b"8x6");
+    assert_eq!(read_frame(&mut frame.as_slice(), 2).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    // 大きな長さを名乗っても、届いた数バイトで終わればそれだけしか確保せずに失敗する
+    let mut truncated = (MAX_JOB_LEN as u32).to_be_bytes().to_vec();
+    truncated.extend_from_slice(b"8x6");
+    let error = read_frame(&mut truncated.as_slice(), MAX_JOB_LEN).unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    let mut huge = u32::MAX.to_be_bytes().to_vec();
+    huge.extend_from_slice(b"8x6");
+    assert_eq!(read_frame(&mut huge.as_slice(), MAX_JOB_LEN).unwrap_err().kind(), io::ErrorKind::InvalidData);
+}
+
+/// `worker` の待ち受けるアドレスと、開いておく接続の数の上限
+fn parse_worker_args(args: &[String]) -> Result<(&String, usize), String> {
+    match args {
+        [addr] => Ok((addr, DEFAULT_MAX_CONNECTIONS)),
+        [addr, option, value] if option == "--max-connections" => {
+            let max = usize::from_str(value).ok().filter(|&n| n > 0)
+                .ok_or("--max-connections expects a positive integer")?;
+            Ok((addr, max))
+        }
+        _ => Err("worker expects a listen address and optionally --max-connections N".to_string())
+    }
+}
+
+/// `worker ADDR [--max-connections N]` サブコマンド。`ADDR` で待ち受け、接続毎にスレッドを立ててジョブを処理する
+pub fn run_worker(args: &[String]) -> Result<(), Failure> {
+    let (addr, max_connections) = parse_worker_args(args).map_err(Failure::Usage)?;
+    let listener = TcpListener::bind(addr)
+        .map_err(|e| Failure::Runtime(format!("cannot listen on {}: {}", addr, e)))?;
+    eprintln!("worker listening on {}", addr);
+    serve(listener, max_connections);
+    Ok(())
+}
+
+/// `listener` の接続毎にスレッドを立ててジョブを処理する。開いている接続が `max_connections` 件に
+/// 達していれば、スレッドを立てずにすぐ閉じる。coordinator はそのジョブを他の worker に配り直す
+fn serve(listener: TcpListener, max_connections: usize) {
+    let connections = Arc::new(ConnectionLimit::new(max_connections));
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => match connections.try_open() {
+                Some(connection) => {
+                    thread::spawn(move || {
+                        let _connection = connection;
+                        if let Err(e) = serve_connection(stream) {
+                            eprintln!("worker: connection closed: {}", e);
+                        }
+                    });
+                }
+                None => eprintln!("worker: too many connections, closed one from {:?}", stream.peer_addr().ok())
+            },
+            Err(e) => eprintln!("worker: accept failed: {}", e)
+        }
+    }
+}
+
+#[test]
+fn test_serve_connection_limit() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || serve(listener, 1));
+
+    // 依頼を送らずに開いたままの接続が枠を埋めていると、次の接続はすぐ閉じられる
+    let idle = TcpStream::connect(addr).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    let job = Job {
+        bounds: (8, 6),
+        upper_left: Complex { re: -2.0, im: 1.0 },
+        lower_right: Complex { re: 1.0, im: -1.0 },
+        top: 0,
+        rows: 6,
+        options: vec![]
+    };
+    let request = |job: &Job| -> io::Result<Vec<u8>> {
+        let mut stream = TcpStream::connect(addr)?;
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        write_frame(&mut stream, &job.encode())?;
+        read_frame(&mut stream, MAX_FRAME_LEN)
+    };
+    assert!(request(&job).is_err());
+    drop(idle);
+    // 閉じた接続の枠は返る
+    let started = Instant::now();
+    while request(&job).map_or(true, |band| band.len() != 8 * 6) {
+        assert!(started.elapsed() < Duration::from_secs(5));
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn serve_connection(mut stream: TcpStream) -> io::Result<()> {
+    loop {
+        let request = match read_frame(&mut stream, MAX_JOB_LEN) {
+            Ok(request) => request,
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e)
+        };
+        // coordinator が環境変数を重ねたオプションを送るので、worker の環境変数では変えない
+        let (job, params) = parse_job(&request)
+            .map_err(|message| io::Error::new(io::ErrorKind::InvalidData, message))?;
+
+        let mut pixels = vec![0; job.bounds.0 * job.rows];
+        render_rows(&mut pixels, job.bounds, job.top,
+                    job.upper_left, job.lower_right, &params);
+        write_frame(&mut stream, &pixels)?;
+    }
+}
+
+/// coordinator 自身のオプション。これ以外のオプションはそのまま worker に渡す
+struct CoordinatorOptions {
+    workers: Vec<String>,
+    rows_per_job: usize,
+    job_timeout: Duration,
+    render_options: Vec<String>
+}
+
+fn parse_coordinator_options(args: &[String]) -> Result<CoordinatorOptions, String> {
+    let mut options = CoordinatorOptions {
+        workers: vec![],
+        rows_per_job: 16,
+        job_timeout: Duration::from_secs(600),
+        render_options: vec![]
+    };
+    let mut args = args.iter();
+
+    while let Some(name) = args.next() {
+        let mut value = || args.next()
+            .ok_or_else(|| format!("missing value for {}", name));
+        match name.as_str() {
+            "--workers" => {
+                options.workers = parse_list(value()?, ',')
+                    .ok_or("--workers expects a comma separated list of addresses")?;
+            }
+            "--rows-per-job" => {
+                options.rows_per_job = usize::from_str(value()?)
+                    .ok().filter(|&rows| rows > 0)
+                    .ok_or("--rows-per-job expects a positive integer")?;
+            }
+            "--job-timeout" => {
+                let secs = u64::from_str(value()?)
+                    .ok().filter(|&secs| secs > 0)
+                    .ok_or("--job-timeout expects a positive number of seconds")?;
+                options.job_timeout = Duration::from_secs(secs);
+            }
+            _ => options.render_options.push(name.clone())
+        }
+    }
+
+    if options.workers.iter().all(|worker| worker.is_empty()) {
+        return Err("coordinator needs at least one worker (--workers)".to_string());
+    }
+    Ok(options)
+}
+
+/// `coordinator FILE PIXELS UPPERLEFT LOWERRIGHT --workers ADDR,... [OPTIONS]` サブコマンド
+pub fn run_coordinator(args: &[String]) -> Result<(), Failure> {
+    let ((bounds, upper_left, lower_right), options) = parse_coordinator_args(args).map_err(Failure::Usage)?;
+    let jobs = split_jobs(bounds, upper_left, lower_right, &options);
+    let pixels = render_distributed(jobs, &options).map_err(Failure::Runtime)?;
+
+    write_image(&args[0], &pixels, bounds)
+        .map_err(failed("error writing PNG file"))
+}
+
+/// 画像の大きさと、左上と右下の角
+type Area = ((usize, usize), Complex<f64>, Complex<f64>);
+
+/// `coordinator` の位置引数のうち画像の大きさと範囲、オプション
+fn parse_coordinator_args(args: &[String]) -> Result<(Area, CoordinatorOptions), String> {
+    if args.len() < 4 {
+        return Err("coordinator expects FILE PIXELS UPPERLEFT LOWERRIGHT".to_string());
+    }
+    let bounds = parse_pair(&args[1], 'x')
+        .ok_or("error parsing image dimensions")?;
+    let upper_left = parse_complex(&args[2])
+        .ok_or("error parsing upper left corner point")?;
+    let lower_right = parse_complex(&args[3])
+        .ok_or("error parsing lower right corner point")?;
+    let mut options = parse_coordinator_options(&args[4..])?;
+    options.render_options = config::from_environment(config::RENDER, &options.render_options)?;
+    // worker に配る前に、worker と同じ規則で手元でもオプションを検証しておく
+    parse_remote_options(&options.render_options)?;
+    Ok(((bounds, upper_left, lower_right), options))
+}
+
+fn split_jobs(bounds: (usize, usize),
+              upper_left: Complex<f64>,
+              lower_right: Complex<f64>,
+              options: &CoordinatorOptions)
+    -> Vec<Job>
+{
+    (0 .. bounds.1).step_by(options.rows_per_job)
+        .map(|top| Job {
+            bounds,
+            upper_left,
+            lower_right,
+            top,
+            rows: options.rows_per_job.min(bounds.1 - top),
+            options: options.render_options.clone()
+        })
+        .collect()
+}
+
+/// 配布待ちのジョブと、まだ結果が届いていないジョブの数
+struct Queue {
+    pending: Vec<Job>,
+    remaining: usize
+}
+
+/// 各 worker に1本ずつ接続を張ってジョブを配り、届いた結果を画像に組み立てる
+fn render_distributed(jobs: Vec<Job>, options: &CoordinatorOptions) -> Result<Vec<u8>, String> {
+    let bounds = match jobs.first() {
+        Some(job) => job.bounds,
+        None => return Ok(vec![])
+    };
+    let queue = Arc::new((Mutex::new(Queue { remaining: jobs.len(), pending: jobs }),
+                          Condvar::new()));
+    let (sender, receiver) = mpsc::channel();
+
+    for worker in &options.workers {
+        let worker = worker.clone();
+        let queue = queue.clone();
+        let sender = sender.clone();
+        let timeout = options.job_timeout;
+        thread::spawn(move || {
+            if let Err(e) = drive_worker(&worker, timeout, &queue, &sender) {
+                eprintln!("coordinator: worker {} failed: {}", worker, e);
+            }
+        });
+    }
+    // 全ての worker が落ちたら受信側で検知できるよう、手元の送信側は閉じておく
+    drop(sender);
+
+    let mut pixels = vec![0; bounds.0 * bounds.1];
+    for (job, band) in receiver {
+        let job: Job = job;
+        let start = job.top * bounds.0;
+        pixels[start .. start + band.len()].copy_from_slice(&band);
+    }
+
+    let remaining = (queue.0).lock().unwrap().remaining;
+    if remaining > 0 {
+        return Err(format!("all workers failed with {} jobs left", remaining));
+    }
+    Ok(pixels)
+}
+
+fn drive_worker(worker: &str,
+                timeout: Duration,
+                queue: &(Mutex<Queue>, Condvar),
+                sender: &mpsc::Sender<(Job, Vec<u8>)>)
+    -> io::Result<()>
+{
+    let (lock, condvar) = queue;
+    let mut stream = TcpStream::connect(worker)?;
+    stream.set_read_timeout(Some(timeout))?;
+
+    loop {
+        let job = {
+            let mut queue = lock.lock().unwrap();
+            loop {
+                if let Some(job) = queue.pending.pop() {
+                    break job;
+                }
+                if queue.remaining == 0 {
+                    return Ok(());
+                }
+                // 他の worker が落ちてジョブが戻されるかもしれないので完了まで待つ
+                queue = condvar.wait(queue).unwrap();
+            }
+        };
+
+        let band = write_frame(&mut stream, &job.encode())
+            .and_then(|_| read_frame(&mut stream, job.bounds.0 * job.rows))
+            .and_then(|band| {
+                if band.len() == job.bounds.0 * job.rows {
+                    Ok(band)
+                } else {
+                    Err(io::Error::new(io::ErrorKind::InvalidData, "unexpected band size"))
+                }
+            });
+        let mut queue = lock.lock().unwrap();
+        match band {
+            Ok(band) => {
+                queue.remaining -= 1;
+                condvar.notify_all();
+                drop(queue);
+                let _ = sender.send((job, band));
+            }
+            Err(e) => {
+                queue.pending.push(job);
+                condvar.notify_all();
+                return Err(e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+fn spawn_test_worker() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    thread::spawn(move || serve(listener, DEFAULT_MAX_CONNECTIONS));
+    addr
+}
+
+#[test]
+fn test_render_distributed() {
+    use super::render_parallel;
+
+    let bounds = (64, 48);
+    let upper_left = Complex { re: -1.20, im: 0.35 };
+    let lower_right = Complex { re: -1.0, im: 0.20 };
+
+    // 接続直後に切断する壊れた worker が混ざっていても全てのジョブが完了する
+    let broken = TcpListener::bind("127.0.0.1:0").unwrap();
+    let broken_addr = broken.local_addr().unwrap().to_string();
+    thread::spawn(move || for stream in broken.incoming() { drop(stream); });
+
+    let options = CoordinatorOptions {
+        workers: vec![spawn_test_worker(), broken_addr, spawn_test_worker()],
+        rows_per_job: 5,
+        job_timeout: Duration::from_secs(10),
+        render_options: vec!["--passes".to_string(), "64,512".to_string()]
+    };
+    let jobs = split_jobs(bounds, upper_left, lower_right, &options);
+    assert_eq!(jobs.len(), 10);
+    let pixels = render_distributed(jobs, &options).unwrap();
+
+    let mut expected = vec![0; bounds.0 * bounds.1];
+    let params = RenderParams { limits: vec![64, 512], ..RenderParams::default() };
+    render_parallel(&mut expected, bounds, upper_left, lower_right, &params);
+    assert_eq!(pixels, expected);
+}
+
+#[test]
+fn test_render_distributed_all_workers_down() {
+    let broken = TcpListener::bind("127.0.0.1:0").unwrap();
+    let broken_addr = broken.local_addr().unwrap().to_string();
+    thread::spawn(move || for stream in broken.incoming() { drop(stream); });
+
+    let options = CoordinatorOptions {
+        workers: vec![broken_addr],
+        rows_per_job: 4,
+        job_timeout: Duration::from_secs(10),
+        render_options: vec![]
+    };
+    let jobs = split_jobs((8, 8), Complex { re: -1.0, im: 1.0 },
+                          Complex { re: 1.0, im: -1.0 }, &options);
+    assert!(render_distributed(jobs, &options).is_err());
+}
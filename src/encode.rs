@@ -0,0 +1,408 @@
+//! 大きな画像のための並列 PNG エンコーダ
+//!
+//! 画像を数百行ずつのストリップに分け、フィルタと deflate 圧縮をストリップ毎に rayon で並列に行う。
+//! 最後以外のストリップは sync flush で終えるとバイト境界で終わるので、圧縮結果をそのまま繋げれば
+//! 1本の zlib ストリームになる。Adler-32 もストリップ毎に計算して結合する。
+//!
+//! Adam7 でインターレースするときは、7つのパスの縮小画像をそれぞれストリップに分けて同じように並べる。
+//! 途中まで読み込んだ画像でも粗い全体が先に見える。XMP のメタデータ (`xmp.rs`) は iTXt チャンクに入れる。
+
+use std::borrow::Cow;
+use std::io::{self, Write};
+
+use flate2::write::{DeflateEncoder, ZlibEncoder};
+use flate2::{Compression, Crc};
+use rayon::prelude::*;
+
+use super::color::Colors;
+use super::frame::{FrameBuffer, PngPixel};
+
+/// 1つのストリップに含めるフィルタ後のバイト数の目安
+const STRIP_BYTES: usize = 1 << 20;
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+
+/// 色以外の PNG の書き出しの設定
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PngOptions<'a> {
+    /// Adam7 でインターレースする
+    pub interlace: bool,
+    /// 埋め込む XMP のパケット
+    pub xmp: Option<&'a str>
+}
+
+/// グレースケール8ビットの `pixels` を PNG として `output` に書き出す
+pub fn encode_parallel<W: Write>(output: W, pixels: &[u8], bounds: (usize, usize))
+    -> io::Result<()>
+{
+    encode_colored(output, pixels, bounds, &Colors::default(), &PngOptions::default())
+}
+
+/// `encode_parallel` と同じだが、`colors` のパレットで色を付けて透明にする点を抜き、ICC プロファイルを埋め込む
+pub fn encode_colored<W: Write>(output: W, pixels: &[u8], bounds: (usize, usize), colors: &Colors,
+                                options: &PngOptions)
+    -> io::Result<()>
+{
+    assert!(pixels.len() == bounds.0 * bounds.1);
+    let channels = colors.channels();
+    let strip_rows = (STRIP_BYTES / (bounds.0 * channels + 1)).max(1);
+    let width = bounds.0;
+    encode_strips(output, bounds, channels, colors.icc.as_deref(), options, strip_rows,
+                  |y| colors.samples(&pixels[y * width .. (y + 1) * width]))
+}
+
+/// RGB の各8ビットを並べた `rgb` を PNG として書き出し、`icc` のプロファイルを埋め込む
+pub fn encode_rgb<W: Write>(output: W, rgb: &[u8], bounds: (usize, usize), icc: Option<&[u8]>, options: &PngOptions)
+    -> io::Result<()>
+{
+    encode_packed(output, rgb, bounds, 3, icc, options)
+}
+
+/// 画素の形式が型で決まる `frame` を PNG として書き出し、`icc` のプロファイルを埋め込む
+pub fn encode_frame<W: Write, P: PngPixel>(output: W, frame: &FrameBuffer<P>, icc: Option<&[u8]>, options: &PngOptions)
+    -> io::Result<()>
+{
+    let stride = frame.bounds.0 * P::CHANNELS * P::BIT_DEPTH as usize / 8;
+    let strip_rows = (STRIP_BYTES / (stride + 1)).max(1);
+    encode_samples(output, frame.bounds, (P::CHANNELS, P::BIT_DEPTH), icc, options, strip_rows,
+                   |y| Cow::Owned(frame.row_bytes(y)))
+}
+
+fn encode_packed<W: Write>(output: W, samples: &[u8], bounds: (usize, usize), channels: usize, icc: Option<&[u8]>,
+                           options: &PngOptions)
+    -> io::Result<()>
+{
+    assert!(samples.len() == bounds.0 * bounds.1 * channels);
+    let stride = bounds.0 * channels;
+    let strip_rows = (STRIP_BYTES / (stride + 1)).max(1);
+    encode_strips(output, bounds, channels, icc, options, strip_rows,
+                  |y| Cow::Borrowed(&samples[y * stride .. (y + 1) * stride]))
+}
+
+/// Adam7 の7つのパスの (最初の列, 最初の行, 列の間隔, 行の間隔)
+const ADAM7: [(usize, usize, usize, usize); 7] =
+    [(0, 0, 8, 8), (4, 0, 8, 8), (0, 4, 4, 8), (2, 0, 4, 4), (0, 2, 2, 4), (1, 0, 2, 2), (0, 1, 1, 2)];
+
+/// 長さ `len` のうち `start` から `step` おきに取る数
+fn pass_len(len: usize, start: usize, step: usize) -> usize {
+    if len > start { (len - start).div_ceil(step) } else { 0 }
+}
+
+/// `row_at(y)` で得る `y` 行目のサンプル (1ピクセル `channels` バイト) を PNG として書き出す
+fn encode_strips<'a, W, R>(output: W, bounds: (usize, usize), channels: usize, icc: Option<&[u8]>,
+                           options: &PngOptions, strip_rows: usize, row_at: R)
+    -> io::Result<()>
+    where W: Write, R: Fn(usize) -> Cow<'a, [u8]> + Sync
+{
+    encode_samples(output, bounds, (channels, 8), icc, options, strip_rows, row_at)
+}
+
+/// `encode_strips` と同じだが、1ピクセルが (成分の数, 1成分のビット数) の `layout` の行を書き出す。
+/// 16ビットの成分はビッグエンディアンで並べる
+fn encode_samples<'a, W, R>(mut output: W, bounds: (usize, usize), layout: (usize, u8), icc: Option<&[u8]>,
+                            options: &PngOptions, strip_rows: usize, row_at: R)
+    -> io::Result<()>
+    where W: Write, R: Fn(usize) -> Cow<'a, [u8]> + Sync
+{
+    let (width, height) = bounds;
+    let (channels, bit_depth) = layout;
+    // インターレースで拾うときの1ピクセルのバイト数
+    let pixel_bytes = channels * bit_depth as usize / 8;
+    let passes: &[(usize, usize, usize, usize)] = if options.interlace { &ADAM7 } else { &[(0, 0, 1, 1)] };
+    // (パス, 最初の行, 行数) のストリップ。幅か高さが 0 のパスは何も書かない
+    let strips: Vec<(usize, usize, usize)> = passes.iter().enumerate()
+        .flat_map(|(pass, &(left, top, dx, dy))| {
+            let rows = if pass_len(width, left, dx) == 0 { 0 } else { pass_len(height, top, dy) };
+            (0 .. rows.div_ceil(strip_rows)).map(move |i| (pass, i * strip_rows, strip_rows.min(rows - i * strip_rows)))
+        })
+        .collect();
+
+    let parent = tracing::Span::current();
+    let compressed: Vec<(Vec<u8>, u32, usize)> = strips.par_iter().enumerate()
+        .map(|(i, &(pass, top, rows))| {
+            let _span = tracing::info_span!(parent: &parent, "strip").entered();
+            let (left, first, dx, dy) = passes[pass];
+            let pass_width = pass_len(width, left, dx);
+            let pass_row = |y: usize| {
+                let row = row_at(first + y * dy);
+                if dx == 1 {
+                    return row;
+                }
+                let mut picked = Vec::with_capacity(pass_width * pixel_bytes);
+                for x in 0 .. pass_width {
+                    let at = (left + x * dx) * pixel_bytes;
+                    picked.extend_from_slice(&row[at .. at + pixel_bytes]);
+                }
+                Cow::Owned(picked)
+            };
+            let filtered = filter_rows(&pass_row, pass_width * pixel_bytes, top, rows);
+            let deflated = deflate(&filtered, i + 1 == strips.len())?;
+            Ok((deflated, adler32(&filtered), filtered.len()))
+        })
+        .collect::<io::Result<_>>()?;
+
+    output.write_all(&PNG_SIGNATURE)?;
+    let mut header = vec![];
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    // ビット深度 8 か 16、カラータイプ 0 (グレースケール)、4 (グレースケールと不透明度)、2 (RGB) か 6 (RGBA)、
+    // 圧縮・フィルタは既定、インターレースは無しか Adam7
+    let color_type = match channels {
+        1 => 0,
+        2 => 4,
+        3 => 2,
+        _ => 6
+    };
+    header.extend_from_slice(&[bit_depth, color_type, 0, 0, options.interlace as u8]);
+    write_chunk(&mut output, b"IHDR", &header)?;
+    if let Some(profile) = icc {
+        write_chunk(&mut output, b"iCCP", &iccp(profile)?)?;
+    }
+    if let Some(packet) = options.xmp {
+        write_chunk(&mut output, b"iTXt", &xmp_itxt(packet))?;
+    }
+
+    // zlib ヘッダ (deflate、32KiB ウィンドウ、既定の圧縮レベル)
+    write_chunk(&mut output, b"IDAT", &[0x78, 0x9c])?;
+    let mut checksum = 1;
+    for (deflated, adler, len) in &compressed {
+        write_chunk(&mut output, b"IDAT", deflated)?;
+        checksum = adler32_combine(checksum, *adler, *len);
+    }
+    write_chunk(&mut output, b"IDAT", &checksum.to_be_bytes())?;
+    write_chunk(&mut output, b"IEND", &[])?;
+    output.flush()
+}
+
+/// `top` 行目から `rows` 行に Up フィルタを掛ける。直前の行も `row_at` で読むのでストリップの境目でも同じ結果になる。
+/// インターレースするときの行はパスの中の行で、直前の行も同じパスの行
+fn filter_rows<'a, R>(row_at: &R, stride: usize, top: usize, rows: usize) -> Vec<u8>
+    where R: Fn(usize) -> Cow<'a, [u8]>
+{
+    let mut filtered = Vec::with_capacity(rows * (stride + 1));
+    let mut above = if top > 0 { Some(row_at(top - 1)) } else { None };
+    for y in top .. top + rows {
+        let row = row_at(y);
+        filtered.push(2);
+        match above {
+            Some(ref above) => filtered.extend(row.iter().zip(above.iter()).map(|(&p, &a)| p.wrapping_sub(a))),
+            None => filtered.extend_from_slice(&row)
+        }
+        above = Some(row);
+    }
+    filtered
+}
+
+/// ICC プロファイルを埋め込む iCCP チャンクの中身
+fn iccp(profile: &[u8]) -> io::Result<Vec<u8>> {
+    // プロファイル名、区切りの NUL、圧縮方法 0 (zlib) の後に圧縮したプロファイルが続く
+    let mut encoder = ZlibEncoder::new(b"ICC profile\0\0".to_vec(), Compression::default());
+    encoder.write_all(profile)?;
+    encoder.finish()
+}
+
+/// XMP のパケットを入れる iTXt チャンクの中身
+fn xmp_itxt(packet: &str) -> Vec<u8> {
+    // キーワード、区切りの NUL、圧縮しない印と圧縮方法、空の言語タグと訳したキーワードの後に本文が続く
+    let mut data = b"XML:com.adobe.xmp\0\0\0\0\0".to_vec();
+    data.extend_from_slice(packet.as_bytes());
+    data
+}
+
+/// 生の deflate で圧縮する。最後のストリップ以外は sync flush で止めて最終ブロックを書かない
+fn deflate(data: &[u8], last: bool) -> io::Result<Vec<u8>> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    if last {
+        return encoder.finish();
+    }
+    encoder.flush()?;
+    // ここで取り出さないと drop 時に最終ブロックが書き足されるが、取り出した後の空のバッファに書かれるだけ
+    Ok(std::mem::take(encoder.get_mut()))
+}
+
+fn write_chunk<W: Write>(output: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    output.write_all(&(data.len() as u32).to_be_bytes())?;
+    output.write_all(kind)?;
+    output.write_all(data)?;
+    let mut crc = Crc::new();
+    crc.update(kind);
+    crc.update(data);
+    output.write_all(&crc.sum().to_be_bytes())
+}
+
+const ADLER_BASE: u32 = 65521;
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    // 5552 バイトまでは u32 で桁あふれせずに足し込める
+    for block in data.chunks(5552) {
+        for &byte in block {
+            a += byte as u32;
+            b += a;
+        }
+        a %= ADLER_BASE;
+        b %= ADLER_BASE;
+    }
+    (b << 16) | a
+}
+
+/// 前半の Adler-32 `first` と長さ `len` の後半の Adler-32 `second` から全体の値を求める (zlib の adler32_combine)
+fn adler32_combine(first: u32, second: u32, len: usize) -> u32 {
+    let base = ADLER_BASE as u64;
+    let rem = len as u64 % base;
+    let a1 = (first & 0xffff) as u64;
+    let b1 = (first >> 16) as u64;
+    let a2 = (second & 0xffff) as u64;
+    let b2 = (second >> 16) as u64;
+    let a = (a1 + a2 + base - 1) % base;
+    let b = (rem * a1 + b1 + b2 + base - rem) % base;
+    ((b << 16) | a) as u32
+}
+
+#[test]
+fn test_adler32_combine() {
+    let data: Vec<u8> = (0 .. 20000u32).map(|i| (i * 7 % 251) as u8).collect();
+    assert_eq!(adler32(b"Wikipedia"), 0x11e60398);
+    for &split in &[0, 1, 5552, 12345, 20000] {
+        let (head, tail) = data.split_at(split);
+        assert_eq!(adler32_combine(adler32(head), adler32(tail), tail.len()), adler32(&data));
+    }
+}
+
+#[test]
+fn test_encode_parallel() {
+    let bounds = (37, 23);
+    let pixels: Vec<u8> = (0 .. bounds.0 * bounds.1).map(|i| (i * i % 256) as u8).collect();
+    for &strip_rows in &[1, 5, 23, 100] {
+        let mut png = vec![];
+        encode_strips(&mut png, bounds, 1, None, &PngOptions::default(), strip_rows,
+                      |y| Cow::Borrowed(&pixels[y * bounds.0 .. (y + 1) * bounds.0])).unwrap();
+        let decoded = image::load_from_memory(&png).unwrap().to_luma();
+        assert_eq!(decoded.dimensions(), (bounds.0 as u32, bounds.1 as u32));
+        assert_eq!(decoded.into_raw(), pixels);
+    }
+}
+
+#[test]
+fn test_encode_interlaced() {
+    assert_eq!((pass_len(37, 4, 8), pass_len(37, 0, 8), pass_len(3, 4, 8)), (5, 5, 0));
+    // 1x1 や 3x2 では空のパスがある
+    for bounds in [(37, 23), (1, 1), (3, 2), (9, 1)] {
+        let pixels: Vec<u8> = (0 .. bounds.0 * bounds.1).map(|i| (i * i % 256) as u8).collect();
+        for &strip_rows in &[1, 2, 100] {
+            let mut png = vec![];
+            encode_strips(&mut png, bounds, 1, None, &PngOptions { interlace: true, xmp: None }, strip_rows,
+                          |y| Cow::Borrowed(&pixels[y * bounds.0 .. (y + 1) * bounds.0])).unwrap();
+            assert_eq!(png[28], 1);
+            let decoded = image::load_from_memory(&png).unwrap().to_luma();
+            assert_eq!(decoded.into_raw(), pixels);
+        }
+    }
+    // 色を付けた画像も成分毎に並べる
+    let bounds = (19, 11);
+    let pixels: Vec<u8> = (0 .. bounds.0 * bounds.1).map(|i| (i * 7 % 256) as u8).collect();
+    let colors = Colors::resolve(Some("fire"), None, Some("none")).unwrap();
+    let mut png = vec![];
+    encode_colored(&mut png, &pixels, bounds, &colors, &PngOptions { interlace: true, xmp: None }).unwrap();
+    assert_eq!(image::load_from_memory(&png).unwrap().to_rgb().into_raw(), colors.samples(&pixels).into_owned());
+}
+
+#[test]
+fn test_encode_xmp() {
+    let bounds = (7, 5);
+    let pixels: Vec<u8> = (0 .. bounds.0 * bounds.1).map(|i| (i * 5) as u8).collect();
+    let packet = "<?xpacket begin=\"\u{feff}\"?><x:xmpmeta xmlns:x=\"adobe:ns:meta/\"/><?xpacket end=\"w\"?>";
+    let mut png = vec![];
+    encode_colored(&mut png, &pixels, bounds, &Colors::default(), &PngOptions { interlace: false, xmp: Some(packet) })
+        .unwrap();
+    // iTXt は IHDR の直後に置き、キーワードの後に本文をそのまま入れる
+    let len = u32::from_be_bytes([png[33], png[34], png[35], png[36]]) as usize;
+    assert_eq!(&png[37 .. 41], b"iTXt");
+    assert_eq!(&png[41 .. 41 + len], &xmp_itxt(packet)[..]);
+    assert!(png[41 .. 41 + len].ends_with(packet.as_bytes()));
+    assert_eq!(image::load_from_memory(&png).unwrap().to_luma().into_raw(), pixels);
+}
+
+#[test]
+fn test_encode_colored() {
+    let bounds = (37, 23);
+    let pixels: Vec<u8> = (0 .. bounds.0 * bounds.1).map(|i| (i * i % 256) as u8).collect();
+    let colors = Colors::resolve(Some("ultra"), None, None).unwrap();
+    let expected = colors.samples(&pixels).into_owned();
+    for &strip_rows in &[1, 5, 23] {
+        let mut png = vec![];
+        encode_strips(&mut png, bounds, 3, colors.icc.as_deref(), &PngOptions::default(), strip_rows,
+                      |y| colors.samples(&pixels[y * bounds.0 .. (y + 1) * bounds.0])).unwrap();
+        // iCCP は IHDR の直後に置く
+        assert_eq!(&png[33 + 4 .. 33 + 8], b"iCCP");
+        let decoded = image::load_from_memory(&png).unwrap().to_rgb();
+        assert_eq!(decoded.into_raw(), expected);
+    }
+    // 色を付けた画像と、同じ色を RGB で渡した画像は同じ PNG になる
+    let (mut colored, mut rgb) = (vec![], vec![]);
+    encode_colored(&mut colored, &pixels, bounds, &colors, &PngOptions::default()).unwrap();
+    encode_rgb(&mut rgb, &expected, bounds, colors.icc.as_deref(), &PngOptions::default()).unwrap();
+    assert_eq!(colored, rgb);
+
+    // 透明にする点があれば、灰色でも RGB でも不透明度の成分が付く
+    for lut in [None, colors.lut.clone()] {
+        let colors = Colors { lut, icc: None, alpha: Some(super::color::Alpha::Interior) };
+        let mut png = vec![];
+        encode_colored(&mut png, &pixels, bounds, &colors, &PngOptions::default()).unwrap();
+        let decoded = image::load_from_memory(&png).unwrap().to_rgba().into_raw();
+        assert!(decoded.chunks(4).zip(&pixels).all(|(rgba, &gray)| (rgba[3] == 0) == (gray == 0)));
+    }
+}
+
+#[test]
+fn test_encode_frame() {
+    use super::frame::{Gray16, Gray8, Rgba8};
+
+    let bounds = (13, 9);
+    let gray: Vec<u8> = (0 .. bounds.0 * bounds.1).map(|i| (i * 3 % 256) as u8).collect();
+    let frame = FrameBuffer::gray(bounds, &gray);
+    // 8ビットの灰色は encode_parallel と同じ PNG になる
+    let (mut png, mut expected) = (vec![], vec![]);
+    encode_frame(&mut png, &frame, None, &PngOptions::default()).unwrap();
+    encode_parallel(&mut expected, &gray, bounds).unwrap();
+    assert_eq!(png, expected);
+
+    // image は 16ビットの PNG を読めないので、IDAT を展開して Up フィルタを戻し、ビッグエンディアンの成分を読む
+    let deep = frame.map(|Gray8(value)| Gray16(u16::from_be_bytes([value, 255 - value])));
+    let mut png = vec![];
+    encode_frame(&mut png, &deep, None, &PngOptions::default()).unwrap();
+    assert_eq!(png[24], 16);
+    let (mut at, mut zlib) = (8, vec![]);
+    while at < png.len() {
+        let len = u32::from_be_bytes([png[at], png[at + 1], png[at + 2], png[at + 3]]) as usize;
+        if &png[at + 4 .. at + 8] == b"IDAT" {
+            zlib.extend_from_slice(&png[at + 8 .. at + 8 + len]);
+        }
+        at += len + 12;
+    }
+    let mut filtered = vec![];
+    std::io::Read::read_to_end(&mut flate2::read::ZlibDecoder::new(&zlib[..]), &mut filtered).unwrap();
+    let stride = bounds.0 * 2;
+    let mut rows: Vec<Vec<u8>> = vec![];
+    for line in filtered.chunks(stride + 1) {
+        assert_eq!(line[0], 2);
+        let row: Vec<u8> = match rows.last() {
+            Some(above) => line[1 ..].iter().zip(above).map(|(&p, &a)| p.wrapping_add(a)).collect(),
+            None => line[1 ..].to_vec()
+        };
+        rows.push(row);
+    }
+    let values: Vec<u16> = rows.concat().chunks(2).map(|pair| u16::from_be_bytes([pair[0], pair[1]])).collect();
+    assert_eq!(values, deep.pixels.iter().map(|&Gray16(value)| value).collect::<Vec<u16>>());
+    // インターレースするときも1ピクセル2バイトずつ拾う
+    let mut interlaced = vec![];
+    encode_frame(&mut interlaced, &deep, None, &PngOptions { interlace: true, xmp: None }).unwrap();
+    assert_eq!((interlaced[24], interlaced[28]), (16, 1));
+
+    let rgba = frame.map(|Gray8(value)| Rgba8([value, 0, 255 - value, 128]));
+    let mut png = vec![];
+    encode_frame(&mut png, &rgba, None, &PngOptions::default()).unwrap();
+    let decoded = image::load_from_memory(&png).unwrap().to_rgba().into_raw();
+    assert_eq!(decoded, rgba.pixels.iter().flat_map(|&Rgba8(rgba)| rgba).collect::<Vec<u8>>());
+}
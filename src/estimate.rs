@@ -0,0 +1,161 @@
+//! `--dry-run` で表示する描画コストの見積もり
+//!
+//! 画像全体に粗い格子で標本点を取って実際に反復し、1ピクセルあたりの平均反復回数と
+//! 1反復あたりの時間を測って、画像全体の反復回数と所要時間に引き伸ばす。
+//!
+//! 深く拡大するとピクセルの間隔が座標の f64 の刻みに近づき、隣のピクセルが同じ点になって崩れる。
+//! `imprecise_tiles` はタイル毎にその範囲とピクセルの間隔から f64 で足りるかを調べる。足りないタイルは
+//! `precision` が倍々精度で描く。
+
+use std::time::{Duration, Instant};
+
+use num::Complex;
+
+use super::projection;
+use super::{pixel_to_point, Orbit, RenderParams};
+
+/// 見積もりに使う標本点の格子の一辺の最大数
+const MAX_SAMPLES_PER_AXIS: usize = 64;
+
+/// 精度を調べるタイルの一辺のピクセル数
+pub const PRECISION_TILE: usize = 256;
+
+/// 1ピクセルの間に f64 で表せる値がこれだけ無ければ、そのタイルは f64 では描けない
+const MIN_ULPS_PER_PIXEL: f64 = 16.0;
+
+#[derive(Debug)]
+pub struct CostEstimate {
+    pub samples: usize,
+    /// 1ピクセルあたりの平均反復回数
+    pub mean_iterations: f64,
+    pub total_iterations: f64,
+    /// ピクセルバッファに必要なバイト数
+    pub memory_bytes: usize,
+    pub threads: usize,
+    pub wall_clock: Duration
+}
+
+/// 全てのパスを通して `c` の反復回数を数える
+fn iterations(c: Complex<f64>, params: &RenderParams) -> u64 {
+    let mut orbit = Orbit::new(c);
+    for &limit in &params.limits {
+        if orbit.advance(limit, params.fractal, &params.termination).is_some() || orbit.interior {
+            break;
+        }
+    }
+    orbit.iteration as u64
+}
+
+pub fn estimate_cost(bounds: (usize, usize),
+                     upper_left: Complex<f64>,
+                     lower_right: Complex<f64>,
+                     params: &RenderParams)
+    -> CostEstimate
+{
+    let grid = (bounds.0.clamp(1, MAX_SAMPLES_PER_AXIS), bounds.1.clamp(1, MAX_SAMPLES_PER_AXIS));
+    let projection = projection::for_params(params, upper_left, lower_right);
+    let started = Instant::now();
+    let mut sampled_iterations = 0;
+    for row in 0 .. grid.1 {
+        for column in 0 .. grid.0 {
+            // 格子の各マスの中心を標本点にする
+            let pixel = ((column * bounds.0 + bounds.0 / 2) / grid.0,
+                         (row * bounds.1 + bounds.1 / 2) / grid.1);
+            sampled_iterations += iterations(projection.point(bounds, pixel), params);
+        }
+    }
+    let probe = started.elapsed();
+
+    let samples = grid.0 * grid.1;
+    let pixels = bounds.0 * bounds.1;
+    let mean_iterations = sampled_iterations as f64 / samples as f64;
+    let total_iterations = mean_iterations * pixels as f64;
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let per_iteration = probe.as_secs_f64() / sampled_iterations.max(1) as f64;
+
+    CostEstimate {
+        samples,
+        mean_iterations,
+        total_iterations,
+        memory_bytes: pixels,
+        threads,
+        wall_clock: Duration::from_secs_f64(total_iterations * per_iteration / threads as f64)
+    }
+}
+
+#[test]
+fn test_estimate_cost() {
+    let params = RenderParams::default();
+
+    // 脱出半径の外側だけの範囲は1回の反復で発散する
+    let estimate = estimate_cost((1000, 500), Complex { re: 10.0, im: 1.0 },
+                                 Complex { re: 11.0, im: 0.5 }, &params);
+    assert_eq!(estimate.samples, 64 * 64);
+    assert_eq!(estimate.mean_iterations, 1.0);
+    assert_eq!(estimate.total_iterations, 500_000.0);
+    assert_eq!(estimate.memory_bytes, 500_000);
+
+    // 内部だけの範囲は上限まで反復する
+    let estimate = estimate_cost((8, 8), Complex { re: -0.2, im: 0.1 },
+                                 Complex { re: -0.1, im: -0.1 }, &params);
+    assert_eq!(estimate.samples, 64);
+    assert_eq!(estimate.mean_iterations, 255.0);
+}
+
+/// 左上のピクセルが `tile` (列, 行) の `PRECISION_TILE` ピクセル四方のタイルを、刻みが `epsilon` の
+/// 浮動小数点数で描けるか。ピクセルの間隔がタイルの中で最も大きい座標の刻みに対して細かすぎれば描けない
+pub fn tile_resolves(bounds: (usize, usize),
+                     upper_left: Complex<f64>,
+                     lower_right: Complex<f64>,
+                     tile: (usize, usize),
+                     epsilon: f64)
+    -> bool
+{
+    let spacing = ((lower_right.re - upper_left.re) / bounds.0 as f64)
+        .min((upper_left.im - lower_right.im) / bounds.1 as f64);
+    let corner = |x: usize, y: usize| pixel_to_point(bounds, (x.min(bounds.0), y.min(bounds.1)), upper_left, lower_right);
+    let (a, b) = (corner(tile.0, tile.1), corner(tile.0 + PRECISION_TILE, tile.1 + PRECISION_TILE));
+    let magnitude = a.re.abs().max(b.re.abs()).max(a.im.abs()).max(b.im.abs());
+    spacing >= MIN_ULPS_PER_PIXEL * magnitude * epsilon
+}
+
+/// `PRECISION_TILE` ピクセル四方のタイルのうち、刻みが `epsilon` の浮動小数点数では描けないものの数と、タイルの数
+pub fn unresolved_tiles(bounds: (usize, usize), upper_left: Complex<f64>, lower_right: Complex<f64>, epsilon: f64)
+    -> (usize, usize)
+{
+    let tiles = |len: usize| (0 .. len).step_by(PRECISION_TILE);
+    let mut unresolved = 0;
+    let mut total = 0;
+    for top in tiles(bounds.1) {
+        for left in tiles(bounds.0) {
+            if !tile_resolves(bounds, upper_left, lower_right, (left, top), epsilon) {
+                unresolved += 1;
+            }
+            total += 1;
+        }
+    }
+    (unresolved, total)
+}
+
+/// f64 では描けないタイルの数と、タイルの数
+pub fn imprecise_tiles(bounds: (usize, usize), upper_left: Complex<f64>, lower_right: Complex<f64>) -> (usize, usize) {
+    unresolved_tiles(bounds, upper_left, lower_right, f64::EPSILON)
+}
+
+#[test]
+fn test_imprecise_tiles() {
+    let full = imprecise_tiles((1000, 500), Complex { re: -2.0, im: 1.0 }, Complex { re: 2.0, im: -1.0 });
+    assert_eq!(full, (0, 4 * 2));
+    // 1e-15 の幅を1000ピクセルに分けると、-0.75 の近くでは f64 の刻みより細かい
+    let deep = imprecise_tiles((1000, 500), Complex { re: -0.75, im: 0.1 }, Complex { re: -0.75 + 1e-15, im: 0.1 - 5e-16 });
+    assert_eq!(deep, (8, 8));
+    // 倍々精度の刻みなら描ける
+    let upper_left = Complex { re: -0.75, im: 0.1 };
+    assert_eq!(unresolved_tiles((1000, 500), upper_left, Complex { re: -0.75 + 1e-15, im: 0.1 - 5e-16 },
+                                f64::EPSILON * f64::EPSILON), (0, 8));
+    assert!(!tile_resolves((1000, 500), upper_left, Complex { re: -0.75 + 1e-15, im: 0.1 - 5e-16 },
+                           (256, 256), f64::EPSILON));
+    // 原点の近くなら同じ幅でも刻みが細かいので描ける
+    let origin = imprecise_tiles((1000, 500), Complex { re: 0.0, im: 5e-16 }, Complex { re: 1e-15, im: 0.0 });
+    assert_eq!(origin, (0, 8));
+}
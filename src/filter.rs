@@ -0,0 +1,125 @@
+//! パレットの色を付ける前の灰色の画像に掛ける `--filter`
+//!
+//! `sobel` は明るさの勾配の大きさで帯の境目を白い線にし、`emboss` は左上から光を当てたように浮き彫りにし、
+//! `unsharp` は 3x3 の平均との差を足して帯の境目をくっきりさせる。`--filter sobel,unsharp` のように
+//! 並べると順に掛ける。どれも 3x3 の近傍だけを見て行毎に並列に計算し、画像の外は端のピクセルが続くとみなす。
+//! 色を付ける前に掛けるので、結果にも `--palette` の色が付く。
+
+use std::str::FromStr;
+
+use rayon::prelude::*;
+
+/// 灰色の画像に掛ける処理
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Filter {
+    Sobel,
+    Emboss,
+    Unsharp
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Filter, String> {
+        match s {
+            "sobel" => Ok(Filter::Sobel),
+            "emboss" => Ok(Filter::Emboss),
+            "unsharp" => Ok(Filter::Unsharp),
+            _ => Err(format!("unknown filter '{}', expected sobel, emboss or unsharp", s))
+        }
+    }
+}
+
+/// `sobel,unsharp` のようにカンマで区切った処理の並び
+pub fn parse_filters(s: &str) -> Result<Vec<Filter>, String> {
+    s.split(',').map(Filter::from_str).collect()
+}
+
+/// Sobel の横方向の勾配の重み。縦方向はこれを転置したもの
+const SOBEL: [[f64; 3]; 3] = [[-1.0, 0.0, 1.0], [-2.0, 0.0, 2.0], [-1.0, 0.0, 1.0]];
+
+/// 左上から光を当てる浮き彫りの重み。合計が 1 なので平らなところの明るさは変わらない
+const EMBOSS: [[f64; 3]; 3] = [[-2.0, -1.0, 0.0], [-1.0, 1.0, 1.0], [0.0, 1.0, 2.0]];
+
+impl Filter {
+    /// `pixels` に掛けた画像
+    pub fn apply(self, pixels: &[u8], bounds: (usize, usize)) -> Vec<u8> {
+        let weighted = |at: &dyn Fn(isize, isize) -> f64, weights: &[[f64; 3]; 3]| {
+            let mut sum = 0.0;
+            for (dy, row) in weights.iter().enumerate() {
+                for (dx, weight) in row.iter().enumerate() {
+                    sum += weight * at(dx as isize - 1, dy as isize - 1);
+                }
+            }
+            sum
+        };
+        convolve(pixels, bounds, |at| match self {
+            Filter::Sobel => {
+                let (gx, gy) = (weighted(at, &SOBEL), weighted(&|dx, dy| at(dy, dx), &SOBEL));
+                // 最も急な段差 (0 と 255 の境目) で 255 を超えないよう 4 で割る
+                (gx * gx + gy * gy).sqrt() / 4.0
+            }
+            Filter::Emboss => weighted(at, &EMBOSS),
+            Filter::Unsharp => {
+                let mean = weighted(at, &[[1.0 / 9.0; 3]; 3]);
+                2.0 * at(0, 0) - mean
+            }
+        })
+    }
+}
+
+/// 各ピクセルの 3x3 の近傍を `kernel` に渡し、返した値を丸めた画像
+fn convolve<K>(pixels: &[u8], bounds: (usize, usize), kernel: K) -> Vec<u8>
+    where K: Fn(&dyn Fn(isize, isize) -> f64) -> f64 + Sync
+{
+    let (width, height) = (bounds.0 as isize, bounds.1 as isize);
+    let mut filtered = vec![0; pixels.len()];
+    filtered.par_chunks_mut(bounds.0).enumerate().for_each(|(row, line)| {
+        for (column, pixel) in line.iter_mut().enumerate() {
+            let at = |dx: isize, dy: isize| {
+                let x = (column as isize + dx).clamp(0, width - 1);
+                let y = (row as isize + dy).clamp(0, height - 1);
+                pixels[(y * width + x) as usize] as f64
+            };
+            *pixel = kernel(&at).round().clamp(0.0, 255.0) as u8;
+        }
+    });
+    filtered
+}
+
+/// `filters` を順に掛けた画像
+pub fn apply_all(pixels: &mut [u8], bounds: (usize, usize), filters: &[Filter]) {
+    for filter in filters {
+        let filtered = filter.apply(pixels, bounds);
+        pixels.copy_from_slice(&filtered);
+    }
+}
+
+#[test]
+fn test_filters() {
+    assert_eq!(parse_filters("sobel,unsharp"), Ok(vec![Filter::Sobel, Filter::Unsharp]));
+    assert!(parse_filters("blur").is_err());
+    assert!(parse_filters("").is_err());
+
+    // 平らな画像は Sobel では黒になり、他では変わらない
+    let flat = vec![100; 4 * 3];
+    assert_eq!(Filter::Sobel.apply(&flat, (4, 3)), vec![0; 12]);
+    assert_eq!(Filter::Emboss.apply(&flat, (4, 3)), flat);
+    assert_eq!(Filter::Unsharp.apply(&flat, (4, 3)), flat);
+
+    // 左半分が黒、右半分が白の縦の段差
+    let step: Vec<u8> = (0 .. 4 * 3).map(|i| if i % 4 < 2 { 0 } else { 255 }).collect();
+    let edges = Filter::Sobel.apply(&step, (4, 3));
+    assert_eq!(&edges[4 .. 8], [0, 255, 255, 0]);
+    // 段差の両側が強められ、暗い側はより暗く、明るい側はより明るくなる
+    let sharpened = Filter::Unsharp.apply(&[50, 50, 200, 200], (4, 1));
+    assert!(sharpened[1] < 50 && sharpened[2] > 200);
+    // 浮き彫りでは、光の来る左上に向いた段差が明るく、反対向きの段差が暗くなる
+    let falling: Vec<u8> = step.iter().map(|&pixel| 255 - pixel).collect();
+    assert!(Filter::Emboss.apply(&step, (4, 3))[5] > step[5]);
+    assert!(Filter::Emboss.apply(&falling, (4, 3))[5] < falling[5]);
+
+    let mut pixels = step.clone();
+    apply_all(&mut pixels, (4, 3), &[Filter::Sobel, Filter::Sobel]);
+    assert_eq!(pixels, Filter::Sobel.apply(&edges, (4, 3)));
+}
@@ -0,0 +1,452 @@
+//! 反復する式を入れ替えた Mandelbrot 集合の仲間
+//!
+//! ほとんどの式は `z_0 = 0` から `z_{n+1} = f(z_n) + c` を反復し、`f` だけが違う。
+//! `abs` を含む式は正則でないので、微分を使う内部判定 (`--interior-check`) は `mandelbrot` でだけ効く。
+//! 反復のループは `Fractal` を実装した型ごとに単相化されるので、式の選択がループの中に分岐を残さない。
+//!
+//! Magnet 型の式は不動点 `z = 1` に収束する領域を持つ。`Fractal::converged` で収束を判定し、
+//! 収束した点は内部の点と同じく発散しなかったものとして打ち切る。
+//!
+//! Nova は緩和付きの Newton 法に `+ c` を足した式で、ほとんどの点が `z^p = 1` の根に収束する。
+//! 発散までの回数の代わりに収束までの回数で色付けする。
+//!
+//! Collatz は整数の上で Collatz 写像に一致する `cos` を使った整関数を、`c` を始点にして反復する。
+//! `--hybrid MMB` は2次の式を1文字ずつ並べた列を繰り返し、反復の番号で1回ごとに式を切り替える。
+//!
+//! 脱出半径の既定値は式ごとに `FractalKind::default_bailout` で決める。`z^2 + c` の 2 は他の式では適切でない。
+
+use std::str::FromStr;
+
+use num::Complex;
+
+use super::plugin::Plugin;
+
+/// 1回の反復の式
+pub trait Fractal {
+    /// 式が正則で、`z^2 + c` と同じ微分 `2z` を持つか
+    const ANALYTIC: bool = false;
+
+    /// `converged` で収束を判定する式か
+    const CONVERGES: bool = false;
+
+    /// 収束までの回数を発散までの回数の代わりに使って色付けするか。
+    /// 偽なら収束した点は内部の点として扱う
+    const COLORS_CONVERGENCE: bool = false;
+
+    /// 点 `c` の軌道の始点 `z_0`
+    fn start(&self, _c: Complex<f64>) -> Complex<f64> {
+        Complex { re: 0.0, im: 0.0 }
+    }
+
+    /// `z_n` と `c` から `z_{n+1}` を求める
+    fn step(&self, z: Complex<f64>, c: Complex<f64>) -> Complex<f64>;
+
+    /// `n` 回目の反復。反復の番号で式を切り替えるときに実装する
+    #[inline(always)]
+    fn step_at(&self, _n: u32, z: Complex<f64>, c: Complex<f64>) -> Complex<f64> {
+        self.step(z, c)
+    }
+
+    /// 1つ前の `previous` から `z` に進んだ軌道が吸引的な不動点に収束したか
+    fn converged(&self, _previous: Complex<f64>, _z: Complex<f64>) -> bool {
+        false
+    }
+}
+
+/// 収束したとみなす距離の2乗
+const CONVERGENCE_EPSILON: f64 = 1e-12;
+
+/// `z^2 + c`
+pub struct Mandelbrot;
+
+impl Fractal for Mandelbrot {
+    const ANALYTIC: bool = true;
+
+    #[inline(always)]
+    fn step(&self, z: Complex<f64>, c: Complex<f64>) -> Complex<f64> {
+        z * z + c
+    }
+}
+
+/// 実部と虚部の絶対値を取ってから2乗する `(|Re z| + i |Im z|)^2 + c`
+pub struct BurningShip;
+
+impl Fractal for BurningShip {
+    #[inline(always)]
+    fn step(&self, z: Complex<f64>, c: Complex<f64>) -> Complex<f64> {
+        Complex { re: z.re * z.re - z.im * z.im + c.re, im: 2.0 * (z.re * z.im).abs() + c.im }
+    }
+}
+
+/// 実部の絶対値を取る `|Re z^2| + i Im z^2 + c`
+pub struct Celtic;
+
+impl Fractal for Celtic {
+    #[inline(always)]
+    fn step(&self, z: Complex<f64>, c: Complex<f64>) -> Complex<f64> {
+        Complex { re: (z.re * z.re - z.im * z.im).abs() + c.re, im: 2.0 * z.re * z.im + c.im }
+    }
+}
+
+/// 実部と虚部の両方の絶対値を取る `|Re z^2| - i |Im z^2| + c`
+pub struct Buffalo;
+
+impl Fractal for Buffalo {
+    #[inline(always)]
+    fn step(&self, z: Complex<f64>, c: Complex<f64>) -> Complex<f64> {
+        Complex { re: (z.re * z.re - z.im * z.im).abs() + c.re, im: -2.0 * (z.re * z.im).abs() + c.im }
+    }
+}
+
+/// 虚部だけ絶対値を取ってから2乗する `(Re z - i |Im z|)^2 + c`
+pub struct PerpendicularBurningShip;
+
+impl Fractal for PerpendicularBurningShip {
+    #[inline(always)]
+    fn step(&self, z: Complex<f64>, c: Complex<f64>) -> Complex<f64> {
+        Complex { re: z.re * z.re - z.im * z.im + c.re, im: -2.0 * z.re * z.im.abs() + c.im }
+    }
+}
+
+/// 実部だけ絶対値を取ってから2乗する `(|Re z| - i Im z)^2 + c`
+pub struct PerpendicularMandelbrot;
+
+impl Fractal for PerpendicularMandelbrot {
+    #[inline(always)]
+    fn step(&self, z: Complex<f64>, c: Complex<f64>) -> Complex<f64> {
+        Complex { re: z.re * z.re - z.im * z.im + c.re, im: -2.0 * z.re.abs() * z.im + c.im }
+    }
+}
+
+/// 磁性体の繰り込み群から得られた `((z^2 + c - 1) / (2z + c - 2))^2`
+pub struct MagnetI;
+
+impl Fractal for MagnetI {
+    const CONVERGES: bool = true;
+
+    #[inline(always)]
+    fn step(&self, z: Complex<f64>, c: Complex<f64>) -> Complex<f64> {
+        let one = Complex { re: 1.0, im: 0.0 };
+        let w = (z * z + c - one) / (z * 2.0 + c - one * 2.0);
+        w * w
+    }
+
+    fn converged(&self, _previous: Complex<f64>, z: Complex<f64>) -> bool {
+        (z - Complex { re: 1.0, im: 0.0 }).norm_sqr() < CONVERGENCE_EPSILON
+    }
+}
+
+/// `((z^3 + 3(c - 1)z + (c - 1)(c - 2)) / (3z^2 + 3(c - 2)z + (c - 1)(c - 2) + 1))^2`
+pub struct MagnetII;
+
+impl Fractal for MagnetII {
+    const CONVERGES: bool = true;
+
+    #[inline(always)]
+    fn step(&self, z: Complex<f64>, c: Complex<f64>) -> Complex<f64> {
+        let one = Complex { re: 1.0, im: 0.0 };
+        let (c1, c2) = (c - one, c - one * 2.0);
+        let numerator = z * z * z + c1 * z * 3.0 + c1 * c2;
+        let denominator = z * z * 3.0 + c2 * z * 3.0 + c1 * c2 + one;
+        let w = numerator / denominator;
+        w * w
+    }
+
+    fn converged(&self, previous: Complex<f64>, z: Complex<f64>) -> bool {
+        MagnetI.converged(previous, z)
+    }
+}
+
+/// `z - R (z^p - 1) / (p z^(p-1)) + c`。`z^p - 1` の臨界点 `z_0 = 1` から反復する
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Nova {
+    /// 多項式の次数 `p`
+    pub degree: u32,
+    /// 緩和係数 `R`。1 なら Newton 法そのもの
+    pub relaxation: f64
+}
+
+impl Default for Nova {
+    fn default() -> Nova {
+        Nova { degree: 3, relaxation: 1.0 }
+    }
+}
+
+/// Nova の次数の上限。`step` は次数に比例する回数だけ掛け算する
+pub const MAX_NOVA_DEGREE: u32 = 64;
+
+impl Nova {
+    /// 次数が 2 以上 `MAX_NOVA_DEGREE` 以下で、緩和係数が 0 でない有限の値かを確かめる
+    pub fn check(&self) -> Result<(), String> {
+        if !(2 ..= MAX_NOVA_DEGREE).contains(&self.degree) {
+            return Err(format!("--nova-degree expects an integer between 2 and {}", MAX_NOVA_DEGREE));
+        }
+        if self.relaxation == 0.0 || !self.relaxation.is_finite() {
+            return Err("--nova-relaxation expects a non-zero number".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl Fractal for Nova {
+    const CONVERGES: bool = true;
+    const COLORS_CONVERGENCE: bool = true;
+
+    fn start(&self, _c: Complex<f64>) -> Complex<f64> {
+        Complex { re: 1.0, im: 0.0 }
+    }
+
+    #[inline(always)]
+    fn step(&self, z: Complex<f64>, c: Complex<f64>) -> Complex<f64> {
+        let mut power = Complex { re: 1.0, im: 0.0 };
+        for _ in 1 .. self.degree {
+            power *= z;
+        }
+        // power = z^(p-1)
+        let numerator = power * z - Complex { re: 1.0, im: 0.0 };
+        let denominator = power * self.degree as f64;
+        z - numerator / denominator * self.relaxation + c
+    }
+
+    fn converged(&self, previous: Complex<f64>, z: Complex<f64>) -> bool {
+        (z - previous).norm_sqr() < CONVERGENCE_EPSILON
+    }
+}
+
+/// `(2 + 7z - (2 + 5z) cos(pi z)) / 4`。`c` は始点にだけ使う
+pub struct Collatz;
+
+impl Fractal for Collatz {
+    fn start(&self, c: Complex<f64>) -> Complex<f64> {
+        c
+    }
+
+    #[inline(always)]
+    fn step(&self, z: Complex<f64>, _c: Complex<f64>) -> Complex<f64> {
+        let two = Complex { re: 2.0, im: 0.0 };
+        let cosine = (z * std::f64::consts::PI).cos();
+        (two + z * 7.0 - (two + z * 5.0) * cosine) / 4.0
+    }
+}
+
+/// `--hybrid` の列に並べる2次の式
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Formula {
+    Mandelbrot,
+    BurningShip,
+    Celtic,
+    Buffalo,
+    PerpendicularBurningShip,
+    PerpendicularMandelbrot
+}
+
+/// `--hybrid` の列の長さの上限
+const MAX_HYBRID_STEPS: usize = 16;
+
+/// 式の列を繰り返し、`n` 回目の反復では `n % len` 番目の式を使う
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Hybrid {
+    formulas: [Formula; MAX_HYBRID_STEPS],
+    len: usize
+}
+
+impl FromStr for Hybrid {
+    type Err = String;
+
+    /// 1文字で1つの式を表す。M: mandelbrot、B: burning-ship、C: celtic、F: buffalo、
+    /// P: perpendicular-burning-ship、Q: perpendicular-mandelbrot
+    fn from_str(s: &str) -> Result<Hybrid, String> {
+        if s.is_empty() || s.len() > MAX_HYBRID_STEPS {
+            return Err(format!("--hybrid expects 1 to {} formulas such as MMB", MAX_HYBRID_STEPS));
+        }
+        let mut formulas = [Formula::Mandelbrot; MAX_HYBRID_STEPS];
+        for (formula, letter) in formulas.iter_mut().zip(s.chars()) {
+            *formula = match letter {
+                'M' => Formula::Mandelbrot,
+                'B' => Formula::BurningShip,
+                'C' => Formula::Celtic,
+                'F' => Formula::Buffalo,
+                'P' => Formula::PerpendicularBurningShip,
+                'Q' => Formula::PerpendicularMandelbrot,
+                _ => return Err(format!("unknown formula '{}' in --hybrid, expected one of MBCFPQ", letter))
+            };
+        }
+        Ok(Hybrid { formulas, len: s.len() })
+    }
+}
+
+impl Fractal for Hybrid {
+    fn step(&self, z: Complex<f64>, c: Complex<f64>) -> Complex<f64> {
+        self.step_at(0, z, c)
+    }
+
+    #[inline(always)]
+    fn step_at(&self, n: u32, z: Complex<f64>, c: Complex<f64>) -> Complex<f64> {
+        match self.formulas[n as usize % self.len] {
+            Formula::Mandelbrot => Mandelbrot.step(z, c),
+            Formula::BurningShip => BurningShip.step(z, c),
+            Formula::Celtic => Celtic.step(z, c),
+            Formula::Buffalo => Buffalo.step(z, c),
+            Formula::PerpendicularBurningShip => PerpendicularBurningShip.step(z, c),
+            Formula::PerpendicularMandelbrot => PerpendicularMandelbrot.step(z, c)
+        }
+    }
+}
+
+#[test]
+fn test_hybrid() {
+    let hybrid = Hybrid::from_str("MMB").unwrap();
+    let z = Complex { re: -1.5, im: 0.5 };
+    let c = Complex { re: 0.25, im: -0.5 };
+    assert_eq!(hybrid.step_at(0, z, c), Mandelbrot.step(z, c));
+    assert_eq!(hybrid.step_at(1, z, c), Mandelbrot.step(z, c));
+    assert_eq!(hybrid.step_at(2, z, c), BurningShip.step(z, c));
+    assert_eq!(hybrid.step_at(5, z, c), BurningShip.step(z, c));
+    assert_eq!(BurningShip.step(z, c), Complex { re: 2.25, im: 1.0 });
+    assert!(Hybrid::from_str("").is_err());
+    assert!(Hybrid::from_str("MX").is_err());
+    assert!(Hybrid::from_str(&"M".repeat(MAX_HYBRID_STEPS + 1)).is_err());
+}
+
+/// `--fractal` で選ぶ式
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FractalKind {
+    Mandelbrot,
+    BurningShip,
+    Celtic,
+    Buffalo,
+    PerpendicularBurningShip,
+    PerpendicularMandelbrot,
+    MagnetI,
+    MagnetII,
+    /// 次数と緩和係数は `--nova-degree` と `--nova-relaxation` で変える
+    Nova(Nova),
+    Collatz,
+    /// `--hybrid` で与えた式の列
+    Hybrid(Hybrid),
+    /// `--plugin` で読み込んだ式
+    Plugin(Plugin)
+}
+
+impl FractalKind {
+    /// `--bailout` を与えなかったときの脱出半径
+    pub fn default_bailout(self) -> f64 {
+        match self {
+            FractalKind::MagnetI | FractalKind::MagnetII => 100.0,
+            // 根の近くでは |z| が 1 前後なので、本当に発散した点だけを拾うよう大きくする
+            FractalKind::Nova(_) => 1e6,
+            // 虚軸方向には cos が指数関数的に増えるので、整数の上の軌道が収まる程度に取る
+            FractalKind::Collatz => 1e3,
+            FractalKind::Plugin(plugin) => plugin.bailout,
+            _ => 2.0
+        }
+    }
+}
+
+impl FromStr for FractalKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<FractalKind, String> {
+        match s {
+            "mandelbrot" => Ok(FractalKind::Mandelbrot),
+            "burning-ship" => Ok(FractalKind::BurningShip),
+            "celtic" => Ok(FractalKind::Celtic),
+            "buffalo" => Ok(FractalKind::Buffalo),
+            "perpendicular-burning-ship" => Ok(FractalKind::PerpendicularBurningShip),
+            "perpendicular-mandelbrot" => Ok(FractalKind::PerpendicularMandelbrot),
+            "magnet-1" => Ok(FractalKind::MagnetI),
+            "magnet-2" => Ok(FractalKind::MagnetII),
+            "nova" => Ok(FractalKind::Nova(Nova::default())),
+            "collatz" => Ok(FractalKind::Collatz),
+            _ => Err(format!("unknown fractal '{}', expected mandelbrot, burning-ship, celtic, buffalo, \
+                              perpendicular-burning-ship, perpendicular-mandelbrot, \
+                              magnet-1, magnet-2, nova or collatz", s))
+        }
+    }
+}
+
+#[test]
+fn test_fractal_step() {
+    let z = Complex { re: -1.5, im: 0.5 };
+    let c = Complex { re: 0.25, im: -0.5 };
+    // z^2 = 2 - 1.5i
+    assert_eq!(Mandelbrot.step(z, c), Complex { re: 2.25, im: -2.0 });
+    assert_eq!(Celtic.step(z, c), Complex { re: 2.25, im: -2.0 });
+    assert_eq!(Buffalo.step(z, c), Complex { re: 2.25, im: -2.0 });
+    assert_eq!(PerpendicularBurningShip.step(z, c), Complex { re: 2.25, im: 1.0 });
+    assert_eq!(PerpendicularMandelbrot.step(z, c), Complex { re: 2.25, im: -2.0 });
+    // Re z^2 が負になると Celtic と Buffalo の実部が折り返される
+    let z = Complex { re: 0.5, im: 1.0 };
+    assert_eq!(Celtic.step(z, c), Complex { re: 1.0, im: 0.5 });
+    assert_eq!(Buffalo.step(z, c), Complex { re: 1.0, im: -1.5 });
+    assert_eq!(PerpendicularMandelbrot.step(z, c), Complex { re: -0.5, im: -1.5 });
+
+    // c = 0 の Magnet I では z_1 = ((0 - 1) / (0 - 2))^2 = 1/4
+    let zero = Complex { re: 0.0, im: 0.0 };
+    assert_eq!(MagnetI.step(zero, zero), Complex { re: 0.25, im: 0.0 });
+    // Magnet II では z_1 = ((-1)(-2) / ((-1)(-2) + 1))^2 = 4/9
+    assert!((MagnetII.step(zero, zero) - Complex { re: 4.0 / 9.0, im: 0.0 }).norm() < 1e-15);
+    // z = 1 はどちらの式でも不動点
+    let one = Complex { re: 1.0, im: 0.0 };
+    let c = Complex { re: 0.3, im: 0.2 };
+    assert!(MagnetI.converged(one, MagnetI.step(one, c)));
+    assert!(MagnetII.converged(one, MagnetII.step(one, c)));
+    assert!(!Mandelbrot.converged(one, one));
+
+    // 緩和係数 1 の Nova は z^3 = 1 の根で止まり、c を足した分だけずれる
+    let nova = Nova::default();
+    assert_eq!(nova.step(one, zero), one);
+    assert_eq!(nova.step(one, c), one + c);
+    // z = 2: 2 - (8 - 1) / 12 = 17/12
+    let two = Complex { re: 2.0, im: 0.0 };
+    assert!((nova.step(two, zero) - Complex { re: 17.0 / 12.0, im: 0.0 }).norm() < 1e-15);
+    let relaxed = Nova { degree: 3, relaxation: 0.5 };
+    assert!((relaxed.step(two, zero) - Complex { re: 41.0 / 24.0, im: 0.0 }).norm() < 1e-15);
+    assert!(nova.converged(one, one) && !nova.converged(one, two));
+    assert!(nova.check().is_ok() && Nova { degree: MAX_NOVA_DEGREE, relaxation: 0.5 }.check().is_ok());
+    assert!(Nova { degree: 1, relaxation: 1.0 }.check().is_err());
+    assert!(Nova { degree: 4_000_000_000, relaxation: 1.0 }.check().is_err());
+    assert!(Nova { degree: 3, relaxation: f64::NAN }.check().is_err());
+
+    // 整数の上では Collatz 写像 (偶数は n/2、奇数は 3n + 1) になる
+    for &(n, next) in &[(6.0, 3.0), (3.0, 10.0), (1.0, 4.0), (8.0, 4.0)] {
+        let z = Collatz.step(Complex { re: n, im: 0.0 }, zero);
+        assert!((z - Complex { re: next, im: 0.0 }).norm() < 1e-12);
+    }
+    assert_eq!(Collatz.start(c), c);
+
+    assert_eq!(FractalKind::from_str("celtic"), Ok(FractalKind::Celtic));
+    assert_eq!(FractalKind::from_str("nova"), Ok(FractalKind::Nova(Nova::default())));
+    assert_eq!(FractalKind::from_str("magnet-2"), Ok(FractalKind::MagnetII));
+    assert!(FractalKind::from_str("burning-ship-3d").is_err());
+}
+
+/// 小さな画像を描いたピクセルの SHA-1。式を変えてしまったら分かるよう、描画結果を固定しておく
+#[cfg(test)]
+fn golden_digest(fractal: FractalKind) -> String {
+    let bounds = (48, 36);
+    let params = super::RenderParams { fractal, ..super::RenderParams::default() };
+    let mut pixels = vec![0; bounds.0 * bounds.1];
+    super::render(&mut pixels, bounds, Complex { re: -2.2, im: 1.8 }, Complex { re: 1.4, im: -1.8 }, &params);
+    let mut sha1 = sha1_smol::Sha1::new();
+    sha1.update(&pixels);
+    sha1.digest().to_string()
+}
+
+#[test]
+fn test_fractal_golden() {
+    assert_eq!(golden_digest(FractalKind::Mandelbrot), "414f2822ada80d8f447b836bc5ecae5abfe57186");
+    assert_eq!(golden_digest(FractalKind::Celtic), "400f6fde488983ebc78a8f4a6495c30cda6a962e");
+    assert_eq!(golden_digest(FractalKind::Buffalo), "5dbb4130fc711e087bd10fb6b19afe91f56c78a9");
+    assert_eq!(golden_digest(FractalKind::PerpendicularBurningShip), "3c82673ba98e1b460b9b62c13ed11f83502dca1c");
+    assert_eq!(golden_digest(FractalKind::PerpendicularMandelbrot), "828383c3bd7454ef3b790e7b98faefd0d20723ca");
+    assert_eq!(golden_digest(FractalKind::MagnetI), "b4b9cd60ad778ca856ff1a4e288de51aed9ec758");
+    assert_eq!(golden_digest(FractalKind::MagnetII), "a6e216ba7590bbec74a43dfb3a4ca7d2b02096da");
+    assert_eq!(golden_digest(FractalKind::Nova(Nova::default())), "26f6b4d1aed418d7fb8bd38dc95100745d6196a8");
+    assert_eq!(golden_digest(FractalKind::Collatz), "45473f57dd4f65532fd5dbdce997c73b289b0eee");
+    assert_eq!(golden_digest(FractalKind::BurningShip), "8c9dd995409a39557967ee27646de858a32c9bba");
+    assert_eq!(golden_digest(FractalKind::Hybrid(Hybrid::from_str("MMB").unwrap())), "ff3ebc139910fb226c317a47fde0bd99aeea50e3");
+    // 1つの式だけの列は、その式そのもの
+    assert_eq!(golden_digest(FractalKind::Hybrid(Hybrid::from_str("C").unwrap())),
+               golden_digest(FractalKind::Celtic));
+}
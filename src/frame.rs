@@ -0,0 +1,166 @@
+//! 画素の形式を型で区別する画像のバッファ `FrameBuffer<P: Pixel>`
+//!
+//! 描画の結果は1ピクセル1バイトの灰色だが、色を付けた後は RGB や RGBA、深度や距離の見積もりは小数になる。
+//! `&[u8]` と大きさだけを渡すと成分の数やビット数を取り違えやすいので、画素の形式を型にして
+//! 大きさと一緒に持たせる。形式は `Gray8`、`Gray16`、`Rgb8`、`Rgba8`、`F32` で、PNG に書ける形式は
+//! `PngPixel` を実装し、`encode::encode_frame` にそのまま渡せる。
+
+use rayon::prelude::*;
+
+/// バッファの1ピクセル
+pub trait Pixel: Copy + Default + Send + Sync {
+    /// 1ピクセルの成分の数
+    const CHANNELS: usize;
+    /// 1成分のビット数
+    const BIT_DEPTH: u8;
+
+    /// 書き出すときのビッグエンディアンのバイト列を `output` に足す
+    fn extend_bytes(self, output: &mut Vec<u8>);
+}
+
+/// PNG のサンプルとしてそのまま書ける形式
+pub trait PngPixel: Pixel {}
+
+/// 8ビットの灰色
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Gray8(pub u8);
+
+/// 16ビットの灰色
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Gray16(pub u16);
+
+/// 各8ビットの RGB
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Rgb8(pub [u8; 3]);
+
+/// 各8ビットの RGBA。不透明度は乗算していない
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Rgba8(pub [u8; 4]);
+
+/// 32ビットの浮動小数点数の値。深度や距離の見積もりなど、色にする前の量
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct F32(pub f32);
+
+impl Pixel for Gray8 {
+    const CHANNELS: usize = 1;
+    const BIT_DEPTH: u8 = 8;
+
+    fn extend_bytes(self, output: &mut Vec<u8>) {
+        output.push(self.0);
+    }
+}
+
+impl Pixel for Gray16 {
+    const CHANNELS: usize = 1;
+    const BIT_DEPTH: u8 = 16;
+
+    fn extend_bytes(self, output: &mut Vec<u8>) {
+        output.extend_from_slice(&self.0.to_be_bytes());
+    }
+}
+
+impl Pixel for Rgb8 {
+    const CHANNELS: usize = 3;
+    const BIT_DEPTH: u8 = 8;
+
+    fn extend_bytes(self, output: &mut Vec<u8>) {
+        output.extend_from_slice(&self.0);
+    }
+}
+
+impl Pixel for Rgba8 {
+    const CHANNELS: usize = 4;
+    const BIT_DEPTH: u8 = 8;
+
+    fn extend_bytes(self, output: &mut Vec<u8>) {
+        output.extend_from_slice(&self.0);
+    }
+}
+
+impl Pixel for F32 {
+    const CHANNELS: usize = 1;
+    const BIT_DEPTH: u8 = 32;
+
+    fn extend_bytes(self, output: &mut Vec<u8>) {
+        output.extend_from_slice(&self.0.to_be_bytes());
+    }
+}
+
+impl PngPixel for Gray8 {}
+impl PngPixel for Gray16 {}
+impl PngPixel for Rgb8 {}
+impl PngPixel for Rgba8 {}
+
+/// 大きさ `bounds` の、左上から行毎に並べた画像
+#[derive(Clone, Debug, PartialEq)]
+pub struct FrameBuffer<P> {
+    pub bounds: (usize, usize),
+    pub pixels: Vec<P>
+}
+
+impl<P: Pixel> FrameBuffer<P> {
+    /// 全てのピクセルが既定値 (黒か 0) の画像
+    pub fn new(bounds: (usize, usize)) -> FrameBuffer<P> {
+        FrameBuffer { bounds, pixels: vec![P::default(); bounds.0 * bounds.1] }
+    }
+
+    pub fn from_pixels(bounds: (usize, usize), pixels: Vec<P>) -> FrameBuffer<P> {
+        assert!(pixels.len() == bounds.0 * bounds.1);
+        FrameBuffer { bounds, pixels }
+    }
+
+    /// `y` 行目のピクセル
+    pub fn row(&self, y: usize) -> &[P] {
+        &self.pixels[y * self.bounds.0 .. (y + 1) * self.bounds.0]
+    }
+
+    /// 行毎に並列に、各ピクセルを `f` で別の形式にした画像
+    pub fn map<Q: Pixel, F>(&self, f: F) -> FrameBuffer<Q>
+        where F: Fn(P) -> Q + Sync
+    {
+        FrameBuffer { bounds: self.bounds, pixels: self.pixels.par_iter().map(|&pixel| f(pixel)).collect() }
+    }
+
+    /// `y` 行目を書き出すときのバイト列
+    pub fn row_bytes(&self, y: usize) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.bounds.0 * P::CHANNELS * P::BIT_DEPTH as usize / 8);
+        for &pixel in self.row(y) {
+            pixel.extend_bytes(&mut bytes);
+        }
+        bytes
+    }
+}
+
+impl FrameBuffer<Gray8> {
+    /// 描画した灰色の `pixels` を写した画像
+    pub fn gray(bounds: (usize, usize), pixels: &[u8]) -> FrameBuffer<Gray8> {
+        FrameBuffer::from_pixels(bounds, pixels.iter().map(|&gray| Gray8(gray)).collect())
+    }
+}
+
+impl FrameBuffer<Rgba8> {
+    /// RGBA の各8ビットを並べた `samples` から作る
+    pub fn from_rgba(bounds: (usize, usize), samples: &[u8]) -> FrameBuffer<Rgba8> {
+        FrameBuffer::from_pixels(bounds, samples.chunks_exact(4).map(|rgba| Rgba8([rgba[0], rgba[1], rgba[2], rgba[3]]))
+            .collect())
+    }
+}
+
+#[test]
+fn test_frame_buffer() {
+    let frame = FrameBuffer::gray((3, 2), &[0, 10, 20, 30, 40, 50]);
+    assert_eq!(frame.row(1), [Gray8(30), Gray8(40), Gray8(50)]);
+    assert_eq!(frame.row_bytes(0), vec![0, 10, 20]);
+
+    // 形式を変えても大きさは同じで、16ビットや小数はビッグエンディアンで書き出す
+    let deep = frame.map(|Gray8(gray)| Gray16(gray as u16 * 257));
+    assert_eq!(deep.bounds, (3, 2));
+    assert_eq!(deep.row_bytes(0), vec![0, 0, 10, 10, 20, 20]);
+    let rgb = frame.map(|Gray8(gray)| Rgb8([gray, 0, 255]));
+    assert_eq!(rgb.row_bytes(1)[.. 3], [30, 0, 255]);
+    assert_eq!(frame.map(|Gray8(gray)| F32(gray as f32 / 255.0)).row_bytes(0).len(), 3 * 4);
+    assert_eq!(FrameBuffer::<F32>::new((2, 2)).pixels, vec![F32(0.0); 4]);
+
+    let rgba = FrameBuffer::from_rgba((2, 1), &[1, 2, 3, 4, 5, 6, 7, 8]);
+    assert_eq!(rgba.pixels, vec![Rgba8([1, 2, 3, 4]), Rgba8([5, 6, 7, 8])]);
+}
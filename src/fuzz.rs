@@ -0,0 +1,189 @@
+//! 文字列を読む関数に壊れた入力を与えて、パニックしないかを確かめる `fuzz-parsers` サブコマンド
+//!
+//! 座標の組、複素数、番号の範囲、パレットや注記や一括描画の TOML、`.kfr`・`.upr`・`.par` の位置ファイル、
+//! 描画のオプション、`serve` に届く HTTP リクエストと `worker` に届くジョブなど、外から受け取る文字列を
+//! 読む関数と、読んだ注記を小さな画像に描く `Overlay::draw` を対象にする。対象毎に正しい入力の見本から始め、
+//! 文字の置き換え、境界の値になりやすい片 (`1e309` や `18446744073709551615` など) の挿入、削除、繰り返しを
+//! 擬似乱数で重ねた入力を読ませる。
+//! 壊れた入力はエラーになるべきで、パニックした入力を報告する。
+//!
+//! 座標の組、色とパレット、注記、位置ファイルを読む関数と注記の描画はライブラリ `mandelbrot` にあり、
+//! `fuzz/` の cargo-fuzz のターゲットが libFuzzer で調べる。このサブコマンドはそれらに加えて、描画のオプションや
+//! HTTP リクエストのようにバイナリの側に残る関数も、cargo-fuzz 無しにテストと `cargo run -- fuzz-parsers` から回す。
+
+use std::panic::{self, AssertUnwindSafe};
+use std::str::FromStr;
+
+use mandelbrot::Region;
+use num::Complex;
+
+use super::dataset::SplitMix64;
+use super::{channels, color, distributed, fractal, kfr, layers, normalize, overlay, paramfile, projection, server};
+use super::{batch, parse_complex, parse_pair, parse_params_exact, parse_ranges, Failure};
+
+/// 1つの入力で重ねる変更の数の上限
+const MAX_MUTATIONS: usize = 4;
+
+/// 置き換えに使う文字
+const ALPHABET: &[char] = &['0', '1', '9', '.', ',', '-', '+', 'x', 'e', 'E', '/', '=', ':', '#', '[', ']', '{', '}',
+                            '"', '\'', '\n', ' ', '\t', 'é', '▀', '\u{0}'];
+
+/// 挿入する、境界の値になりやすい片
+const TOKENS: &[&str] = &["1e309", "-1e309", "NaN", "inf", "-0", "0", "18446744073709551615", "4294967296",
+                          "99999999999999999999", ",,", "--", "\n[[", "]]", "= ", "\"", "0x", "1/0", "/"];
+
+/// 調べる関数
+struct Target {
+    name: &'static str,
+    seeds: &'static [&'static str],
+    parse: fn(&str)
+}
+
+/// 調べる関数の一覧。結果は捨て、パニックしないことだけを見る
+const TARGETS: &[Target] = &[
+    Target { name: "pair", seeds: &["1000x750", "-1.20,0.35"], parse: |s| {
+        let _ = parse_pair::<usize>(s, 'x');
+        let _ = parse_pair::<f64>(s, ',');
+    } },
+    Target { name: "complex", seeds: &["-1.20,0.35", "1e-300,-0"], parse: |s| { let _ = parse_complex(s); } },
+    Target { name: "ranges", seeds: &["0-3,8,10-11", "5"], parse: |s| { let _ = parse_ranges(s); } },
+    Target { name: "color", seeds: &["#1a2b3c"], parse: |s| { let _ = color::parse_color(s); } },
+    Target { name: "palette", seeds: &["space = \"oklab\"\n[[stop]]\nposition = 0.0\ncolor = \"#000000\"\n\
+                                        [[stop]]\nposition = 1.0\ncolor = \"#ffffff\"\n"],
+             parse: |s| { let _ = color::Palette::parse(s); } },
+    Target { name: "annotations", seeds: &["[[marker]]\nat = \"-0.75,0\"\nshape = \"circle\"\n\
+                                            [[ray]]\nangle = \"1/3\"\n[[grid]]\nspacing = 0.5\n\
+                                            [[label]]\nat = \"0,0\"\ntext = \"a\"\nscale = 2\n"],
+             parse: |s| { let _ = overlay::parse_annotations(s); } },
+    Target { name: "overlay", seeds: &["[[marker]]\nat = \"-0.75,0\"\nradius = 3\n[[ray]]\nangle = \"1/3\"\n\
+                                        [[grid]]\nspacing = 0.5\n[[label]]\nat = \"-1,0.5\"\ntext = \"a\"\n"],
+             parse: |s| {
+                 if let Ok(overlay) = overlay::parse_annotations(s) {
+                     let region = Region::new((16, 12), Complex { re: -2.0, im: 1.5 }, Complex { re: 1.0, im: -1.5 });
+                     overlay.draw(&mut [0; 16 * 12], region);
+                 }
+             } },
+    Target { name: "jobs", seeds: &["concurrency = 2\n[[job]]\noutput = \"a.png\"\npixels = \"100x75\"\n\
+                                     upper_left = \"-1.20,0.35\"\nlower_right = \"-1,0.20\"\noptions = [\"--passes\", \"256\"]\n"],
+             parse: |s| { let _ = batch::parse_job_file(s); } },
+    Target { name: "kfr", seeds: &["Re: -0.75\r\nIm: 0.1\r\nZoom: 2.5E1\r\nIterations: 5000\r\nRotate: 0\r\n"],
+             parse: |s| { let _ = kfr::Location::from_str(s); } },
+    Target { name: "upr", seeds: &["Deep {\nmapping:\n  center=-0.75/0.1 magn=12 angle=0\nformula:\n  maxiter=2500 \
+                                    entry=\"Mandelbrot\"\n}\n"],
+             parse: |s| { let _ = paramfile::parse_upr(s); } },
+    Target { name: "par", seeds: &["Spiral { ; first\n  type=mandel center-mag=-0.75/0.1/8 maxiter=500\n  }\n",
+                                   "Whole {\n  type=mandel corners=-2.5/1.5/-1.5/1.5\n  }"],
+             parse: |s| { let _ = paramfile::parse_par(s); } },
+    Target { name: "channels", seeds: &["count:log,de:sqrt:scale=2,angle", "count:pow=0.5:invert,de,angle"],
+             parse: |s| { let _ = channels::ChannelMap::from_str(s); } },
+    Target { name: "layers", seeds: &["escape,stalks:multiply,binary:alpha=0.25"], parse: |s| { let _ = layers::parse_layers(s); } },
+    Target { name: "hybrid", seeds: &["MMB", "MBCFPQ"], parse: |s| { let _ = fractal::Hybrid::from_str(s); } },
+    Target { name: "mobius", seeds: &["0,0/1,0/1,0/0,0"], parse: |s| { let _ = projection::Mobius::from_str(s); } },
+    Target { name: "percentile", seeds: &["percentile:1,99"], parse: |s| { let _ = normalize::Percentile::from_str(s); } },
+    Target { name: "params", seeds: &["--passes 64,256 --bailout 4 --fractal nova --nova-degree 3",
+                                      "--channels count:log,de --cores 0-3 --normalize percentile:1,99",
+                                      "--layers escape,stalks:multiply --filter sobel,unsharp --hybrid MMB"],
+             parse: |s| {
+                 let args: Vec<String> = s.split_whitespace().map(String::from).collect();
+                 let _ = parse_params_exact(&args);
+             } },
+    Target { name: "http", seeds: &["GET /render?cx=-0.5&cy=0&zoom=2&w=64&h=48&iters=100&palette=fire HTTP/1.1\r\n\
+                                     Host: localhost\r\nIf-None-Match: W/\"abc\"\r\n\r\n"],
+             parse: |s| { let _ = server::parse_render_request(s); } },
+    Target { name: "job", seeds: &["1000x750\n-1.2,0.35\n-1,0.2\n16\n8\n--passes\n256,1024", "8x6\n-2,1\n1,-1\n0\n6"],
+             parse: |s| { let _ = distributed::parse_job(s.as_bytes()); } }
+];
+
+/// `rng` で選んだ変更を `input` に1から `MAX_MUTATIONS` 回重ねる
+fn mutate(input: &str, rng: &mut SplitMix64) -> String {
+    let mut chars: Vec<char> = input.chars().collect();
+    let mut below = |n: usize| (rng.next_u64() % n.max(1) as u64) as usize;
+    for _ in 0 .. 1 + below(MAX_MUTATIONS) {
+        let at = below(chars.len() + 1);
+        match below(4) {
+            0 if at < chars.len() => chars[at] = ALPHABET[below(ALPHABET.len())],
+            1 => {
+                let token = TOKENS[below(TOKENS.len())];
+                chars.splice(at .. at, token.chars());
+            }
+            2 => {
+                let end = (at + 1 + below(8)).min(chars.len());
+                chars.drain(at.min(end) .. end);
+            }
+            _ => {
+                let end = (at + 1 + below(8)).min(chars.len());
+                let copied: Vec<char> = chars[at.min(end) .. end].to_vec();
+                chars.splice(end .. end, copied);
+            }
+        }
+    }
+    chars.into_iter().collect()
+}
+
+/// `target` に見本を `iterations` 回変えた入力を読ませ、パニックした最初の入力を返す
+fn fuzz(target: &Target, iterations: usize, seed: u64) -> Option<String> {
+    let mut rng = SplitMix64(seed);
+    let mut inputs = target.seeds.iter().map(|seed| seed.to_string())
+        .chain((0 .. iterations).map(|i| mutate(target.seeds[i % target.seeds.len()], &mut rng)));
+    inputs.find(|input| panic::catch_unwind(AssertUnwindSafe(|| (target.parse)(input))).is_err())
+}
+
+#[test]
+fn test_fuzz_parsers() {
+    let mut rng = SplitMix64(1);
+    let mutants: Vec<String> = (0 .. 100).map(|_| mutate("0-3,8", &mut rng)).collect();
+    assert!(mutants.iter().any(|mutant| mutant != "0-3,8"));
+    assert!(mutants.iter().any(|mutant| mutant.len() > 5) && mutants.iter().any(|mutant| mutant.len() < 5));
+    // どの対象も壊れた入力でパニックしない
+    for target in TARGETS {
+        assert_eq!(fuzz(target, 2000, 7), None, "{}", target.name);
+    }
+}
+
+/// `fuzz-parsers [--iterations N] [--seed S] [--target NAME]` サブコマンド
+/// `fuzz-parsers` の引数を試す入力の数、乱数の種と試すパーサに分ける
+fn parse_fuzz_args(args: &[String]) -> Result<(usize, u64, Vec<&'static Target>), String> {
+    let (mut iterations, mut seed, mut only) = (100_000, 0, None);
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().map(String::as_str).ok_or(format!("{} expects a value", arg));
+        match arg.as_str() {
+            "--iterations" => iterations = usize::from_str(value()?).map_err(|_| "--iterations expects an integer")?,
+            "--seed" => seed = u64::from_str(value()?).map_err(|_| "--seed expects an integer")?,
+            "--target" => only = Some(value()?.to_string()),
+            _ => return Err(format!("unknown fuzz-parsers option: {}", arg))
+        }
+    }
+    let targets: Vec<&Target> = TARGETS.iter().filter(|target| only.as_deref().is_none_or(|name| name == target.name))
+        .collect();
+    if targets.is_empty() {
+        let names: Vec<&str> = TARGETS.iter().map(|target| target.name).collect();
+        return Err(format!("unknown target, expected one of {}", names.join(", ")));
+    }
+    Ok((iterations, seed, targets))
+}
+
+pub fn run_fuzz(args: &[String]) -> Result<(), Failure> {
+    let (iterations, seed, targets) = parse_fuzz_args(args).map_err(Failure::Usage)?;
+
+    // パニックした入力は最後にまとめて表示するので、途中のメッセージは出さない
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+    let found: Vec<(&str, Option<String>)> = targets.iter().map(|target| (target.name, fuzz(target, iterations, seed))).collect();
+    panic::set_hook(hook);
+
+    let mut failed = 0;
+    for (name, input) in &found {
+        match input {
+            None => println!("ok      {} ({} inputs)", name, iterations),
+            Some(input) => {
+                failed += 1;
+                println!("PANIC   {}: {:?}", name, input);
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(Failure::Runtime(format!("{} of {} parsers panicked", failed, found.len())));
+    }
+    Ok(())
+}
@@ -0,0 +1,193 @@
+//! 画像に埋め込む ICC プロファイル
+//!
+//! sRGB と、sRGB と同じ階調の灰色のプロファイルはここで組み立てる (ICC v2.1 のディスプレイ用プロファイル)。
+//! 色域は D50 に Bradford 変換した sRGB の原色、階調は 1024 点の表の sRGB の曲線。
+//! 利用者が指定したファイルは、画像の色 (RGB か灰色) と色空間が合うかだけを確かめてそのまま埋め込む。
+
+use std::fs;
+
+/// プロファイルのヘッダーの長さ
+const HEADER_LEN: usize = 128;
+
+/// PCS の白色点 D50
+const D50: [f64; 3] = [0.9642, 1.0, 0.8249];
+
+/// D50 に Bradford 変換した sRGB の原色の XYZ
+const SRGB_PRIMARIES: [[f64; 3]; 3] = [
+    [0.4360747, 0.2225045, 0.0139322],
+    [0.3850649, 0.7168786, 0.0971045],
+    [0.1430804, 0.0606169, 0.7141733]
+];
+
+fn s15_fixed16(value: f64) -> [u8; 4] {
+    ((value * 65536.0).round() as i32).to_be_bytes()
+}
+
+fn xyz(value: [f64; 3]) -> Vec<u8> {
+    let mut data = b"XYZ \0\0\0\0".to_vec();
+    for v in value {
+        data.extend_from_slice(&s15_fixed16(v));
+    }
+    data
+}
+
+/// v2 の textDescriptionType。Unicode と ScriptCode の説明は空にする
+fn description(text: &str) -> Vec<u8> {
+    let mut data = b"desc\0\0\0\0".to_vec();
+    data.extend_from_slice(&(text.len() as u32 + 1).to_be_bytes());
+    data.extend_from_slice(text.as_bytes());
+    data.push(0);
+    data.extend_from_slice(&[0; 8]);
+    data.extend_from_slice(&[0; 3]);
+    data.extend_from_slice(&[0; 67]);
+    data
+}
+
+fn text(text: &str) -> Vec<u8> {
+    let mut data = b"text\0\0\0\0".to_vec();
+    data.extend_from_slice(text.as_bytes());
+    data.push(0);
+    data
+}
+
+/// sRGB の階調曲線の表
+fn srgb_curve() -> Vec<u8> {
+    const POINTS: u32 = 1024;
+    let mut data = b"curv\0\0\0\0".to_vec();
+    data.extend_from_slice(&POINTS.to_be_bytes());
+    for i in 0 .. POINTS {
+        let c = i as f64 / (POINTS - 1) as f64;
+        let linear = if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) };
+        data.extend_from_slice(&((linear * 65535.0).round() as u16).to_be_bytes());
+    }
+    data
+}
+
+/// 色空間 `space` のディスプレイ用プロファイルを、`tags` を並べて組み立てる。同じ内容のタグは1つを共有する
+fn profile(space: &[u8; 4], tags: &[(&[u8; 4], Vec<u8>)]) -> Vec<u8> {
+    let table_len = 4 + 12 * tags.len();
+    let mut data: Vec<u8> = vec![];
+    let mut entries: Vec<(&[u8; 4], usize, &Vec<u8>)> = vec![];
+    for (signature, tag) in tags {
+        let start = HEADER_LEN + table_len;
+        let offset = match entries.iter().find(|(_, _, other)| *other == tag) {
+            Some(&(_, offset, _)) => offset,
+            None => {
+                let offset = start + data.len();
+                data.extend_from_slice(tag);
+                // タグは4バイト境界から始める
+                data.resize(data.len().div_ceil(4) * 4, 0);
+                offset
+            }
+        };
+        entries.push((signature, offset, tag));
+    }
+
+    let size = HEADER_LEN + table_len + data.len();
+    let mut header = Vec::with_capacity(size);
+    header.extend_from_slice(&(size as u32).to_be_bytes());
+    header.extend_from_slice(&[0; 4]);
+    header.extend_from_slice(&[2, 0x10, 0, 0]);
+    header.extend_from_slice(b"mntr");
+    header.extend_from_slice(space);
+    header.extend_from_slice(b"XYZ ");
+    // 作成日時は決まった値にし、同じ設定なら同じファイルになるようにする
+    for part in [2024u16, 1, 1, 0, 0, 0] {
+        header.extend_from_slice(&part.to_be_bytes());
+    }
+    header.extend_from_slice(b"acsp");
+    header.extend_from_slice(&[0; 24]);
+    // レンダリングインテントは知覚的
+    header.extend_from_slice(&[0; 4]);
+    for v in D50 {
+        header.extend_from_slice(&s15_fixed16(v));
+    }
+    header.resize(HEADER_LEN, 0);
+
+    header.extend_from_slice(&(tags.len() as u32).to_be_bytes());
+    for (signature, offset, tag) in &entries {
+        header.extend_from_slice(*signature);
+        header.extend_from_slice(&(*offset as u32).to_be_bytes());
+        header.extend_from_slice(&(tag.len() as u32).to_be_bytes());
+    }
+    header.extend_from_slice(&data);
+    header
+}
+
+const COPYRIGHT: &str = "No copyright, use freely";
+
+/// sRGB のプロファイル
+pub fn srgb() -> Vec<u8> {
+    let curve = srgb_curve();
+    profile(b"RGB ", &[
+        (b"desc", description("sRGB")),
+        (b"cprt", text(COPYRIGHT)),
+        (b"wtpt", xyz(D50)),
+        (b"rXYZ", xyz(SRGB_PRIMARIES[0])),
+        (b"gXYZ", xyz(SRGB_PRIMARIES[1])),
+        (b"bXYZ", xyz(SRGB_PRIMARIES[2])),
+        (b"rTRC", curve.clone()),
+        (b"gTRC", curve.clone()),
+        (b"bTRC", curve)
+    ])
+}
+
+/// sRGB と同じ階調の灰色のプロファイル
+pub fn srgb_gray() -> Vec<u8> {
+    profile(b"GRAY", &[
+        (b"desc", description("Gray with sRGB tone curve")),
+        (b"cprt", text(COPYRIGHT)),
+        (b"wtpt", xyz(D50)),
+        (b"kTRC", srgb_curve())
+    ])
+}
+
+/// プロファイル `data` の色空間。ICC プロファイルでなければ `None`
+fn color_space(data: &[u8]) -> Option<&[u8]> {
+    if data.len() < HEADER_LEN + 4 || &data[36 .. 40] != b"acsp" {
+        return None;
+    }
+    Some(&data[16 .. 20])
+}
+
+/// `path` のプロファイルを読む。`rgb` なら RGB の、そうでなければ灰色のプロファイルでなければならない
+pub fn load(path: &str, rgb: bool) -> Result<Vec<u8>, String> {
+    let data = fs::read(path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+    check(&data, rgb).map_err(|e| format!("{}: {}", path, e))?;
+    Ok(data)
+}
+
+fn check(data: &[u8], rgb: bool) -> Result<(), String> {
+    let space = color_space(data).ok_or("not an ICC profile")?;
+    let expected: &[u8] = if rgb { b"RGB " } else { b"GRAY" };
+    if space != expected {
+        return Err(format!("the image is {} but the profile is for {}",
+                           String::from_utf8_lossy(expected).trim(), String::from_utf8_lossy(space).trim()));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_profiles() {
+    let u32_at = |data: &[u8], at: usize| u32::from_be_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]]) as usize;
+    for (profile, tags) in [(srgb(), 9), (srgb_gray(), 4)] {
+        assert_eq!(u32_at(&profile, 0), profile.len());
+        assert_eq!(u32_at(&profile, HEADER_LEN), tags);
+        // どのタグも4バイト境界から始まってプロファイルに収まり、型の署名から始まる
+        for entry in 0 .. tags {
+            let at = HEADER_LEN + 4 + 12 * entry;
+            let (offset, len) = (u32_at(&profile, at + 4), u32_at(&profile, at + 8));
+            assert_eq!(offset % 4, 0);
+            assert!(offset + len <= profile.len());
+            assert!([&b"desc"[..], b"text", b"XYZ ", b"curv"].contains(&&profile[offset .. offset + 4]));
+        }
+    }
+    let profile = srgb();
+    // 3つの階調曲線は同じ場所を指す
+    assert_eq!(profile[HEADER_LEN + 4 + 12 * 6 + 4 .. HEADER_LEN + 4 + 12 * 7],
+               profile[HEADER_LEN + 4 + 12 * 8 + 4 .. HEADER_LEN + 4 + 12 * 9]);
+    assert_eq!(check(&profile, true), Ok(()));
+    assert!(check(&profile, false).unwrap_err().contains("profile is for RGB"));
+    assert_eq!(check(&srgb_gray(), false), Ok(()));
+    assert!(check(b"not a profile", true).is_err());
+}
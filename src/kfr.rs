@@ -0,0 +1,114 @@
+//! Kalles Fraktaler の位置ファイル `.kfr` の読み書き
+//!
+//! `.kfr` は `Re: -0.75` のように `キー: 値` を1行ずつ並べたテキストで、ここで使うのは中心の
+//! `Re` と `Im`、倍率 `Zoom`、反復回数 `Iterations` だけ。KF の倍率 Z は画像の高さが `4 / Z` になる値で、
+//! 幅が基準のこのリポジトリの倍率とは違う。色などの他のキーは読み飛ばし、書き出すときも4つのキーだけを書く。
+//! 回転 `Rotate` した位置は範囲の長方形で表せないので読み込まない。ファイルの読み書きは `paramfile.rs` で行う。
+//!
+//! ```text
+//! Re: -0.743643887037151
+//! Im: 0.13182590420533
+//! Zoom: 1E5
+//! Iterations: 5000
+//! ```
+
+use std::str::FromStr;
+
+use num::Complex;
+
+use super::Region;
+
+/// `.kfr` の位置
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Location {
+    pub center: Complex<f64>,
+    /// 画像の高さが `4 / zoom` になる KF の倍率
+    pub zoom: f64,
+    pub iterations: u32
+}
+
+impl Location {
+    /// 範囲 `region` を反復回数 `iterations` で描く位置
+    pub fn from_region(region: &Region, iterations: u32) -> Location {
+        let Region { upper_left, lower_right, .. } = *region;
+        Location {
+            center: (upper_left + lower_right) / 2.0,
+            zoom: 4.0 / (upper_left.im - lower_right.im),
+            iterations
+        }
+    }
+
+    /// 大きさ `bounds` の画像に描く左上と右下の点
+    pub fn corners(&self, bounds: (usize, usize)) -> (Complex<f64>, Complex<f64>) {
+        let height = 4.0 / self.zoom;
+        let width = height * bounds.0 as f64 / bounds.1 as f64;
+        (Complex { re: self.center.re - width / 2.0, im: self.center.im + height / 2.0 },
+         Complex { re: self.center.re + width / 2.0, im: self.center.im - height / 2.0 })
+    }
+
+    /// `.kfr` のテキストにする
+    pub fn kfr_text(&self) -> String {
+        format!("Re: {}\nIm: {}\nZoom: {:E}\nIterations: {}\n",
+                self.center.re, self.center.im, self.zoom, self.iterations)
+    }
+}
+
+impl FromStr for Location {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Location, String> {
+        let (mut re, mut im, mut zoom, mut iterations) = (None, None, None, None);
+        for line in s.lines() {
+            let (key, value) = match line.split_once(':') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => continue
+            };
+            let number = || f64::from_str(value).map_err(|_| format!("invalid {}: {}", key, value));
+            match key {
+                "Re" => re = Some(number()?),
+                "Im" => im = Some(number()?),
+                "Zoom" => zoom = Some(number()?),
+                "Iterations" => {
+                    iterations = Some(u32::from_str(value).map_err(|_| format!("invalid Iterations: {}", value))?);
+                }
+                "Rotate" if number()? != 0.0 => return Err("rotated locations are not supported".to_string()),
+                _ => {}
+            }
+        }
+        let missing = |key: &str| format!("missing {}", key);
+        let zoom = zoom.ok_or_else(|| missing("Zoom"))?;
+        if !(zoom > 0.0 && zoom.is_finite()) {
+            return Err(format!("Zoom must be a positive number: {}", zoom));
+        }
+        let iterations = iterations.ok_or_else(|| missing("Iterations"))?;
+        if iterations == 0 {
+            return Err("Iterations must be positive".to_string());
+        }
+        Ok(Location {
+            center: Complex { re: re.ok_or_else(|| missing("Re"))?, im: im.ok_or_else(|| missing("Im"))? },
+            zoom,
+            iterations
+        })
+    }
+}
+
+#[test]
+fn test_location() {
+    let location: Location = "Re: -0.75\r\nIm: 0.1\r\nZoom: 2.5E1\r\nIterations: 5000\r\nIterDiv: 0.010000\r\nRotate: 0\r\n"
+        .parse().unwrap();
+    assert_eq!(location, Location { center: Complex { re: -0.75, im: 0.1 }, zoom: 25.0, iterations: 5000 });
+    assert_eq!(location.kfr_text(), "Re: -0.75\nIm: 0.1\nZoom: 2.5E1\nIterations: 5000\n");
+    assert_eq!(location.kfr_text().parse(), Ok(location));
+
+    // 高さ 4 / 25 = 0.16 で、幅は縦横比に合わせる
+    let (upper_left, lower_right) = location.corners((300, 200));
+    assert!((upper_left - Complex { re: -0.87, im: 0.18 }).norm() < 1e-12);
+    assert!((lower_right - Complex { re: -0.63, im: 0.02 }).norm() < 1e-12);
+    let back = Location::from_region(&Region::new((300, 200), upper_left, lower_right), 5000);
+    assert!((back.center - location.center).norm() < 1e-12 && (back.zoom - 25.0).abs() < 1e-9);
+
+    assert!("Re: 0\nIm: 0\nIterations: 100".parse::<Location>().is_err());
+    assert!("Re: 0\nIm: 0\nZoom: 0\nIterations: 100".parse::<Location>().is_err());
+    assert!("Re: 0\nIm: x\nZoom: 1\nIterations: 100".parse::<Location>().is_err());
+    assert!("Re: 0\nIm: 0\nZoom: 1\nIterations: 100\nRotate: 45".parse::<Location>().is_err());
+}
@@ -0,0 +1,130 @@
+//! 複数の色付けを重ねる `--layers`
+//!
+//! 各点を1度だけ反復し、その軌道から全てのレイヤーの明るさを `0.0 ..= 1.0` の浮動小数で求める。
+//! 最初のレイヤーを下地に、続くレイヤーを順に合成モードで重ねてから8ビットに丸める。
+//!
+//! `--layers escape,stalks:multiply,binary:alpha=0.3` のように `色付け[:合成モード]` をカンマで区切って並べる。
+//! 合成モードは `multiply`、`overlay`、`lighten`、`alpha[=不透明度]` (既定の不透明度は 0.5)。
+
+use std::str::FromStr;
+
+use super::coloring::{Coloring, Traced};
+use super::parse_list;
+
+/// 下のレイヤー `a` に上のレイヤー `b` を重ねる方法
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Blend {
+    Multiply,
+    Overlay,
+    Lighten,
+    /// 不透明度 `0.0 ..= 1.0` で上に塗る
+    Alpha(f32)
+}
+
+impl Blend {
+    fn apply(self, a: f32, b: f32) -> f32 {
+        match self {
+            Blend::Multiply => a * b,
+            Blend::Overlay => {
+                if a < 0.5 { 2.0 * a * b } else { 1.0 - 2.0 * (1.0 - a) * (1.0 - b) }
+            }
+            Blend::Lighten => a.max(b),
+            Blend::Alpha(opacity) => a * (1.0 - opacity) + b * opacity
+        }
+    }
+}
+
+impl FromStr for Blend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Blend, String> {
+        match s {
+            "multiply" => Ok(Blend::Multiply),
+            "overlay" => Ok(Blend::Overlay),
+            "lighten" => Ok(Blend::Lighten),
+            "alpha" => Ok(Blend::Alpha(0.5)),
+            _ => {
+                let opacity = s.strip_prefix("alpha=")
+                    .and_then(|opacity| f32::from_str(opacity).ok())
+                    .ok_or(format!("unknown blend '{}', expected multiply, overlay, lighten or alpha=OPACITY", s))?;
+                if !(0.0 ..= 1.0).contains(&opacity) {
+                    return Err("alpha opacity must be between 0 and 1".to_string());
+                }
+                Ok(Blend::Alpha(opacity))
+            }
+        }
+    }
+}
+
+/// 1枚のレイヤー。下地のレイヤーの `blend` は使わない
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Layer {
+    pub coloring: Coloring,
+    pub blend: Blend
+}
+
+/// `--layers` の値を読む
+pub fn parse_layers(s: &str) -> Result<Vec<Layer>, String> {
+    let specs: Vec<String> = parse_list(s, ',').ok_or("--layers expects COLORING[:BLEND],...")?;
+    if specs.is_empty() {
+        return Err("--layers expects at least one coloring".to_string());
+    }
+    specs.iter().map(|spec| {
+        let (coloring, blend) = match spec.split_once(':') {
+            Some((coloring, blend)) => (coloring, Blend::from_str(blend)?),
+            None => (spec.as_str(), Blend::Alpha(1.0))
+        };
+        Ok(Layer { coloring: Coloring::from_str(coloring)?, blend })
+    }).collect()
+}
+
+#[test]
+fn test_parse_layers() {
+    assert_eq!(parse_layers("escape,stalks:multiply,binary:alpha=0.25"), Ok(vec![
+        Layer { coloring: Coloring::Escape, blend: Blend::Alpha(1.0) },
+        Layer { coloring: Coloring::Stalks, blend: Blend::Multiply },
+        Layer { coloring: Coloring::Binary, blend: Blend::Alpha(0.25) }
+    ]));
+    assert_eq!(parse_layers("escape:overlay").map(|layers| layers[0].blend), Ok(Blend::Overlay));
+    assert!(parse_layers("").is_err());
+    assert!(parse_layers("escape,stalks:screen").is_err());
+    assert!(parse_layers("escape,stalks:alpha=2").is_err());
+    assert!(parse_layers("rainbow").is_err());
+}
+
+/// 下地に続くレイヤーを順に重ねる。`values` は下地から順のレイヤーの明るさ
+fn composite(layers: &[Layer], values: &[f32]) -> f32 {
+    layers.iter().zip(values).skip(1)
+        .fold(values[0], |below, (layer, &above)| layer.blend.apply(below, above))
+        .clamp(0.0, 1.0)
+}
+
+#[test]
+fn test_composite() {
+    let layers = parse_layers("escape,stalks:multiply,binary:lighten").unwrap();
+    assert_eq!(composite(&layers, &[0.5, 0.5, 0.0]), 0.25);
+    assert_eq!(composite(&layers, &[0.5, 0.5, 1.0]), 1.0);
+    assert_eq!(Blend::Overlay.apply(0.25, 0.5), 0.25);
+    assert_eq!(Blend::Overlay.apply(0.75, 0.5), 0.75);
+    assert_eq!(Blend::Alpha(0.25).apply(0.0, 1.0), 0.25);
+}
+
+/// 反復を終えた軌道から全てのレイヤーを重ねた明るさを求める
+pub fn blend(traced: &Traced, layers: &[Layer], limit: u32) -> u8 {
+    let values: Vec<f32> = layers.iter()
+        .map(|layer| traced.value(layer.coloring, limit) as f32 / 255.0)
+        .collect();
+    (composite(layers, &values) * 255.0).round() as u8
+}
+
+#[test]
+fn test_blend() {
+    let params = super::RenderParams::default();
+    let (traced, _) = super::coloring::trace(num::Complex { re: -1.5, im: 0.0 }, &params);
+    // 1枚だけなら色付けをそのまま使うのと同じ
+    let single = parse_layers("stalks").unwrap();
+    assert_eq!(blend(&traced, &single, 255), traced.value(Coloring::Stalks, 255));
+    // 茎 (白) を掛けても escape の明るさは変わらない
+    let layers = parse_layers("escape,stalks:multiply").unwrap();
+    assert_eq!(blend(&traced, &layers, 255), traced.value(Coloring::Escape, 255));
+}
@@ -0,0 +1,29 @@
+//! 描画とは独立に使える Mandelbrot 集合の計算
+//!
+//! プロッタや解析ツールからピクセルを介さずに使えるよう、`mandelbrot-rewrite` コマンドとは別のライブラリとして公開する。
+//! GUI などに組み込む側のために、呼び出し側のバッファへ直接描く `render_gray` と `render_u32` もある。
+//! 反復の `escape_time` と明るさの `shade` はコマンドもこのライブラリのものを使う。
+//!
+//! 外から受け取る文字列を読むもの (座標の組、色とパレット、注記、`.kfr`・`.upr`・`.par` の位置ファイル) と、
+//! 注記を描く `overlay` もここに置く。コマンドはこれらを使い、`fuzz/` の cargo-fuzz のターゲットもこれをリンクする。
+
+extern crate num;
+
+mod classify;
+pub mod color;
+pub mod font;
+pub mod frame;
+pub mod icc;
+pub mod kfr;
+pub mod overlay;
+pub mod paramfile;
+mod parse;
+mod region;
+mod render;
+
+pub use classify::{classify, interior_distance, PointClass};
+pub use parse::{parse_complex, parse_list, parse_pair, parse_ranges};
+pub use region::Region;
+pub use render::{
+    escape_time, gray_argb, render_gray, render_panned, render_u32, render_with_deadline, scroll, shade, Completeness
+};
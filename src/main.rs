@@ -2,118 +2,1583 @@ extern crate num;
 extern crate image;
 extern crate crossbeam;
 extern crate rayon;
+extern crate sha1_smol;
+extern crate base64;
+extern crate serde;
+extern crate toml;
+extern crate serde_json;
+extern crate core_affinity;
+extern crate memmap2;
+extern crate flate2;
+extern crate csv;
+extern crate tracing;
+extern crate tracing_subscriber;
+extern crate tracing_flame;
+extern crate crossterm;
+extern crate libloading;
+#[cfg(feature = "async")]
+extern crate tokio;
 use num::Complex;
 use std::str::FromStr;
 use image::ColorType;
 use image::png::PNGEncoder;
 use std::fs::File;
 use std::io::Write;
-use rayon::prelude::*;
+use std::sync::Arc;
+use std::time::Instant;
+use backend::{Backend, IterationBudget, WorkLog};
+use buffer::PixelBuffer;
+use coloring::{Coloring, Interior, OrbitStats};
+use fractal::{Fractal, FractalKind};
+use layers::Layer;
+use texture::{Texture, TextureMode};
+use projection::{Mobius, Projection, ProjectionKind};
+use mandelbrot::{color, frame, kfr, overlay, paramfile};
+use mandelbrot::{parse_complex, parse_list, parse_pair, parse_ranges, shade, Region};
+#[cfg(test)]
+use mandelbrot::escape_time;
+
+mod admission;
+mod backend;
+mod batch;
+mod bench;
+mod boundary;
+mod buffer;
+mod cache;
+mod channels;
+mod coloring;
+mod compare;
+mod completions;
+mod composite;
+mod config;
+mod contour;
+mod contrast;
+mod coords;
+mod dataset;
+mod depth;
+mod filter;
+mod distributed;
+mod encode;
+mod estimate;
+mod fractal;
+mod fuzz;
+mod layers;
+mod metrics;
+mod normalize;
+mod palette;
+mod plugin;
+mod precision;
+mod profile;
+mod projection;
+mod resize;
+mod schedmap;
+mod server;
+mod sidecar;
+mod site;
+mod texture;
+mod tiff;
+mod tui;
+mod validate;
+mod wallpaper;
+mod webformat;
+mod xmp;
 
 fn main() {
     let args: Vec<String> = std::env::args().collect();
 
-    if args.len() != 5 {
-        writeln!(std::io::stderr(),
-                 "Usage: mandelbrot FILE PIXELS UPPERLEFT LOWERRIGHT")
-            .unwrap();
-        writeln!(std::io::stderr(),
-                 "Example: {} mandle.png 1000x750 -1.20,0.35 -1,20",
-                 args[0])
-            .unwrap();
+    let subcommand = match args.get(1).map(String::as_str) {
+        Some("worker") => Some(distributed::run_worker(&args[2..])),
+        Some("coordinator") => Some(distributed::run_coordinator(&args[2..])),
+        Some("serve-api") => Some(server::run_server(&args[2..])),
+        Some("render-batch") => Some(batch::run_batch(&args[2..])),
+        Some("bench") => Some(bench::run_bench(&args[2..])),
+        Some("explore-tui") => Some(tui::run_explore(&args[2..])),
+        Some("palette") => Some(palette::run_palette(&args[2..])),
+        Some("coords") => Some(coords::run_coords(&args[2..])),
+        Some("convert-params") => Some(run_convert(&args[2..])),
+        Some("diff") => Some(compare::run_diff(&args[2..])),
+        Some("validate-backends") => Some(validate::run_validate(&args[2..])),
+        Some("fuzz-parsers") => Some(fuzz::run_fuzz(&args[2..])),
+        Some("export-site") => Some(site::run_export_site(&args[2..])),
+        Some("dataset") => Some(dataset::run_dataset(&args[2..])),
+        Some("wallpaper") => Some(wallpaper::run_wallpaper(&args[2..])),
+        Some("completions") => Some(completions::run_completions(&args[2..])),
+        Some("--help") | Some("-h") => {
+            print!("{}\n{}", usage(&args[0]), EXAMPLES);
+            return;
+        }
+        _ => None
+    };
+    match subcommand {
+        Some(Ok(())) => return,
+        Some(Err(Failure::Usage(message))) => {
+            eprintln!("error: {}", message);
+            print_usage(&args[0]);
+            std::process::exit(1);
+        }
+        Some(Err(Failure::Runtime(message))) => {
+            eprintln!("error: {}", message);
+            std::process::exit(1);
+        }
+        None => {}
+    }
+
+    if args.len() < 5 {
+        print_usage(&args[0]);
         std::process::exit(1);
     }
 
-    let bounds = parse_pair(&args[2], 'x')
-        .expect("error parsing image dimensions");
-    let upper_left = parse_complex(&args[3])
-        .expect("error parsing upper left corner point");
-    let lower_right = parse_complex(&args[4])
-        .expect("error parsing lower right corner point");
+    let bounds = parse_pair(&args[2], 'x').filter(|&(width, height)| width > 0 && height > 0)
+        .unwrap_or_else(|| exit_with_usage(&args[0], &format!("PIXELS expects WxH with positive sizes: {}", args[2])));
+    let (upper_left, lower_right, options) = if args[3] == "--location" {
+        // 範囲と反復回数を位置ファイルから取る。反復回数は後の --passes で上書きできる
+        let location = paramfile::load(&args[4])
+            .unwrap_or_else(|message| exit_with_usage(&args[0], &message));
+        let (upper_left, lower_right) = location.corners(bounds);
+        let mut options = vec!["--passes".to_string(), location.iterations.to_string()];
+        options.extend_from_slice(&args[5..]);
+        (upper_left, lower_right, options)
+    } else {
+        let upper_left = parse_complex(&args[3])
+            .unwrap_or_else(|| exit_with_usage(&args[0], &format!("UPPERLEFT expects RE,IM: {}", args[3])));
+        let lower_right = parse_complex(&args[4])
+            .unwrap_or_else(|| exit_with_usage(&args[0], &format!("LOWERRIGHT expects RE,IM: {}", args[4])));
+        (upper_left, lower_right, args[5..].to_vec())
+    };
+    match render_file(&args[1], bounds, upper_left, lower_right, &options, true) {
+        Ok(()) => {}
+        Err(Failure::Usage(message)) => exit_with_usage(&args[0], &message),
+        Err(Failure::Runtime(message)) => {
+            eprintln!("{}", message);
+            std::process::exit(1);
+        }
+    }
+}
 
-    // マクロ呼び出しvec![v; n]で長さnのベクタを作り、vで初期化
-    let mut pixels = vec![0; bounds.0 * bounds.1];
+/// コマンドの失敗
+#[derive(Debug, PartialEq)]
+enum Failure {
+    /// 引数やオプションが読めない。使い方も表示する
+    Usage(String),
+    /// 引数は正しいが、書き出しなどに失敗した
+    Runtime(String)
+}
 
-    // 水平の帯に `pixels` を分割したスライスのスコープ
-    {
-        let bands: Vec<(usize, &mut [u8])> = pixels
-            .chunks_mut(bounds.0)
-            .enumerate()
-            .collect();
-
-        // 用意したタスクbandsを並列イテレータに変換して .weight_max() でCPUを重く消費するヒントを与えて実行
-        bands.into_par_iter()
-            .weight_max()
-            .for_each(|(i, band)| {
-                let top = i;
-                let band_bounds = (bounds.0, 1);
-                let band_upper_left = pixel_to_point(bounds, (0, top),
-                                                     upper_left, lower_right);
-                let band_lower_right = pixel_to_point(bounds, (bounds.0, top + 1),
-                                                      upper_left, lower_right);
-                render(band, band_bounds, band_upper_left, band_lower_right);
-            });
-    }
-
-    write_image(&args[1], &pixels, bounds)
-        .expect("error writing PNG file");
-}
-
-#[allow(dead_code)]
-/// `limit` を繰り返しの上限として、`c` がマンデルブロ集合に含まれるかを判定する
+impl Failure {
+    fn message(self) -> String {
+        match self {
+            Failure::Usage(message) | Failure::Runtime(message) => message
+        }
+    }
+}
+
+/// `convert-params IN OUT` サブコマンド
+fn run_convert(args: &[String]) -> Result<(), Failure> {
+    match args {
+        [input, output] => paramfile::convert(input, output).map_err(Failure::Runtime),
+        _ => Err(Failure::Usage("convert-params expects IN OUT".to_string()))
+    }
+}
+
+/// 書き出しなどの失敗 `error` を `context` と一緒にした `Failure::Runtime` にする
+fn failed<E: std::fmt::Display>(context: &'static str) -> impl FnOnce(E) -> Failure {
+    move |error| Failure::Runtime(format!("{}: {}", context, error))
+}
+
+/// 範囲 `upper_left` から `lower_right` を大きさ `bounds` で描画し、`path` に書き出す
 ///
-/// `c` がマンデルブロ集合に含まれないなら `Some(i)` を返す
-fn escape_time(c: Complex<f64>, limit: u32) -> Option<u32> {
-    let mut z = Complex { re: 0.0, im: 0.0 };
+/// `options` は描画コマンドの全てのオプションで、書き出す形式は `path` の拡張子で決まる。通常の描画コマンドのほか、
+/// `render-batch` のジョブと `export-site` のタイルもこれで描くので、どれでも同じオプションが使える。
+/// `interactive` でなければ、標準入出力を使う `--dry-run` と `--preview-first` は断る。
+fn render_file(path: &str,
+               bounds: (usize, usize),
+               upper_left: Complex<f64>,
+               lower_right: Complex<f64>,
+               options: &[String],
+               interactive: bool)
+    -> Result<(), Failure>
+{
+    let (command, rest) = split_command_options(options).map_err(Failure::Usage)?;
+    if !interactive && (command.dry_run || command.preview_first) {
+        return Err(Failure::Usage("--dry-run and --preview-first only work on the command line".to_string()));
+    }
+    // 解析から計測できるよう、パラメータを解析する前にプロファイルを始める
+    let _profile = command.profile.as_ref()
+        .map(|profile| profile::start(profile))
+        .transpose()
+        .map_err(failed("error starting profiler"))?;
+    let params = tracing::info_span!("parse").in_scope(|| parse_params(&rest)).map_err(Failure::Usage)?;
+    let params = RenderParams {
+        limits: adaptive_limits(bounds, upper_left, lower_right, &params, command.pass_stop.unwrap_or(DEFAULT_PASS_STOP)),
+        ..params
+    };
+    let params = match command.normalize {
+        Some(percentile) => RenderParams {
+            stretch: percentile.range(&probe_counts(bounds, upper_left, lower_right, &params)),
+            ..params
+        },
+        None => params
+    };
+    let params = RenderParams { mark_missing: command.missing_color.is_some(), ..params };
+    let overlay = command.overlay().map_err(Failure::Usage)?;
+    resize::check_sizes(&command.also_sizes, bounds).map_err(Failure::Usage)?;
+    let encoding = command.encoding()
+        .map(|encoding| webformat::Encoding {
+            xmp: command.xmp.then(|| xmp::packet(bounds, upper_left, lower_right, &rest, &params)),
+            ..encoding
+        }).map_err(Failure::Usage)?;
+    let format = webformat::OutputFormat::of(path)
+        .and_then(|format| encoding.check(format).map(|_| format)).map_err(Failure::Usage)?;
 
-    for i in 0..limit {
-        z = z * z + c;
-        if z.norm_sqr() > 4.0 {
-            return Some(i);
+    if command.depth_map.is_some() || command.anaglyph.is_some() {
+        command.depth_source.check(&params).map_err(Failure::Usage)?;
+        if command.parallax.is_some_and(|parallax| parallax > bounds.0 as f64) {
+            return Err(Failure::Usage(format!("--parallax must be at most the image width {}", bounds.0)));
         }
     }
+    command.render.check(&params).map_err(Failure::Usage)?;
+    let backdrop = command.composite_over.as_ref()
+        .map(|path| composite_checks(&command, format, path))
+        .transpose().map_err(Failure::Usage)?;
 
-    None
+    let (imprecise, tiles) = estimate::imprecise_tiles(bounds, upper_left, lower_right);
+    let double = precision::supports(&params) && command.channels.is_none() && command.render == boundary::Mode::Full;
+    if imprecise > 0 && !double {
+        eprintln!("warning: {} of {} tiles are too deep for f64 and will look blocky; double-double only renders \
+                   the Mandelbrot set on the plane with escape-time coloring", imprecise, tiles);
+    } else if imprecise > 0 {
+        let (unresolved, _) = estimate::unresolved_tiles(bounds, upper_left, lower_right, precision::EPSILON);
+        if unresolved > 0 {
+            eprintln!("warning: {} of {} tiles are too deep even for double-double and will look blocky",
+                      unresolved, tiles);
+        }
+    }
+    if command.dry_run {
+        print_dry_run(bounds, upper_left, lower_right, &params);
+        return Ok(());
+    }
+    if command.preview_first && !preview_and_confirm(bounds, upper_left, lower_right, &params) {
+        return Err(Failure::Runtime("render cancelled".to_string()));
+    }
+
+    let work = WorkLog::default();
+    let started = Instant::now();
+    if let Some(channels) = &command.channels {
+        let icc = render_checks(&command, format, &params, channels).map_err(Failure::Usage)?;
+        let rgb = tracing::info_span!("render").in_scope(|| {
+            channels::render(bounds, upper_left, lower_right, &params, channels)
+        });
+        let elapsed = started.elapsed();
+        tracing::info_span!("encode").in_scope(|| {
+            File::create(path).and_then(|file| {
+                encode::encode_rgb(std::io::BufWriter::new(file), &rgb, bounds, icc.as_deref(), &encoding.png())
+            })
+        }).map_err(failed("error writing image"))?;
+        return finish_render(path, bounds, upper_left, lower_right, &rest, &params, &command, &work, elapsed);
+    }
+    // 描いた後で画像全体を使うものが無ければ、BigTIFF は描いたタイルから書き出して画像全体を持たない
+    if format == webformat::OutputFormat::Tiff && !command.needs_whole_image() {
+        tracing::info_span!("render").in_scope(|| {
+            tiff::render_tiled(path, bounds, upper_left, lower_right, &params, &encoding,
+                               Some(&work).filter(|_| command.work_stats))
+        }).map_err(failed("error writing TIFF file"))?;
+        return finish_render(path, bounds, upper_left, lower_right, &rest, &params, &command, &work, started.elapsed());
+    }
+    let mut pixels = PixelBuffer::new(bounds.0 * bounds.1, command.mmap_buffer)
+        .map_err(failed("error allocating pixel buffer"))?;
+    let complete = tracing::info_span!("render").in_scope(|| match command.render {
+        boundary::Mode::Boundary => {
+            let width = command.boundary_width.unwrap_or(boundary::DEFAULT_WIDTH);
+            boundary::render(&mut pixels, bounds, upper_left, lower_right, &params, width);
+            Ok(true)
+        }
+        boundary::Mode::Full => render_buffer(&mut pixels, bounds, upper_left, lower_right, &params,
+                                              Some(&work).filter(|_| command.work_stats || command.scheduling_map.is_some()))
+    }).map_err(failed("error writing pixel buffer"))?;
+    if !complete && params.mark_missing {
+        let missing = pixels.chunks(bounds.0).filter(|row| row[0] == color::MISSING).count();
+        eprintln!("warning: {} rows were not rendered and are drawn in --missing-color", missing);
+    } else if !complete {
+        eprintln!("warning: some rows were not rendered and look like interior points");
+    }
+    let elapsed = started.elapsed();
+    if let Some(filename) = &command.scheduling_map {
+        schedmap::write_scheduling_map(filename, &pixels, bounds, &work.bands())
+            .map_err(failed("error writing scheduling map"))?;
+    }
+    if command.contour_map {
+        tracing::info_span!("contour").in_scope(|| contour::recolor(&mut pixels, bounds));
+    }
+    tracing::info_span!("filter").in_scope(|| filter::apply_all(&mut pixels, bounds, &command.filters));
+    if command.check_contrast {
+        print_contrast_check(&pixels, encoding.colors.lut.as_ref(), params.limit());
+    }
+    if command.depth_map.is_some() || command.anaglyph.is_some() {
+        tracing::info_span!("depth").in_scope(|| {
+            write_depth(&command, &pixels, bounds, upper_left, lower_right, &params, &encoding)
+        }).map_err(Failure::Usage)?;
+    }
+    if let Some(overlay) = &overlay {
+        overlay.draw(&mut pixels, Region::new(bounds, upper_left, lower_right));
+    }
+
+    if let Some((mut backdrop, icc)) = backdrop {
+        tracing::info_span!("encode").in_scope(|| {
+            backdrop.over(&composite::layer(&frame::FrameBuffer::gray(bounds, &pixels), &encoding.colors),
+                          command.placement);
+            File::create(path).and_then(|file| {
+                encode::encode_frame(std::io::BufWriter::new(file), &backdrop.frame, icc.as_deref(), &encoding.png())
+            })
+        }).map_err(failed("error writing image"))?;
+        return finish_render(path, bounds, upper_left, lower_right, &rest, &params, &command, &work, elapsed);
+    }
+    tracing::info_span!("encode").in_scope(|| webformat::write_output(path, &pixels, bounds, &encoding))
+        .map_err(failed("error writing image"))?;
+    for &size in &command.also_sizes {
+        let downsampled = resize::downsample(&pixels, bounds, size);
+        webformat::write_output(&resize::sized_path(path, size), &downsampled, size, &encoding)
+            .map_err(failed("error writing image"))?;
+    }
+    finish_render(path, bounds, upper_left, lower_right, &rest, &params, &command, &work, elapsed)
 }
 
-fn parse_pair<T: FromStr>(s: &str, separator: char) -> Option<(T, T)> {
-    match s.find(separator) {
-        None => None,
-        Some(index) => {
-            match (T::from_str(&s[..index]), T::from_str(&s[index + 1..])) {
-                // find(separator)した結果、区切り文字で分割してどちらも期待する型にマッチしてOだった場合
-                (Ok(l), Ok(r)) => Some((l, r)),
-                // 上記マッチパターンに入らなかったワイルドカードパターン_
-                _ => None
+/// 画像を書き出した後の、仕事量の表示と位置ファイル、サイドカーの書き出し
+#[allow(clippy::too_many_arguments)]
+fn finish_render(path: &str,
+                 bounds: (usize, usize),
+                 upper_left: Complex<f64>,
+                 lower_right: Complex<f64>,
+                 options: &[String],
+                 params: &RenderParams,
+                 command: &CommandOptions,
+                 work: &WorkLog,
+                 elapsed: std::time::Duration)
+    -> Result<(), Failure>
+{
+    if command.work_stats {
+        print_work_stats(work, elapsed);
+    }
+    if let Some(location_path) = &command.export_location {
+        let location = kfr::Location::from_region(&Region::new(bounds, upper_left, lower_right), params.limit());
+        paramfile::save(location_path, &location).map_err(failed("error writing location file"))?;
+    }
+    if command.sidecar {
+        sidecar::Sidecar::new(path, bounds, upper_left, lower_right, options, params, elapsed)
+            .write(&sidecar::Sidecar::path_for(path))
+            .map_err(failed("error writing sidecar"))?;
+    }
+    Ok(())
+}
+
+/// `--channels` で描けるか確かめ、埋め込む ICC プロファイルを返す
+fn render_checks(command: &CommandOptions,
+                 format: webformat::OutputFormat,
+                 params: &RenderParams,
+                 channels: &channels::ChannelMap)
+    -> Result<Option<Vec<u8>>, String>
+{
+    if format != webformat::OutputFormat::Png {
+        return Err("--channels writes PNG only".to_string());
+    }
+    if command.palette.is_some() || command.alpha.is_some() || command.needs_whole_image() {
+        return Err("--channels cannot be combined with --palette, --alpha or options that draw on the gray image"
+                   .to_string());
+    }
+    channels.check(params)?;
+    color::rgb_profile(command.icc_profile.as_deref())
+}
+
+/// 注釈を描き込む前の画像から深度マップとアナグリフを書き出す
+fn write_depth(command: &CommandOptions,
+               pixels: &[u8],
+               bounds: (usize, usize),
+               upper_left: Complex<f64>,
+               lower_right: Complex<f64>,
+               params: &RenderParams,
+               encoding: &webformat::Encoding)
+    -> Result<(), String>
+{
+    let heights = depth::heights(bounds, upper_left, lower_right, params, command.depth_source);
+    if let Some(path) = &command.depth_map {
+        let gray = webformat::Encoding { xmp: encoding.xmp.clone(), ..webformat::Encoding::default() };
+        let written = if webformat::OutputFormat::of(path) == Ok(webformat::OutputFormat::Png) {
+            File::create(path).and_then(|file| {
+                encode::encode_frame(std::io::BufWriter::new(file), &depth::depth_map(&heights), None, &gray.png())
+            }).map_err(|e| e.to_string())
+        } else {
+            let map: Vec<u8> = depth::depth_map8(&heights).pixels.iter().map(|pixel| pixel.0).collect();
+            webformat::write_output(path, &map, bounds, &gray)
+        };
+        written.map_err(|e| format!("cannot write {}: {}", path, e))?;
+    }
+    if let Some(path) = &command.anaglyph {
+        let parallax = command.parallax.unwrap_or(bounds.0 as f64 / 50.0);
+        let rgb = depth::anaglyph(&frame::FrameBuffer::gray(bounds, pixels), &encoding.colors, &heights, parallax);
+        let icc = color::rgb_profile(command.icc_profile.as_deref())?;
+        File::create(path)
+            .and_then(|file| encode::encode_frame(std::io::BufWriter::new(file), &rgb, icc.as_deref(), &encoding.png()))
+            .map_err(|e| format!("cannot write {}: {}", path, e))?;
+    }
+    Ok(())
+}
+
+/// `--composite-over` で重ねられるか確かめ、背景の画像 `path` と埋め込む ICC プロファイルを読む
+fn composite_checks(command: &CommandOptions, format: webformat::OutputFormat, path: &str)
+    -> Result<(composite::Backdrop, Option<Vec<u8>>), String>
+{
+    if format != webformat::OutputFormat::Png {
+        return Err("--composite-over writes PNG only".to_string());
+    }
+    if command.channels.is_some() || !command.also_sizes.is_empty() {
+        return Err("--composite-over cannot be combined with --channels or --also-sizes".to_string());
+    }
+    Ok((composite::Backdrop::open(path)?, color::rgb_profile(command.icc_profile.as_deref())?))
+}
+
+/// 描いた画像の隣り合う反復の帯が、パレットの色で見分けられるかを表示する
+fn print_contrast_check(pixels: &[u8], lut: Option<&color::Lut>, limit: u32) {
+    let warnings = contrast::check(pixels, lut.unwrap_or(&color::Lut::gray()), limit);
+    for warning in &warnings {
+        eprintln!("warning: {}", warning);
+    }
+    if warnings.is_empty() {
+        eprintln!("contrast: adjacent iteration bands are distinguishable with normal vision, protanopia and deuteranopia");
+    }
+}
+
+/// オプションの誤りを知らせ、使い方を表示して終了する
+fn exit_with_usage(program: &str, message: &str) -> ! {
+    eprintln!("error parsing options: {}", message);
+    print_usage(program);
+    std::process::exit(1);
+}
+
+/// 各点の計算には影響しない、通常の描画コマンドだけのオプション
+#[derive(Debug, Default, PartialEq)]
+struct CommandOptions {
+    /// 描画せずに解析したパラメータとコストの見積もりを表示する
+    dry_run: bool,
+    /// 先に縮小版を描画して確認を取ってから本番の大きさで描画する
+    preview_first: bool,
+    /// ピクセルバッファを一時ファイルにマップする
+    mmap_buffer: bool,
+    /// 描画後にパレットが隣り合う反復の帯を見分けられるか調べる
+    check_contrast: bool,
+    /// 描画後に反復の回数とスレッド毎の仕事量を表示する
+    work_stats: bool,
+    /// 帯を描いたスレッドで色分けした画像の書き出し先
+    scheduling_map: Option<String>,
+    /// `tracing` のスパンを folded 形式で書き出す先
+    profile: Option<String>,
+    /// 書き出す前に描き込む注釈のファイル
+    annotations: Option<String>,
+    /// 値を添えた格子と軸を描き込む
+    grid: bool,
+    /// 範囲の大きさに合わせた物差しを描き込む
+    scale_bar: bool,
+    /// 描いた位置を書き出す位置ファイル (.kfr, .upr, .par)
+    export_location: Option<String>,
+    /// 画像と並べてパラメータと描画の記録を JSON で書く
+    sidecar: bool,
+    /// 描いた位置とパラメータを XMP で画像に埋め込む
+    xmp: bool,
+    /// 発散までの回数の分布に合わせて明るさを引き伸ばす
+    normalize: Option<normalize::Percentile>,
+    /// `--passes` で1つのパスで発散した点が残りのこの割合未満なら以降のパスを打ち切る
+    pass_stop: Option<f64>,
+    /// 全ての点を色付けするか、境界の近くだけを線で描くか
+    render: boundary::Mode,
+    /// `--render boundary` で線にする境界からのピクセル数
+    boundary_width: Option<f64>,
+    /// 色を付ける前の灰色の画像に順に掛ける処理
+    filters: Vec<filter::Filter>,
+    /// 外部の帯を繋がった領域毎に塗り分ける
+    contour_map: bool,
+    /// 描画を諦めた点に使う色。無ければ内部と同じ黒のまま
+    missing_color: Option<[u8; 3]>,
+    /// 描いた画像を縮小して一緒に書き出す大きさ
+    also_sizes: Vec<(usize, usize)>,
+    /// 形式毎の書き出しの設定
+    encoding: webformat::Encoding,
+    /// 色を付けるパレットの名前かファイル
+    palette: Option<String>,
+    /// パレットを補間する色空間
+    interp_space: Option<color::InterpSpace>,
+    /// 埋め込む ICC プロファイル (`srgb`、`none`、ファイル)
+    icc_profile: Option<String>,
+    /// 透明にする点
+    alpha: Option<color::Alpha>,
+    /// 描いた画像を重ねる背景の画像
+    composite_over: Option<String>,
+    /// 背景に重ねる位置と倍率
+    placement: composite::Placement,
+    /// 高さを明るさにした深度マップの書き出し先
+    depth_map: Option<String>,
+    /// 赤青のアナグリフの書き出し先
+    anaglyph: Option<String>,
+    /// 深度マップとアナグリフの高さにする量
+    depth_source: depth::Source,
+    /// アナグリフのいちばん高い点の視差のピクセル数
+    parallax: Option<f64>,
+    /// 灰色の代わりに RGB の成分毎に決めた量で描く
+    channels: Option<channels::ChannelMap>
+}
+
+impl CommandOptions {
+    /// 描いた後で画像全体が要るか。要らなければ BigTIFF は画像全体を持たずに書き出せる
+    fn needs_whole_image(&self) -> bool {
+        self.grid || self.scale_bar || self.annotations.is_some() || self.scheduling_map.is_some()
+            || !self.also_sizes.is_empty() || self.check_contrast || self.depth_map.is_some() || self.anaglyph.is_some()
+            || self.render == boundary::Mode::Boundary || self.contour_map || !self.filters.is_empty()
+    }
+
+    /// 格子、注釈、物差しの順に重ねる注釈。どれも無ければ `None`
+    fn overlay(&self) -> Result<Option<overlay::Overlay>, String> {
+        if !self.grid && !self.scale_bar && self.annotations.is_none() {
+            return Ok(None);
+        }
+        let mut overlay = overlay::Overlay::default();
+        if self.grid {
+            overlay.push(overlay::Shape::Axes, 255);
+        }
+        if let Some(path) = &self.annotations {
+            overlay.extend(overlay::Overlay::load(path)?);
+        }
+        if self.scale_bar {
+            overlay.push(overlay::Shape::ScaleBar, 255);
+        }
+        Ok(Some(overlay))
+    }
+
+    /// パレットと ICC プロファイルを読んだ書き出しの設定
+    fn encoding(&self) -> Result<webformat::Encoding, String> {
+        let colors = color::Colors {
+            alpha: self.alpha,
+            ..color::Colors::resolve(self.palette.as_deref(), self.interp_space, self.icc_profile.as_deref())?
+        };
+        let colors = match self.missing_color {
+            Some(missing) => colors.with_missing(missing, self.icc_profile.as_deref())?,
+            None => colors
+        };
+        Ok(webformat::Encoding { colors, ..self.encoding.clone() })
+    }
+}
+
+/// コマンドだけのオプションを取り除き、残りを `parse_params` に渡せるように返す
+fn split_command_options(args: &[String]) -> Result<(CommandOptions, Vec<String>), String> {
+    let mut command = CommandOptions::default();
+    let mut rest = vec![];
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--scheduling-map" => {
+                command.scheduling_map = Some(args.next()
+                    .ok_or("--scheduling-map expects a file name")?.clone());
+            }
+            "--profile" => {
+                command.profile = Some(args.next().ok_or("--profile expects a file name")?.clone());
+            }
+            "--annotations" => {
+                command.annotations = Some(args.next().ok_or("--annotations expects a file name")?.clone());
             }
+            "--export-location" => {
+                command.export_location = Some(args.next().ok_or("--export-location expects a file name")?.clone());
+            }
+            "--also-sizes" => {
+                command.also_sizes = args.next().ok_or("--also-sizes expects WxH,WxH,...")?
+                    .split(',')
+                    .map(|size| parse_pair(size, 'x').ok_or(format!("--also-sizes expects WxH: {}", size)))
+                    .collect::<Result<_, _>>()?;
+            }
+            "--quality" => {
+                command.encoding.quality = args.next().and_then(|q| f32::from_str(q).ok())
+                    .ok_or("--quality expects a number from 1 to 100")?;
+            }
+            "--lossless" => command.encoding.lossless = true,
+            "--interlace" => command.encoding.interlace = true,
+            "--tile-size" => {
+                command.encoding.tiling.tile_size = args.next().and_then(|n| usize::from_str(n).ok())
+                    .ok_or("--tile-size expects a multiple of 16")?;
+            }
+            "--pyramid" => command.encoding.tiling.pyramid = true,
+            "--palette" => {
+                command.palette = Some(args.next().ok_or("--palette expects a name or a file name")?.clone());
+            }
+            "--interp-space" => {
+                command.interp_space = Some(color::InterpSpace::from_str(
+                    args.next().ok_or("--interp-space expects srgb, linear, oklab or lch")?)?);
+            }
+            "--icc-profile" => {
+                command.icc_profile = Some(args.next().ok_or("--icc-profile expects srgb, none or a file name")?.clone());
+            }
+            "--alpha" => {
+                command.alpha = Some(color::Alpha::from_str(args.next().ok_or("--alpha expects exterior or interior")?)?);
+            }
+            "--composite-over" => {
+                command.composite_over = Some(args.next().ok_or("--composite-over expects a file name")?.clone());
+            }
+            "--composite-offset" => {
+                command.placement.offset = args.next().and_then(|offset| parse_pair(offset, ','))
+                    .ok_or("--composite-offset expects X,Y")?;
+            }
+            "--composite-scale" => {
+                command.placement.scale = composite::parse_scale(args.next().ok_or("--composite-scale expects a number")?)?;
+            }
+            "--depth-map" => {
+                command.depth_map = Some(args.next().ok_or("--depth-map expects a file name")?.clone());
+            }
+            "--anaglyph" => {
+                command.anaglyph = Some(args.next().ok_or("--anaglyph expects a file name")?.clone());
+            }
+            "--depth-source" => {
+                command.depth_source = depth::Source::from_str(args.next().ok_or("--depth-source expects count or de")?)?;
+            }
+            "--parallax" => {
+                command.parallax = Some(args.next().and_then(|n| f64::from_str(n).ok())
+                    .filter(|n| n.is_finite() && *n >= 0.0)
+                    .ok_or("--parallax expects a number of pixels")?);
+            }
+            "--sidecar" => command.sidecar = true,
+            "--xmp" => command.xmp = true,
+            "--normalize" => {
+                command.normalize = Some(normalize::Percentile::from_str(
+                    args.next().ok_or("--normalize expects percentile:LO,HI")?)?);
+            }
+            "--render" => {
+                command.render = boundary::Mode::from_str(args.next().ok_or("--render expects full or boundary")?)?;
+            }
+            "--boundary-width" => {
+                command.boundary_width = Some(boundary::parse_width(args.next().ok_or("--boundary-width expects a number")?)?);
+            }
+            "--missing-color" => {
+                let color = args.next().ok_or("--missing-color expects #rrggbb")?;
+                command.missing_color = Some(color::parse_color(color)
+                    .ok_or(format!("--missing-color expects #rrggbb: {}", color))?);
+            }
+            "--filter" => {
+                command.filters = filter::parse_filters(args.next().ok_or("--filter expects sobel, emboss or unsharp")?)?;
+            }
+            "--contour-map" => command.contour_map = true,
+            "--grid" => command.grid = true,
+            "--scale-bar" => command.scale_bar = true,
+            "--dry-run" => command.dry_run = true,
+            "--preview-first" => command.preview_first = true,
+            "--mmap-buffer" => command.mmap_buffer = true,
+            "--work-stats" => command.work_stats = true,
+            "--pass-stop" => {
+                command.pass_stop = Some(args.next().and_then(|n| f64::from_str(n).ok()).filter(|n| (0.0 ..= 1.0).contains(n))
+                    .ok_or("--pass-stop expects a fraction from 0 to 1")?);
+            }
+            "--check-contrast" => command.check_contrast = true,
+            "--channels" => {
+                command.channels = Some(channels::ChannelMap::from_str(
+                    args.next().ok_or("--channels expects three channels for R,G,B")?)?);
+            }
+            _ => rest.push(arg.clone())
         }
     }
+
+    Ok((command, rest))
 }
 
 #[test]
-fn test_parse_pair() {
-    assert_eq!(parse_pair::<i32>("",        ','), None);
-    assert_eq!(parse_pair::<i32>("10,",     ','), None);
-    assert_eq!(parse_pair::<i32>(",10",     ','), None);
-    assert_eq!(parse_pair::<i32>("10,20",   ','), Some((10, 20)));
-    assert_eq!(parse_pair::<i32>("10,20xy", ','), None);
-    assert_eq!(parse_pair::<f64>("0.5x",    'x'), None);
-    assert_eq!(parse_pair::<f64>("0.5x1.5", 'x'), Some((0.5, 1.5)));
+fn test_split_command_options() {
+    let args = |s: &str| -> Vec<String> {
+        s.split_whitespace().map(String::from).collect()
+    };
+    assert_eq!(split_command_options(&args("--passes 256,1024")),
+               Ok((CommandOptions::default(), args("--passes 256,1024"))));
+    assert_eq!(split_command_options(&args("--dry-run --interior-check")),
+               Ok((CommandOptions { dry_run: true, ..CommandOptions::default() },
+                   args("--interior-check"))));
+    assert_eq!(split_command_options(&args("--mmap-buffer --passes 64")),
+               Ok((CommandOptions { mmap_buffer: true, ..CommandOptions::default() },
+                   args("--passes 64"))));
+    assert_eq!(split_command_options(&args("--scheduling-map map.png --threads 4")),
+               Ok((CommandOptions { scheduling_map: Some("map.png".to_string()),
+                                    ..CommandOptions::default() },
+                   args("--threads 4"))));
+    assert_eq!(split_command_options(&args("--annotations notes.toml")),
+               Ok((CommandOptions { annotations: Some("notes.toml".to_string()),
+                                    ..CommandOptions::default() },
+                   vec![])));
+    assert_eq!(split_command_options(&args("--grid --passes 64 --scale-bar")),
+               Ok((CommandOptions { grid: true, scale_bar: true, ..CommandOptions::default() },
+                   args("--passes 64"))));
+    assert_eq!(split_command_options(&args("--also-sizes 640x360,64x36")),
+               Ok((CommandOptions { also_sizes: vec![(640, 360), (64, 36)], ..CommandOptions::default() },
+                   vec![])));
+    assert!(split_command_options(&args("--also-sizes 640x")).is_err());
+    assert_eq!(split_command_options(&args("--quality 75 --lossless")),
+               Ok((CommandOptions { encoding: webformat::Encoding { quality: 75.0, lossless: true,
+                                                                    ..webformat::Encoding::default() },
+                                    ..CommandOptions::default() },
+                   vec![])));
+    assert_eq!(split_command_options(&args("--interlace")).map(|(command, _)| command.encoding.interlace), Ok(true));
+    assert_eq!(split_command_options(&args("--normalize percentile:1,99")).map(|(command, _)| command.normalize),
+               Ok(Some(normalize::Percentile { low: 1.0, high: 99.0 })));
+    assert!(split_command_options(&args("--normalize percentile:")).is_err());
+    assert_eq!(split_command_options(&args("--missing-color #ff00ff")).map(|(command, _)| command.missing_color),
+               Ok(Some([255, 0, 255])));
+    assert!(split_command_options(&args("--missing-color magenta")).is_err());
+    assert_eq!(split_command_options(&args("--render boundary --boundary-width 3"))
+                   .map(|(command, _)| (command.render, command.boundary_width)),
+               Ok((boundary::Mode::Boundary, Some(3.0))));
+    assert!(split_command_options(&args("--boundary-width 0")).is_err());
+    assert_eq!(split_command_options(&args("--filter sobel,emboss")).map(|(command, _)| command.filters),
+               Ok(vec![filter::Filter::Sobel, filter::Filter::Emboss]));
+    assert!(split_command_options(&args("--filter sharpen")).is_err());
+    assert_eq!(split_command_options(&args("--contour-map --passes 64")),
+               Ok((CommandOptions { contour_map: true, ..CommandOptions::default() }, args("--passes 64"))));
+    assert_eq!(split_command_options(&args("--xmp --passes 64")),
+               Ok((CommandOptions { xmp: true, ..CommandOptions::default() }, args("--passes 64"))));
+    assert_eq!(split_command_options(&args("--tile-size 512 --pyramid")).map(|(command, _)| command.encoding.tiling),
+               Ok(tiff::Tiling { tile_size: 512, pyramid: true }));
+    assert_eq!(split_command_options(&args("--palette fire --interp-space lch --icc-profile none")),
+               Ok((CommandOptions { palette: Some("fire".to_string()), interp_space: Some(color::InterpSpace::Lch),
+                                    icc_profile: Some("none".to_string()), ..CommandOptions::default() },
+                   vec![])));
+    assert!(split_command_options(&args("--interp-space hsv")).is_err());
+    assert_eq!(split_command_options(&args("--alpha interior")).map(|(command, _)| command.alpha),
+               Ok(Some(color::Alpha::Interior)));
+    assert!(split_command_options(&args("--alpha both")).is_err());
+    assert_eq!(split_command_options(&args("--composite-over poster.png --composite-offset -40,120 --composite-scale 0.5")),
+               Ok((CommandOptions { composite_over: Some("poster.png".to_string()),
+                                    placement: composite::Placement { offset: (-40, 120), scale: 0.5 },
+                                    ..CommandOptions::default() },
+                   vec![])));
+    assert!(split_command_options(&args("--composite-offset 40")).is_err());
+    assert_eq!(split_command_options(&args("--anaglyph 3d.png --depth-source de --parallax 12")),
+               Ok((CommandOptions { anaglyph: Some("3d.png".to_string()), depth_source: depth::Source::De,
+                                    parallax: Some(12.0), ..CommandOptions::default() },
+                   vec![])));
+    assert_eq!(split_command_options(&args("--depth-map depth.png")).map(|(command, _)| command.needs_whole_image()),
+               Ok(true));
+    assert!(split_command_options(&args("--parallax -1")).is_err());
+    assert!(split_command_options(&args("--parallax inf")).is_err());
+    assert!(split_command_options(&args("--channels count,de,angle")).is_ok_and(|(command, _)| command.channels.is_some()));
+    assert!(split_command_options(&args("--channels count,de")).is_err());
+    assert_eq!(split_command_options(&args("--check-contrast")).map(|(command, _)| command.needs_whole_image()),
+               Ok(true));
+    assert!(split_command_options(&args("--quality high")).is_err());
+    assert!(split_command_options(&args("--scheduling-map")).is_err());
+    assert_eq!(split_command_options(&args("--pass-stop 0.01")).map(|(command, _)| command.pass_stop), Ok(Some(0.01)));
+    assert!(split_command_options(&args("--pass-stop 2")).is_err());
 }
 
-fn parse_complex(s: &str) -> Option<Complex<f64>> {
-    match parse_pair(s, ',') {
-        Some((re, im)) => Some(Complex { re, im }),
-        None => None
+/// プレビューの幅と高さの上限
+const PREVIEW_SIZE: usize = 320;
+
+/// 縦横比を保ったまま `bounds` を `max` ピクセル四方に収まるよう縮める
+fn preview_bounds(bounds: (usize, usize), max: usize) -> (usize, usize) {
+    let longest = bounds.0.max(bounds.1);
+    if longest <= max {
+        return bounds;
     }
+    ((bounds.0 * max / longest).max(1), (bounds.1 * max / longest).max(1))
 }
 
 #[test]
-fn test_parse_complex() {
-    assert_eq!(parse_complex("1.25,-0.0625"),
-               Some(Complex { re: 1.25, im: -0.0625}));
-    assert_eq!(parse_complex(",-0.0625)"),
-               None);
+fn test_preview_bounds() {
+    assert_eq!(preview_bounds((4000, 3000), 320), (320, 240));
+    assert_eq!(preview_bounds((3000, 4000), 320), (240, 320));
+    assert_eq!(preview_bounds((10000, 10), 320), (320, 1));
+    assert_eq!(preview_bounds((200, 100), 320), (200, 100));
+}
+
+/// `input` から1行読み、`y` か `yes` なら `true` を返す
+fn confirm<R: std::io::BufRead>(mut input: R) -> bool {
+    let mut answer = String::new();
+    if input.read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim().to_ascii_lowercase().as_str(), "y" | "yes")
+}
+
+#[test]
+fn test_confirm() {
+    assert!(confirm(&b"y\n"[..]));
+    assert!(confirm(&b" Yes \n"[..]));
+    assert!(!confirm(&b"n\n"[..]));
+    assert!(!confirm(&b"\n"[..]));
+    assert!(!confirm(&b""[..]));
+}
+
+/// 縮小版を一時ファイルに描画して場所を示し、本番の描画に進むか尋ねる
+fn preview_and_confirm(bounds: (usize, usize),
+                       upper_left: Complex<f64>,
+                       lower_right: Complex<f64>,
+                       params: &RenderParams)
+    -> bool
+{
+    let preview = preview_bounds(bounds, PREVIEW_SIZE);
+    let path = std::env::temp_dir()
+        .join(format!("mandelbrot-preview-{}.png", std::process::id()));
+    let mut pixels = vec![0; preview.0 * preview.1];
+    render_parallel(&mut pixels, preview, upper_left, lower_right, params);
+    if let Err(e) = write_image(&path.to_string_lossy(), &pixels, preview) {
+        eprintln!("error writing preview: {}", e);
+        return false;
+    }
+
+    let estimate = estimate::estimate_cost(bounds, upper_left, lower_right, params);
+    eprintln!("preview written to {}", path.display());
+    eprint!("render {}x{} (estimated {:.2}s)? [y/N] ",
+            bounds.0, bounds.1, estimate.wall_clock.as_secs_f64());
+    let _ = std::io::stderr().flush();
+    confirm(std::io::stdin().lock())
+}
+
+fn print_dry_run(bounds: (usize, usize),
+                 upper_left: Complex<f64>,
+                 lower_right: Complex<f64>,
+                 params: &RenderParams)
+{
+    let estimate = estimate::estimate_cost(bounds, upper_left, lower_right, params);
+    let limits: Vec<String> = params.limits.iter().map(u32::to_string).collect();
+
+    println!("region:               {},{} .. {},{} ({} x {})",
+             upper_left.re, upper_left.im, lower_right.re, lower_right.im,
+             lower_right.re - upper_left.re, upper_left.im - lower_right.im);
+    println!("pixels:               {}x{} ({})", bounds.0, bounds.1, bounds.0 * bounds.1);
+    println!("limits:               {}", limits.join(","));
+    println!("bailout:              {} ({:?})", params.termination.radius, params.termination.norm);
+    println!("interior check:       {}", params.termination.detect_interior);
+    println!("estimated iterations: {:.3e} (mean {:.1} per pixel over {} samples)",
+             estimate.total_iterations, estimate.mean_iterations, estimate.samples);
+    println!("estimated memory:     {} bytes", estimate.memory_bytes);
+    println!("estimated time:       {:.2}s on {} threads",
+             estimate.wall_clock.as_secs_f64(), estimate.threads);
+    let (imprecise, tiles) = estimate::imprecise_tiles(bounds, upper_left, lower_right);
+    println!("f64 precision:        enough in {} of {} tiles", tiles - imprecise, tiles);
+    if imprecise > 0 && precision::supports(params) {
+        let (unresolved, _) = estimate::unresolved_tiles(bounds, upper_left, lower_right, precision::EPSILON);
+        println!("double-double:        enough in {} of {} tiles", tiles - unresolved, tiles);
+    }
+}
+
+/// `pixels` を水平の帯に分割し、`params.scheduling.backend` で並列に描画する。
+/// `params.deadline` を過ぎたか `params.budget` を使い切って描画しきれなかった行があれば `false` を返す。
+fn render_parallel(pixels: &mut [u8],
+                   bounds: (usize, usize),
+                   upper_left: Complex<f64>,
+                   lower_right: Complex<f64>,
+                   params: &RenderParams)
+    -> bool
+{
+    backend::render_rows(pixels, bounds, 0, upper_left, lower_right, params)
+}
+
+/// `pixels` を `strip_rows` 行ずつ描画し、描き終えた帯から順にバッファに書き出させる。
+/// ヒープ上のバッファは1回で全体を描画する
+fn render_buffer(pixels: &mut PixelBuffer,
+                 bounds: (usize, usize),
+                 upper_left: Complex<f64>,
+                 lower_right: Complex<f64>,
+                 params: &RenderParams,
+                 work: Option<&WorkLog>)
+    -> std::io::Result<bool>
+{
+    let strip = pixels.strip_rows(bounds.0);
+    let mut complete = true;
+    for top in (0 .. bounds.1).step_by(strip) {
+        let rows = strip.min(bounds.1 - top);
+        let range = top * bounds.0 .. (top + rows) * bounds.0;
+        complete &= backend::render_rows_logged(&mut pixels[range.clone()], bounds, top,
+                                                upper_left, lower_right, params, work);
+        pixels.finish(range)?;
+    }
+    Ok(complete)
+}
+
+#[test]
+fn test_render_buffer() {
+    let bounds = (64, 48);
+    let upper_left = Complex { re: -1.20, im: 0.35 };
+    let lower_right = Complex { re: -1.0, im: 0.20 };
+    let params = RenderParams::default();
+    let mut expected = vec![0; bounds.0 * bounds.1];
+    render_parallel(&mut expected, bounds, upper_left, lower_right, &params);
+    for &mapped in &[false, true] {
+        let mut pixels = PixelBuffer::new(bounds.0 * bounds.1, mapped).unwrap();
+        assert_eq!(render_buffer(&mut pixels, bounds, upper_left, lower_right, &params, None).ok(),
+                   Some(true));
+        assert_eq!(&pixels[..], &expected[..]);
+    }
+}
+
+/// 描画で行った反復の回数と、その速さをスレッド毎に表示する。
+/// 近道や内部の検出で反復が減った効果と、1回の反復の速さの変化を切り分けるためのもの
+fn print_work_stats(work: &WorkLog, elapsed: std::time::Duration) {
+    let rate = |iterations: u64, secs: f64| iterations as f64 / secs.max(1e-9) / 1e6;
+    let total = work.iterations();
+    eprintln!("{} iterations in {:.3}s ({:.1} M iter/s)",
+              total, elapsed.as_secs_f64(), rate(total, elapsed.as_secs_f64()));
+    eprintln!("{:>6} {:>7} {:>14} {:>9} {:>11}", "thread", "bands", "iterations", "busy", "M iter/s");
+    for (i, thread) in work.threads().iter().enumerate() {
+        let busy = thread.busy.as_secs_f64();
+        eprintln!("{:>6} {:>7} {:>14} {:>8.3}s {:>11.1}",
+                  i, thread.bands, thread.iterations, busy, rate(thread.iterations, busy));
+    }
+}
+
+/// `--help` で使い方の後に表示する例
+const EXAMPLES: &str = "\
+Examples:
+    # 全体を 1000x750 で描く
+    mandelbrot mandel.png 1000x750 -2.5,1.5 1.5,-1.5
+
+    # 反復を 256 回で一度描き、発散しなかった点だけ 4096 回まで続ける
+    mandelbrot deep.png 1920x1080 -0.7463,0.1102 -0.7453,0.1096 --passes 256,4096
+
+    # Burning Ship を Pickover の茎で色付けし、格子と物差しを描き込む
+    mandelbrot ship.png 800x600 -2,-0.2 -1.6,-0.5 --fractal burning-ship --coloring stalks --grid --scale-bar
+
+    # 所要時間を見積もってから、確認の後に描く
+    mandelbrot big.png 20000x15000 -2.5,1.5 1.5,-1.5 --dry-run
+    mandelbrot big.png 20000x15000 -2.5,1.5 1.5,-1.5 --preview-first
+
+    # 描画 API を 8 並列で立てる
+    mandelbrot serve-api 127.0.0.1:8080 --max-concurrent 8 --cache-dir /tmp/tiles
+
+    # シェルの補完を有効にする (bash)
+    source <(mandelbrot completions bash)
+";
+
+fn print_usage(program: &str) {
+    eprint!("{}", usage(program));
+}
+
+/// 使い方の説明。`completions` はここに書いたサブコマンドとオプションを補完する
+fn usage(program: &str) -> String {
+    let mut text = String::new();
+    let mut line = |s: &str| {
+        text.push_str(s);
+        text.push('\n');
+    };
+    line("Usage: mandelbrot FILE PIXELS UPPERLEFT LOWERRIGHT [OPTIONS]");
+    line("       mandelbrot FILE PIXELS --location LOCATION.{kfr,upr,par} [OPTIONS]");
+    line("       mandelbrot worker ADDR [--max-connections N]");
+    line("       mandelbrot coordinator FILE PIXELS UPPERLEFT LOWERRIGHT --workers ADDR,ADDR,... [OPTIONS]");
+    line("       mandelbrot serve-api ADDR [SERVER OPTIONS]");
+    line("       mandelbrot render-batch JOBS.toml [--jobs N] [--watch]");
+    line("       mandelbrot render-batch --jobs -");
+    line("       mandelbrot bench PIXELS UPPERLEFT LOWERRIGHT [--runs N] [--history FILE.csv [--threshold PCT]] [OPTIONS]");
+    line("       mandelbrot bench PIXELS UPPERLEFT LOWERRIGHT --scaling MAXTHREADS [--svg FILE] [--runs N] [OPTIONS]");
+    line("       mandelbrot bench PIXELS UPPERLEFT LOWERRIGHT --coordinates [--runs N]");
+    line("       mandelbrot explore-tui [--center RE,IM] [--zoom Z] [--save-size WxH] [OPTIONS]");
+    line("       mandelbrot palette edit FILE.toml [--from NAME|FILE.toml] [--center RE,IM] [--zoom Z] [OPTIONS]");
+    line("       mandelbrot convert-params IN.{kfr,upr,par} OUT.{kfr,upr,par}");
+    line("       mandelbrot diff A.png B.png [--output DIFF.png] [--amplify N]");
+    line("       mandelbrot validate-backends [--size WxH] [--tolerance N] [OPTIONS]");
+    line("       mandelbrot fuzz-parsers [--iterations N] [--seed S] [--target NAME]");
+    line("       mandelbrot export-site DIR [--center RE,IM] [--zoom Z] [--levels N] [--tile-size N] [OPTIONS]");
+    line("       mandelbrot dataset --count N --out DIR [--size WxH] [--seed S] [--min-zoom Z] [--max-zoom Z] [OPTIONS]");
+    line("       mandelbrot wallpaper [--preset NAME] [--size WxH] [--output FILE] [--set] [--list] [OPTIONS]");
+    line("       mandelbrot completions bash|zsh|fish");
+    line("       mandelbrot --help");
+    line("       mandelbrot coords PIXELS (UPPERLEFT LOWERRIGHT | --center RE,IM [--zoom Z]) [--pixel X,Y]... [--point RE,IM]...");
+    line(&format!("Example: {} mandle.png 1000x750 -1.20,0.35 -1,20", program));
+    line("");
+    line("Options:");
+    line("    --dry-run           描画せずにパラメータとコストの見積もりを表示する");
+    line("    --preview-first     縮小版を描画して確認してから本番の描画に進む");
+    line("    --mmap-buffer       ピクセルバッファを一時ファイルにマップする (メモリに収まらない画像向け)");
+    line("    --work-stats        描画後に反復の回数と M iter/s をスレッド毎に表示する");
+    line("    --scheduling-map FILE  帯を描いたスレッドで色分けした PNG も書き出す");
+    line("    --profile FILE      解析・描画・帯・エンコードの所要時間をフレームグラフ用の folded 形式で書き出す");
+    line("    --annotations FILE  印・軌道・外射線・格子・文字を TOML のファイルから描き込んでから書き出す");
+    line("    --grid              値を添えた格子と目盛り、実軸と虚軸を描き込む");
+    line("    --scale-bar         範囲の幅に合わせた長さの物差しを左下に描き込む");
+    line("    --also-sizes WxH,...  描いた画像を面積平均で縮小して FILE-WxH.png にも書き出す");
+    line("    --quality Q         FILE が .webp か .avif のときの画質 1..100 (既定値: 90)");
+    line("    --lossless          FILE が .webp のとき可逆圧縮で書き出す");
+    line("    --interlace         PNG を Adam7 でインターレースし、読み込み途中でも粗い全体が見えるようにする");
+    line("    --tile-size N       FILE が .tif のときの BigTIFF のタイルの一辺、16 の倍数 (既定値: 256)");
+    line("    --pyramid           FILE が .tif のとき、半分ずつ縮小した画像を重ねて書く");
+    line("    --normalize percentile:LO,HI  粗く描いた回数の分布の LO % を白、HI % を黒にして明るさを引き伸ばす");
+    line("    --render boundary   距離の見積もりで集合の縁だけを黒い線で描き、他は白にする (mandelbrot のみ)");
+    line("    --boundary-width W  --render boundary の線にする縁からのピクセル数 (既定値: 1)");
+    line("    --contour-map       同じ明るさで繋がった外部の帯を領域毎に塗り分け、等高線図のようにする");
+    line("    --filter F,...      色を付ける前に sobel (境目の線)、emboss (浮き彫り)、unsharp (鮮鋭化) を順に掛ける");
+    line("    --missing-color #rrggbb  描画を諦めた点を内部の黒と区別してこの色で書く");
+    line("    --palette NAME|FILE.toml  明るさをパレットの色に置き換える gray (既定値)|ultra|fire|ocean|");
+    line("                        cividis|blue-orange (cividis と blue-orange は1型・2型の2色覚でも見分けやすい)");
+    line("    --interp-space SPACE  パレットを補間する色空間 srgb|linear|oklab (既定値)|lch");
+    line("    --channels R,G,B    灰色の代わりに量 count|de|angle[:linear|sqrt|log|pow=G][:scale=S][:invert] を成分毎に");
+    line("                        割り当てた RGB の PNG を書く (例: count:log,de:sqrt,angle)");
+    line("    --check-contrast    隣り合う反復の帯が色覚の型によらず見分けられるか調べ、見分けにくい帯を知らせる");
+    line("    --alpha exterior|interior  発散した点か集合の内部の点を透明にする (PNG、TIFF、WebP、AVIF)");
+    line("    --composite-over BG  描いた画像を色付けして透明な点を抜き (既定値: --alpha exterior)、画像 BG に重ねた");
+    line("                        BG と同じ大きさの PNG を書く");
+    line("    --composite-offset X,Y  BG に重ねる左上のピクセル (既定値: 0,0)");
+    line("    --composite-scale S  描いた画像を S 倍にして重ねる (既定値: 1)");
+    line("    --depth-map FILE    反復から求めた高さを明るさ (手前が白) にした深度マップを FILE に書く");
+    line("    --anaglyph FILE     高さで左右の視差を付けた赤青のアナグリフの PNG を FILE に書く");
+    line("    --depth-source count|de  高さにする量、連続化した回数 (既定値) か距離推定 (mandelbrot のみ)");
+    line("    --parallax N        アナグリフのいちばん高い点の視差のピクセル数、幅まで (既定値: 幅の 1/50)");
+    line("    --icc-profile srgb|none|FILE  PNG と TIFF に埋め込む ICC プロファイル (色を付けたときの既定値: srgb)");
+    line("    --sidecar           画像の拡張子を .json に替えたファイルに、パラメータと描画時間などを書く");
+    line("    --xmp               描いたソフト、中心と倍率、オプションを XMP で PNG と TIFF に埋め込む");
+    line("    --export-location FILE  描いた範囲と反復回数を拡張子の形式 (.kfr, .upr, .par) の位置ファイルに書き出す");
+    line("    --passes N,N,...    反復回数の上限を段階的に引き上げて描画する (既定値: 255)");
+    line("    --pass-stop F       --passes で1つのパスで発散した点が残りの F 未満なら以降のパスを打ち切る (既定値: 0.01)");
+    line("    --fractal NAME      反復する式 mandelbrot (既定値)|burning-ship|celtic|buffalo|");
+    line("                        perpendicular-burning-ship|perpendicular-mandelbrot|magnet-1|magnet-2|nova|collatz");
+    line("    --hybrid SEQ        式の列を1回ずつ切り替えて繰り返す。M: mandelbrot、B: burning-ship、");
+    line("                        C: celtic、F: buffalo、P: perpendicular-burning-ship、");
+    line("                        Q: perpendicular-mandelbrot (例: MMB)");
+    line("    --plugin FILE       共有ライブラリのプラグインの式で反復する (ABI は src/plugin.rs)");
+    line("    --nova-degree P     nova の多項式の次数 (2 から 64、既定値: 3)");
+    line("    --nova-relaxation R nova の緩和係数 (既定値: 1.0)");
+    line("    --interior-check    軌道の微分から内部の吸引サイクルを検出して反復を打ち切る (mandelbrot のみ)");
+    line("    --bailout R         発散とみなす脱出半径 (既定値: 2.0、magnet は 100、nova は 1e6、collatz は 1000)");
+    line("    --bailout-norm N    脱出判定のノルム euclidean|real|imag|manhattan (既定値: euclidean)");
+    line("    --coloring MODE     escape (既定値: 発散までの回数)、stalks (Pickover の茎)、");
+    line("                        atom-domains (|z| が最小になった反復の番号)、");
+    line("                        binary (発散したときの Im z の符号)");
+    line("    --interior MODE     内部の点を black (既定値) か、境界までの距離の見積もり de で塗る (mandelbrot のみ)");
+    line("    --layers SPEC       色付けを重ねる。例: escape,stalks:multiply,binary:alpha=0.3");
+    line("                        (合成モードは multiply, overlay, lighten, alpha[=不透明度])");
+    line("    --exterior-texture FILE  脱出角と連続化した反復回数を座標にして外側に画像を貼る");
+    line("    --texture-mode MODE wrap (既定値: 繰り返す) か mirror (帯ごとに反転する)");
+    line("    --projection KIND   plane (既定値) か、リーマン球面を正距円筒図法で広げる sphere");
+    line("    --mobius A/B/C/D    各点 w を (A w + B) / (C w + D) に移してから反復する (例: 0,0/1,0/1,0/0,0 で 1/w)");
+    line("    --chunk-size N      1つの帯に含める行数 (既定値: 1)");
+    line("    --min-band-height N rayon がそれ以上分割しない帯の本数 (with_min_len, 既定値: 1)");
+    line("    --backend NAME      並列化の方法 rayon|crossbeam|atomic|tokio (既定値: rayon)");
+    line("    --bands N           crossbeam で静的に分割する帯の本数 (既定値: CPU数)");
+    line("    --threads N         atomic で帯を取り合うスレッド数 (既定値: CPU数)");
+    line("    --pin-threads       ワーカースレッドを物理コアに1つずつ固定する");
+    line("    --cores LIST        固定に使うコアの番号 (例: 0-7,16)。--pin-threads を含む");
+    line("    --numa-local        帯を描画スレッドが確保したバッファに描き、最後に組み立てる");
+    line("");
+    line("Coordinator options:");
+    line("    --workers ADDR,...  ジョブを配る worker のアドレス");
+    line("    --rows-per-job N    1つのジョブで描画する行数 (既定値: 16)");
+    line("    --job-timeout SECS  worker からの応答を待つ秒数 (既定値: 600)");
+    line("");
+    line("Server options:");
+    line("    --max-connections N  同時に開いておく接続の数の上限。超えるとすぐ 503 (既定値: 256)");
+    line("    --max-concurrent N  同時に描画するリクエスト数の上限 (既定値: 4)");
+    line("    --max-queue N       描画の枠の空きを待たせるリクエスト数の上限。超えると 429 (既定値: 16)");
+    line("    --rate-limit N      クライアントの IP アドレス毎の1分あたりの描画数の上限。超えると 429");
+    line("    --timeout SECS      1リクエストの描画時間の上限 (既定値: 30)");
+    line("    --header-timeout SECS  リクエストヘッダを読み終えるまでの期限 (既定値: 10)");
+    line("    --max-size N        画像の幅と高さの上限 (既定値: 4096)");
+    line("    --max-iters N       反復回数の上限 (既定値: 100000)");
+    line("    --iteration-budget N  1リクエストで行う反復の合計の上限 (既定値: 10000000000)");
+    line("    --cache-dir DIR     描画済みの画像を保存するディレクトリ");
+    line("    --cache-size MB     キャッシュの合計サイズの上限 (既定値: 256)");
+    line("");
+    line("Environment:");
+    line("    MANDELBROT_THREADS など、オプション名を大文字にして MANDELBROT_ を付けた環境変数で既定値を変える");
+    line("                        (対応するオプションは src/config.rs、コマンドラインの指定が優先する)");
+    text
+}
+
+/// 位置引数に続けて指定する描画パラメータ
+#[derive(Clone, Debug, PartialEq)]
+struct RenderParams {
+    /// 反復回数の上限。昇順に複数与えると、前のパスで発散しなかったピクセルだけを
+    /// `z` の途中経過から再開して次の上限まで反復する
+    limits: Vec<u32>,
+    /// 反復の打ち切り条件
+    termination: Termination,
+    /// 反復する式
+    fractal: FractalKind,
+    /// 明るさの決め方
+    coloring: Coloring,
+    /// 重ねる色付け。空でなければ `coloring` の代わりに使う
+    layers: Vec<Layer>,
+    /// 発散した点に貼るテクスチャ
+    exterior_texture: Option<Arc<Texture>>,
+    texture_mode: TextureMode,
+    /// ピクセルと複素平面上の点の対応
+    projection: ProjectionKind,
+    /// 写像で得た点に掛けてから反復するメビウス変換
+    mobius: Option<Mobius>,
+    /// 並列化の粒度。描画結果には影響しない
+    scheduling: Scheduling,
+    /// この時刻を過ぎたらまだ描画していない行を諦める。コマンドラインからは指定しない
+    deadline: Option<Instant>,
+    /// 反復の合計がこれを超えたらまだ描画していない行を諦める。コマンドラインからは指定しない
+    budget: Option<Arc<IterationBudget>>,
+    /// 白から黒に割り当てる発散までの回数の範囲。無ければ 0 から上限まで。`--normalize` が粗く描いて決める
+    stretch: Option<(u32, u32)>,
+    /// 描画を諦めた行を `color::MISSING` で埋め、描いた点はそれより暗くする。`--missing-color` のとき
+    mark_missing: bool,
+    /// 内部の点の塗り方
+    interior: Interior
+}
+
+impl Default for RenderParams {
+    fn default() -> RenderParams {
+        RenderParams {
+            limits: vec![255],
+            termination: Termination::default(),
+            fractal: FractalKind::Mandelbrot,
+            coloring: Coloring::Escape,
+            layers: vec![],
+            exterior_texture: None,
+            texture_mode: TextureMode::Wrap,
+            projection: ProjectionKind::Plane,
+            mobius: None,
+            scheduling: Scheduling::default(),
+            deadline: None,
+            budget: None,
+            stretch: None,
+            mark_missing: false,
+            interior: Interior::Black
+        }
+    }
+}
+
+impl RenderParams {
+    /// 最終パスの反復回数の上限
+    fn limit(&self) -> u32 {
+        *self.limits.last().unwrap()
+    }
+
+    /// 発散までの回数 `count` の明るさ
+    fn shade(&self, count: Option<u32>) -> u8 {
+        match self.stretch {
+            Some(range) => normalize::stretched_shade(count, range),
+            None => shade(count, self.limit())
+        }
+    }
+
+    /// 発散までの回数だけでなく軌道を見て色付けするか
+    fn tracks_orbits(&self) -> bool {
+        self.coloring != Coloring::Escape || !self.layers.is_empty() || self.exterior_texture.is_some()
+            || self.interior != Interior::Black
+    }
+}
+
+/// 並列化の方法とタスクの粒度
+#[derive(Clone, Debug, PartialEq)]
+struct Scheduling {
+    backend: Backend,
+    /// 1つの帯に含める行数
+    band_height: usize,
+    /// rayon がそれ以上分割しない帯の本数 (`with_min_len`)
+    min_len: usize,
+    /// crossbeam で静的に分割する帯の本数。`None` なら CPU 数
+    bands: Option<usize>,
+    /// atomic で帯を取り合うワーカースレッドの数。`None` なら CPU 数
+    threads: Option<usize>,
+    /// ワーカースレッドをコアに固定する
+    pin_threads: bool,
+    /// 固定に使うコアの番号。空なら物理コア毎に1つずつ選ぶ
+    cores: Vec<usize>,
+    /// 帯を描画するスレッド自身が確保したバッファに描き、最後にまとめて組み立てる
+    local_buffers: bool
+}
+
+impl Default for Scheduling {
+    fn default() -> Scheduling {
+        Scheduling {
+            backend: Backend::Rayon,
+            band_height: 1,
+            min_len: 1,
+            bands: None,
+            threads: None,
+            pin_threads: false,
+            cores: vec![],
+            local_buffers: false
+        }
+    }
+}
+
+/// `--name value` 形式のオプション列を、環境変数の設定 (`config::RENDER`) の上に重ねて `RenderParams` に変換する
+fn parse_params(args: &[String]) -> Result<RenderParams, String> {
+    parse_params_exact(&config::from_environment(config::RENDER, args)?)
+}
+
+/// `parse_params` と同じだが環境変数を見ない
+fn parse_params_exact(args: &[String]) -> Result<RenderParams, String> {
+    let mut params = RenderParams::default();
+    // --fractal nova より前に書かれても効くよう、最後に Nova に設定する
+    let mut nova_degree = None;
+    let mut nova_relaxation = None;
+    let mut bailout = None;
+    let mut args = args.iter();
+
+    while let Some(name) = args.next() {
+        let mut value = || args.next()
+            .ok_or_else(|| format!("missing value for {}", name));
+        match name.as_str() {
+            "--passes" => {
+                let limits: Vec<u32> = parse_list(value()?, ',')
+                    .ok_or("--passes expects a comma separated list of integers")?;
+                if limits.is_empty() || limits[0] == 0
+                    || limits.windows(2).any(|w| w[0] >= w[1]) {
+                    return Err("--passes must be strictly increasing positive integers".to_string());
+                }
+                params.limits = limits;
+            }
+            "--interior-check" => params.termination.detect_interior = true,
+            "--bailout" => {
+                let radius = f64::from_str(value()?)
+                    .map_err(|_| "--bailout expects a number")?;
+                if !(radius > 0.0 && radius.is_finite()) {
+                    return Err("--bailout must be a positive number".to_string());
+                }
+                bailout = Some(radius);
+            }
+            "--bailout-norm" => params.termination.norm = Norm::from_str(value()?)?,
+            "--fractal" => params.fractal = FractalKind::from_str(value()?)?,
+            "--plugin" => params.fractal = FractalKind::Plugin(plugin::Plugin::open(value()?)?),
+            "--hybrid" => params.fractal = FractalKind::Hybrid(fractal::Hybrid::from_str(value()?)?),
+            "--nova-degree" => {
+                nova_degree = Some(u32::from_str(value()?).ok()
+                    .filter(|degree| (2 ..= fractal::MAX_NOVA_DEGREE).contains(degree))
+                    .ok_or_else(|| format!("--nova-degree expects an integer between 2 and {}",
+                                           fractal::MAX_NOVA_DEGREE))?);
+            }
+            "--nova-relaxation" => {
+                nova_relaxation = Some(f64::from_str(value()?).ok().filter(|r| *r != 0.0 && r.is_finite())
+                    .ok_or("--nova-relaxation expects a non-zero number")?);
+            }
+            "--coloring" => params.coloring = Coloring::from_str(value()?)?,
+            "--interior" => params.interior = Interior::from_str(value()?)?,
+            "--layers" => params.layers = layers::parse_layers(value()?)?,
+            "--exterior-texture" => params.exterior_texture = Some(Arc::new(Texture::open(value()?)?)),
+            "--texture-mode" => params.texture_mode = TextureMode::from_str(value()?)?,
+            "--projection" => params.projection = ProjectionKind::from_str(value()?)?,
+            "--mobius" => params.mobius = Some(Mobius::from_str(value()?)?),
+            "--chunk-size" => {
+                params.scheduling.band_height = usize::from_str(value()?)
+                    .ok().filter(|&rows| rows > 0)
+                    .ok_or("--chunk-size expects a positive integer")?;
+            }
+            "--min-band-height" => {
+                params.scheduling.min_len = usize::from_str(value()?)
+                    .ok().filter(|&bands| bands > 0)
+                    .ok_or("--min-band-height expects a positive integer")?;
+            }
+            "--backend" => params.scheduling.backend = Backend::from_str(value()?)?,
+            "--bands" => {
+                params.scheduling.bands = Some(usize::from_str(value()?)
+                    .ok().filter(|&bands| bands > 0)
+                    .ok_or("--bands expects a positive integer")?);
+            }
+            "--threads" => {
+                params.scheduling.threads = Some(usize::from_str(value()?)
+                    .ok().filter(|&threads| threads > 0)
+                    .ok_or("--threads expects a positive integer")?);
+            }
+            "--pin-threads" => params.scheduling.pin_threads = true,
+            "--numa-local" => params.scheduling.local_buffers = true,
+            "--cores" => {
+                params.scheduling.cores = parse_ranges(value()?)
+                    .ok_or("--cores expects a list of core numbers such as 0-3,8")?;
+                params.scheduling.pin_threads = true;
+            }
+            _ => return Err(format!("unknown option {}", name))
+        }
+    }
+
+    if nova_degree.is_some() || nova_relaxation.is_some() {
+        match params.fractal {
+            FractalKind::Nova(ref mut nova) => {
+                nova.degree = nova_degree.unwrap_or(nova.degree);
+                nova.relaxation = nova_relaxation.unwrap_or(nova.relaxation);
+                nova.check()?;
+            }
+            _ => return Err("--nova-degree and --nova-relaxation need --fractal nova".to_string())
+        }
+    }
+    params.termination.radius = bailout.unwrap_or(params.fractal.default_bailout());
+    if params.interior == Interior::Distance && params.fractal != FractalKind::Mandelbrot {
+        return Err("--interior de needs --fractal mandelbrot".to_string());
+    }
+
+    Ok(params)
+}
+
+#[test]
+fn test_parse_params() {
+    let args = |s: &str| -> Vec<String> {
+        s.split_whitespace().map(String::from).collect()
+    };
+    assert_eq!(parse_params(&args("")), Ok(RenderParams::default()));
+    assert_eq!(parse_params(&args("--passes 256,1024,4096")).map(|p| p.limits),
+               Ok(vec![256, 1024, 4096]));
+    assert!(parse_params(&args("--passes")).is_err());
+    assert!(parse_params(&args("--passes 1024,256")).is_err());
+    assert!(parse_params(&args("--passes 0,256")).is_err());
+    assert!(parse_params(&args("--interior-check")).unwrap().termination.detect_interior);
+    assert_eq!(parse_params(&args("--fractal buffalo")).map(|p| p.fractal), Ok(FractalKind::Buffalo));
+    assert!(parse_params(&args("--fractal julia")).is_err());
+    assert!(parse_params(&args("--hybrid MMB")).is_ok_and(|p| matches!(p.fractal, FractalKind::Hybrid(_))));
+    assert!(parse_params(&args("--hybrid MZ")).is_err());
+    assert!(parse_params(&args("--plugin /nonexistent.so")).is_err());
+    assert_eq!(parse_params(&args("--nova-relaxation 0.5 --fractal nova --nova-degree 4")).map(|p| p.fractal),
+               Ok(FractalKind::Nova(fractal::Nova { degree: 4, relaxation: 0.5 })));
+    assert!(parse_params(&args("--nova-degree 4")).is_err());
+    assert_eq!(parse_params(&args("--fractal collatz")).map(|p| p.termination.radius), Ok(1e3));
+    assert_eq!(parse_params(&args("--bailout 8 --fractal collatz")).map(|p| p.termination.radius), Ok(8.0));
+    assert!(parse_params(&args("--fractal nova --nova-degree 1")).is_err());
+    assert!(parse_params(&args("--fractal nova --nova-degree 64")).is_ok());
+    assert!(parse_params(&args("--fractal nova --nova-degree 65")).is_err());
+    assert!(parse_params(&args("--fractal nova --nova-degree 4000000000")).is_err());
+    assert_eq!(parse_params(&args("--bailout 10 --bailout-norm manhattan"))
+                   .map(|p| (p.termination.radius, p.termination.norm)),
+               Ok((10.0, Norm::Manhattan)));
+    assert_eq!(parse_params(&args("--chunk-size 8 --min-band-height 4")).map(|p| p.scheduling),
+               Ok(Scheduling { band_height: 8, min_len: 4, ..Scheduling::default() }));
+    assert_eq!(parse_params(&args("--backend crossbeam --bands 3")).map(|p| p.scheduling),
+               Ok(Scheduling { backend: Backend::Crossbeam, bands: Some(3), ..Scheduling::default() }));
+    assert_eq!(parse_params(&args("--backend atomic --threads 2")).map(|p| p.scheduling),
+               Ok(Scheduling { backend: Backend::Atomic, threads: Some(2), ..Scheduling::default() }));
+    assert_eq!(parse_params(&args("--cores 0-2,6")).map(|p| p.scheduling),
+               Ok(Scheduling { pin_threads: true, cores: vec![0, 1, 2, 6], ..Scheduling::default() }));
+    assert!(parse_params(&args("--backend openmp")).is_err());
+    assert!(parse_params(&args("--chunk-size 0")).is_err());
+    assert_eq!(parse_params(&args("--projection sphere")).map(|p| p.projection),
+               Ok(ProjectionKind::Sphere));
+    assert!(parse_params(&args("--projection globe")).is_err());
+    assert_eq!(parse_params(&args("--interior de")).map(|p| p.interior), Ok(Interior::Distance));
+    assert!(parse_params(&args("--interior de --fractal celtic")).is_err());
+    assert_eq!(parse_params(&args("--coloring stalks")).map(|p| p.coloring), Ok(Coloring::Stalks));
+    assert!(parse_params(&args("--layers escape,stalks:multiply")).is_ok_and(|p| p.tracks_orbits()));
+    assert_eq!(parse_params(&args("--texture-mode mirror")).map(|p| p.texture_mode), Ok(TextureMode::Mirror));
+    assert!(parse_params(&args("--exterior-texture /nonexistent.png")).is_err());
+    assert!(parse_params(&args("--mobius 0,0/1,0/1,0/0,0")).is_ok_and(|p| p.mobius.is_some()));
+    assert!(parse_params(&args("--bailout -1")).is_err());
+    assert!(parse_params(&args("--bailout-norm taxicab")).is_err());
+    assert!(parse_params(&args("--unknown 1")).is_err());
+}
+
+/// 反復の打ち切り条件
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Termination {
+    /// `norm` で測った `z` の大きさがこれを超えたら発散したとみなす
+    radius: f64,
+    norm: Norm,
+    /// 軌道の `z` に関する微分が十分小さくなったら、吸引サイクルに捕まった内部の点とみなして打ち切る
+    detect_interior: bool
+}
+
+impl Default for Termination {
+    fn default() -> Termination {
+        Termination { radius: 2.0, norm: Norm::Euclidean, detect_interior: false }
+    }
+}
+
+impl Termination {
+    /// `z` が脱出半径の外に出たかを判定する
+    fn escaped(&self, z: Complex<f64>) -> bool {
+        match self.norm {
+            Norm::Euclidean => z.norm_sqr() > self.radius * self.radius,
+            Norm::Real => z.re.abs() > self.radius,
+            Norm::Imag => z.im.abs() > self.radius,
+            Norm::Manhattan => z.re.abs() + z.im.abs() > self.radius
+        }
+    }
+}
+
+/// 脱出判定に使うノルム
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Norm {
+    Euclidean,
+    Real,
+    Imag,
+    Manhattan
+}
+
+impl FromStr for Norm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Norm, String> {
+        match s {
+            "euclidean" => Ok(Norm::Euclidean),
+            "real" => Ok(Norm::Real),
+            "imag" => Ok(Norm::Imag),
+            "manhattan" => Ok(Norm::Manhattan),
+            _ => Err(format!("unknown bailout norm {}", s))
+        }
+    }
+}
+
+#[test]
+fn test_termination_escaped() {
+    let z = Complex { re: 1.5, im: -1.5 };
+    let termination = |norm| Termination { norm, ..Termination::default() };
+    assert!(termination(Norm::Euclidean).escaped(z));
+    assert!(!termination(Norm::Real).escaped(z));
+    assert!(!termination(Norm::Imag).escaped(z));
+    assert!(termination(Norm::Manhattan).escaped(z));
+    assert!(!Termination { radius: 3.0, ..Termination::default() }.escaped(z));
+}
+
+/// 微分の大きさの2乗がこれを下回ったら吸引サイクルに収束したとみなす
+const INTERIOR_EPSILON: f64 = 1e-20;
+
+/// 点 `c` の軌道の途中経過。上限を引き上げた次のパスで続きから反復できるよう `z` を保持する
+#[derive(Clone, Copy, Debug)]
+struct Orbit {
+    c: Complex<f64>,
+    z: Complex<f64>,
+    /// `z_1` を起点とした軌道の `z` に関する微分
+    derivative: Complex<f64>,
+    iteration: u32,
+    /// 内部の点か、不動点に収束したと判定済みならそれ以上反復しない
+    interior: bool,
+    /// 色付けのために集める軌道の統計。`Orbit::tracked` で作ったときだけ集める
+    stats: Option<OrbitStats>
+}
+
+impl Orbit {
+    fn new(c: Complex<f64>) -> Orbit {
+        Orbit {
+            c,
+            z: Complex { re: 0.0, im: 0.0 },
+            derivative: Complex { re: 1.0, im: 0.0 },
+            iteration: 0,
+            interior: false,
+            stats: None
+        }
+    }
+
+    /// 反復しながら `OrbitStats` を集める軌道
+    fn tracked(c: Complex<f64>) -> Orbit {
+        Orbit { stats: Some(OrbitStats::default()), ..Orbit::new(c) }
+    }
+
+    /// 反復回数が `limit` に達するまで `fractal` の式で軌道を進め、発散したら `Some(i)` を返す。
+    /// 収束までの回数で色付けする式では、収束したときにも `Some(i)` を返す
+    fn advance(&mut self, limit: u32, fractal: FractalKind, termination: &Termination) -> Option<u32> {
+        // 統計を集めない軌道では observe が空の関数に展開され、反復のループに分岐が残らない
+        match self.stats.take() {
+            None => self.advance_fractal(limit, fractal, termination, |_, _| {}),
+            Some(mut stats) => {
+                let count = self.advance_fractal(limit, fractal, termination,
+                                                 |z, n| stats.observe(z, n));
+                self.stats = Some(stats);
+                count
+            }
+        }
+    }
+
+    /// 式ごとに単相化した `advance_observing` を選ぶ
+    fn advance_fractal<O>(&mut self, limit: u32, fractal: FractalKind, termination: &Termination,
+                          observe: O)
+        -> Option<u32>
+        where O: FnMut(Complex<f64>, u32)
+    {
+        match fractal {
+            FractalKind::Mandelbrot =>
+                self.advance_observing(&fractal::Mandelbrot, limit, termination, observe),
+            FractalKind::BurningShip =>
+                self.advance_observing(&fractal::BurningShip, limit, termination, observe),
+            FractalKind::Celtic =>
+                self.advance_observing(&fractal::Celtic, limit, termination, observe),
+            FractalKind::Buffalo =>
+                self.advance_observing(&fractal::Buffalo, limit, termination, observe),
+            FractalKind::PerpendicularBurningShip =>
+                self.advance_observing(&fractal::PerpendicularBurningShip, limit, termination, observe),
+            FractalKind::PerpendicularMandelbrot =>
+                self.advance_observing(&fractal::PerpendicularMandelbrot, limit, termination, observe),
+            FractalKind::MagnetI =>
+                self.advance_observing(&fractal::MagnetI, limit, termination, observe),
+            FractalKind::MagnetII =>
+                self.advance_observing(&fractal::MagnetII, limit, termination, observe),
+            FractalKind::Nova(nova) => self.advance_observing(&nova, limit, termination, observe),
+            FractalKind::Collatz =>
+                self.advance_observing(&fractal::Collatz, limit, termination, observe),
+            FractalKind::Hybrid(hybrid) => self.advance_observing(&hybrid, limit, termination, observe),
+            FractalKind::Plugin(plugin) => self.advance_observing(&plugin, limit, termination, observe)
+        }
+    }
+
+    /// `advance` の本体。反復で得た `z_n` を1つずつ `n` と一緒に `observe` に渡す。
+    /// 式と `observe` の組ごとに単相化したループを呼び出し側に展開すると遅くなるので、関数のまま呼ぶ
+    #[inline(never)]
+    fn advance_observing<F, O>(&mut self, fractal: &F, limit: u32, termination: &Termination,
+                               mut observe: O)
+        -> Option<u32>
+        where F: Fractal, O: FnMut(Complex<f64>, u32)
+    {
+        if self.iteration == 0 {
+            self.z = fractal.start(self.c);
+        }
+        while self.iteration < limit && !self.interior {
+            let i = self.iteration;
+            // z_0 = 0 は臨界点なので、微分は z_1 から積み上げる
+            if F::ANALYTIC && termination.detect_interior && i > 0 {
+                self.derivative = self.derivative * self.z * 2.0;
+                if self.derivative.norm_sqr() < INTERIOR_EPSILON {
+                    self.interior = true;
+                    break;
+                }
+            }
+            let previous = self.z;
+            self.z = fractal.step_at(i, self.z, self.c);
+            self.iteration += 1;
+            observe(self.z, self.iteration);
+            if termination.escaped(self.z) {
+                return Some(i);
+            }
+            // 不動点に収束した軌道はもう発散しないので、内部の点として打ち切る
+            if F::CONVERGES && fractal.converged(previous, self.z) {
+                if F::COLORS_CONVERGENCE {
+                    return Some(i);
+                }
+                self.interior = true;
+                break;
+            }
+        }
+
+        None
+    }
+}
+
+#[test]
+fn test_orbit_resume() {
+    let c = Complex { re: -0.75, im: 0.05 };
+    let mut orbit = Orbit::new(c);
+    assert_eq!(orbit.advance(16, FractalKind::Mandelbrot, &Termination::default()), None);
+    assert_eq!(orbit.advance(1000, FractalKind::Mandelbrot, &Termination::default()), escape_time(c, 1000));
+    assert!(orbit.iteration < 1000);
+}
+
+#[test]
+fn test_orbit_detect_interior() {
+    let termination = Termination { detect_interior: true, ..Termination::default() };
+
+    // 主カージオイドと周期2の円板の内部の点は上限よりずっと早く打ち切られる
+    for &c in &[Complex { re: -0.1, im: 0.1 }, Complex { re: -1.0, im: 0.05 }] {
+        let mut orbit = Orbit::new(c);
+        assert_eq!(orbit.advance(1_000_000, FractalKind::Mandelbrot, &termination), None);
+        assert!(orbit.interior);
+        assert!(orbit.iteration < 1000);
+    }
+
+    // 外部の点の発散回数は変わらない
+    let c = Complex { re: -0.75, im: 0.05 };
+    assert_eq!(Orbit::new(c).advance(1000, FractalKind::Mandelbrot, &termination), escape_time(c, 1000));
+}
+
+/// 中心 `center` と倍率 `zoom` で指定した範囲を、`bounds` の縦横比に合わせた左上と右下の点に変換する。
+/// 倍率1のとき実軸方向の幅が4になる。
+fn region_from_center(center: Complex<f64>, zoom: f64, bounds: (usize, usize))
+    -> (Complex<f64>, Complex<f64>)
+{
+    let width = 4.0 / zoom;
+    let height = width * bounds.1 as f64 / bounds.0 as f64;
+    (Complex { re: center.re - width / 2.0, im: center.im + height / 2.0 },
+     Complex { re: center.re + width / 2.0, im: center.im - height / 2.0 })
+}
+
+#[test]
+fn test_region_from_center() {
+    assert_eq!(region_from_center(Complex { re: -0.5, im: 0.0 }, 1.0, (400, 200)),
+               (Complex { re: -2.5, im: 1.0 }, Complex { re: 1.5, im: -1.0 }));
+    assert_eq!(region_from_center(Complex { re: 0.0, im: 0.0 }, 4.0, (100, 100)),
+               (Complex { re: -0.5, im: 0.5 }, Complex { re: 0.5, im: -0.5 }));
 }
 
 /// 出力される画像のピクセル位置を取り、対応する複素平面上の点を返す。
@@ -126,12 +1591,7 @@ fn pixel_to_point(bounds: (usize, usize),
                   lower_right: Complex<f64>)
     -> Complex<f64>
 {
-    let (width, height) = (lower_right.re - upper_left.re,
-                           upper_left.im - lower_right.im);
-    Complex {
-        re: upper_left.re + pixel.0 as f64 * width / bounds.0 as f64,
-        im: upper_left.im - pixel.1 as f64 * height / bounds.1 as f64
-    }
+    Region::new(bounds, upper_left, lower_right).pixel_to_point(pixel)
 }
 
 #[test]
@@ -142,29 +1602,356 @@ fn test_pixel_to_point() {
                Complex { re: -0.5, im: -0.5 });
 }
 
+#[test]
+fn test_render_matches_library() {
+    // ライブラリの render_gray は既定の描画と同じ明るさになる
+    let (bounds, upper_left, lower_right) = ((40, 30), Complex { re: -2.2, im: 1.2 }, Complex { re: 0.8, im: -1.2 });
+    let mut expected = vec![0; bounds.0 * bounds.1];
+    render(&mut expected, bounds, upper_left, lower_right, &RenderParams::default());
+    let mut pixels = vec![0; bounds.0 * bounds.1];
+    mandelbrot::render_gray(&mut pixels, bounds.0, &Region::new(bounds, upper_left, lower_right),
+                            RenderParams::default().limit()).unwrap();
+    assert_eq!(pixels, expected);
+}
+
 /// 矩形範囲のマンデルプロ集合をピクセルのバッファに描画する。
 /// 仮引数 `bounds` はバッファ `pixels` のグレースケールの値をバイトで保持する。
 /// `upper_left` と `lower_right`
 /// はピクセルバッファの左上と右下に対応する複素平面上の点を指定する。
+/// 実際に行った反復の回数を返す。
 fn render(pixels: &mut [u8],
           bounds: (usize, usize),
           upper_left: Complex<f64>,
-          lower_right: Complex<f64>)
+          lower_right: Complex<f64>,
+          params: &RenderParams)
+    -> u64
 {
     assert!(pixels.len() == bounds.0 * bounds.1);
 
-    for row in 0 .. bounds.1 {
-        for column in 0 .. bounds.0 {
-            let point = pixel_to_point(bounds, (column, row),
-                                       upper_left, lower_right);
-            pixels[row * bounds.0 + column] = match escape_time(point, 255) {
-                None => 0,
-                Some(count) => 255 - count as u8
+    if params.tracks_orbits() {
+        let projection = projection::Plane { upper_left, lower_right };
+        return render_projected(pixels, bounds, 0, &projection, params);
+    }
+    let (counts, iterations) = escape_counts(bounds, upper_left, lower_right, params);
+    for (pixel, count) in pixels.iter_mut().zip(counts) {
+        *pixel = params.shade(count);
+    }
+    iterations
+}
+
+/// `render` と同じだが、大きさ `bounds` の画像の `top` 行目から始まる帯 `pixels` を
+/// `projection` で対応させた点で描画する
+fn render_projected(pixels: &mut [u8],
+                    bounds: (usize, usize),
+                    top: usize,
+                    projection: &dyn Projection,
+                    params: &RenderParams)
+    -> u64
+{
+    let rows = pixels.len() / bounds.0;
+    let points = (top .. top + rows).flat_map(|row| {
+        (0 .. bounds.0).map(move |column| projection.point(bounds, (column, row)))
+    });
+    if params.tracks_orbits() {
+        let mut iterations = 0;
+        for (index, (pixel, point)) in pixels.iter_mut().zip(points).enumerate() {
+            // ピクセルの大きさは写像によって場所毎に違うので、隣のピクセルの点までの距離で測る
+            let pixel_size = if params.interior == Interior::Distance {
+                let (column, row) = (index % bounds.0, top + index / bounds.0);
+                (projection.point(bounds, (column + 1, row)) - point).norm()
+            } else {
+                0.0
             };
+            let (value, executed) = coloring::color_for_params(point, params, pixel_size);
+            *pixel = value;
+            iterations += executed;
+        }
+        return iterations;
+    }
+    let (counts, iterations) = count_escapes(points, pixels.len(), params);
+    for (pixel, count) in pixels.iter_mut().zip(counts) {
+        *pixel = params.shade(count);
+    }
+    iterations
+}
+
+#[test]
+fn test_render_projected() {
+    let bounds = (40, 30);
+    let upper_left = Complex { re: -1.20, im: 0.35 };
+    let lower_right = Complex { re: -1.0, im: 0.20 };
+    let params = RenderParams::default();
+    let mut expected = vec![0; bounds.0 * bounds.1];
+    render(&mut expected, bounds, upper_left, lower_right, &params);
+
+    // 線形の写像で帯に分けて描いても、画像全体を一度に描いたのと同じになる
+    let plane = projection::projection(ProjectionKind::Plane, upper_left, lower_right);
+    let mut pixels = vec![0; bounds.0 * bounds.1];
+    for (i, band) in pixels.chunks_mut(bounds.0 * 7).enumerate() {
+        render_projected(band, bounds, i * 7, &*plane, &params);
+    }
+    assert_eq!(pixels, expected);
+}
+
+/// 大きさ `bounds` の画像を `normalize::PROBE_PIXELS` 以下のピクセルに粗くした各点
+fn probe_points(bounds: (usize, usize), upper_left: Complex<f64>, lower_right: Complex<f64>, params: &RenderParams)
+    -> Vec<Complex<f64>>
+{
+    let scale = (normalize::PROBE_PIXELS as f64 / (bounds.0 * bounds.1) as f64).sqrt().min(1.0);
+    let probe = (((bounds.0 as f64 * scale) as usize).max(1), ((bounds.1 as f64 * scale) as usize).max(1));
+    let projection = projection::for_params(params, upper_left, lower_right);
+    (0 .. probe.1).flat_map(|row| {
+        let projection = &projection;
+        (0 .. probe.0).map(move |column| projection.point(probe, (column, row)))
+    }).collect()
+}
+
+/// `--normalize` で分布を取るため、粗くした画像の各点の発散までの回数
+fn probe_counts(bounds: (usize, usize), upper_left: Complex<f64>, lower_right: Complex<f64>, params: &RenderParams)
+    -> Vec<Option<u32>>
+{
+    let points = probe_points(bounds, upper_left, lower_right, params);
+    count_escapes(points.iter().copied(), points.len(), params).0
+}
+
+/// `--pass-stop` の既定値
+const DEFAULT_PASS_STOP: f64 = 0.01;
+
+/// `params.limits` のパスのうち実際に使うもの。粗くした画像でパスを順に進め、まだ発散していない点のうち
+/// `stop` の割合以上が発散したパスの後で、`stop` の割合より少なくしか発散しなかったパスで打ち切る。
+/// 残りの点はそれ以上上限を上げてもほとんど発散しない内部の点なので、最後のパスの上限まで反復しても無駄になる。
+/// 深く拡大した範囲では最初のパスでまだ何も発散しないことがあるので、発散し始める前には打ち切らない
+///
+/// 画像全体で一度だけ決めるので、どう帯に分けて描いても同じ上限になる
+fn adaptive_limits(bounds: (usize, usize),
+                   upper_left: Complex<f64>,
+                   lower_right: Complex<f64>,
+                   params: &RenderParams,
+                   stop: f64)
+    -> Vec<u32>
+{
+    if params.limits.len() < 2 || stop <= 0.0 {
+        return params.limits.clone();
+    }
+    let mut pending: Vec<Orbit> = probe_points(bounds, upper_left, lower_right, params).into_iter().map(Orbit::new)
+        .collect();
+    let mut escaping = false;
+    for (pass, &limit) in params.limits.iter().enumerate() {
+        let before = pending.len();
+        let mut escaped = 0;
+        pending.retain_mut(|orbit| {
+            let count = orbit.advance(limit, params.fractal, &params.termination);
+            escaped += count.is_some() as usize;
+            count.is_none() && !orbit.interior
+        });
+        let few = (escaped as f64) < stop * before as f64;
+        if pending.is_empty() || (escaping && few) {
+            return params.limits[..= pass].to_vec();
+        }
+        escaping |= !few;
+    }
+    params.limits.clone()
+}
+
+#[test]
+fn test_adaptive_limits() {
+    let params = RenderParams { limits: vec![64, 256, 1024, 4096], ..RenderParams::default() };
+    // 全体像では 256 回までに発散しなかった点の 1% も 1024 回までに発散しないので、その先のパスは使わない
+    let (upper_left, lower_right) = (Complex { re: -2.5, im: 1.25 }, Complex { re: 1.0, im: -1.25 });
+    assert_eq!(adaptive_limits((160, 120), upper_left, lower_right, &params, 0.01), vec![64, 256, 1024]);
+    // 割合を 0 にすると全てのパスを使い、上限が1つなら何もしない
+    assert_eq!(adaptive_limits((160, 120), upper_left, lower_right, &params, 0.0), params.limits);
+    let single = RenderParams { limits: vec![256], ..RenderParams::default() };
+    assert_eq!(adaptive_limits((160, 120), upper_left, lower_right, &single, 0.5), vec![256]);
+    // 発散し切るまで深い上限が要る縁の近くでは最後のパスまで使う
+    let (upper_left, lower_right) = (Complex { re: -0.74364, im: 0.13187 }, Complex { re: -0.74362, im: 0.131855 });
+    assert_eq!(adaptive_limits((160, 120), upper_left, lower_right, &params, 0.01), params.limits);
+}
+
+#[test]
+fn test_probe_counts() {
+    let params = RenderParams { limits: vec![4096], ..RenderParams::default() };
+    let (upper_left, lower_right) = (Complex { re: -0.7437, im: 0.1320 }, Complex { re: -0.7435, im: 0.1318 });
+    let counts = probe_counts((1000, 1000), upper_left, lower_right, &params);
+    assert_eq!(counts.len(), normalize::PROBE_PIXELS);
+    // 深いところは上限の一部の回数にしか集まらないので、引き伸ばすと使う段が増える
+    let range = normalize::Percentile { low: 1.0, high: 99.0 }.range(&counts).unwrap();
+    let stretched = RenderParams { stretch: Some(range), ..params.clone() };
+    let levels = |params: &RenderParams| {
+        counts.iter().map(|&count| params.shade(count)).collect::<std::collections::BTreeSet<u8>>().len()
+    };
+    assert!(levels(&stretched) > 2 * levels(&params));
+    assert_eq!(probe_counts((3, 2), upper_left, lower_right, &params).len(), 6);
+}
+
+/// 各ピクセルの発散までの反復回数と、全てのピクセルで実際に行った反復の合計を求める。
+/// `params.limits` を複数与えた場合は、前のパスで発散しなかったピクセルだけを次の上限まで反復する。
+fn escape_counts(bounds: (usize, usize),
+                 upper_left: Complex<f64>,
+                 lower_right: Complex<f64>,
+                 params: &RenderParams)
+    -> (Vec<Option<u32>>, u64)
+{
+    count_escapes(image_points(bounds, upper_left, lower_right), bounds.0 * bounds.1, params)
+}
+
+/// `len` 個の点 `points` それぞれの発散までの反復回数と、行った反復の合計を求める
+fn count_escapes<I>(points: I, len: usize, params: &RenderParams) -> (Vec<Option<u32>>, u64)
+    where I: Iterator<Item = Complex<f64>>
+{
+    let termination = &params.termination;
+    let mut iterations = 0;
+    if let [limit] = *params.limits {
+        let counts = points.map(|point| {
+            let mut orbit = Orbit::new(point);
+            let count = orbit.advance(limit, params.fractal, termination);
+            iterations += orbit.iteration as u64;
+            count
+        }).collect();
+        return (counts, iterations);
+    }
+
+    let mut counts = vec![None; len];
+    let mut pending: Vec<(usize, Orbit)> = points.map(Orbit::new).enumerate().collect();
+    for &limit in &params.limits {
+        pending.retain_mut(|(index, orbit)| {
+            let count = orbit.advance(limit, params.fractal, termination);
+            let finished = count.is_some() || orbit.interior;
+            if finished {
+                counts[*index] = count;
+                iterations += orbit.iteration as u64;
+            }
+            !finished
+        });
+    }
+    iterations += pending.iter().map(|(_, orbit)| orbit.iteration as u64).sum::<u64>();
+
+    (counts, iterations)
+}
+
+#[test]
+fn test_shade() {
+    assert_eq!(shade(None, 255), 0);
+    assert_eq!(shade(Some(0), 255), 255);
+    assert_eq!(shade(Some(10), 255), 245);
+    assert_eq!(shade(Some(512), 1024), 128);
+}
+
+/// 大きさ `bounds` の画像の各ピクセルの左上の角に対応する点を、行の順に並べる
+///
+/// ピクセル毎に `pixel_to_point` で線形補間をやり直さず、実軸方向の増分と行毎の虚部を先に求めておく。
+/// 割り算の順序が違うので、`pixel_to_point` とは最後の桁が違うことがある。
+fn image_points(bounds: (usize, usize),
+                upper_left: Complex<f64>,
+                lower_right: Complex<f64>)
+    -> impl Iterator<Item = Complex<f64>>
+{
+    let re_step = (lower_right.re - upper_left.re) / bounds.0 as f64;
+    let im_step = (upper_left.im - lower_right.im) / bounds.1 as f64;
+    (0 .. bounds.1).flat_map(move |row| {
+        let im = upper_left.im - row as f64 * im_step;
+        (0 .. bounds.0).map(move |column| {
+            Complex { re: upper_left.re + column as f64 * re_step, im }
+        })
+    })
+}
+
+#[test]
+fn test_image_points_match_pixel_to_point() {
+    // 増分で求めた点は pixel_to_point と丸めの誤差の範囲で一致する
+    for (bounds, upper_left, lower_right) in [
+        ((40, 30), Complex { re: -1.20, im: 0.35 }, Complex { re: -1.0, im: 0.20 }),
+        ((1000, 750), Complex { re: -2.5, im: 1.5 }, Complex { re: 1.5, im: -1.5 }),
+        ((64, 48), Complex { re: -0.7436448, im: 0.1318260 }, Complex { re: -0.7436437, im: 0.1318252 })
+    ] {
+        let points: Vec<Complex<f64>> = image_points(bounds, upper_left, lower_right).collect();
+        assert_eq!(points.len(), bounds.0 * bounds.1);
+        let scale = upper_left.norm().max(lower_right.norm());
+        for row in 0 .. bounds.1 {
+            for column in 0 .. bounds.0 {
+                let expected = pixel_to_point(bounds, (column, row), upper_left, lower_right);
+                let point = points[row * bounds.0 + column];
+                assert!((point - expected).norm() <= 4.0 * f64::EPSILON * scale,
+                        "{:?} {:?} {:?}", (column, row), point, expected);
+            }
         }
     }
 }
 
+#[test]
+fn test_render_parallel_scheduling() {
+    let bounds = (64, 48);
+    let upper_left = Complex { re: -1.20, im: 0.35 };
+    let lower_right = Complex { re: -1.0, im: 0.20 };
+    let render_with = |scheduling| {
+        let mut pixels = vec![0; bounds.0 * bounds.1];
+        let params = RenderParams { scheduling, ..RenderParams::default() };
+        assert!(render_parallel(&mut pixels, bounds, upper_left, lower_right, &params));
+        pixels
+    };
+    let expected = render_with(Scheduling::default());
+    assert_eq!(render_with(Scheduling { min_len: 8, ..Scheduling::default() }), expected);
+    assert_eq!(render_with(Scheduling { band_height: 5, ..Scheduling::default() }), expected);
+    for &bands in &[1, 3, 7, 100] {
+        assert_eq!(render_with(Scheduling {
+            backend: Backend::Crossbeam,
+            bands: Some(bands),
+            ..Scheduling::default()
+        }), expected);
+        assert_eq!(render_with(Scheduling {
+            backend: Backend::Atomic,
+            threads: Some(bands),
+            band_height: 3,
+            ..Scheduling::default()
+        }), expected);
+        #[cfg(feature = "async")]
+        assert_eq!(render_with(Scheduling {
+            backend: Backend::Tokio,
+            threads: Some(bands),
+            band_height: 2,
+            ..Scheduling::default()
+        }), expected);
+    }
+    for &backend in Backend::ALL {
+        assert_eq!(render_with(Scheduling {
+            backend,
+            band_height: 3,
+            local_buffers: true,
+            ..Scheduling::default()
+        }), expected);
+    }
+}
+
+#[test]
+fn test_escape_counts_multi_pass() {
+    let bounds = (40, 30);
+    let upper_left = Complex { re: -1.20, im: 0.35 };
+    let lower_right = Complex { re: -1.0, im: 0.20 };
+    let params = |limits: Vec<u32>| RenderParams { limits, ..RenderParams::default() };
+    assert_eq!(escape_counts(bounds, upper_left, lower_right, &params(vec![8, 64, 1024])),
+               escape_counts(bounds, upper_left, lower_right, &params(vec![1024])));
+}
+
+#[test]
+fn test_escape_counts_iterations() {
+    let bounds = (40, 30);
+    let upper_left = Complex { re: -1.20, im: 0.35 };
+    let lower_right = Complex { re: -1.0, im: 0.20 };
+    let (counts, iterations) = escape_counts(bounds, upper_left, lower_right,
+                                             &RenderParams::default());
+    // i 回目で発散した点は i + 1 回、発散しなかった点は上限まで反復している
+    let expected: u64 = counts.iter().map(|count| count.map_or(255, |i| i as u64 + 1)).sum();
+    assert_eq!(iterations, expected);
+
+    // 内部の検出が効けば反復の合計は減るが、発散までの回数は変わらない
+    let mut params = RenderParams { limits: vec![100_000], ..RenderParams::default() };
+    let (counts, iterations) = escape_counts(bounds, upper_left, lower_right, &params);
+    params.termination.detect_interior = true;
+    let (detected, fewer) = escape_counts(bounds, upper_left, lower_right, &params);
+    assert_eq!(detected, counts);
+    assert!(fewer < iterations);
+}
+
 /// 大きさが `bounds` で指定されたバッファ `pixels` を `filename` で指定されたファイルに書き出す。
 fn write_image(filename: &str, pixels: &[u8], bounds: (usize, usize))
     -> Result<(), std::io::Error>
@@ -177,8 +1964,21 @@ fn write_image(filename: &str, pixels: &[u8], bounds: (usize, usize))
     // 注) ?演算子はResultを返す関数の中で使う。 main関数には返り値が無いのでmain関数の中では使えない。
     let output = File::create(filename)?;
 
+    encode_png(output, pixels, bounds)
+}
+
+/// これ以上のピクセル数の画像は `encode::encode_parallel` でストリップ毎に並列に圧縮する
+const PARALLEL_ENCODE_PIXELS: usize = 4_000_000;
+
+/// バッファ `pixels` を PNG として `output` に書き出す
+fn encode_png<W: Write>(output: W, pixels: &[u8], bounds: (usize, usize))
+    -> Result<(), std::io::Error>
+{
+    if pixels.len() >= PARALLEL_ENCODE_PIXELS {
+        return encode::encode_parallel(output, pixels, bounds);
+    }
     let encoder = PNGEncoder::new(output);
-        encoder.encode(&pixels,
+        encoder.encode(pixels,
                        bounds.0 as u32, bounds.1 as u32,
                        ColorType::Gray(8))?;
 
@@ -0,0 +1,163 @@
+//! `serve-api` の `/metrics` で公開する Prometheus 形式の計測値
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// 描画時間のヒストグラムのバケットの上限 (秒)
+const DURATION_BUCKETS: [f64; 10] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0, 30.0];
+
+/// 上限値ごとの累積ではなくバケット毎の件数を持つヒストグラム。出力時に累積する
+#[derive(Debug, Default)]
+struct Histogram {
+    counts: [u64; DURATION_BUCKETS.len()],
+    count: u64,
+    sum: f64
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        if let Some(bucket) = DURATION_BUCKETS.iter().position(|&bound| value <= bound) {
+            self.counts[bucket] += 1;
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    renders: u64,
+    render_timeouts: u64,
+    render_budget_exceeded: u64,
+    cache_hits: u64,
+    cache_misses: u64,
+    responses: BTreeMap<u16, u64>,
+    render_duration: Histogram
+}
+
+/// サーバ全体で共有する計測値
+#[derive(Debug, Default)]
+pub struct Metrics {
+    counters: Mutex<Counters>
+}
+
+impl Metrics {
+    /// 描画を1回終えたことを記録する
+    pub fn observe_render(&self, duration: Duration) {
+        let mut counters = self.counters.lock().unwrap();
+        counters.renders += 1;
+        counters.render_duration.observe(duration.as_secs_f64());
+    }
+
+    /// 描画が時間切れで打ち切られたことを記録する
+    pub fn count_timeout(&self) {
+        self.counters.lock().unwrap().render_timeouts += 1;
+    }
+
+    /// 描画が反復の上限を使い切って打ち切られたことを記録する
+    pub fn count_budget_exceeded(&self) {
+        self.counters.lock().unwrap().render_budget_exceeded += 1;
+    }
+
+    /// キャッシュを引いた結果を記録する
+    pub fn count_cache(&self, hit: bool) {
+        let mut counters = self.counters.lock().unwrap();
+        if hit {
+            counters.cache_hits += 1;
+        } else {
+            counters.cache_misses += 1;
+        }
+    }
+
+    /// 返したレスポンスのステータスを記録する
+    pub fn count_response(&self, status: u16) {
+        *self.counters.lock().unwrap().responses.entry(status).or_insert(0) += 1;
+    }
+
+    /// Prometheus のテキスト形式で書き出す。`active` は描画中のリクエスト数
+    pub fn render(&self, active: usize, queued: usize) -> String {
+        let counters = self.counters.lock().unwrap();
+        let mut output = String::new();
+
+        let _ = writeln!(output, "# HELP mandelbrot_renders_total Number of completed renders.");
+        let _ = writeln!(output, "# TYPE mandelbrot_renders_total counter");
+        let _ = writeln!(output, "mandelbrot_renders_total {}", counters.renders);
+
+        let _ = writeln!(output, "# HELP mandelbrot_render_timeouts_total Number of renders aborted by the timeout.");
+        let _ = writeln!(output, "# TYPE mandelbrot_render_timeouts_total counter");
+        let _ = writeln!(output, "mandelbrot_render_timeouts_total {}", counters.render_timeouts);
+
+        let _ = writeln!(output, "# HELP mandelbrot_render_budget_exceeded_total Number of renders aborted by the iteration budget.");
+        let _ = writeln!(output, "# TYPE mandelbrot_render_budget_exceeded_total counter");
+        let _ = writeln!(output, "mandelbrot_render_budget_exceeded_total {}", counters.render_budget_exceeded);
+
+        let _ = writeln!(output, "# HELP mandelbrot_cache_hits_total Number of renders served from the tile cache.");
+        let _ = writeln!(output, "# TYPE mandelbrot_cache_hits_total counter");
+        let _ = writeln!(output, "mandelbrot_cache_hits_total {}", counters.cache_hits);
+
+        let _ = writeln!(output, "# HELP mandelbrot_cache_misses_total Number of renders not found in the tile cache.");
+        let _ = writeln!(output, "# TYPE mandelbrot_cache_misses_total counter");
+        let _ = writeln!(output, "mandelbrot_cache_misses_total {}", counters.cache_misses);
+
+        let _ = writeln!(output, "# HELP mandelbrot_active_renders Number of renders in progress.");
+        let _ = writeln!(output, "# TYPE mandelbrot_active_renders gauge");
+        let _ = writeln!(output, "mandelbrot_active_renders {}", active);
+
+        let _ = writeln!(output, "# HELP mandelbrot_queued_renders Number of renders waiting for a render slot.");
+        let _ = writeln!(output, "# TYPE mandelbrot_queued_renders gauge");
+        let _ = writeln!(output, "mandelbrot_queued_renders {}", queued);
+
+        let _ = writeln!(output, "# HELP mandelbrot_http_responses_total Number of HTTP responses by status.");
+        let _ = writeln!(output, "# TYPE mandelbrot_http_responses_total counter");
+        for (status, count) in &counters.responses {
+            let _ = writeln!(output, "mandelbrot_http_responses_total{{status=\"{}\"}} {}", status, count);
+        }
+
+        let histogram = &counters.render_duration;
+        let _ = writeln!(output, "# HELP mandelbrot_render_duration_seconds Time spent rendering an image.");
+        let _ = writeln!(output, "# TYPE mandelbrot_render_duration_seconds histogram");
+        let mut cumulative = 0;
+        for (bound, count) in DURATION_BUCKETS.iter().zip(&histogram.counts) {
+            cumulative += count;
+            let _ = writeln!(output, "mandelbrot_render_duration_seconds_bucket{{le=\"{}\"}} {}", bound, cumulative);
+        }
+        let _ = writeln!(output, "mandelbrot_render_duration_seconds_bucket{{le=\"+Inf\"}} {}", histogram.count);
+        let _ = writeln!(output, "mandelbrot_render_duration_seconds_sum {}", histogram.sum);
+        let _ = writeln!(output, "mandelbrot_render_duration_seconds_count {}", histogram.count);
+
+        output
+    }
+}
+
+#[test]
+fn test_metrics_render() {
+    let metrics = Metrics::default();
+    metrics.observe_render(Duration::from_millis(20));
+    metrics.observe_render(Duration::from_secs(60));
+    metrics.count_timeout();
+    metrics.count_budget_exceeded();
+    metrics.count_cache(true);
+    metrics.count_cache(false);
+    metrics.count_cache(false);
+    metrics.count_response(200);
+    metrics.count_response(200);
+    metrics.count_response(400);
+
+    let output = metrics.render(3, 2);
+    assert!(output.contains("mandelbrot_renders_total 2\n"));
+    assert!(output.contains("mandelbrot_render_timeouts_total 1\n"));
+    assert!(output.contains("mandelbrot_render_budget_exceeded_total 1\n"));
+    assert!(output.contains("mandelbrot_cache_hits_total 1\n"));
+    assert!(output.contains("mandelbrot_cache_misses_total 2\n"));
+    assert!(output.contains("mandelbrot_active_renders 3\n"));
+    assert!(output.contains("mandelbrot_queued_renders 2\n"));
+    assert!(output.contains("mandelbrot_http_responses_total{status=\"200\"} 2\n"));
+    assert!(output.contains("mandelbrot_http_responses_total{status=\"400\"} 1\n"));
+    assert!(output.contains("mandelbrot_render_duration_seconds_bucket{le=\"0.01\"} 0\n"));
+    assert!(output.contains("mandelbrot_render_duration_seconds_bucket{le=\"0.025\"} 1\n"));
+    assert!(output.contains("mandelbrot_render_duration_seconds_bucket{le=\"30\"} 1\n"));
+    assert!(output.contains("mandelbrot_render_duration_seconds_bucket{le=\"+Inf\"} 2\n"));
+    assert!(output.contains("mandelbrot_render_duration_seconds_count 2\n"));
+}
@@ -0,0 +1,83 @@
+//! 発散までの回数の分布に合わせて明るさを引き伸ばす `--normalize`
+//!
+//! 深い拡大では発散までの回数が狭い範囲に集まり、上限までを 0 から 255 に割り当てると数段の灰色しか使わない。
+//! `--normalize percentile:LO,HI` は本番の前に粗く描いて発散した点の回数の分布を取り、下から LO % の回数を白、
+//! HI % の回数を黒にして、その間を 256 段に割り当て直してから明るさにする。8ビットに丸めた後で引き伸ばすのと違い、
+//! 段が粗くならない。回数で明るさを決める `--coloring escape` にだけ効き、内部の点は変わらず黒。
+
+use std::str::FromStr;
+
+/// 分布を取るために粗く描くピクセル数の上限
+pub const PROBE_PIXELS: usize = 256 * 256;
+
+/// 白と黒にする回数を決める百分位
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Percentile {
+    pub low: f64,
+    pub high: f64
+}
+
+impl FromStr for Percentile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Percentile, String> {
+        let error = || format!("--normalize expects percentile:LO,HI with 0 <= LO < HI <= 100: {}", s);
+        let (low, high) = s.strip_prefix("percentile:").and_then(|range| range.split_once(',')).ok_or_else(error)?;
+        match (f64::from_str(low), f64::from_str(high)) {
+            (Ok(low), Ok(high)) if 0.0 <= low && low < high && high <= 100.0 => Ok(Percentile { low, high }),
+            _ => Err(error())
+        }
+    }
+}
+
+impl Percentile {
+    /// 粗く描いた回数 `counts` から、白と黒にする回数。発散した点が無ければ `None`
+    pub fn range(&self, counts: &[Option<u32>]) -> Option<(u32, u32)> {
+        let mut escaped: Vec<u32> = counts.iter().flatten().copied().collect();
+        if escaped.is_empty() {
+            return None;
+        }
+        escaped.sort_unstable();
+        let at = |percent: f64| escaped[((escaped.len() - 1) as f64 * percent / 100.0).round() as usize];
+        let low = at(self.low);
+        Some((low, at(self.high).max(low + 1)))
+    }
+}
+
+/// 発散までの回数を、`range` の下端を白、上端を黒にした明るさにする。発散しなかった点は黒になる
+pub fn stretched_shade(count: Option<u32>, (low, high): (u32, u32)) -> u8 {
+    match count {
+        None => 0,
+        Some(count) => {
+            let offset = count.clamp(low, high) - low;
+            255 - (offset as u64 * 255 / (high - low) as u64) as u8
+        }
+    }
+}
+
+#[test]
+fn test_normalize() {
+    assert_eq!(Percentile::from_str("percentile:1,99"), Ok(Percentile { low: 1.0, high: 99.0 }));
+    assert_eq!(Percentile::from_str("percentile:0,100"), Ok(Percentile { low: 0.0, high: 100.0 }));
+    assert!(Percentile::from_str("percentile:99,1").is_err());
+    assert!(Percentile::from_str("percentile:1").is_err());
+    assert!(Percentile::from_str("minmax").is_err());
+
+    // 回数が 900 から 999 に集まっていれば、その範囲を白から黒にする
+    let counts: Vec<Option<u32>> = (900 .. 1000).map(Some).chain([None, None]).collect();
+    let range = Percentile { low: 0.0, high: 100.0 }.range(&counts);
+    assert_eq!(range, Some((900, 999)));
+    assert_eq!(Percentile { low: 10.0, high: 90.0 }.range(&counts), Some((910, 989)));
+    assert_eq!(Percentile { low: 1.0, high: 99.0 }.range(&[None, Some(7)]), Some((7, 8)));
+    assert_eq!(Percentile { low: 1.0, high: 99.0 }.range(&[None]), None);
+
+    let range = range.unwrap();
+    assert_eq!(stretched_shade(Some(900), range), 255);
+    assert_eq!(stretched_shade(Some(999), range), 0);
+    assert_eq!(stretched_shade(Some(10), range), 255);
+    assert_eq!(stretched_shade(Some(5000), range), 0);
+    assert_eq!(stretched_shade(None, range), 0);
+    // 上限の 10000 に比べて狭い範囲でも、ほぼ全ての段を使う
+    let levels: std::collections::BTreeSet<u8> = counts.iter().map(|&count| stretched_shade(count, range)).collect();
+    assert!(levels.len() > 90);
+}
@@ -0,0 +1,689 @@
+//! 描画の上に重ねる注釈 `--annotations FILE`
+//!
+//! 教材の図のように、点の印、軌道、外射線、格子と文字を PNG に書き出す前に描き込む。
+//! 注釈ファイルは次のような TOML で、点は他のオプションと同じ `re,im` の形で書く。
+//!
+//! ```toml
+//! [[grid]]
+//! spacing = 0.5
+//! gray = 96
+//!
+//! [[ray]]
+//! angle = "1/3"       # 角度は回転数の分数 p/q
+//! depth = 64
+//!
+//! [[orbit]]
+//! c = "-0.1225,0.7448"
+//! iterations = 60
+//!
+//! [[marker]]
+//! at = "-0.75,0"
+//! shape = "circle"    # cross (既定), circle, dot
+//! radius = 6
+//!
+//! [[label]]
+//! at = "-0.72,0.05"
+//! text = "period 2"
+//! scale = 2
+//! ```
+//!
+//! 色は灰色の濃さ `gray` (既定 255) で、格子、外射線、軌道、印、文字の順に重ねる。
+//! 外射線と軌道は式 `--fractal` に関わらず Mandelbrot 集合 `z^2 + c` のもの。
+//! 文字の倍率、印の半径、外射線の深さ、軌道の反復回数には上限を設け、塗る長方形は画像の中に切り詰める。
+//! ファイルを介さずに `Overlay::push` で注釈を足すこともできる。`--grid` の値を添えた格子と
+//! `--scale-bar` の物差しも同じ仕組みで、注釈の下と上にそれぞれ重ねる。
+
+use std::f64::consts::PI;
+use std::fs;
+use std::str::FromStr;
+
+use num::Complex;
+use serde::Deserialize;
+
+use super::font::{self, GLYPH_HEIGHT, GLYPH_WIDTH};
+use super::{parse_complex, Region};
+
+/// 外射線を辿り始める半径。ここでは Böttcher 座標がほぼ c そのものになる
+const RAY_ESCAPE_RADIUS: f64 = 65536.0;
+
+/// 外射線の1反復あたりの点の数
+const RAY_SHARPNESS: u32 = 8;
+
+/// 1本の格子で引く線の上限。細かすぎる間隔で画像を塗り潰さないようにする
+const MAX_GRID_LINES: f64 = 1000.0;
+
+/// 注釈のファイルで受け付ける値の上限。大きな値で描き込みがいつまでも終わらないようにする
+const MAX_LABEL_SCALE: u32 = 256;
+const MAX_MARKER_RADIUS: u32 = 1024;
+const MAX_RAY_DEPTH: u32 = 256;
+const MAX_ORBIT_ITERATIONS: u32 = 10_000;
+
+/// 回転数の分数 `numerator / denominator` で表した外射線の角度
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Angle {
+    numerator: u64,
+    denominator: u64
+}
+
+impl Angle {
+    /// 角度を2倍にする。射線は z^2 で偏角が倍の射線に移る
+    fn double(self) -> Angle {
+        Angle { numerator: self.numerator * 2 % self.denominator, ..self }
+    }
+
+    fn radians(self) -> f64 {
+        2.0 * PI * self.numerator as f64 / self.denominator as f64
+    }
+}
+
+impl FromStr for Angle {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Angle, String> {
+        let error = || format!("angle must be a fraction p/q with 0 <= p < q: {}", s);
+        let (numerator, denominator) = s.split_once('/').ok_or_else(error)?;
+        let numerator: u64 = numerator.trim().parse().map_err(|_| error())?;
+        let denominator: u64 = denominator.trim().parse().map_err(|_| error())?;
+        // 2倍しても溢れないよう、分母は 2^62 まで
+        if numerator >= denominator || denominator > 1 << 62 {
+            return Err(error());
+        }
+        Ok(Angle { numerator, denominator })
+    }
+}
+
+/// 点の印の形
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MarkerStyle {
+    Cross,
+    Circle,
+    Dot
+}
+
+impl FromStr for MarkerStyle {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<MarkerStyle, String> {
+        match s {
+            "cross" => Ok(MarkerStyle::Cross),
+            "circle" => Ok(MarkerStyle::Circle),
+            "dot" => Ok(MarkerStyle::Dot),
+            _ => Err(format!("unknown marker shape: {}", s))
+        }
+    }
+}
+
+/// 注釈の図形
+#[derive(Clone, Debug, PartialEq)]
+pub enum Shape {
+    /// 点 `at` を中心に半径 `radius` ピクセルの印
+    Marker { at: Complex<f64>, radius: u32, style: MarkerStyle },
+    /// 0 から始めた `z^2 + c` の軌道を `iterations` 回まで折れ線で結ぶ
+    Orbit { c: Complex<f64>, iterations: u32 },
+    /// 角度 `angle` の外射線を `depth` 回の反復に相当する深さまで
+    Ray { angle: Angle, depth: u32 },
+    /// 実部と虚部が `spacing` の倍数の位置に引く格子
+    Grid { spacing: f64 },
+    /// 点 `at` を左上の角として、`scale` 倍の大きさで書く文字
+    Label { at: Complex<f64>, text: String, scale: u32 },
+    /// 切りの良い間隔の格子と縁の目盛りに値を添え、見えていれば実軸と虚軸を太く引く `--grid`
+    Axes,
+    /// 範囲の幅に合わせた切りの良い長さの物差しを左下に置く `--scale-bar`
+    ScaleBar
+}
+
+/// 重ねる注釈の並び
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Overlay {
+    annotations: Vec<(Shape, u8)>
+}
+
+impl Overlay {
+    /// 図形 `shape` を灰色の濃さ `gray` で最後に重ねる
+    pub fn push(&mut self, shape: Shape, gray: u8) {
+        self.annotations.push((shape, gray));
+    }
+
+    /// `other` の注釈を後ろに重ねる
+    pub fn extend(&mut self, other: Overlay) {
+        self.annotations.extend(other.annotations);
+    }
+
+    /// 注釈ファイル `path` を読む
+    pub fn load(path: &str) -> Result<Overlay, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("cannot read annotations {}: {}", path, e))?;
+        parse_annotations(&text).map_err(|e| format!("invalid annotations {}: {}", path, e))
+    }
+
+    /// 範囲 `region` に描いた画像 `pixels` に注釈を描き込む
+    pub fn draw(&self, pixels: &mut [u8], region: Region) {
+        let mut canvas = Canvas { pixels, region };
+        for (shape, gray) in &self.annotations {
+            canvas.draw(shape, *gray);
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct AnnotationFile {
+    #[serde(default)]
+    grid: Vec<GridEntry>,
+    #[serde(default)]
+    ray: Vec<RayEntry>,
+    #[serde(default)]
+    orbit: Vec<OrbitEntry>,
+    #[serde(default)]
+    marker: Vec<MarkerEntry>,
+    #[serde(default)]
+    label: Vec<LabelEntry>
+}
+
+fn default_gray() -> u8 { 255 }
+fn default_radius() -> u32 { 4 }
+fn default_depth() -> u32 { 64 }
+fn default_iterations() -> u32 { 100 }
+fn default_scale() -> u32 { 1 }
+
+#[derive(Debug, Deserialize)]
+struct GridEntry {
+    spacing: f64,
+    #[serde(default = "default_gray")]
+    gray: u8
+}
+
+#[derive(Debug, Deserialize)]
+struct RayEntry {
+    angle: String,
+    #[serde(default = "default_depth")]
+    depth: u32,
+    #[serde(default = "default_gray")]
+    gray: u8
+}
+
+#[derive(Debug, Deserialize)]
+struct OrbitEntry {
+    c: String,
+    #[serde(default = "default_iterations")]
+    iterations: u32,
+    #[serde(default = "default_gray")]
+    gray: u8
+}
+
+#[derive(Debug, Deserialize)]
+struct MarkerEntry {
+    at: String,
+    shape: Option<String>,
+    #[serde(default = "default_radius")]
+    radius: u32,
+    #[serde(default = "default_gray")]
+    gray: u8
+}
+
+#[derive(Debug, Deserialize)]
+struct LabelEntry {
+    at: String,
+    text: String,
+    #[serde(default = "default_scale")]
+    scale: u32,
+    #[serde(default = "default_gray")]
+    gray: u8
+}
+
+fn parse_point(s: &str) -> Result<Complex<f64>, String> {
+    parse_complex(s).ok_or_else(|| format!("invalid point: {}", s))
+}
+
+pub fn parse_annotations(text: &str) -> Result<Overlay, String> {
+    let file: AnnotationFile = toml::from_str(text).map_err(|e| e.to_string())?;
+    let mut overlay = Overlay::default();
+    for entry in file.grid {
+        if !(entry.spacing > 0.0 && entry.spacing.is_finite()) {
+            return Err(format!("grid spacing must be a positive number: {}", entry.spacing));
+        }
+        overlay.push(Shape::Grid { spacing: entry.spacing }, entry.gray);
+    }
+    for entry in file.ray {
+        if entry.depth > MAX_RAY_DEPTH {
+            return Err(format!("ray depth must be at most {}", MAX_RAY_DEPTH));
+        }
+        overlay.push(Shape::Ray { angle: entry.angle.parse()?, depth: entry.depth }, entry.gray);
+    }
+    for entry in file.orbit {
+        if entry.iterations > MAX_ORBIT_ITERATIONS {
+            return Err(format!("orbit iterations must be at most {}", MAX_ORBIT_ITERATIONS));
+        }
+        overlay.push(Shape::Orbit { c: parse_point(&entry.c)?, iterations: entry.iterations }, entry.gray);
+    }
+    for entry in file.marker {
+        let style = match entry.shape {
+            Some(shape) => shape.parse()?,
+            None => MarkerStyle::Cross
+        };
+        if entry.radius > MAX_MARKER_RADIUS {
+            return Err(format!("marker radius must be at most {}", MAX_MARKER_RADIUS));
+        }
+        overlay.push(Shape::Marker { at: parse_point(&entry.at)?, radius: entry.radius, style }, entry.gray);
+    }
+    for entry in file.label {
+        if entry.scale == 0 || entry.scale > MAX_LABEL_SCALE {
+            return Err(format!("label scale must be between 1 and {}", MAX_LABEL_SCALE));
+        }
+        overlay.push(Shape::Label { at: parse_point(&entry.at)?, text: entry.text, scale: entry.scale },
+                     entry.gray);
+    }
+    Ok(overlay)
+}
+
+#[test]
+fn test_parse_annotations() {
+    let overlay = parse_annotations(r#"
+        [[marker]]
+        at = "-0.75,0"
+        shape = "circle"
+
+        [[label]]
+        at = "-0.72,0.05"
+        text = "period 2"
+        scale = 2
+        gray = 0
+
+        [[ray]]
+        angle = "1/3"
+
+        [[grid]]
+        spacing = 0.5
+    "#).unwrap();
+    let at = Complex { re: -0.75, im: 0.0 };
+    assert_eq!(overlay.annotations, vec![
+        (Shape::Grid { spacing: 0.5 }, 255),
+        (Shape::Ray { angle: Angle { numerator: 1, denominator: 3 }, depth: 64 }, 255),
+        (Shape::Marker { at, radius: 4, style: MarkerStyle::Circle }, 255),
+        (Shape::Label { at: Complex { re: -0.72, im: 0.05 }, text: "period 2".to_string(), scale: 2 }, 0)
+    ]);
+
+    assert_eq!(parse_annotations(""), Ok(Overlay::default()));
+    assert!(parse_annotations("[[marker]]\nat = \"1\"").is_err());
+    assert!(parse_annotations("[[marker]]\nat = \"0,0\"\nshape = \"star\"").is_err());
+    assert!(parse_annotations("[[ray]]\nangle = \"3/2\"").is_err());
+    assert!(parse_annotations("[[grid]]\nspacing = 0.0").is_err());
+    assert!(parse_annotations("[[label]]\nat = \"0,0\"\ntext = \"a\"\nscale = 0").is_err());
+    assert!(parse_annotations("[[label]]\nat = \"0,0\"\ntext = \"a\"\nscale = 4000000000").is_err());
+    assert!(parse_annotations("[[marker]]\nat = \"0,0\"\nradius = 4000000000").is_err());
+    assert!(parse_annotations("[[ray]]\nangle = \"1/3\"\ndepth = 4000000000").is_err());
+    assert!(parse_annotations("[[orbit]]\nc = \"-1,0\"\niterations = 4000000000").is_err());
+    assert!(parse_annotations("[[grid]]\nspacing = 1\ngray = 300").is_err());
+}
+
+/// 外射線の上の点を外側から並べる
+///
+/// 反復 m 回目の値 z_m(c) が半径 r、偏角 2^(m-1) θ の点になる c を、ひとつ前の点から Newton 法で求める。
+/// r を `RAY_ESCAPE_RADIUS` からその平方根まで `RAY_SHARPNESS` 段で縮めてから m を増やす。
+fn external_ray(angle: Angle, depth: u32) -> Vec<Complex<f64>> {
+    let mut c = Complex::from_polar(&RAY_ESCAPE_RADIUS, &angle.radians());
+    let mut points = vec![c];
+    let mut angle = angle;
+    for m in 1 ..= depth {
+        for j in 1 ..= RAY_SHARPNESS {
+            let radius = RAY_ESCAPE_RADIUS.powf(0.5f64.powf(j as f64 / RAY_SHARPNESS as f64));
+            let target = Complex::from_polar(&radius, &angle.radians());
+            for _ in 0 .. 64 {
+                let mut z = Complex { re: 0.0, im: 0.0 };
+                let mut dz = Complex { re: 0.0, im: 0.0 };
+                for _ in 0 .. m {
+                    dz = z * dz * 2.0 + Complex { re: 1.0, im: 0.0 };
+                    z = z * z + c;
+                }
+                let step = (z - target) / dz;
+                c -= step;
+                if step.norm_sqr() <= 1e-30 * c.norm_sqr() {
+                    break;
+                }
+            }
+            if !(c.re.is_finite() && c.im.is_finite()) {
+                return points;
+            }
+            points.push(c);
+        }
+        angle = angle.double();
+    }
+    points
+}
+
+#[test]
+fn test_external_ray() {
+    // 角度 0 の射線は実軸上を 1/4 に向かって降りてくる
+    let ray = external_ray("0/1".parse().unwrap(), 16);
+    assert!(ray.iter().all(|c| c.im.abs() < 1e-9));
+    assert!(ray.windows(2).all(|pair| pair[1].re < pair[0].re));
+    assert!((ray.last().unwrap().re - 0.25).abs() < 0.05);
+    // 1/3 と 2/3 の射線は周期2の成分の根 -3/4 に、1/7 と 2/7 の射線は兎 (周期3の成分) の根に降りてくる。
+    // 放物型の点には近付くのが遅いので、見た目に分かる程度しか近付かない
+    let landing = |angle: &str| *external_ray(angle.parse().unwrap(), 64).last().unwrap();
+    let period2 = Complex { re: -0.75, im: 0.0 };
+    assert!((landing("1/3") - period2).norm() < 0.06);
+    assert!((landing("2/3") - period2).norm() < 0.06);
+    assert!(landing("1/3").im > 0.0 && landing("2/3").im < 0.0);
+    let rabbit = Complex { re: -0.125, im: 0.649519 };
+    assert!((landing("1/7") - rabbit).norm() < 0.04);
+    assert!((landing("2/7") - rabbit).norm() < 0.04);
+}
+
+/// 注釈を描き込む画像
+struct Canvas<'a> {
+    pixels: &'a mut [u8],
+    region: Region
+}
+
+impl Canvas<'_> {
+    fn draw(&mut self, shape: &Shape, gray: u8) {
+        match shape {
+            Shape::Marker { at, radius, style } => self.marker(*at, *radius as i64, *style, gray),
+            Shape::Orbit { c, iterations } => {
+                let mut z = Complex { re: 0.0, im: 0.0 };
+                let mut orbit = vec![z];
+                for _ in 0 .. *iterations {
+                    z = z * z + c;
+                    // 発散した先は画像の外なので、そこで止める
+                    if z.norm_sqr() > 1e6 {
+                        break;
+                    }
+                    orbit.push(z);
+                }
+                self.polyline(&orbit, gray);
+                for &z in &orbit {
+                    self.marker(z, 1, MarkerStyle::Dot, gray);
+                }
+            }
+            Shape::Ray { angle, depth } => self.polyline(&external_ray(*angle, *depth), gray),
+            Shape::Grid { spacing } => self.grid(*spacing, gray),
+            Shape::Label { at, text, scale } => {
+                let (x, y) = self.position(*at);
+                self.text(x.floor() as i64, y.floor() as i64, text, *scale as i64, gray);
+            }
+            Shape::Axes => self.axes(gray),
+            Shape::ScaleBar => self.scale_bar(gray)
+        }
+    }
+
+    fn plot(&mut self, x: i64, y: i64, gray: u8) {
+        let (width, height) = self.region.bounds;
+        if x >= 0 && y >= 0 && (x as usize) < width && (y as usize) < height {
+            self.pixels[y as usize * width + x as usize] = gray;
+        }
+    }
+
+    fn position(&self, point: Complex<f64>) -> (f64, f64) {
+        self.region.point_to_position(point)
+    }
+
+    fn marker(&mut self, at: Complex<f64>, radius: i64, style: MarkerStyle, gray: u8) {
+        let (x, y) = self.position(at);
+        let (x, y) = (x.floor() as i64, y.floor() as i64);
+        for dy in -radius ..= radius {
+            for dx in -radius ..= radius {
+                let distance = dx * dx + dy * dy;
+                let on = match style {
+                    MarkerStyle::Cross => dx == 0 || dy == 0,
+                    MarkerStyle::Circle => (distance - radius * radius).abs() <= radius,
+                    MarkerStyle::Dot => distance <= radius * radius
+                };
+                if on {
+                    self.plot(x.saturating_add(dx), y.saturating_add(dy), gray);
+                }
+            }
+        }
+    }
+
+    fn polyline(&mut self, points: &[Complex<f64>], gray: u8) {
+        for pair in points.windows(2) {
+            let (from, to) = (self.position(pair[0]), self.position(pair[1]));
+            self.line(from, to, gray);
+        }
+    }
+
+    /// ピクセル単位の位置 `from` から `to` まで線を引く。画像の外の部分は先に切り落とす
+    fn line(&mut self, from: (f64, f64), to: (f64, f64), gray: u8) {
+        let (width, height) = (self.region.bounds.0 as f64, self.region.bounds.1 as f64);
+        let (dx, dy) = (to.0 - from.0, to.1 - from.1);
+        // Liang-Barsky のクリッピング
+        let (mut t0, mut t1) = (0.0f64, 1.0f64);
+        for &(p, q) in &[(-dx, from.0 + 1.0), (dx, width - from.0), (-dy, from.1 + 1.0), (dy, height - from.1)] {
+            if p == 0.0 {
+                if q < 0.0 {
+                    return;
+                }
+            } else {
+                let t = q / p;
+                if p < 0.0 { t0 = t0.max(t) } else { t1 = t1.min(t) }
+            }
+        }
+        if t0 > t1 {
+            return;
+        }
+        let start = (from.0 + t0 * dx, from.1 + t0 * dy);
+        let (dx, dy) = ((t1 - t0) * dx, (t1 - t0) * dy);
+        let steps = dx.abs().max(dy.abs()).ceil().max(1.0) as i64;
+        for step in 0 ..= steps {
+            let t = step as f64 / steps as f64;
+            self.plot((start.0 + t * dx).floor() as i64, (start.1 + t * dy).floor() as i64, gray);
+        }
+    }
+
+    fn grid(&mut self, spacing: f64, gray: u8) {
+        let Region { bounds: (width, height), upper_left, lower_right } = self.region;
+        // 線の数は整数にする前に f64 で確かめる。間隔が細かいと番号が i64 に収まらない
+        let lines = |from: f64, to: f64| {
+            let (low, high) = (from.min(to), from.max(to));
+            let first = (low / spacing).ceil();
+            let count = (high / spacing).floor() - first + 1.0;
+            let count = if (0.0 ..= MAX_GRID_LINES + 1.0).contains(&count) { count as usize } else { 0 };
+            (0 .. count).map(move |k| (first + k as f64) * spacing)
+        };
+        for re in lines(upper_left.re, lower_right.re) {
+            let (x, _) = self.position(Complex { re, im: 0.0 });
+            self.line((x, 0.0), (x, height as f64), gray);
+        }
+        for im in lines(lower_right.im, upper_left.im) {
+            let (_, y) = self.position(Complex { re: 0.0, im });
+            self.line((0.0, y), (width as f64, y), gray);
+        }
+    }
+
+    /// ピクセル (x, y) を左上の角として文字を書く
+    fn text(&mut self, x: i64, y: i64, text: &str, scale: i64, gray: u8) {
+        for (index, ch) in text.chars().enumerate() {
+            let glyph = font::glyph(ch);
+            // 字の間を1ピクセル空ける
+            let left = x.saturating_add((index as i64).saturating_mul((GLYPH_WIDTH as i64 + 1) * scale));
+            for row in 0 .. GLYPH_HEIGHT {
+                for column in 0 .. GLYPH_WIDTH {
+                    if font::is_set(glyph, column, row) {
+                        self.fill(left.saturating_add(column as i64 * scale), y.saturating_add(row as i64 * scale),
+                                  scale, scale, gray);
+                    }
+                }
+            }
+        }
+    }
+
+    /// 背景の上でも読めるよう、反対の濃さの箱に入れて文字を書く
+    fn boxed_text(&mut self, x: i64, y: i64, text: &str, gray: u8) {
+        let width = text.chars().count() as i64 * (GLYPH_WIDTH as i64 + 1) + 1;
+        self.fill(x - 1, y - 1, width + 1, GLYPH_HEIGHT as i64 + 2, opposite(gray));
+        self.text(x, y, text, 1, gray);
+    }
+
+    /// (x, y) を左上の角とする長方形を塗る。画像の外の部分は先に切り落とす
+    fn fill(&mut self, x: i64, y: i64, width: i64, height: i64, gray: u8) {
+        let (image_width, image_height) = (self.region.bounds.0 as i64, self.region.bounds.1 as i64);
+        let (left, right) = (x.max(0), x.saturating_add(width).min(image_width));
+        let (top, bottom) = (y.max(0), y.saturating_add(height).min(image_height));
+        for py in top .. bottom {
+            for px in left .. right {
+                self.plot(px, py, gray);
+            }
+        }
+    }
+
+    fn axes(&mut self, gray: u8) {
+        let Region { bounds: (width, height), upper_left, lower_right } = self.region;
+        let (width, height) = (width as f64, height as f64);
+        let step = nice_step((lower_right.re - upper_left.re).min(upper_left.im - lower_right.im) / 5.0);
+        if !(step > 0.0 && step.is_finite()) {
+            return;
+        }
+        let ticks = |from: f64, to: f64, step: f64| {
+            let (low, high) = (from.min(to), from.max(to));
+            ((low / step).ceil() as i64 ..= (high / step).floor() as i64).map(move |k| k as f64 * step)
+        };
+
+        // 細かい目盛りは間隔の 1/5 毎に縁だけに付ける
+        for re in ticks(upper_left.re, lower_right.re, step / 5.0) {
+            let (x, _) = self.position(Complex { re, im: 0.0 });
+            self.line((x, 0.0), (x, 3.0), gray);
+            self.line((x, height - 3.0), (x, height), gray);
+        }
+        for im in ticks(lower_right.im, upper_left.im, step / 5.0) {
+            let (_, y) = self.position(Complex { re: 0.0, im });
+            self.line((0.0, y), (3.0, y), gray);
+            self.line((width - 3.0, y), (width, y), gray);
+        }
+
+        let dim = ((gray as u16 + opposite(gray) as u16) / 2) as u8;
+        for re in ticks(upper_left.re, lower_right.re, step) {
+            let (x, _) = self.position(Complex { re, im: 0.0 });
+            self.line((x, 0.0), (x, height), if re == 0.0 { gray } else { dim });
+            if re == 0.0 {
+                self.line((x + 1.0, 0.0), (x + 1.0, height), gray);
+            }
+            self.boxed_text(x.floor() as i64 + 3, 5, &format_tick(re, step), gray);
+        }
+        for im in ticks(lower_right.im, upper_left.im, step) {
+            let (_, y) = self.position(Complex { re: 0.0, im });
+            self.line((0.0, y), (width, y), if im == 0.0 { gray } else { dim });
+            if im == 0.0 {
+                self.line((0.0, y + 1.0), (width, y + 1.0), gray);
+            }
+            self.boxed_text(5, y.floor() as i64 + 3, &(format_tick(im, step) + "i"), gray);
+        }
+    }
+
+    fn scale_bar(&mut self, gray: u8) {
+        let Region { bounds: (width, height), upper_left, lower_right } = self.region;
+        let range = lower_right.re - upper_left.re;
+        let length = nice_below(range / 4.0);
+        let pixels = (length / range * width as f64).round() as i64;
+        let label = format_tick(length, length);
+        let (left, bottom) = (10, height as i64 - 10);
+        let label_width = label.len() as i64 * (GLYPH_WIDTH as i64 + 1);
+        // 物差しと上に書く長さをまとめて箱に入れる
+        self.fill(left - 3, bottom - GLYPH_HEIGHT as i64 - 11, pixels.max(label_width) + 7,
+                  GLYPH_HEIGHT as i64 + 14, opposite(gray));
+        self.fill(left, bottom - 3, pixels + 1, 3, gray);
+        self.fill(left, bottom - 7, 1, 7, gray);
+        self.fill(left + pixels, bottom - 7, 1, 7, gray);
+        self.text(left + (pixels - label_width).max(0) / 2, bottom - GLYPH_HEIGHT as i64 - 9, &label, 1, gray);
+    }
+}
+
+/// 濃さ `gray` の線や文字が読める背景の濃さ
+fn opposite(gray: u8) -> u8 {
+    if gray >= 128 { 0 } else { 255 }
+}
+
+/// `raw` 以上で最も小さい、1, 2, 5 に 10 の冪を掛けた値
+fn nice_step(raw: f64) -> f64 {
+    let magnitude = 10f64.powf(raw.log10().floor());
+    [1.0, 2.0, 5.0, 10.0].iter().map(|m| m * magnitude).find(|&step| step >= raw).unwrap_or(10.0 * magnitude)
+}
+
+/// `raw` 以下で最も大きい、1, 2, 5 に 10 の冪を掛けた値
+fn nice_below(raw: f64) -> f64 {
+    let magnitude = 10f64.powf(raw.log10().floor());
+    [5.0, 2.0, 1.0].iter().map(|m| m * magnitude).find(|&length| length <= raw).unwrap_or(magnitude)
+}
+
+/// 間隔 `step` の目盛りの値 `value` を、間隔が表せる桁数で書く
+fn format_tick(value: f64, step: f64) -> String {
+    if value.abs() < step / 2.0 {
+        return "0".to_string();
+    }
+    let decimals = (-step.log10().floor()).max(0.0) as usize;
+    format!("{:.*}", decimals, value)
+}
+
+#[test]
+fn test_nice_values() {
+    assert_eq!(nice_step(0.7), 1.0);
+    assert_eq!(nice_step(1.5), 2.0);
+    assert_eq!(nice_step(3.0), 5.0);
+    assert_eq!(nice_step(0.03), 0.05);
+    assert_eq!(nice_below(0.7), 0.5);
+    assert_eq!(nice_below(1.5), 1.0);
+    assert_eq!(nice_below(30.0), 20.0);
+    assert_eq!(format_tick(-1.5, 0.5), "-1.5");
+    assert_eq!(format_tick(2.0, 1.0), "2");
+    assert_eq!(format_tick(0.6000000000000001, 0.2), "0.6");
+    assert_eq!(format_tick(1e-17, 0.1), "0");
+    assert_eq!(format_tick(-0.0012, 0.0002), "-0.0012");
+}
+#[test]
+fn test_overlay_draw() {
+    let region = Region::new((40, 20), Complex { re: -2.0, im: 1.0 }, Complex { re: 2.0, im: -1.0 });
+    let draw = |shape: Shape| {
+        let mut overlay = Overlay::default();
+        overlay.push(shape, 255);
+        let mut pixels = vec![0; 40 * 20];
+        overlay.draw(&mut pixels, region);
+        pixels
+    };
+    let lit = |pixels: &[u8]| -> Vec<(usize, usize)> {
+        (0 .. pixels.len()).filter(|&i| pixels[i] == 255).map(|i| (i % 40, i / 40)).collect()
+    };
+
+    // 間隔 1 の格子は x = 0, 10, 20, 30 の列と y = 0, 10 の行。右端と下端の線は画像の外
+    let grid = draw(Shape::Grid { spacing: 1.0 });
+    assert!((0 .. 20).all(|y| [0, 10, 20, 30].iter().all(|&x| grid[y * 40 + x] == 255)));
+    assert!((0 .. 40).all(|x| grid[x] == 255 && grid[10 * 40 + x] == 255));
+    assert_eq!(lit(&grid).len(), 4 * 20 + 2 * 40 - 4 * 2);
+
+    let cross = draw(Shape::Marker { at: Complex { re: 0.05, im: -0.05 }, radius: 2, style: MarkerStyle::Cross });
+    assert_eq!(lit(&cross), vec![(20, 8), (20, 9), (18, 10), (19, 10), (20, 10), (21, 10), (22, 10),
+                                 (20, 11), (20, 12)]);
+    // 画像の端にはみ出した印も描ける所まで描く
+    let corner = draw(Shape::Marker { at: Complex { re: -2.0, im: 1.0 }, radius: 1, style: MarkerStyle::Dot });
+    assert_eq!(lit(&corner), vec![(0, 0), (1, 0), (0, 1)]);
+
+    // 'I' は中央の縦棒と上下の横棒
+    let label = draw(Shape::Label { at: Complex { re: 0.0, im: 0.0 }, text: "I".to_string(), scale: 1 });
+    assert_eq!(lit(&label).len(), 3 + 5 + 3);
+    // 大きな文字や遠くの文字は画像の中だけを塗る
+    // 縦棒の1ピクセルが画像全体を覆う位置に置く
+    let huge = draw(Shape::Label { at: Complex { re: -62.0, im: 91.0 }, text: "I".to_string(), scale: MAX_LABEL_SCALE });
+    assert_eq!(lit(&huge).len(), 40 * 20);
+    let far = Complex { re: 1e300, im: -1e300 };
+    assert!(lit(&draw(Shape::Label { at: far, text: "I".to_string(), scale: 1 })).is_empty());
+    assert!(lit(&draw(Shape::Marker { at: far, radius: 2, style: MarkerStyle::Dot })).is_empty());
+    // 細かすぎる格子は引かない
+    assert!(lit(&draw(Shape::Grid { spacing: 1e-300 })).is_empty());
+    assert!(label[10 * 40 + 21] == 255 && label[16 * 40 + 23] == 255 && label[13 * 40 + 20] == 0);
+
+    // 幅 4 の範囲の物差しは長さ 1 で 10 ピクセル。箱の中に描く
+    let bar = draw(Shape::ScaleBar);
+    assert!((10 ..= 20).all(|x| bar[8 * 40 + x] == 255));
+    assert_eq!((bar[8 * 40 + 9], bar[8 * 40 + 21]), (0, 0));
+    // 虚軸は2ピクセルの太さで、間隔 0.5 の格子は暗く引く
+    let mut axes = vec![0; 200 * 100];
+    let mut overlay = Overlay::default();
+    overlay.push(Shape::Axes, 255);
+    overlay.draw(&mut axes, Region::new((200, 100), region.upper_left, region.lower_right));
+    assert_eq!([100, 101, 110, 125].iter().map(|x| axes[60 * 200 + x]).collect::<Vec<_>>(), [255, 255, 0, 127]);
+    // 縁の細かい目盛りは 0.1 毎
+    assert_eq!((axes[99 * 200 + 105], axes[99 * 200 + 107]), (255, 0));
+
+    // c = -1 の軌道は 0 と -1 を行き来する
+    let orbit = draw(Shape::Orbit { c: Complex { re: -1.0, im: 0.0 }, iterations: 10 });
+    assert!((10 ..= 20).all(|x| orbit[10 * 40 + x] == 255));
+    assert_eq!(orbit[10 * 40 + 25], 0);
+}
@@ -0,0 +1,341 @@
+//! 端末の中でグラデーションの段階を動かしながらパレットを作る `palette edit` サブコマンド
+//!
+//! 画面の上には `explore-tui` と同じ半ブロック文字で見本の範囲を、その下にグラデーションの帯と段階の位置の印を描き、
+//! 段階を変える度に描き直す。見本の範囲は端末の大きさが変わったときだけ描き直し、色はその灰色の画像を
+//! パレットの表で置き換えて付けるので、段階を動かしても反復はやり直さない。
+//! `w` で `--palette` に渡せる TOML のファイル (`color.rs`) に書き出す。
+//!
+//! Tab / Shift+Tab で段階を選び、左右の矢印キーで位置を動かし、`r` `g` `b` (大文字で増やす) で色を変える。
+//! `a` で次の段階との中間に段階を足し、`d` で消し、`i` で補間の色空間を切り替える。`q` で終了する。
+//!
+//! ```bash
+//! $ mandelbrot palette edit mine.toml --from ultra --center -0.745,0.11 --zoom 80 --passes 512
+//! ```
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind};
+use crossterm::style::{Color, Print, ResetColor, SetBackgroundColor, SetForegroundColor};
+use crossterm::{cursor, execute, queue, terminal};
+use num::Complex;
+
+use super::color::{InterpSpace, Palette, Stop};
+use super::{failed, parse_complex, parse_params, region_from_center, render_parallel, Failure, RenderParams};
+
+/// 左右の矢印キーで段階を動かす量
+const MOVE_STEP: f64 = 0.01;
+
+/// `r` `g` `b` で色の成分を変える量
+const CHANNEL_STEP: i16 = 8;
+
+/// 見本の範囲の下に使う行の数 (グラデーションの帯、段階の印、状態)
+const FOOTER_LINES: usize = 3;
+
+/// キー入力に対する操作
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Action {
+    /// 選ぶ段階を前後に変える
+    Select(isize),
+    /// 選んだ段階の位置を動かす
+    Move(f64),
+    /// 選んだ段階の色の成分 (0 が赤) を変える
+    Channel(usize, i16),
+    Add,
+    Delete,
+    /// 補間の色空間を次に切り替える
+    Space,
+    Save,
+    Quit
+}
+
+fn action_for(key: KeyEvent) -> Option<Action> {
+    match key.code {
+        KeyCode::Tab => Some(Action::Select(1)),
+        KeyCode::BackTab => Some(Action::Select(-1)),
+        KeyCode::Left => Some(Action::Move(-MOVE_STEP)),
+        KeyCode::Right => Some(Action::Move(MOVE_STEP)),
+        KeyCode::Char(c @ ('r' | 'g' | 'b' | 'R' | 'G' | 'B')) => {
+            let channel = "rgb".find(c.to_ascii_lowercase()).unwrap();
+            Some(Action::Channel(channel, if c.is_ascii_uppercase() { CHANNEL_STEP } else { -CHANNEL_STEP }))
+        }
+        KeyCode::Char('a') => Some(Action::Add),
+        KeyCode::Char('d') => Some(Action::Delete),
+        KeyCode::Char('i') => Some(Action::Space),
+        KeyCode::Char('w') => Some(Action::Save),
+        KeyCode::Char('q') | KeyCode::Esc => Some(Action::Quit),
+        _ => None
+    }
+}
+
+/// 編集中のパレットと選んでいる段階
+#[derive(Clone, Debug, PartialEq)]
+struct Editor {
+    palette: Palette,
+    selected: usize
+}
+
+impl Editor {
+    fn space(&self) -> InterpSpace {
+        self.palette.space.unwrap_or(InterpSpace::Oklab)
+    }
+
+    /// `Save` と `Quit` 以外の操作をする
+    fn apply(&mut self, action: Action) {
+        let stops = &mut self.palette.stops;
+        match action {
+            Action::Select(step) => {
+                self.selected = (self.selected as isize + step).rem_euclid(stops.len() as isize) as usize;
+            }
+            Action::Move(step) => {
+                // 両隣の段階を越えないように動かす
+                let lower = if self.selected == 0 { 0.0 } else { stops[self.selected - 1].position };
+                let upper = stops.get(self.selected + 1).map_or(1.0, |stop| stop.position);
+                let position = &mut stops[self.selected].position;
+                *position = ((*position + step).clamp(lower, upper) * 1e4).round() / 1e4;
+            }
+            Action::Channel(channel, step) => {
+                let value = &mut stops[self.selected].color[channel];
+                *value = (*value as i16 + step).clamp(0, 255) as u8;
+            }
+            Action::Add => {
+                // 最後の段階なら前の段階との中間に足す
+                let (a, b) = if self.selected + 1 < stops.len() {
+                    (self.selected, self.selected + 1)
+                } else {
+                    (self.selected - 1, self.selected)
+                };
+                let position = ((stops[a].position + stops[b].position) / 2.0 * 1e4).round() / 1e4;
+                let color = self.palette.sample(position, self.palette.space.unwrap_or(InterpSpace::Oklab));
+                self.palette.stops.insert(b, Stop { position, color });
+                self.selected = b;
+            }
+            Action::Delete if stops.len() > 2 => {
+                stops.remove(self.selected);
+                self.selected = self.selected.min(stops.len() - 1);
+            }
+            Action::Space => {
+                let index = InterpSpace::ALL.iter().position(|&space| space == self.space()).unwrap();
+                self.palette.space = Some(InterpSpace::ALL[(index + 1) % InterpSpace::ALL.len()]);
+            }
+            Action::Delete | Action::Save | Action::Quit => {}
+        }
+    }
+
+    fn describe(&self) -> String {
+        let stop = self.palette.stops[self.selected];
+        let [r, g, b] = stop.color;
+        format!("stop {}/{} at {:.4} #{:02x}{:02x}{:02x}  {}", self.selected + 1, self.palette.stops.len(),
+                stop.position, r, g, b, self.space().name())
+    }
+}
+
+#[test]
+fn test_editor() {
+    let key = |code| KeyEvent::new(code, event::KeyModifiers::NONE);
+    assert_eq!(action_for(key(KeyCode::Char('G'))), Some(Action::Channel(1, CHANNEL_STEP)));
+    assert_eq!(action_for(key(KeyCode::Char('b'))), Some(Action::Channel(2, -CHANNEL_STEP)));
+    assert_eq!(action_for(key(KeyCode::BackTab)), Some(Action::Select(-1)));
+    assert_eq!(action_for(key(KeyCode::Char('x'))), None);
+
+    let mut editor = Editor { palette: Palette::builtin("gray").unwrap(), selected: 0 };
+    editor.apply(Action::Select(-1));
+    assert_eq!(editor.selected, 1);
+    // 端の段階は 0 から 1 の外にも、隣の段階の先にも動かない
+    editor.apply(Action::Move(MOVE_STEP));
+    assert_eq!(editor.palette.stops[1].position, 1.0);
+    editor.apply(Action::Add);
+    assert_eq!((editor.selected, editor.palette.stops[1].position), (1, 0.5));
+    editor.apply(Action::Move(-0.7));
+    assert_eq!(editor.palette.stops[1].position, 0.0);
+    for _ in 0 .. 3 {
+        editor.apply(Action::Move(MOVE_STEP));
+    }
+    assert_eq!(editor.palette.stops[1].position, 0.03);
+    editor.apply(Action::Channel(0, CHANNEL_STEP * 40));
+    assert_eq!(editor.palette.stops[1].color[0], 255);
+    editor.apply(Action::Space);
+    assert_eq!(editor.space(), InterpSpace::Lch);
+    assert!(editor.describe().starts_with("stop 2/3 at 0.0300 #ff"));
+
+    editor.apply(Action::Delete);
+    editor.apply(Action::Delete);
+    assert_eq!((editor.selected, editor.palette.stops.len()), (1, 2));
+    assert_eq!(Palette::parse(&editor.palette.to_toml()), Ok(editor.palette));
+}
+
+/// `palette edit` のオプション
+#[derive(Debug)]
+struct EditOptions {
+    /// 書き出すファイル。あれば最初に読む
+    path: String,
+    /// ファイルが無いときに始めるパレット
+    from: String,
+    center: Complex<f64>,
+    zoom: f64,
+    params: RenderParams
+}
+
+fn parse_edit_args(args: &[String]) -> Result<EditOptions, String> {
+    let mut options = EditOptions {
+        path: String::new(),
+        from: "ultra".to_string(),
+        center: Complex { re: -0.5, im: 0.0 },
+        zoom: 1.0,
+        params: RenderParams::default()
+    };
+    let mut rest = vec![];
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().map(String::as_str).ok_or(format!("{} expects a value", arg));
+        match arg.as_str() {
+            "--from" => options.from = value()?.to_string(),
+            "--center" => options.center = parse_complex(value()?).ok_or("--center expects RE,IM")?,
+            "--zoom" => {
+                options.zoom = f64::from_str(value()?).ok().filter(|zoom| *zoom > 0.0 && zoom.is_finite())
+                    .ok_or("--zoom expects a positive number")?;
+            }
+            path if options.path.is_empty() && path.ends_with(".toml") => options.path = path.to_string(),
+            _ => rest.push(arg.clone())
+        }
+    }
+    if options.path.is_empty() {
+        return Err("palette edit expects FILE.toml".to_string());
+    }
+    options.params = parse_params(&rest)?;
+    Ok(options)
+}
+
+#[test]
+fn test_parse_edit_args() {
+    let args = |s: &str| -> Vec<String> { s.split_whitespace().map(String::from).collect() };
+    let options = parse_edit_args(&args("mine.toml --from fire --zoom 8 --passes 64")).unwrap();
+    assert_eq!((options.path.as_str(), options.from.as_str(), options.zoom), ("mine.toml", "fire", 8.0));
+    assert_eq!(options.params.limits, vec![64]);
+    assert!(parse_edit_args(&args("--from fire")).is_err());
+    assert!(parse_edit_args(&args("mine.toml --zoom -1")).is_err());
+}
+
+/// `palette edit FILE.toml [--from NAME|FILE.toml] [--center RE,IM] [--zoom Z] [OPTIONS]` サブコマンド
+pub fn run_palette(args: &[String]) -> Result<(), Failure> {
+    match args.first().map(String::as_str) {
+        Some("edit") => {}
+        _ => return Err(Failure::Usage("usage: palette edit FILE.toml [--from NAME|FILE.toml] [--center RE,IM] [--zoom Z]"
+                                       .to_string()))
+    }
+    let options = parse_edit_args(&args[1 ..]).map_err(Failure::Usage)?;
+    let palette = if Path::new(&options.path).exists() {
+        Palette::load(&options.path)
+    } else {
+        Palette::load(&options.from)
+    }.map_err(Failure::Runtime)?;
+    let error = |e: io::Error| format!("terminal error: {}", e);
+
+    terminal::enable_raw_mode().map_err(failed("terminal error"))?;
+    let mut stdout = io::stdout();
+    let result = execute!(stdout, terminal::EnterAlternateScreen, cursor::Hide)
+        .map_err(error)
+        .and_then(|_| edit(&mut stdout, &options, Editor { palette, selected: 0 }));
+    // 途中で失敗しても端末は元に戻す
+    let _ = execute!(stdout, ResetColor, cursor::Show, terminal::LeaveAlternateScreen);
+    let _ = terminal::disable_raw_mode();
+    result.map_err(Failure::Runtime)
+}
+
+fn edit<W: Write>(output: &mut W, options: &EditOptions, mut editor: Editor) -> Result<(), String> {
+    let error = |e: io::Error| format!("terminal error: {}", e);
+    let mut status = "tab: select  arrows: move  r/g/b R/G/B: color  a/d: add/delete  i: space  w: save  q: quit"
+        .to_string();
+    let mut sample: Option<((usize, usize), Vec<u8>)> = None;
+    loop {
+        let (columns, lines) = terminal::size().map_err(error)?;
+        let bounds = (columns.max(1) as usize, (lines as usize).saturating_sub(FOOTER_LINES).max(1) * 2);
+        if sample.as_ref().is_none_or(|(sampled, _)| *sampled != bounds) {
+            let (upper_left, lower_right) = region_from_center(options.center, options.zoom, bounds);
+            let mut pixels = vec![0; bounds.0 * bounds.1];
+            render_parallel(&mut pixels, bounds, upper_left, lower_right, &options.params);
+            sample = Some((bounds, pixels));
+        }
+        let (_, pixels) = sample.as_ref().unwrap();
+        let line = format!("{}  {}", editor.describe(), status);
+        draw(output, pixels, bounds, &editor, &line).map_err(error)?;
+
+        let key = match event::read().map_err(error)? {
+            Event::Key(key) if key.kind != KeyEventKind::Release => key,
+            _ => continue
+        };
+        match action_for(key) {
+            Some(Action::Save) => {
+                status = match fs::write(&options.path, editor.palette.to_toml()) {
+                    Ok(()) => format!("saved {}", options.path),
+                    Err(e) => format!("cannot save {}: {}", options.path, e)
+                };
+            }
+            Some(Action::Quit) => return Ok(()),
+            Some(action) => editor.apply(action),
+            None => {}
+        }
+    }
+}
+
+fn rgb([r, g, b]: [u8; 3]) -> Color {
+    Color::Rgb { r, g, b }
+}
+
+/// 見本の範囲をパレットの色で、その下にグラデーションの帯と段階の印と `status` を描く
+fn draw<W: Write>(output: &mut W, pixels: &[u8], bounds: (usize, usize), editor: &Editor, status: &str)
+    -> io::Result<()>
+{
+    let lut = editor.palette.lut(editor.space());
+    queue!(output, cursor::MoveTo(0, 0))?;
+    for line in 0 .. bounds.1 / 2 {
+        if line > 0 {
+            queue!(output, cursor::MoveToNextLine(1))?;
+        }
+        let upper = &pixels[line * 2 * bounds.0 .. (line * 2 + 1) * bounds.0];
+        let lower = &pixels[(line * 2 + 1) * bounds.0 .. (line * 2 + 2) * bounds.0];
+        // 色が変わるときだけエスケープシーケンスを出して、送る量を減らす
+        let mut previous = None;
+        for (&top, &bottom) in upper.iter().zip(lower) {
+            if previous != Some((top, bottom)) {
+                queue!(output, SetForegroundColor(rgb(lut.0[top as usize])),
+                       SetBackgroundColor(rgb(lut.0[bottom as usize])))?;
+                previous = Some((top, bottom));
+            }
+            queue!(output, Print('▀'))?;
+        }
+    }
+
+    // 帯は左端が位置 0 (すぐに発散した点)、右端が位置 1 (内部)
+    let width = bounds.0;
+    let column_of = |position: f64| (position * (width - 1) as f64).round() as usize;
+    queue!(output, cursor::MoveToNextLine(1))?;
+    for column in 0 .. width {
+        let t = if width > 1 { column as f64 / (width - 1) as f64 } else { 0.0 };
+        queue!(output, SetBackgroundColor(rgb(editor.palette.sample(t, editor.space()))), Print(' '))?;
+    }
+    let mut marks = vec![' '; width];
+    for (index, stop) in editor.palette.stops.iter().enumerate() {
+        let mark = &mut marks[column_of(stop.position)];
+        if *mark != '▲' {
+            *mark = if index == editor.selected { '▲' } else { '^' };
+        }
+    }
+    queue!(output, ResetColor, cursor::MoveToNextLine(1), Print(marks.iter().collect::<String>()),
+           cursor::MoveToNextLine(1), terminal::Clear(terminal::ClearType::CurrentLine), Print(status))?;
+    output.flush()
+}
+
+#[test]
+fn test_draw() {
+    let editor = Editor { palette: Palette::builtin("fire").unwrap(), selected: 2 };
+    let mut screen = vec![];
+    draw(&mut screen, &[0, 255, 255, 0, 128, 128, 128, 128], (4, 2), &editor, "ready").unwrap();
+    let screen = String::from_utf8(screen).unwrap();
+    assert_eq!(screen.matches('▀').count(), 4);
+    // 5つの段階のうち 0.95 と 1.0 は同じ列になる
+    assert!(screen.contains("^^▲^"));
+    assert!(screen.ends_with("ready"));
+}
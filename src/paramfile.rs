@@ -0,0 +1,255 @@
+//! 他のソフトウェアの位置ファイルを拡張子で見分けて読み書きする
+//!
+//! - `.kfr`: Kalles Fraktaler (`kfr.rs`)
+//! - `.upr`: UltraFractal のパラメータ。`mapping:` の `center=RE/IM magn=M` と `formula:` の `maxiter=N` を使う。
+//!   UltraFractal の倍率 1 は画像の高さが 3 になる
+//! - `.par`: Fractint のパラメータ。最初の項目の `center-mag=RE/IM/MAG` か `corners=XMIN/XMAX/YMIN/YMAX` と
+//!   `maxiter=N` を使う。Fractint の倍率 1 は画像の高さが 2 になる
+//!
+//! どの形式でも式は Mandelbrot 集合のものだけを読み、回転や傾きのある位置は範囲の長方形で表せないので読み込まない。
+//! 読んだ位置は `kfr::Location` にまとめ、反復回数は `--passes` に対応させる。
+//! `convert-params IN OUT` サブコマンドで形式を変換できる。
+
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use num::Complex;
+
+use super::kfr::Location;
+
+/// 位置ファイルの形式
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Format {
+    Kfr,
+    Upr,
+    Par
+}
+
+impl Format {
+    fn of(path: &str) -> Result<Format, String> {
+        match Path::new(path).extension().and_then(|e| e.to_str()).map(str::to_ascii_lowercase).as_deref() {
+            Some("kfr") => Ok(Format::Kfr),
+            Some("upr") => Ok(Format::Upr),
+            Some("par") => Ok(Format::Par),
+            _ => Err(format!("unknown location file format (expected .kfr, .upr or .par): {}", path))
+        }
+    }
+}
+
+/// 位置ファイル `path` を読む
+pub fn load(path: &str) -> Result<Location, String> {
+    let format = Format::of(path)?;
+    let text = fs::read_to_string(path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+    let location = match format {
+        Format::Kfr => text.parse(),
+        Format::Upr => parse_upr(&text),
+        Format::Par => parse_par(&text)
+    };
+    location.map_err(|e| format!("invalid location file {}: {}", path, e))
+}
+
+/// 位置 `location` を `path` の拡張子の形式で書く。UltraFractal と Fractint の項目名はファイル名から取る
+pub fn save(path: &str, location: &Location) -> Result<(), String> {
+    let name = Path::new(path).file_stem().and_then(|s| s.to_str()).unwrap_or("location");
+    let text = match Format::of(path)? {
+        Format::Kfr => location.kfr_text(),
+        Format::Upr => upr_text(name, location),
+        Format::Par => par_text(name, location)
+    };
+    fs::write(path, text).map_err(|e| format!("cannot write {}: {}", path, e))
+}
+
+/// `{` と `}` で囲まれた最初の項目の `key=value` を並べる。`"` で囲んだ値は空白を含められ、
+/// `;` から行末までは注釈。`section:` の形の語は、続く値がどの節のものかを表す
+fn entry_fields(text: &str) -> Result<Vec<(String, String, String)>, String> {
+    let body = text.lines().map(strip_comment).collect::<Vec<_>>().join("\n");
+    let start = body.find('{').ok_or("no { in parameter file")?;
+    let end = body[start ..].find('}').map(|end| start + end).ok_or("no } in parameter file")?;
+
+    let mut fields = vec![];
+    let mut section = String::new();
+    let mut chars = body[start + 1 .. end].chars().peekable();
+    loop {
+        while chars.peek().is_some_and(|c| c.is_whitespace()) {
+            chars.next();
+        }
+        if chars.peek().is_none() {
+            return Ok(fields);
+        }
+        let mut word = String::new();
+        let mut quoted = false;
+        while let Some(&c) = chars.peek() {
+            if c == '"' {
+                quoted = !quoted;
+            } else if c.is_whitespace() && !quoted {
+                break;
+            } else {
+                word.push(c);
+            }
+            chars.next();
+        }
+        match word.split_once('=') {
+            Some((key, value)) => fields.push((section.clone(), key.to_ascii_lowercase(), value.to_string())),
+            None if word.ends_with(':') => section = word.trim_end_matches(':').to_ascii_lowercase(),
+            None => return Err(format!("unexpected word: {}", word))
+        }
+    }
+}
+
+/// `"` の外の `;` から行末を取り除く
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ';' if !quoted => return &line[.. i],
+            _ => {}
+        }
+    }
+    line
+}
+
+/// `/` で区切った数の並び
+fn numbers(key: &str, value: &str) -> Result<Vec<f64>, String> {
+    value.split('/').map(|n| f64::from_str(n).map_err(|_| format!("invalid {}: {}", key, value))).collect()
+}
+
+fn iterations(value: &str) -> Result<u32, String> {
+    u32::from_str(value).ok().filter(|n| *n > 0).ok_or_else(|| format!("maxiter must be a positive integer: {}", value))
+}
+
+/// 画像の高さ `height` の位置
+fn location(center: Complex<f64>, height: f64, iterations: u32) -> Result<Location, String> {
+    if !(height > 0.0 && height.is_finite()) {
+        return Err(format!("invalid magnification (height {})", height));
+    }
+    Ok(Location { center, zoom: 4.0 / height, iterations })
+}
+
+pub fn parse_upr(text: &str) -> Result<Location, String> {
+    let (mut center, mut magnification, mut iterations) = (None, None, None);
+    for (section, key, value) in entry_fields(text)? {
+        match (section.as_str(), key.as_str()) {
+            ("mapping", "center") => match numbers(&key, &value)?[..] {
+                [re, im] => center = Some(Complex { re, im }),
+                _ => return Err(format!("center expects RE/IM: {}", value))
+            },
+            ("mapping", "magn") => magnification = Some(numbers(&key, &value)?[0]),
+            ("mapping", "angle") if numbers(&key, &value)?[0] != 0.0 => {
+                return Err("rotated locations are not supported".to_string());
+            }
+            ("formula", "maxiter") => iterations = Some(self::iterations(&value)?),
+            ("formula", "entry") if value != "Mandelbrot" => {
+                return Err(format!("only the Mandelbrot formula is supported: {}", value));
+            }
+            _ => {}
+        }
+    }
+    let center = center.ok_or("missing center")?;
+    let height = 3.0 / magnification.ok_or("missing magn")?;
+    location(center, height, iterations.ok_or("missing maxiter")?)
+}
+
+fn upr_text(name: &str, location: &Location) -> String {
+    let height = 4.0 / location.zoom;
+    format!("{name} {{\nfractal:\n  title=\"{name}\" layers=1\nmapping:\n  center={}/{} magn={}\n\
+             formula:\n  maxiter={} filename=\"Standard.ufm\" entry=\"Mandelbrot\"\n}}\n",
+            location.center.re, location.center.im, 3.0 / height, location.iterations, name = name)
+}
+
+pub fn parse_par(text: &str) -> Result<Location, String> {
+    let (mut view, mut iterations) = (None, None);
+    for (_, key, value) in entry_fields(text)? {
+        match key.as_str() {
+            "type" if value != "mandel" => return Err(format!("only type=mandel is supported: {}", value)),
+            "center-mag" => {
+                let numbers = numbers(&key, &value)?;
+                // 4つ目以降は縦横の倍率の比、回転、傾き
+                let extra = numbers.get(3 ..).unwrap_or(&[]);
+                if numbers.len() < 3 || extra.first().is_some_and(|&x| x != 1.0)
+                    || extra.iter().skip(1).any(|&x| x != 0.0) {
+                    return Err(format!("center-mag expects X/Y/MAG without stretch or rotation: {}", value));
+                }
+                view = Some((Complex { re: numbers[0], im: numbers[1] }, 2.0 / numbers[2]));
+            }
+            "corners" => match numbers(&key, &value)?[..] {
+                [x_min, x_max, y_min, y_max] => {
+                    view = Some((Complex { re: (x_min + x_max) / 2.0, im: (y_min + y_max) / 2.0 }, y_max - y_min));
+                }
+                _ => return Err(format!("corners expects XMIN/XMAX/YMIN/YMAX without rotation: {}", value))
+            },
+            "maxiter" => iterations = Some(self::iterations(&value)?),
+            _ => {}
+        }
+    }
+    let (center, height) = view.ok_or("missing center-mag or corners")?;
+    // Fractint の maxiter の既定値
+    location(center, height, iterations.unwrap_or(150))
+}
+
+fn par_text(name: &str, location: &Location) -> String {
+    let height = 4.0 / location.zoom;
+    format!("{} {{\n  reset=2004 type=mandel\n  center-mag={}/{}/{} maxiter={}\n  }}\n",
+            name, location.center.re, location.center.im, 2.0 / height, location.iterations)
+}
+
+#[test]
+fn test_parse_upr() {
+    let location = parse_upr(r#"
+        Deep {
+        fractal:
+          title="Deep spiral" width=640 height=480 layers=1
+          credits="someone;1/1/2020"
+        layer:
+          caption="Background" opacity=100
+        mapping:
+          center=-0.75/0.1 magn=12 angle=0
+        formula:
+          maxiter=2500 filename="Standard.ufm" entry="Mandelbrot"
+        inside:
+          transfer=none
+        outside:
+          transfer=linear filename="Standard.ucl" entry="Smooth"
+        }
+    "#).unwrap();
+    assert_eq!(location, Location { center: Complex { re: -0.75, im: 0.1 }, zoom: 16.0, iterations: 2500 });
+    assert_eq!(parse_upr(&upr_text("deep", &location)), Ok(location));
+
+    assert!(parse_upr("x {\nmapping:\n  center=0/0 magn=1\nformula:\n  maxiter=10 entry=\"Julia\"\n}").is_err());
+    assert!(parse_upr("x {\nmapping:\n  center=0/0 magn=1 angle=30\nformula:\n  maxiter=10\n}").is_err());
+    assert!(parse_upr("x {\nmapping:\n  center=0/0\nformula:\n  maxiter=10\n}").is_err());
+}
+
+#[test]
+fn test_parse_par() {
+    let par = "; a comment\nSpiral { ; the first entry\n  reset=2004 type=mandel\n  \
+               center-mag=-0.75/0.1/8 maxiter=500 inside=0\n  }\nOther {\n  type=julia\n  }\n";
+    let location = parse_par(par).unwrap();
+    assert_eq!(location, Location { center: Complex { re: -0.75, im: 0.1 }, zoom: 16.0, iterations: 500 });
+    assert_eq!(parse_par(&par_text("spiral", &location)), Ok(location));
+    assert_eq!(parse_par("Whole {\n  type=mandel corners=-2.5/1.5/-1.5/1.5\n  }"),
+               Ok(Location { center: Complex { re: -0.5, im: 0.0 }, zoom: 4.0 / 3.0, iterations: 150 }));
+    assert_eq!(parse_par("x { center-mag=0/0/1/1/0/0 }").map(|l| l.zoom), Ok(2.0));
+
+    assert!(parse_par("x { type=julia center-mag=0/0/1 }").is_err());
+    assert!(parse_par("x { center-mag=0/0/1/1/45 }").is_err());
+    assert!(parse_par("x { center-mag=0/0/1/1.5 }").is_err());
+    assert!(parse_par("x { corners=0/1/0/1/0/0 }").is_err());
+    assert!(parse_par("x { maxiter=10 }").is_err());
+    assert!(parse_par("type=mandel").is_err());
+}
+
+/// 位置ファイル `input` を読み、`output` の拡張子の形式で書き出す。`convert-params IN OUT` サブコマンド
+pub fn convert(input: &str, output: &str) -> Result<(), String> {
+    save(output, &load(input)?)
+}
+
+#[test]
+fn test_format_of() {
+    assert_eq!(Format::of("a/b.KFR"), Ok(Format::Kfr));
+    assert_eq!(Format::of("b.upr"), Ok(Format::Upr));
+    assert_eq!(Format::of("c.par"), Ok(Format::Par));
+    assert!(Format::of("d.txt").is_err());
+    assert!(Format::of("par").is_err());
+}
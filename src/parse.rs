@@ -0,0 +1,88 @@
+//! コマンドラインや位置ファイルから受け取る、座標の組や番号の範囲などの短い文字列の解析
+//!
+//! どれも壊れた入力には `None` を返し、パニックしない。fuzz のターゲットからも呼べるようライブラリに置く。
+
+use std::str::FromStr;
+
+use num::Complex;
+
+/// `s` を `separator` で区切った2つの値の組として解析する
+pub fn parse_pair<T: FromStr>(s: &str, separator: char) -> Option<(T, T)> {
+    match s.find(separator) {
+        None => None,
+        Some(index) => {
+            match (T::from_str(&s[..index]), T::from_str(&s[index + 1..])) {
+                // find(separator)した結果、区切り文字で分割してどちらも期待する型にマッチしてOだった場合
+                (Ok(l), Ok(r)) => Some((l, r)),
+                // 上記マッチパターンに入らなかったワイルドカードパターン_
+                _ => None
+            }
+        }
+    }
+}
+
+#[test]
+fn test_parse_pair() {
+    assert_eq!(parse_pair::<i32>("",        ','), None);
+    assert_eq!(parse_pair::<i32>("10,",     ','), None);
+    assert_eq!(parse_pair::<i32>(",10",     ','), None);
+    assert_eq!(parse_pair::<i32>("10,20",   ','), Some((10, 20)));
+    assert_eq!(parse_pair::<i32>("10,20xy", ','), None);
+    assert_eq!(parse_pair::<f64>("0.5x",    'x'), None);
+    assert_eq!(parse_pair::<f64>("0.5x1.5", 'x'), Some((0.5, 1.5)));
+}
+
+/// `s` を `separator` で区切った値のリストとして解析する
+pub fn parse_list<T: FromStr>(s: &str, separator: char) -> Option<Vec<T>> {
+    s.split(separator).map(|item| T::from_str(item).ok()).collect()
+}
+
+#[test]
+fn test_parse_list() {
+    assert_eq!(parse_list::<u32>("256",        ','), Some(vec![256]));
+    assert_eq!(parse_list::<u32>("256,1024",   ','), Some(vec![256, 1024]));
+    assert_eq!(parse_list::<u32>("256,,1024",  ','), None);
+    assert_eq!(parse_list::<u32>("",           ','), None);
+}
+
+/// `parse_ranges` で展開する番号の数の上限。桁の多い範囲で巨大な列を作らないようにする
+const MAX_RANGE_NUMBERS: usize = 1 << 16;
+
+/// `0-3,8,10-11` のような番号と範囲のリストを番号の列に展開する
+pub fn parse_ranges(s: &str) -> Option<Vec<usize>> {
+    let mut numbers = vec![];
+    for item in s.split(',') {
+        match parse_pair::<usize>(item, '-') {
+            Some((first, last)) if first <= last && last - first < MAX_RANGE_NUMBERS.saturating_sub(numbers.len()) => {
+                numbers.extend(first ..= last)
+            }
+            Some(_) => return None,
+            None => numbers.push(usize::from_str(item).ok()?)
+        }
+    }
+    Some(numbers)
+}
+
+#[test]
+fn test_parse_ranges() {
+    assert_eq!(parse_ranges("0-3"),         Some(vec![0, 1, 2, 3]));
+    assert_eq!(parse_ranges("0-1,8,10-11"), Some(vec![0, 1, 8, 10, 11]));
+    assert_eq!(parse_ranges("5"),           Some(vec![5]));
+    assert_eq!(parse_ranges("3-1"),         None);
+    assert_eq!(parse_ranges("0-"),          None);
+    assert_eq!(parse_ranges(""),            None);
+    assert_eq!(parse_ranges("0-18446744073709551615"), None);
+}
+
+/// `re,im` の形の複素数を解析する
+pub fn parse_complex(s: &str) -> Option<Complex<f64>> {
+    parse_pair(s, ',').map(|(re, im)| Complex { re, im })
+}
+
+#[test]
+fn test_parse_complex() {
+    assert_eq!(parse_complex("1.25,-0.0625"),
+               Some(Complex { re: 1.25, im: -0.0625}));
+    assert_eq!(parse_complex(",-0.0625)"),
+               None);
+}
@@ -0,0 +1,167 @@
+//! 共有ライブラリで配布する式のプラグイン `--plugin path.so`
+//!
+//! プラグインは次の3つの C の関数を公開する。ABI はこの3つと `PluginDescriptor` のレイアウトだけで、
+//! `PLUGIN_ABI_VERSION` を上げない限り変えない。Rust で書く例は `examples/plugin_formula.rs`。
+//!
+//! ```c
+//! /* PLUGIN_ABI_VERSION (2)。他の関数より先に呼び、違えば他の関数は呼ばない */
+//! uint32_t plugin_abi_version(void);
+//!
+//! struct PluginDescriptor {
+//!     const char *name;      /* NUL 終端の UTF-8。プラグインを閉じるまで有効であること */
+//!     double bailout;        /* --bailout を与えなかったときの脱出半径 */
+//! };
+//! struct PluginDescriptor plugin_descriptor(void);
+//!
+//! /* z_n と c から z_{n+1} を求めて out[0] (実部) と out[1] (虚部) に書く。
+//!    複数のスレッドから同時に呼ばれるので、状態を持たないこと */
+//! void iterate(double z_re, double z_im, double c_re, double c_im, double *out);
+//! ```
+//!
+//! 軌道は `z_0 = 0` から始め、脱出判定は組み込みの式と同じく `--bailout` と `--bailout-norm` で行う。
+//! バージョンは構造体を値で受け取る前に別の関数で確かめるので、レイアウトの違う版の構造体を読むことはない。
+//! ABI 1 のプラグインは `plugin_abi_version` を持たないので読み込まない。
+//!
+//! 読み込んだライブラリはプロセスの終わりまで閉じず、パス毎に一度だけ開く。一括描画のジョブ毎に
+//! オプションを読み直しても、同じライブラリを開き直したり名前を複製したりしない。
+
+use std::ffi::CStr;
+use std::os::raw::c_char;
+use std::sync::Mutex;
+
+use libloading::Library;
+use num::Complex;
+
+use super::fractal::Fractal;
+
+/// このバージョンのプラグインだけを読み込む
+pub const PLUGIN_ABI_VERSION: u32 = 2;
+
+/// プラグインが `plugin_descriptor` で返す説明
+#[repr(C)]
+pub struct PluginDescriptor {
+    pub name: *const c_char,
+    pub bailout: f64
+}
+
+/// 読み込んだプラグインと、そのパス
+static LOADED: Mutex<Vec<(String, Plugin)>> = Mutex::new(Vec::new());
+
+/// プラグインの `iterate`
+type Iterate = unsafe extern "C" fn(f64, f64, f64, f64, *mut f64);
+
+/// 読み込んだプラグインの式
+#[derive(Clone, Copy, Debug)]
+pub struct Plugin {
+    pub name: &'static str,
+    pub bailout: f64,
+    iterate: Iterate
+}
+
+impl PartialEq for Plugin {
+    fn eq(&self, other: &Plugin) -> bool {
+        // 関数ポインタの比較は当てにならないので、同じ名前の同じプラグインとみなす
+        self.name == other.name && self.bailout == other.bailout
+    }
+}
+
+impl Plugin {
+    /// 共有ライブラリ `path` を読み込む。同じパスを前に読み込んでいればそれを返す
+    pub fn open(path: &str) -> Result<Plugin, String> {
+        // 同じパスを同時に開かないよう、読み込む間もロックしておく
+        let mut loaded = LOADED.lock().unwrap();
+        if let Some(&(_, plugin)) = loaded.iter().find(|(loaded, _)| loaded == path) {
+            return Ok(plugin);
+        }
+        let plugin = Plugin::load(path)?;
+        loaded.push((path.to_string(), plugin));
+        Ok(plugin)
+    }
+
+    fn load(path: &str) -> Result<Plugin, String> {
+        let error = |e: libloading::Error| format!("cannot load plugin {}: {}", path, e);
+        let invalid = |e: String| format!("invalid plugin {}: {}", path, e);
+        // Safety: 初期化処理を含めて、ライブラリが上の ABI を守っていると信じるしかない
+        let library = unsafe { Library::new(path) }.map_err(error)?;
+        let version = unsafe {
+            let version = library.get::<unsafe extern "C" fn() -> u32>(b"plugin_abi_version\0")
+                .map_err(|_| invalid(format!("no plugin_abi_version, expected ABI version {}", PLUGIN_ABI_VERSION)))?;
+            version()
+        };
+        check_abi_version(version).map_err(invalid)?;
+        let (descriptor, iterate) = unsafe {
+            let describe = library.get::<unsafe extern "C" fn() -> PluginDescriptor>(b"plugin_descriptor\0")
+                .map_err(error)?;
+            let iterate = library.get::<Iterate>(b"iterate\0").map_err(error)?;
+            (describe(), *iterate)
+        };
+        let plugin = unsafe { Plugin::from_descriptor(&descriptor, iterate) }.map_err(invalid)?;
+        // iterate を呼べるよう、ライブラリは閉じずに残す
+        std::mem::forget(library);
+        Ok(plugin)
+    }
+
+    /// `descriptor` を確かめて `Plugin` にする
+    ///
+    /// # Safety
+    /// `descriptor.name` は NULL か、NUL 終端の文字列を指していること
+    unsafe fn from_descriptor(descriptor: &PluginDescriptor, iterate: Iterate) -> Result<Plugin, String> {
+        if descriptor.name.is_null() {
+            return Err("name is null".to_string());
+        }
+        let name = CStr::from_ptr(descriptor.name).to_str().map_err(|_| "name is not UTF-8")?;
+        if !(descriptor.bailout > 0.0 && descriptor.bailout.is_finite()) {
+            return Err("bailout must be a positive number".to_string());
+        }
+        Ok(Plugin {
+            name: Box::leak(name.to_string().into_boxed_str()),
+            bailout: descriptor.bailout,
+            iterate
+        })
+    }
+}
+
+/// `plugin_abi_version` が返したバージョン `version` を読み込めるか
+fn check_abi_version(version: u32) -> Result<(), String> {
+    if version != PLUGIN_ABI_VERSION {
+        return Err(format!("ABI version {} is not supported, expected {}", version, PLUGIN_ABI_VERSION));
+    }
+    Ok(())
+}
+
+impl Fractal for Plugin {
+    #[inline(always)]
+    fn step(&self, z: Complex<f64>, c: Complex<f64>) -> Complex<f64> {
+        let mut out = [0.0; 2];
+        // Safety: out は2つの f64 を書ける
+        unsafe { (self.iterate)(z.re, z.im, c.re, c.im, out.as_mut_ptr()) };
+        Complex { re: out[0], im: out[1] }
+    }
+}
+
+#[cfg(test)]
+unsafe extern "C" fn iterate_conjugate(z_re: f64, z_im: f64, c_re: f64, c_im: f64, out: *mut f64) {
+    // Tricorn: conj(z)^2 + c
+    *out = z_re * z_re - z_im * z_im + c_re;
+    *out.add(1) = -2.0 * z_re * z_im + c_im;
+}
+
+#[test]
+fn test_plugin_descriptor() {
+    let descriptor = |name: &'static [u8], bailout| PluginDescriptor { name: name.as_ptr() as *const c_char, bailout };
+    let plugin = unsafe { Plugin::from_descriptor(&descriptor(b"tricorn\0", 4.0), iterate_conjugate) }.unwrap();
+    assert_eq!((plugin.name, plugin.bailout), ("tricorn", 4.0));
+    let z = Complex { re: 1.0, im: 2.0 };
+    assert_eq!(plugin.step(z, Complex { re: 0.5, im: 0.0 }), Complex { re: -2.5, im: -4.0 });
+
+    assert!(check_abi_version(PLUGIN_ABI_VERSION).is_ok());
+    assert!(check_abi_version(1).is_err() && check_abi_version(3).is_err());
+    assert!(unsafe { Plugin::from_descriptor(&descriptor(b"tricorn\0", 0.0), iterate_conjugate) }.is_err());
+    assert!(unsafe { Plugin::from_descriptor(&descriptor(b"\xff\0", 4.0), iterate_conjugate) }.is_err());
+    assert!(Plugin::open("/nonexistent/plugin.so").is_err());
+
+    // 一度読み込んだパスはライブラリを開き直さずに同じプラグインを返す
+    LOADED.lock().unwrap().push(("/cached/tricorn.so".to_string(), plugin));
+    assert_eq!(Plugin::open("/cached/tricorn.so"), Ok(plugin));
+    assert!(std::ptr::eq(Plugin::open("/cached/tricorn.so").unwrap().name, plugin.name));
+}
@@ -0,0 +1,255 @@
+//! f64 では刻みが足りないタイルを倍々精度 (double-double) で描く
+//!
+//! 倍々精度は2つの f64 の和 `hi + lo` で約 106 ビットの仮数を表す。描画の前に `estimate::tile_resolves` で
+//! `PRECISION_TILE` ピクセル四方のタイル毎に f64 で足りるかを調べ、足りないタイルのピクセルだけを倍々精度で反復する。
+//! 足りるタイルは今までどおり f64 で描くので、浅いところの速さは変わらない。
+//!
+//! 倍々精度で描けるのはマンデルブロ集合を `plane` の写像で描き、発散までの回数で色付けするときだけ。
+//! 他の式や写像、色付けでは足りないタイルも f64 で描く。倍々精度でも足りないほど深いタイルのための
+//! 摂動法はまだ無い。
+
+use std::ops::{Add, Mul, Sub};
+use std::ops::Range;
+
+use num::Complex;
+
+use super::estimate::{tile_resolves, PRECISION_TILE};
+use super::{projection, FractalKind, RenderParams, Termination, INTERIOR_EPSILON};
+
+/// 倍々精度の刻み
+pub const EPSILON: f64 = f64::EPSILON * f64::EPSILON;
+
+/// `hi + lo` で表す倍々精度の数。`lo` は `hi` の最後の桁の半分より小さい
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct DoubleDouble {
+    hi: f64,
+    lo: f64
+}
+
+impl DoubleDouble {
+    fn from_f64(value: f64) -> DoubleDouble {
+        DoubleDouble { hi: value, lo: 0.0 }
+    }
+
+    /// `a + b` を丸めずに表す
+    fn two_sum(a: f64, b: f64) -> DoubleDouble {
+        let hi = a + b;
+        let b_part = hi - a;
+        DoubleDouble { hi, lo: (a - (hi - b_part)) + (b - b_part) }
+    }
+
+    /// `|a| >= |b|` のときの `two_sum`
+    fn quick_two_sum(a: f64, b: f64) -> DoubleDouble {
+        let hi = a + b;
+        DoubleDouble { hi, lo: b - (hi - a) }
+    }
+
+    fn mul_f64(self, other: f64) -> DoubleDouble {
+        let hi = self.hi * other;
+        let lo = self.hi.mul_add(other, -hi) + self.lo * other;
+        DoubleDouble::quick_two_sum(hi, lo)
+    }
+
+    fn div_f64(self, other: f64) -> DoubleDouble {
+        let quotient = self.hi / other;
+        let remainder = self - DoubleDouble::from_f64(quotient).mul_f64(other);
+        DoubleDouble::quick_two_sum(quotient, remainder.hi / other)
+    }
+}
+
+impl Add for DoubleDouble {
+    type Output = DoubleDouble;
+
+    fn add(self, other: DoubleDouble) -> DoubleDouble {
+        let high = DoubleDouble::two_sum(self.hi, other.hi);
+        let low = DoubleDouble::two_sum(self.lo, other.lo);
+        let sum = DoubleDouble::quick_two_sum(high.hi, high.lo + low.hi);
+        DoubleDouble::quick_two_sum(sum.hi, sum.lo + low.lo)
+    }
+}
+
+impl Sub for DoubleDouble {
+    type Output = DoubleDouble;
+
+    fn sub(self, other: DoubleDouble) -> DoubleDouble {
+        self + DoubleDouble { hi: -other.hi, lo: -other.lo }
+    }
+}
+
+impl Mul for DoubleDouble {
+    type Output = DoubleDouble;
+
+    fn mul(self, other: DoubleDouble) -> DoubleDouble {
+        let hi = self.hi * other.hi;
+        let lo = self.hi.mul_add(other.hi, -hi) + (self.hi * other.lo + self.lo * other.hi);
+        DoubleDouble::quick_two_sum(hi, lo)
+    }
+}
+
+/// 実部と虚部が倍々精度の複素数
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct DoubleComplex {
+    re: DoubleDouble,
+    im: DoubleDouble
+}
+
+impl DoubleComplex {
+    fn to_f64(self) -> Complex<f64> {
+        Complex { re: self.re.hi, im: self.im.hi }
+    }
+}
+
+/// 大きさ `bounds` の画像の `pixel` (列, 行) に対応する点を倍々精度で求める。`pixel_to_point` と同じ補間
+fn pixel_point(bounds: (usize, usize),
+               pixel: (usize, usize),
+               upper_left: Complex<f64>,
+               lower_right: Complex<f64>)
+    -> DoubleComplex
+{
+    // f64 どうしの差は倍々精度なら丸めずに表せる
+    let width = DoubleDouble::two_sum(lower_right.re, -upper_left.re);
+    let height = DoubleDouble::two_sum(upper_left.im, -lower_right.im);
+    DoubleComplex {
+        re: DoubleDouble::from_f64(upper_left.re) + width.mul_f64(pixel.0 as f64).div_f64(bounds.0 as f64),
+        im: DoubleDouble::from_f64(upper_left.im) - height.mul_f64(pixel.1 as f64).div_f64(bounds.1 as f64)
+    }
+}
+
+/// 点 `c` を倍々精度で `z^2 + c` で反復し、発散までの回数と行った反復の回数を返す。
+/// 脱出と内部の判定は f64 の `Orbit::advance` と同じで、どちらも f64 に丸めた `z` で足りる
+fn escape_time(c: DoubleComplex, limit: u32, termination: &Termination) -> (Option<u32>, u32) {
+    let mut z = DoubleComplex { re: DoubleDouble::from_f64(0.0), im: DoubleDouble::from_f64(0.0) };
+    let mut derivative = Complex { re: 1.0, im: 0.0 };
+    for i in 0 .. limit {
+        if termination.detect_interior && i > 0 {
+            derivative = derivative * z.to_f64() * 2.0;
+            if derivative.norm_sqr() < INTERIOR_EPSILON {
+                return (None, i);
+            }
+        }
+        let re_im = z.re * z.im;
+        z = DoubleComplex {
+            re: z.re * z.re - z.im * z.im + c.re,
+            im: re_im + re_im + c.im
+        };
+        if termination.escaped(z.to_f64()) {
+            return (Some(i), i + 1);
+        }
+    }
+    (None, limit)
+}
+
+/// `params` の描画を倍々精度でもできるか
+pub fn supports(params: &RenderParams) -> bool {
+    params.fractal == FractalKind::Mandelbrot && projection::is_plane(params) && !params.tracks_orbits()
+}
+
+/// 大きさ `bounds` の画像の `top` 行目から `rows` 行の帯のうち、f64 では描けないタイルに入るピクセルの
+/// 帯の中での添字の範囲
+pub fn double_spans(bounds: (usize, usize),
+                    top: usize,
+                    rows: usize,
+                    upper_left: Complex<f64>,
+                    lower_right: Complex<f64>)
+    -> Vec<Range<usize>>
+{
+    let mut spans = vec![];
+    for row in top .. top + rows {
+        for left in (0 .. bounds.0).step_by(PRECISION_TILE) {
+            let tile = (left, row / PRECISION_TILE * PRECISION_TILE);
+            if !tile_resolves(bounds, upper_left, lower_right, tile, f64::EPSILON) {
+                let start = (row - top) * bounds.0 + left;
+                spans.push(start .. start + PRECISION_TILE.min(bounds.0 - left));
+            }
+        }
+    }
+    spans
+}
+
+/// 大きさ `bounds` の画像の `top` 行目から始まる帯 `band` のうち `spans` のピクセルを倍々精度で描き、
+/// 行った反復の回数を返す
+pub fn render_spans(band: &mut [u8],
+                    spans: &[Range<usize>],
+                    bounds: (usize, usize),
+                    top: usize,
+                    upper_left: Complex<f64>,
+                    lower_right: Complex<f64>,
+                    params: &RenderParams)
+    -> u64
+{
+    let mut iterations = 0;
+    for span in spans {
+        for index in span.clone() {
+            let pixel = (index % bounds.0, top + index / bounds.0);
+            let c = pixel_point(bounds, pixel, upper_left, lower_right);
+            let (count, executed) = escape_time(c, params.limit(), &params.termination);
+            band[index] = params.shade(count);
+            iterations += executed as u64;
+        }
+    }
+    iterations
+}
+
+#[test]
+fn test_double_double() {
+    let one = DoubleDouble::from_f64(1.0);
+    let tiny = DoubleDouble::from_f64(1e-20);
+    // f64 では 1 + 1e-20 が 1 に丸まるが、倍々精度なら差が残る
+    assert_eq!((one + tiny - one).hi, 1e-20);
+    assert_eq!(((one + tiny) * (one + tiny) - one).hi, 2e-20);
+    let third = one.div_f64(3.0);
+    assert!((third.mul_f64(3.0) - one).hi.abs() < 1e-31);
+}
+
+#[test]
+fn test_escape_time_matches_f64() {
+    // 浅いところでは f64 と同じ回数で発散する
+    let params = RenderParams::default();
+    for &(re, im) in &[(0.3, 0.0), (-0.75, 0.1), (0.26, 0.0015), (-1.0, 0.0), (1.0, 1.0)] {
+        let point = Complex { re, im };
+        let mut orbit = super::Orbit::new(point);
+        let expected = orbit.advance(params.limit(), params.fractal, &params.termination);
+        let c = DoubleComplex { re: DoubleDouble::from_f64(re), im: DoubleDouble::from_f64(im) };
+        assert_eq!(escape_time(c, params.limit(), &params.termination), (expected, orbit.iteration));
+    }
+}
+
+#[test]
+fn test_double_spans() {
+    // 実部は 1e-4 と 2e-4 の間で f64 の刻みが足りなくなるので、右のタイルだけを倍々精度で描く
+    let (upper_left, lower_right) = (Complex { re: 0.0, im: 2e-18 }, Complex { re: 2e-4, im: 0.0 });
+    assert_eq!(double_spans((512, 4), 1, 2, upper_left, lower_right), vec![256 .. 512, 768 .. 1024]);
+    assert_eq!(double_spans((300, 4), 0, 1, upper_left, Complex { re: 1.5e-4, im: 0.0 }), vec![256 .. 300]);
+    let shallow = (Complex { re: -2.0, im: 1.0 }, Complex { re: 1.0, im: -1.0 });
+    assert!(double_spans((512, 4), 0, 4, shallow.0, shallow.1).is_empty());
+}
+
+#[test]
+fn test_render_deep_tile() {
+    // -2 から隣の f64 までを 16 列に分けると、f64 では列が2つの点に潰れて段が2つしか無い
+    let upper_left = Complex { re: -2.0, im: 2e-16 };
+    let lower_right = Complex { re: -2.0 + f64::EPSILON, im: 1e-16 };
+    let bounds = (16, 8);
+    let params = RenderParams::default();
+    assert!(supports(&params));
+    let mut flat = vec![0; bounds.0 * bounds.1];
+    super::render(&mut flat, bounds, upper_left, lower_right, &params);
+
+    let spans = double_spans(bounds, 0, bounds.1, upper_left, lower_right);
+    assert_eq!(spans.len(), bounds.1);
+    let mut deep = vec![0; bounds.0 * bounds.1];
+    assert!(render_spans(&mut deep, &spans, bounds, 0, upper_left, lower_right, &params) > 0);
+    let levels = |pixels: &[u8]| pixels[.. bounds.0].iter().collect::<std::collections::BTreeSet<_>>().len();
+    assert!(levels(&flat) == 2 && levels(&deep) > 2);
+    assert_eq!(deep[0], flat[0]);
+
+    // 帯に分けて並列に描くと、足りないタイルは倍々精度になる
+    let mut rendered = vec![0; bounds.0 * bounds.1];
+    super::render_parallel(&mut rendered, bounds, upper_left, lower_right,
+                           &RenderParams { scheduling: super::Scheduling { band_height: 3, ..Default::default() },
+                                           ..params.clone() });
+    assert_eq!(rendered, deep);
+    // 倍々精度で描けない色付けでは f64 のまま
+    let stalks = RenderParams { coloring: super::Coloring::Stalks, ..params };
+    assert!(!supports(&stalks));
+}
@@ -0,0 +1,24 @@
+//! `--profile FILE` で有効になる `tracing` のプロファイル出力
+//!
+//! 解析・描画・帯・エンコードの各処理は `tracing` のスパンで囲んである。購読者を登録しなければスパンは
+//! ほぼ何もしないが、ここで `tracing-flame` の層を登録すると同じスタックの所要時間を畳み込んだ
+//! folded 形式が書き出され、`inferno-flamegraph` などでそのままフレームグラフにできる。
+
+use std::fs::File;
+use std::io::BufWriter;
+
+use tracing_flame::{FlameLayer, FlushGuard};
+use tracing_subscriber::prelude::*;
+
+/// プロファイルの記録を始める。返り値を捨てるとファイルに書き出される
+pub fn start(path: &str) -> Result<FlushGuard<BufWriter<File>>, String> {
+    let (layer, guard) = FlameLayer::with_file(path)
+        .map_err(|e| format!("cannot write {}: {}", path, e))?;
+    // スレッド毎に分けると帯の数だけ細い山ができるので、全てのスレッドを1つのスタックに畳み込む
+    let layer = layer.with_threads_collapsed(true).with_file_and_line(false);
+    tracing_subscriber::registry()
+        .with(layer)
+        .try_init()
+        .map_err(|e| format!("cannot start profiler: {}", e))?;
+    Ok(guard)
+}
@@ -0,0 +1,190 @@
+//! 画像のピクセルと、反復する複素平面上の点との対応
+//!
+//! 既定の `plane` は左上と右下の点の間を線形に補間する (`pixel_to_point`)。
+//! `sphere` はリーマン球面を正距円筒図法で画像に広げ、無限遠の近くまで含めた平面全体を1枚に収める。
+//! 画像の下端が南極 (範囲の中心)、上端が北極 (無限遠) で、赤道が範囲に内接する円になる。
+//!
+//! `--mobius` を与えると、どちらの写像で得た点にもメビウス変換 `(a w + b) / (c w + d)` を掛けてから反復する。
+//! 例えば `0,0/1,0/1,0/0,0` は `1/w` で、無限遠を中心に持ってきた反転図になる。
+
+use std::f64::consts::PI;
+use std::str::FromStr;
+
+use num::Complex;
+
+use super::{parse_complex, parse_list, pixel_to_point, RenderParams};
+
+/// ピクセルから複素平面上の点への写像
+pub trait Projection {
+    /// 大きさ `bounds` の画像の `pixel` (列, 行) に対応する点
+    fn point(&self, bounds: (usize, usize), pixel: (usize, usize)) -> Complex<f64>;
+}
+
+/// 左上と右下の点で指定した長方形をそのまま画像にする
+pub struct Plane {
+    pub upper_left: Complex<f64>,
+    pub lower_right: Complex<f64>
+}
+
+impl Projection for Plane {
+    fn point(&self, bounds: (usize, usize), pixel: (usize, usize)) -> Complex<f64> {
+        pixel_to_point(bounds, pixel, self.upper_left, self.lower_right)
+    }
+}
+
+/// 中心 `center` で半径 `radius` の円を赤道とするリーマン球面を、経度と緯度で画像に広げる
+pub struct Sphere {
+    pub center: Complex<f64>,
+    pub radius: f64
+}
+
+impl Projection for Sphere {
+    fn point(&self, bounds: (usize, usize), pixel: (usize, usize)) -> Complex<f64> {
+        // 極そのものを避けるため、ピクセルの中心の経度と緯度を使う
+        let longitude = (pixel.0 as f64 + 0.5) / bounds.0 as f64 * 2.0 * PI - PI;
+        let latitude = PI / 2.0 - (pixel.1 as f64 + 0.5) / bounds.1 as f64 * PI;
+        // 北極からの立体射影で球面上の点を平面に移す
+        let scale = self.radius * latitude.cos() / (1.0 - latitude.sin());
+        self.center + Complex::from_polar(&scale, &longitude)
+    }
+}
+
+/// メビウス変換 `(a w + b) / (c w + d)`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Mobius {
+    pub a: Complex<f64>,
+    pub b: Complex<f64>,
+    pub c: Complex<f64>,
+    pub d: Complex<f64>
+}
+
+impl Mobius {
+    pub fn apply(&self, w: Complex<f64>) -> Complex<f64> {
+        let denominator = self.c * w + self.d;
+        if denominator.norm_sqr() == 0.0 {
+            // 極は無限遠に移る。NaN にすると発散しない点として扱われてしまうので無限大にする
+            return Complex { re: f64::INFINITY, im: 0.0 };
+        }
+        (self.a * w + self.b) / denominator
+    }
+}
+
+impl FromStr for Mobius {
+    type Err = String;
+
+    /// `a/b/c/d` の形で、それぞれを `re,im` で与える
+    fn from_str(s: &str) -> Result<Mobius, String> {
+        let coefficients = parse_list::<String>(s, '/')
+            .and_then(|parts| parts.iter().map(|part| parse_complex(part)).collect::<Option<Vec<_>>>())
+            .filter(|coefficients| coefficients.len() == 4)
+            .ok_or("--mobius expects four complex numbers a/b/c/d such as 0,0/1,0/1,0/0,0")?;
+        let mobius = Mobius {
+            a: coefficients[0],
+            b: coefficients[1],
+            c: coefficients[2],
+            d: coefficients[3]
+        };
+        if (mobius.a * mobius.d - mobius.b * mobius.c).norm_sqr() == 0.0 {
+            return Err("--mobius needs ad - bc != 0".to_string());
+        }
+        Ok(mobius)
+    }
+}
+
+#[test]
+fn test_mobius() {
+    let inversion = Mobius::from_str("0,0/1,0/1,0/0,0").unwrap();
+    assert_eq!(inversion.apply(Complex { re: 2.0, im: 0.0 }), Complex { re: 0.5, im: 0.0 });
+    assert_eq!(inversion.apply(Complex { re: 0.0, im: 1.0 }), Complex { re: 0.0, im: -1.0 });
+    assert!(inversion.apply(Complex { re: 0.0, im: 0.0 }).re.is_infinite());
+    assert!(Mobius::from_str("1,0/1,0/1,0/1,0").is_err());
+    assert!(Mobius::from_str("1,0/0,0/0,0").is_err());
+    assert!(Mobius::from_str("1,0/0,0/0,0/x").is_err());
+}
+
+/// 別の写像で得た点にメビウス変換を掛ける
+struct Transformed {
+    inner: Box<dyn Projection>,
+    mobius: Mobius
+}
+
+impl Projection for Transformed {
+    fn point(&self, bounds: (usize, usize), pixel: (usize, usize)) -> Complex<f64> {
+        self.mobius.apply(self.inner.point(bounds, pixel))
+    }
+}
+
+/// `--projection` で選ぶ写像の種類
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProjectionKind {
+    Plane,
+    Sphere
+}
+
+impl FromStr for ProjectionKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ProjectionKind, String> {
+        match s {
+            "plane" => Ok(ProjectionKind::Plane),
+            "sphere" => Ok(ProjectionKind::Sphere),
+            _ => Err(format!("unknown projection '{}', expected plane or sphere", s))
+        }
+    }
+}
+
+/// 描画する範囲の左上と右下の点から `kind` の写像を作る。
+/// `sphere` では範囲の中心を南極、実軸方向の幅の半分を赤道の半径にする
+pub fn projection(kind: ProjectionKind, upper_left: Complex<f64>, lower_right: Complex<f64>)
+    -> Box<dyn Projection>
+{
+    match kind {
+        ProjectionKind::Plane => Box::new(Plane { upper_left, lower_right }),
+        ProjectionKind::Sphere => Box::new(Sphere {
+            center: (upper_left + lower_right) / 2.0,
+            radius: (lower_right.re - upper_left.re).abs() / 2.0
+        })
+    }
+}
+
+/// `params` の写像とメビウス変換を合わせた写像を作る
+pub fn for_params(params: &RenderParams, upper_left: Complex<f64>, lower_right: Complex<f64>)
+    -> Box<dyn Projection>
+{
+    let inner = projection(params.projection, upper_left, lower_right);
+    match params.mobius {
+        Some(mobius) => Box::new(Transformed { inner, mobius }),
+        None => inner
+    }
+}
+
+/// `params` の写像が左上と右下の点の間の線形補間そのものか
+pub fn is_plane(params: &RenderParams) -> bool {
+    params.projection == ProjectionKind::Plane && params.mobius.is_none()
+}
+
+#[test]
+fn test_sphere_projection() {
+    let sphere = projection(ProjectionKind::Sphere,
+                            Complex { re: -2.5, im: 2.0 }, Complex { re: 1.5, im: -2.0 });
+    let bounds = (360, 180);
+    let center = Complex { re: -0.5, im: 0.0 };
+    // 赤道は半径2の円、南極の近くは中心、北極の近くは無限遠に行く
+    let equator = sphere.point(bounds, (180, 90)) - center;
+    assert!((equator.norm() - 2.0).abs() < 0.05);
+    assert!((sphere.point(bounds, (0, 179)) - center).norm() < 0.05);
+    assert!((sphere.point(bounds, (0, 0)) - center).norm() > 100.0);
+    // 経度 0 は実軸の正の向き
+    assert!(equator.re > 0.0 && equator.im.abs() < 0.05);
+
+    let plane = projection(ProjectionKind::Plane,
+                           Complex { re: -1.0, im: 1.0 }, Complex { re: 1.0, im: -1.0 });
+    assert_eq!(plane.point((100, 100), (25, 75)), Complex { re: -0.5, im: -0.5 });
+}
+
+#[test]
+fn test_projection_from_str() {
+    assert_eq!(ProjectionKind::from_str("plane"), Ok(ProjectionKind::Plane));
+    assert_eq!(ProjectionKind::from_str("sphere"), Ok(ProjectionKind::Sphere));
+    assert!(ProjectionKind::from_str("mercator").is_err());
+}
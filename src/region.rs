@@ -0,0 +1,162 @@
+//! 画像のピクセルと複素平面上の長方形の対応
+//!
+//! 範囲を動かしたとき、`diff` で前の範囲からのピクセル単位のずれを求め、`exposed` で新しく見えた長方形を求めると、
+//! 前の画像をずらして残りだけを描き直せる。
+
+use num::Complex;
+
+/// 縮尺が同じとみなす大きさの相対的な差
+const SCALE_TOLERANCE: f64 = 1e-9;
+
+/// ずれがピクセルの整数倍とみなす、ピクセル単位の差
+const SHIFT_TOLERANCE: f64 = 1e-3;
+
+/// 大きさ `bounds` (幅, 高さ) の画像に描く、左上 `upper_left` と右下 `lower_right` の長方形
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Region {
+    pub bounds: (usize, usize),
+    pub upper_left: Complex<f64>,
+    pub lower_right: Complex<f64>
+}
+
+impl Region {
+    #[inline]
+    pub fn new(bounds: (usize, usize), upper_left: Complex<f64>, lower_right: Complex<f64>) -> Region {
+        Region { bounds, upper_left, lower_right }
+    }
+
+    /// ピクセル `pixel` (列, 行) の左上の角に対応する点
+    #[inline]
+    pub fn pixel_to_point(&self, pixel: (usize, usize)) -> Complex<f64> {
+        self.position_to_point((pixel.0 as f64, pixel.1 as f64))
+    }
+
+    /// ピクセル単位の位置 (列, 行) に対応する点。ピクセルの中心は `(列 + 0.5, 行 + 0.5)`
+    #[inline]
+    pub fn position_to_point(&self, position: (f64, f64)) -> Complex<f64> {
+        let (width, height) = self.size();
+        Complex {
+            re: self.upper_left.re + position.0 * width / self.bounds.0 as f64,
+            im: self.upper_left.im - position.1 * height / self.bounds.1 as f64
+        }
+    }
+
+    /// `position_to_point` の逆。画像の外の点では範囲外の位置になる
+    #[inline]
+    pub fn point_to_position(&self, point: Complex<f64>) -> (f64, f64) {
+        let (width, height) = self.size();
+        ((point.re - self.upper_left.re) / width * self.bounds.0 as f64,
+         (self.upper_left.im - point.im) / height * self.bounds.1 as f64)
+    }
+
+    /// `pixel_to_point` の逆。`point` を含むピクセル (列, 行) で、画像の外なら `None`
+    pub fn point_to_pixel(&self, point: Complex<f64>) -> Option<(usize, usize)> {
+        let (x, y) = self.point_to_position(point);
+        if x >= 0.0 && y >= 0.0 && x < self.bounds.0 as f64 && y < self.bounds.1 as f64 {
+            Some((x as usize, y as usize))
+        } else {
+            None
+        }
+    }
+
+    /// `next` のピクセル (x, y) に写る点が、`self` のピクセル (x + dx, y + dy) に写る点と同じになるずれ (dx, dy)。
+    /// 大きさと縮尺が同じで、ずれがピクセルの整数倍のときだけ求まる
+    pub fn diff(&self, next: &Region) -> Option<(isize, isize)> {
+        if self.bounds != next.bounds || self.bounds.0 == 0 || self.bounds.1 == 0 {
+            return None;
+        }
+        let ((width, height), (next_width, next_height)) = (self.size(), next.size());
+        if (width - next_width).abs() > width.abs() * SCALE_TOLERANCE
+            || (height - next_height).abs() > height.abs() * SCALE_TOLERANCE
+        {
+            return None;
+        }
+        let (x, y) = self.point_to_position(next.upper_left);
+        let (dx, dy) = (x.round(), y.round());
+        if (x - dx).abs() > SHIFT_TOLERANCE || (y - dy).abs() > SHIFT_TOLERANCE {
+            return None;
+        }
+        Some((dx as isize, dy as isize))
+    }
+
+    /// 前の範囲から `shift` だけずれたこの範囲のうち、前の範囲に写っていない長方形の ((列, 行), (幅, 高さ)) の並び
+    pub fn exposed(&self, (dx, dy): (isize, isize)) -> Vec<((usize, usize), (usize, usize))> {
+        let (width, height) = self.bounds;
+        if dx.unsigned_abs() >= width || dy.unsigned_abs() >= height {
+            return vec![((0, 0), self.bounds)];
+        }
+        let (columns, rows) = (dx.unsigned_abs(), dy.unsigned_abs());
+        let mut exposed = vec![];
+        // 上下に見えた行は幅いっぱいに、残りの行では左右に見えた列だけ
+        let kept_top = if dy > 0 { 0 } else { rows };
+        match dy.signum() {
+            1 => exposed.push(((0, height - rows), (width, rows))),
+            -1 => exposed.push(((0, 0), (width, rows))),
+            _ => {}
+        }
+        match dx.signum() {
+            1 => exposed.push(((width - columns, kept_top), (columns, height - rows))),
+            -1 => exposed.push(((0, kept_top), (columns, height - rows))),
+            _ => {}
+        }
+        exposed
+    }
+
+    /// ピクセル `origin` (列, 行) から大きさ `bounds` の部分を描く範囲
+    pub fn sub_region(&self, origin: (usize, usize), bounds: (usize, usize)) -> Region {
+        Region::new(bounds, self.pixel_to_point(origin),
+                    self.pixel_to_point((origin.0 + bounds.0, origin.1 + bounds.1)))
+    }
+
+    /// 実軸方向の幅と虚軸方向の高さ
+    #[inline]
+    fn size(&self) -> (f64, f64) {
+        (self.lower_right.re - self.upper_left.re, self.upper_left.im - self.lower_right.im)
+    }
+}
+
+#[test]
+fn test_region_round_trip() {
+    let region = Region::new((100, 80), Complex { re: -2.0, im: 1.0 }, Complex { re: 0.5, im: -1.0 });
+    assert_eq!(region.pixel_to_point((0, 0)), region.upper_left);
+    assert_eq!(region.point_to_pixel(region.upper_left), Some((0, 0)));
+    for &pixel in &[(0, 0), (1, 0), (17, 42), (99, 79), (50, 40)] {
+        // 角の点は丸め誤差で隣のピクセルに入りかねないので、ピクセルの中心で往復させる
+        let center = region.position_to_point((pixel.0 as f64 + 0.5, pixel.1 as f64 + 0.5));
+        assert_eq!(region.point_to_pixel(center), Some(pixel));
+        let (x, y) = region.point_to_position(region.pixel_to_point(pixel));
+        assert!((x - pixel.0 as f64).abs() < 1e-9 && (y - pixel.1 as f64).abs() < 1e-9);
+    }
+    // 右下の角と画像の外はピクセルにならない
+    assert_eq!(region.point_to_pixel(region.lower_right), None);
+    assert_eq!(region.point_to_pixel(Complex { re: -2.1, im: 0.0 }), None);
+    assert_eq!(region.point_to_pixel(Complex { re: 0.0, im: 1.5 }), None);
+    assert_eq!(region.point_to_position(Complex { re: 0.5 + 2.5, im: 1.0 }), (200.0, 0.0));
+}
+
+#[test]
+fn test_region_diff() {
+    let region = Region::new((64, 32), Complex { re: -2.0, im: 1.0 }, Complex { re: 2.0, im: -1.0 });
+    // 1ピクセルは 1/16。右へ 3 ピクセル、下へ 2 ピクセル動かした範囲
+    let moved = Region::new((64, 32), Complex { re: -2.0 + 3.0 / 16.0, im: 1.0 - 2.0 / 16.0 },
+                            Complex { re: 2.0 + 3.0 / 16.0, im: -1.0 - 2.0 / 16.0 });
+    assert_eq!(region.diff(&moved), Some((3, 2)));
+    assert_eq!(moved.diff(&region), Some((-3, -2)));
+    assert_eq!(region.diff(&region), Some((0, 0)));
+    // 半ピクセルのずれ、違う縮尺、違う大きさでは求まらない
+    let half = Region::new((64, 32), Complex { re: -2.0 + 0.5 / 16.0, im: 1.0 }, Complex { re: 2.0 + 0.5 / 16.0, im: -1.0 });
+    assert_eq!(region.diff(&half), None);
+    assert_eq!(region.diff(&Region::new((64, 32), region.upper_left, Complex { re: 1.0, im: -1.0 })), None);
+    assert_eq!(region.diff(&Region::new((32, 16), region.upper_left, region.lower_right)), None);
+
+    assert_eq!(moved.exposed((3, 2)), vec![((0, 30), (64, 2)), ((61, 0), (3, 30))]);
+    assert_eq!(moved.exposed((-3, -2)), vec![((0, 0), (64, 2)), ((0, 2), (3, 30))]);
+    assert_eq!(moved.exposed((0, 0)), vec![]);
+    assert_eq!(moved.exposed((64, 0)), vec![((0, 0), (64, 32))]);
+    // 見えた長方形と残る部分を合わせると、ちょうど画像全体になる
+    let covered: usize = moved.exposed((5, -7)).iter().map(|(_, (w, h))| w * h).sum();
+    assert_eq!(covered, 64 * 32 - (64 - 5) * (32 - 7));
+
+    let sub = region.sub_region((16, 8), (8, 4));
+    assert_eq!((sub.upper_left, sub.lower_right), (Complex { re: -1.0, im: 0.5 }, Complex { re: -0.5, im: 0.25 }));
+}
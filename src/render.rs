@@ -0,0 +1,291 @@
+//! 呼び出し側が持つバッファに直接描く
+//!
+//! GUI への組み込みなどで毎フレーム描き直すとき、描画のたびに画像を確保して写さずに済むよう、
+//! 呼び出し側の `&mut [u8]` (1ピクセル1バイトの灰色) か `&mut [u32]` (1ピクセル1語の色) にそのまま描く。
+//! `stride` は行の先頭どうしの間隔を要素の数で表したもので、幅以上なら行の終わりの余りには触れない。
+//! 明るさは `mandelbrot-rewrite` の既定の描画と同じで、内部の点が 0、早く発散するほど 255 に近い。
+//!
+//! `render_with_deadline` は毎フレームの時間の予算の中で描けるところまで描く。まず 8x8 の正方形を1点で、
+//! 上限を減らした反復で粗く塗り、4x4、2x2 と細かくして、最後に本来の上限で全てのピクセルを描く。
+//! 予算を使い切るとその段の残りの行は描かずに戻り、どの段まで描き終えたかを返す。
+//!
+//! `render_panned` は範囲をピクセルの整数倍だけ動かしたとき、前の画像を `scroll` でずらし、新しく見えた所だけを描く。
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use num::Complex;
+use rayon::prelude::*;
+
+use super::Region;
+
+/// 点 `c` が `limit` 回の反復のうちに発散すれば、その回数
+pub fn escape_time(c: Complex<f64>, limit: u32) -> Option<u32> {
+    let mut z = Complex { re: 0.0, im: 0.0 };
+    for i in 0 .. limit {
+        z = z * z + c;
+        if z.norm_sqr() > 4.0 {
+            return Some(i);
+        }
+    }
+    None
+}
+
+/// 発散までの回数 `count` の明るさ。発散しなかった点は黒になる
+pub fn shade(count: Option<u32>, limit: u32) -> u8 {
+    match count {
+        None => 0,
+        Some(count) => 255 - (count as u64 * 255 / limit as u64) as u8
+    }
+}
+
+/// `render_with_deadline` で最初に塗る正方形の大きさ
+const COARSEST_BLOCK: usize = 8;
+
+/// 長さ `len` のバッファに `stride` 間隔の行で大きさ `bounds` の画像を描けるか確かめ、描くのに使う要素の数を返す
+fn checked_len(len: usize, stride: usize, bounds: (usize, usize), limit: u32) -> Result<usize, String> {
+    let (width, height) = bounds;
+    if stride < width {
+        return Err(format!("stride {} is smaller than the width {}", stride, width));
+    }
+    if limit == 0 {
+        return Err("the iteration limit must be positive".to_string());
+    }
+    let needed = if height == 0 { 0 } else { stride * (height - 1) + width };
+    if len < needed {
+        return Err(format!("buffer of {} pixels is too small for {}x{} with stride {}; {} are needed",
+                           len, width, height, stride, needed));
+    }
+    Ok(needed)
+}
+
+/// ピクセル (列, 行) の左上の角の点を求める。実軸方向の増分と行毎の虚部を先に求めておく
+fn point_at(region: &Region) -> impl Fn(usize, usize) -> Complex<f64> + Sync {
+    let (upper_left, (width, height)) = (region.upper_left, region.bounds);
+    let re_step = (region.lower_right.re - upper_left.re) / width as f64;
+    let im_step = (upper_left.im - region.lower_right.im) / height as f64;
+    move |column, row| Complex { re: upper_left.re + column as f64 * re_step, im: upper_left.im - row as f64 * im_step }
+}
+
+/// `region` を `stride` 間隔の行で `buffer` に描き、各ピクセルには明るさを `pixel` で変換した値を入れる
+fn render_rows<T, F>(buffer: &mut [T], stride: usize, region: &Region, limit: u32, pixel: F) -> Result<(), String>
+    where T: Send, F: Fn(u8) -> T + Sync
+{
+    let needed = checked_len(buffer.len(), stride, region.bounds, limit)?;
+    let point = point_at(region);
+    buffer[.. needed].par_chunks_mut(stride.max(1)).enumerate().for_each(|(row, line)| {
+        for (column, value) in line[.. region.bounds.0].iter_mut().enumerate() {
+            *value = pixel(shade(escape_time(point(column, row), limit), limit));
+        }
+    });
+    Ok(())
+}
+
+/// `region` を最大 `limit` 回の反復で、`stride` 要素ずつ並んだ行の灰色として `buffer` に描く
+pub fn render_gray(buffer: &mut [u8], stride: usize, region: &Region, limit: u32) -> Result<(), String> {
+    render_rows(buffer, stride, region, limit, |gray| gray)
+}
+
+/// `render_gray` と同じだが、明るさを `palette` で引いた1語の色 (`0xAARRGGBB` など、並びは呼び出し側が決める) を描く
+pub fn render_u32(buffer: &mut [u32], stride: usize, region: &Region, limit: u32, palette: &[u32; 256])
+    -> Result<(), String>
+{
+    render_rows(buffer, stride, region, limit, |gray| palette[gray as usize])
+}
+
+/// `stride` 間隔の行に並んだ大きさ `bounds` の画像を、`Region::diff` のずれ `shift` だけずらす。
+/// ずらした先に写る元の無いところは前の内容のまま
+pub fn scroll<T: Copy>(buffer: &mut [T], stride: usize, bounds: (usize, usize), (dx, dy): (isize, isize))
+    -> Result<(), String>
+{
+    checked_len(buffer.len(), stride, bounds, 1)?;
+    let (width, height) = (bounds.0 as isize, bounds.1 as isize);
+    if dx.abs() >= width || dy.abs() >= height {
+        return Ok(());
+    }
+    let (left, right) = ((-dx).max(0), width.min(width - dx));
+    let rows = (-dy).max(0) .. height.min(height - dy);
+    // 上へずらすときは上の行から、下へずらすときは下の行から写すと、写す前の行を上書きしない
+    let rows: Box<dyn Iterator<Item = isize>> = if dy >= 0 { Box::new(rows) } else { Box::new(rows.rev()) };
+    for row in rows {
+        let source = ((row + dy) * stride as isize + left + dx) as usize;
+        buffer.copy_within(source .. source + (right - left) as usize, (row * stride as isize + left) as usize);
+    }
+    Ok(())
+}
+
+/// 前に `previous` を描いた `buffer` に `next` を描き、反復したピクセルの数を返す。
+/// `Region::diff` でずれが求まれば前の画像をずらして新しく見えた所だけを描き、求まらなければ全体を描く
+pub fn render_panned(buffer: &mut [u8], stride: usize, previous: &Region, next: &Region, limit: u32)
+    -> Result<usize, String>
+{
+    let needed = checked_len(buffer.len(), stride, next.bounds, limit)?;
+    let shift = match previous.diff(next) {
+        Some(shift) => shift,
+        None => {
+            render_gray(buffer, stride, next, limit)?;
+            return Ok(next.bounds.0 * next.bounds.1);
+        }
+    };
+    scroll(buffer, stride, next.bounds, shift)?;
+    // 見えた所も全体の範囲から点を求め、全体を描き直したときと同じ値にする
+    let point = point_at(next);
+    let mut computed = 0;
+    for ((left, top), (width, height)) in next.exposed(shift) {
+        buffer[.. needed].par_chunks_mut(stride).enumerate().skip(top).take(height).for_each(|(row, line)| {
+            for (column, pixel) in line.iter_mut().enumerate().skip(left).take(width) {
+                *pixel = shade(escape_time(point(column, row), limit), limit);
+            }
+        });
+        computed += width * height;
+    }
+    Ok(computed)
+}
+
+/// `render_with_deadline` がどこまで描いたか
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Completeness {
+    /// 最後まで描き終えた段の正方形の大きさ。1 なら全てのピクセルを本来の上限で描いた。
+    /// 最初の段も終わらなければ `None` で、描けなかった行には前の内容が残る
+    pub block: Option<usize>
+}
+
+impl Completeness {
+    /// 全てのピクセルを描き終えたか
+    pub fn is_complete(&self) -> bool {
+        self.block == Some(1)
+    }
+}
+
+/// `render_gray` と同じだが、`budget` の時間の中で粗い段から順に描けるところまで描く
+pub fn render_with_deadline(buffer: &mut [u8], stride: usize, region: &Region, limit: u32, budget: Duration)
+    -> Result<Completeness, String>
+{
+    let needed = checked_len(buffer.len(), stride, region.bounds, limit)?;
+    let (width, height) = region.bounds;
+    let started = Instant::now();
+    let point = point_at(region);
+    if width == 0 || height == 0 {
+        return Ok(Completeness { block: Some(1) });
+    }
+    let mut completeness = Completeness { block: None };
+    let mut block = COARSEST_BLOCK;
+    loop {
+        // 粗い段は正方形の幅に比例して上限を減らし、明るさは本来の上限で決める
+        let pass_limit = if block == 1 { limit } else { (limit / block as u32).max(1) };
+        let expired = AtomicBool::new(false);
+        buffer[.. needed].par_chunks_mut(stride * block).enumerate().for_each(|(band, lines)| {
+            if expired.load(Ordering::Relaxed) || started.elapsed() >= budget {
+                expired.store(true, Ordering::Relaxed);
+                return;
+            }
+            let top = band * block;
+            let rows = block.min(height - top);
+            for left in (0 .. width).step_by(block) {
+                let value = shade(escape_time(point(left, top), pass_limit), limit);
+                for row in 0 .. rows {
+                    let start = row * stride + left;
+                    lines[start .. start + block.min(width - left)].iter_mut().for_each(|pixel| *pixel = value);
+                }
+            }
+        });
+        if expired.into_inner() {
+            break;
+        }
+        completeness.block = Some(block);
+        if block == 1 {
+            break;
+        }
+        block /= 2;
+    }
+    Ok(completeness)
+}
+
+/// 明るさをそのまま RGB にした不透明な `0xAARRGGBB` の色の表
+pub fn gray_argb() -> [u32; 256] {
+    let mut palette = [0; 256];
+    for (gray, color) in palette.iter_mut().enumerate() {
+        let gray = gray as u32;
+        *color = 0xff00_0000 | (gray * 0x01_0101);
+    }
+    palette
+}
+
+#[test]
+fn test_render_into() {
+    let region = Region::new((7, 5), Complex { re: -2.0, im: 1.0 }, Complex { re: 1.0, im: -1.0 });
+    let mut packed = vec![1; 7 * 5];
+    render_gray(&mut packed, 7, &region, 255).unwrap();
+    // 行の間隔を広げても同じ絵になり、行の終わりの余りはそのまま
+    let mut padded = vec![1; 10 * 4 + 7];
+    render_gray(&mut padded, 10, &region, 255).unwrap();
+    for row in 0 .. 5 {
+        assert_eq!(padded[row * 10 .. row * 10 + 7], packed[row * 7 .. row * 7 + 7]);
+        if row < 4 {
+            assert_eq!(padded[row * 10 + 7 .. row * 10 + 10], [1, 1, 1]);
+        }
+    }
+    // 原点を含む中央は内部なので黒、左上の角は外部
+    assert_eq!(packed[2 * 7 + 4], 0);
+    assert!(packed[0] > 0);
+
+    let mut words = vec![0; 7 * 5];
+    render_u32(&mut words, 7, &region, 255, &gray_argb()).unwrap();
+    assert!(words.iter().zip(&packed).all(|(&word, &gray)| word == 0xff00_0000 | (gray as u32 * 0x01_0101)));
+
+    assert!(render_gray(&mut packed, 6, &region, 255).is_err());
+    assert!(render_gray(&mut [0; 7 * 5 - 1], 7, &region, 255).is_err());
+    assert!(render_gray(&mut packed, 7, &region, 0).is_err());
+}
+
+#[test]
+fn test_render_panned() {
+    // 1ピクセルが 1/16 なので、ずらした点と描き直した点がちょうど一致する
+    let (bounds, stride) = ((48, 32), 50);
+    let region = Region::new(bounds, Complex { re: -2.0, im: 1.0 }, Complex { re: 1.0, im: -1.0 });
+    let panned = |dx: f64, dy: f64| Region::new(bounds, Complex { re: -2.0 + dx / 16.0, im: 1.0 - dy / 16.0 },
+                                                Complex { re: 1.0 + dx / 16.0, im: -1.0 - dy / 16.0 });
+    let mut pixels = vec![0; stride * 32];
+    render_gray(&mut pixels, stride, &region, 255).unwrap();
+    let mut previous = region;
+    for &(dx, dy) in &[(5.0, 0.0), (-3.0, 4.0), (2.0, -6.0), (0.0, 0.0)] {
+        let next = panned(dx, dy);
+        let computed = render_panned(&mut pixels, stride, &previous, &next, 255).unwrap();
+        let exposed: usize = next.exposed(previous.diff(&next).unwrap()).iter().map(|(_, (w, h))| w * h).sum();
+        assert_eq!(computed, exposed);
+        let mut expected = vec![0; stride * 32];
+        render_gray(&mut expected, stride, &next, 255).unwrap();
+        for row in 0 .. bounds.1 {
+            assert_eq!(pixels[row * stride .. row * stride + bounds.0], expected[row * stride .. row * stride + bounds.0]);
+        }
+        previous = next;
+    }
+    // 縮尺が変われば全体を描く
+    let zoomed = Region::new(bounds, region.upper_left, Complex { re: 0.0, im: 0.0 });
+    assert_eq!(render_panned(&mut pixels, stride, &previous, &zoomed, 255), Ok(48 * 32));
+
+    let mut words = [1, 2, 3, 4, 5, 6];
+    scroll(&mut words, 3, (3, 2), (1, 1)).unwrap();
+    assert_eq!(words, [5, 6, 3, 4, 5, 6]);
+    scroll(&mut words, 3, (3, 2), (-1, 0)).unwrap();
+    assert_eq!(words, [5, 5, 6, 4, 4, 5]);
+}
+
+#[test]
+fn test_render_with_deadline() {
+    let region = Region::new((21, 13), Complex { re: -2.0, im: 1.0 }, Complex { re: 1.0, im: -1.0 });
+    let mut expected = vec![0; 24 * 12 + 21];
+    render_gray(&mut expected, 24, &region, 255).unwrap();
+    // 予算が十分なら最後の段まで描き、render_gray と同じになる
+    let mut pixels = vec![0; 24 * 12 + 21];
+    let completeness = render_with_deadline(&mut pixels, 24, &region, 255, Duration::from_secs(60)).unwrap();
+    assert!(completeness.is_complete());
+    assert_eq!(pixels, expected);
+    // 予算が無ければ何も描かず、前の内容が残る
+    let mut stale = vec![7; 24 * 12 + 21];
+    let completeness = render_with_deadline(&mut stale, 24, &region, 255, Duration::from_secs(0)).unwrap();
+    assert_eq!(completeness, Completeness { block: None });
+    assert!(!completeness.is_complete());
+    assert!(stale.iter().all(|&pixel| pixel == 7));
+    assert!(render_with_deadline(&mut stale, 20, &region, 255, Duration::from_secs(1)).is_err());
+}
@@ -0,0 +1,99 @@
+//! 描画した画像を縮小して、大きさ違いの画像を同じ実行で書き出す `--also-sizes`
+//!
+//! 縮小は面積平均で行う。縮小先の1ピクセルが覆う元の画像の範囲を、端のピクセルは覆う割合で重み付けして平均するので、
+//! 倍率が整数でなくてもモアレや偏りが出ない。横と縦に分けて掛ける。
+
+use std::path::Path;
+
+use rayon::prelude::*;
+
+/// 縮小先の各ピクセルについて、元のピクセルの番号と重みの組。重みの和は1
+fn weights(from: usize, to: usize) -> Vec<Vec<(usize, f64)>> {
+    let scale = from as f64 / to as f64;
+    (0 .. to).map(|i| {
+        let (start, end) = (i as f64 * scale, (i + 1) as f64 * scale);
+        (start.floor() as usize .. (end.ceil() as usize).min(from))
+            .map(|j| {
+                let covered = (end.min(j as f64 + 1.0) - start.max(j as f64)) / scale;
+                (j, covered)
+            })
+            .filter(|&(_, weight)| weight > 0.0)
+            .collect()
+    }).collect()
+}
+
+/// 大きさ `from` の画像 `pixels` を `to` に縮小する
+pub fn downsample(pixels: &[u8], from: (usize, usize), to: (usize, usize)) -> Vec<u8> {
+    assert!(to.0 <= from.0 && to.1 <= from.1 && to.0 > 0 && to.1 > 0);
+    let (columns, rows) = (weights(from.0, to.0), weights(from.1, to.1));
+
+    // 先に各行を横に縮め、それから縦に縮める
+    let mut narrow = vec![0.0; to.0 * from.1];
+    narrow.par_chunks_mut(to.0).enumerate().for_each(|(y, row)| {
+        let source = &pixels[y * from.0 .. (y + 1) * from.0];
+        for (value, weights) in row.iter_mut().zip(&columns) {
+            *value = weights.iter().map(|&(x, weight)| source[x] as f64 * weight).sum();
+        }
+    });
+    let mut output = vec![0; to.0 * to.1];
+    output.par_chunks_mut(to.0).zip(&rows).for_each(|(row, weights)| {
+        for (x, value) in row.iter_mut().enumerate() {
+            let sum: f64 = weights.iter().map(|&(y, weight)| narrow[y * to.0 + x] * weight).sum();
+            *value = sum.round().min(255.0) as u8;
+        }
+    });
+    output
+}
+
+/// `sizes` が大きさ `bounds` の画像から縦横比を保って縮小できるか確かめる
+pub fn check_sizes(sizes: &[(usize, usize)], bounds: (usize, usize)) -> Result<(), String> {
+    for &(width, height) in sizes {
+        if width > bounds.0 || height > bounds.1 || width == 0 || height == 0 {
+            return Err(format!("--also-sizes {}x{} must not be larger than the render {}x{}",
+                               width, height, bounds.0, bounds.1));
+        }
+        // 丸めで1ピクセルずれるのは許す
+        let expected = bounds.1 as f64 * width as f64 / bounds.0 as f64;
+        if (height as f64 - expected).abs() > 1.0 {
+            return Err(format!("--also-sizes {}x{} has a different aspect ratio from the render {}x{}",
+                               width, height, bounds.0, bounds.1));
+        }
+    }
+    Ok(())
+}
+
+/// `mandel.png` を `mandel-640x360.png` のように、大きさを付けたファイル名にする
+pub fn sized_path(path: &str, size: (usize, usize)) -> String {
+    let path = Path::new(path);
+    let stem = path.file_stem().map_or_else(String::new, |s| s.to_string_lossy().into_owned());
+    let name = match path.extension() {
+        Some(extension) => format!("{}-{}x{}.{}", stem, size.0, size.1, extension.to_string_lossy()),
+        None => format!("{}-{}x{}", stem, size.0, size.1)
+    };
+    path.with_file_name(name).to_string_lossy().into_owned()
+}
+
+#[test]
+fn test_downsample() {
+    // 整数倍の縮小は単純な平均
+    let pixels = [0, 10, 20, 30,
+                  40, 50, 60, 70];
+    assert_eq!(downsample(&pixels, (4, 2), (2, 1)), vec![25, 45]);
+    assert_eq!(downsample(&pixels, (4, 2), (4, 2)), pixels.to_vec());
+    // 3 から 2 では真ん中のピクセルを半分ずつ分ける
+    assert_eq!(downsample(&[0, 90, 180], (3, 1), (2, 1)), vec![30, 150]);
+    assert!(weights(7, 3).iter().all(|w| (w.iter().map(|&(_, weight)| weight).sum::<f64>() - 1.0).abs() < 1e-12));
+    // 一様な画像は一様なまま
+    assert!(downsample(&[200; 37 * 23], (37, 23), (10, 6)).iter().all(|&p| p == 200));
+}
+
+#[test]
+fn test_sizes() {
+    assert!(check_sizes(&[(1920, 1080), (640, 360)], (3840, 2160)).is_ok());
+    assert!(check_sizes(&[(641, 360)], (3840, 2160)).is_ok());
+    assert!(check_sizes(&[(640, 480)], (3840, 2160)).is_err());
+    assert!(check_sizes(&[(4000, 2250)], (3840, 2160)).is_err());
+    assert!(check_sizes(&[(0, 0)], (3840, 2160)).is_err());
+    assert_eq!(sized_path("out/mandel.png", (640, 360)), "out/mandel-640x360.png");
+    assert_eq!(sized_path("mandel", (64, 36)), "mandel-64x36");
+}
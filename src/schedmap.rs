@@ -0,0 +1,73 @@
+//! どの帯をどのスレッドが描いたかを色分けした「スケジューリングマップ」
+//!
+//! スレッド毎に色相を変えて帯を塗り、明るさには描画結果を薄く重ねて図形が分かるようにする。
+//! 静的に分割する crossbeam と動的に取り合う rayon・atomic の負荷の偏りを見比べるための教材。
+
+use std::fs::File;
+use std::io;
+
+use image::png::PNGEncoder;
+use image::ColorType;
+
+use super::backend::BandOwner;
+
+/// `thread` 番目のスレッドの色。黄金角ずつ色相をずらして隣り合う番号を見分けやすくする
+fn thread_color(thread: usize) -> [f64; 3] {
+    let hue = (thread as f64 * 137.508) % 360.0 / 60.0;
+    let (saturation, value) = (0.75, 1.0);
+    let chroma = value * saturation;
+    let x = chroma * (1.0 - (hue % 2.0 - 1.0).abs());
+    let (r, g, b) = match hue as u32 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x)
+    };
+    let m = value - chroma;
+    [r + m, g + m, b + m]
+}
+
+/// グレースケールの描画結果 `pixels` に、帯を描いたスレッドの色を付けた RGB のバッファを作る
+pub fn scheduling_map(pixels: &[u8], bounds: (usize, usize), bands: &[BandOwner]) -> Vec<u8> {
+    let mut rgb = vec![0; pixels.len() * 3];
+    for band in bands {
+        let color = thread_color(band.thread);
+        let start = band.top * bounds.0;
+        let end = ((band.top + band.rows) * bounds.0).min(pixels.len());
+        for (i, &pixel) in pixels[start .. end].iter().enumerate() {
+            let brightness = 0.35 + 0.65 * pixel as f64 / 255.0;
+            for (channel, &c) in color.iter().enumerate() {
+                rgb[(start + i) * 3 + channel] = (c * brightness * 255.0).round() as u8;
+            }
+        }
+    }
+    rgb
+}
+
+/// スケジューリングマップを PNG として `filename` に書き出す
+pub fn write_scheduling_map(filename: &str, pixels: &[u8], bounds: (usize, usize),
+                            bands: &[BandOwner])
+    -> io::Result<()>
+{
+    let output = File::create(filename)?;
+    PNGEncoder::new(output).encode(&scheduling_map(pixels, bounds, bands),
+                                   bounds.0 as u32, bounds.1 as u32, ColorType::RGB(8))
+}
+
+#[test]
+fn test_scheduling_map() {
+    let bounds = (4, 3);
+    let pixels = vec![255; 12];
+    let bands = [
+        BandOwner { top: 0, rows: 2, thread: 0 },
+        BandOwner { top: 2, rows: 1, thread: 1 }
+    ];
+    let rgb = scheduling_map(&pixels, bounds, &bands);
+    assert_eq!(rgb.len(), 36);
+    // 同じスレッドの帯は同じ色、違うスレッドは違う色になる
+    assert_eq!(&rgb[0 .. 3], &rgb[21 .. 24]);
+    assert_ne!(&rgb[0 .. 3], &rgb[24 .. 27]);
+    assert_eq!(&rgb[0 .. 3], &[255, 64, 64]);
+}
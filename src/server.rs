@@ -0,0 +1,1079 @@
+//! PNG を返す HTTP の描画 API `serve-api`
+//!
+//! `GET /render?cx=&cy=&zoom=&w=&h=&iters=&palette=` の形で中心と倍率を受け取り、
+//! 描画した画像をそのまま返す。`palette` は `--palette` と同じ組み込みのパレットの名前で、既定値は `gray`。
+//! サーバのファイルを読ませないよう、パレットのファイルは受け付けない。同時に描画するリクエスト数には上限を設け、空きを待つ列 (`admission.rs`) も
+//! 埋まっていれば 429 を、1リクエストの描画時間の上限を超えれば 504 を返す。大きな `iters` でピクセルの多くが内部になる範囲のような
+//! 重いリクエストに備え、1リクエストで行う反復の合計にも上限 `--iteration-budget` を設ける。
+//! 描画は帯毎に期限と残りの反復を確かめ、使い切ったらまだ描いていない帯を諦めて 422 を返す。
+//!
+//! リクエストヘッダは合計 `MAX_HEADER_LEN` バイトまでしか読まず、`--header-timeout` 秒 (既定値: 10) までに
+//! 届かなければ接続を閉じるので、改行の無い巨大な行や少しずつしか送らない接続でメモリやスレッドを占有されない。
+//!
+//! `/metrics` では描画回数や描画時間などの計測値を Prometheus の形式で返す。
+//! `--cache-dir` を指定すると、描画した画像をディスクに保存して同じ要求に使い回す。
+//!
+//! 画像は描画条件と、バージョン、git のリビジョン、`RENDER_FORMAT` だけで決まるので、条件のハッシュ (キャッシュの鍵と同じもの) を `ETag` にして
+//! 1年間の `Cache-Control: immutable` と一緒に返す。`If-None-Match` が一致すれば描画せずに 304 を返すので、
+//! ブラウザや CDN はタイルを何度も取りに来ない。
+//!
+//! 同じクエリで `/stream` に WebSocket で接続すると、粗い解像度から順に描画した PNG を
+//! 1枚ずつバイナリメッセージで送り、最後に原寸の画像を送って接続を閉じる。
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use num::Complex;
+use sha1_smol::Sha1;
+
+use super::cache::TileCache;
+use super::metrics::Metrics;
+use super::admission::{ConnectionLimit, RateLimiter, Rejection, RenderQueue, Slot};
+use super::backend::IterationBudget;
+use super::color::{palette_names, Colors, Palette};
+use super::config;
+use super::encode::{encode_colored, PngOptions};
+use super::{encode_png, pixel_to_point, region_from_center, render_parallel, Failure, RenderParams};
+
+/// リクエストヘッダの大きさの上限
+const MAX_HEADER_LEN: usize = 8192;
+
+/// 接続の数の上限を超えた接続に 503 を書き出すときの期限。受け付けを止めないよう短くする
+const REJECT_WRITE_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// 応答を書き出すときの1回の書き込みの期限
+const WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// `/stream` で順に送るプレビューの縮小率。1枚の画像を粗い段から描き足していくので、各段は前の段の半分にする
+const PREVIEW_SCALES: [usize; 4] = [8, 4, 2, 1];
+
+/// 描画した画像をキャッシュしてよい秒数
+const CACHE_MAX_AGE: u64 = 365 * 24 * 60 * 60;
+
+/// 描画結果の形式の版。同じ条件で画像が変わる変更をしたら上げ、キャッシュの鍵と `ETag` を変える
+const RENDER_FORMAT: u32 = 1;
+
+/// `Sec-WebSocket-Accept` の計算で鍵に連結する GUID (RFC 6455)
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// `serve-api` のオプション
+#[derive(Clone, Debug, PartialEq)]
+struct ServerOptions {
+    /// 同時に開いておく接続の数の上限
+    max_connections: usize,
+    max_concurrent: usize,
+    /// 描画の枠の空きを待たせるリクエスト数の上限
+    max_queue: usize,
+    /// クライアント毎の1分あたりの描画数の上限
+    rate_limit: Option<u32>,
+    timeout: Duration,
+    /// リクエストヘッダを読み終えるまでの期限
+    header_timeout: Duration,
+    max_size: usize,
+    max_iters: u32,
+    /// 1リクエストで行う反復の合計の上限
+    iteration_budget: u64,
+    cache_dir: Option<PathBuf>,
+    /// キャッシュの合計サイズの上限 (バイト)
+    cache_size: u64
+}
+
+impl Default for ServerOptions {
+    fn default() -> ServerOptions {
+        ServerOptions {
+            max_connections: 256,
+            max_concurrent: 4,
+            max_queue: 16,
+            rate_limit: None,
+            timeout: Duration::from_secs(30),
+            header_timeout: Duration::from_secs(10),
+            max_size: 4096,
+            max_iters: 100_000,
+            iteration_budget: 10_000_000_000,
+            cache_dir: None,
+            cache_size: 256 << 20
+        }
+    }
+}
+
+fn parse_server_options(args: &[String]) -> Result<ServerOptions, String> {
+    let mut options = ServerOptions::default();
+    let mut args = args.iter();
+
+    while let Some(name) = args.next() {
+        let value = args.next()
+            .ok_or_else(|| format!("missing value for {}", name))?;
+        let positive = || u64::from_str(value).ok().filter(|&n| n > 0)
+            .ok_or_else(|| format!("{} expects a positive integer", name));
+        match name.as_str() {
+            "--max-connections" => options.max_connections = positive()? as usize,
+            "--max-concurrent" => options.max_concurrent = positive()? as usize,
+            "--max-queue" => {
+                options.max_queue = usize::from_str(value)
+                    .map_err(|_| format!("{} expects a non-negative integer", name))?;
+            }
+            "--rate-limit" => options.rate_limit = Some(positive()?.min(u32::MAX as u64) as u32),
+            "--timeout" => options.timeout = Duration::from_secs(positive()?),
+            "--header-timeout" => options.header_timeout = Duration::from_secs(positive()?),
+            "--max-size" => options.max_size = positive()? as usize,
+            "--max-iters" => options.max_iters = positive()?.min(u32::MAX as u64) as u32,
+            "--iteration-budget" => options.iteration_budget = positive()?,
+            "--cache-dir" => options.cache_dir = Some(PathBuf::from(value)),
+            "--cache-size" => options.cache_size = positive()?.saturating_mul(1 << 20),
+            _ => return Err(format!("unknown option {}", name))
+        }
+    }
+
+    Ok(options)
+}
+
+#[test]
+fn test_parse_server_options() {
+    let args = |s: &str| -> Vec<String> {
+        s.split_whitespace().map(String::from).collect()
+    };
+    assert_eq!(parse_server_options(&args("")), Ok(ServerOptions::default()));
+    assert_eq!(parse_server_options(&args("--max-concurrent 2 --timeout 5")),
+               Ok(ServerOptions {
+                   max_concurrent: 2,
+                   timeout: Duration::from_secs(5),
+                   ..ServerOptions::default()
+               }));
+    assert_eq!(parse_server_options(&args("--cache-dir /tmp/tiles --cache-size 16"))
+                   .map(|o| (o.cache_dir, o.cache_size)),
+               Ok((Some(PathBuf::from("/tmp/tiles")), 16 << 20)));
+    assert_eq!(parse_server_options(&args("--header-timeout 2")).map(|o| o.header_timeout), Ok(Duration::from_secs(2)));
+    assert_eq!(parse_server_options(&args("--iteration-budget 1000")).map(|o| o.iteration_budget), Ok(1000));
+    assert_eq!(parse_server_options(&args("--max-queue 0 --rate-limit 60")).map(|o| (o.max_queue, o.rate_limit)),
+               Ok((0, Some(60))));
+    assert_eq!(parse_server_options(&args("--max-connections 8")).map(|o| o.max_connections), Ok(8));
+    assert!(parse_server_options(&args("--rate-limit 0")).is_err());
+    assert!(parse_server_options(&args("--max-size 0")).is_err());
+    assert!(parse_server_options(&args("--timeout")).is_err());
+}
+
+/// `serve-api ADDR [OPTIONS]` サブコマンド
+pub fn run_server(args: &[String]) -> Result<(), Failure> {
+    let (addr, options) = match args.split_first() {
+        Some((addr, rest)) => {
+            let options = config::from_environment(config::SERVER, rest).and_then(|rest| parse_server_options(&rest));
+            (addr, options.map_err(Failure::Usage)?)
+        }
+        None => return Err(Failure::Usage("serve-api expects a listen address".to_string()))
+    };
+    let listener = TcpListener::bind(addr)
+        .map_err(|e| Failure::Runtime(format!("cannot listen on {}: {}", addr, e)))?;
+    eprintln!("serving on http://{}", addr);
+
+    let cache = match options.cache_dir {
+        Some(ref dir) => Some(TileCache::open(dir, options.cache_size)
+            .map_err(|e| Failure::Runtime(format!("cannot open cache {}: {}", dir.display(), e)))?),
+        None => None
+    };
+    let mut server = Server::new(options);
+    server.cache = cache;
+    serve(listener, Arc::new(server));
+    Ok(())
+}
+
+/// `listener` の接続毎にスレッドを立てて `server` で処理する。開いている接続が上限に達していれば、
+/// スレッドを立てずにすぐ 503 を返して閉じる
+fn serve(listener: TcpListener, server: Arc<Server>) {
+    let connections = Arc::new(ConnectionLimit::new(server.options.max_connections));
+    for stream in listener.incoming() {
+        match stream {
+            Ok(mut stream) => match connections.try_open() {
+                Some(connection) => {
+                    let server = server.clone();
+                    thread::spawn(move || {
+                        let _connection = connection;
+                        if let Err(e) = server.serve_connection(stream) {
+                            eprintln!("serve-api: connection closed: {}", e);
+                        }
+                    });
+                }
+                None => {
+                    server.metrics.count_response(503);
+                    let _ = stream.set_write_timeout(Some(REJECT_WRITE_TIMEOUT));
+                    let _ = Response::text(503, "too many connections").with_header("Retry-After", "1".to_string())
+                        .write_to(&mut stream);
+                }
+            },
+            Err(e) => eprintln!("serve-api: accept failed: {}", e)
+        }
+    }
+}
+
+#[test]
+fn test_serve_connection_limit() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = Server::new(ServerOptions { max_connections: 1, ..ServerOptions::default() });
+    thread::spawn(move || serve(listener, Arc::new(server)));
+
+    // ヘッダを送らずに開いたままの接続が枠を埋めていると、次の接続はすぐ 503 で断られる
+    let idle = TcpStream::connect(addr).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    let get = || {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let _ = stream.write_all(b"GET /render?w=8&h=8 HTTP/1.1\r\n\r\n");
+        let mut response = Vec::new();
+        let _ = stream.read_to_end(&mut response);
+        response
+    };
+    let response = get();
+    assert!(response.starts_with(b"HTTP/1.1 503 "), "{}", String::from_utf8_lossy(&response));
+    drop(idle);
+    // 閉じた接続の枠は返る
+    let started = Instant::now();
+    while !get().starts_with(b"HTTP/1.1 200 ") {
+        assert!(started.elapsed() < Duration::from_secs(5));
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// 読む度に、期限 `deadline` までの残りの時間を読み込みの期限にする接続。1バイトずつ送って来る相手にも
+/// 全体で期限を守らせる
+struct DeadlineReader {
+    stream: TcpStream,
+    deadline: Instant
+}
+
+impl Read for DeadlineReader {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "request header timed out"));
+        }
+        self.stream.set_read_timeout(Some(remaining))?;
+        self.stream.read(buffer)
+    }
+}
+
+/// `reader` からリクエスト行とヘッダを空行まで読む。読めなければ 400 の応答に書く理由を返す
+///
+/// `reader` は `MAX_HEADER_LEN` を1バイト超えたところで終わるよう `take` で区切っておく。
+fn read_head<R: BufRead>(reader: &mut R) -> io::Result<Result<Request, &'static str>> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut request = Request::parse(&line);
+    let mut header_len = line.len();
+    loop {
+        let mut header = String::new();
+        let read = reader.read_line(&mut header)?;
+        header_len += read;
+        if read == 0 || header == "\r\n" || header == "\n" || header_len > MAX_HEADER_LEN {
+            break;
+        }
+        if let Some(request) = request.as_mut() {
+            request.add_header(&header);
+        }
+    }
+    Ok(match request {
+        _ if header_len > MAX_HEADER_LEN => Err("request header too large"),
+        Some(request) => Ok(request),
+        None => Err("malformed request")
+    })
+}
+
+/// 接続から読むのと同じ手順でリクエストの頭 `head` を読み、既定の設定の `/render` の依頼として確かめる
+///
+/// `fuzz-parsers` が外から届く HTTP リクエストの読み方を調べるのに使う。
+pub fn parse_render_request(head: &str) -> Result<(), String> {
+    let request = read_head(&mut head.as_bytes().take(MAX_HEADER_LEN as u64 + 1))
+        .map_err(|e| e.to_string())?
+        .map_err(String::from)?;
+    RenderRequest::from_request(&request, &ServerOptions::default()).map(|_| ())
+}
+
+/// 解析済みの HTTP リクエスト
+#[derive(Debug, PartialEq)]
+struct Request {
+    method: String,
+    path: String,
+    query: Vec<(String, String)>,
+    /// 名前を小文字にそろえたヘッダ
+    headers: Vec<(String, String)>,
+    /// 接続してきたクライアントのアドレス
+    client: Option<IpAddr>
+}
+
+impl Request {
+    /// リクエスト行 `GET /path?query HTTP/1.1` を解析する
+    fn parse(line: &str) -> Option<Request> {
+        let mut parts = line.split_whitespace();
+        let method = parts.next()?.to_string();
+        let target = parts.next()?;
+        if !parts.next()?.starts_with("HTTP/") {
+            return None;
+        }
+        let (path, query) = match target.find('?') {
+            Some(index) => (&target[..index], &target[index + 1..]),
+            None => (target, "")
+        };
+        let query = query.split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| match pair.find('=') {
+                Some(index) => (pair[..index].to_string(), pair[index + 1..].to_string()),
+                None => (pair.to_string(), String::new())
+            })
+            .collect();
+        Some(Request { method, path: path.to_string(), query, headers: vec![], client: None })
+    }
+
+    /// ヘッダ行 `Name: value` を追加する
+    fn add_header(&mut self, line: &str) {
+        if let Some(index) = line.find(':') {
+            self.headers.push((line[..index].trim().to_ascii_lowercase(),
+                               line[index + 1..].trim().to_string()));
+        }
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+    }
+
+    /// `If-None-Match` に `etag` (引用符を含む) か `*` があるか。弱い比較なので `W/` は無視する
+    fn matches_etag(&self, etag: &str) -> bool {
+        self.header("if-none-match").is_some_and(|tags| {
+            tags.split(',').map(str::trim).any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+        })
+    }
+
+    /// WebSocket へのアップグレード要求かどうか
+    fn is_websocket_upgrade(&self) -> bool {
+        let upgrade = self.header("upgrade").is_some_and(|v| v.eq_ignore_ascii_case("websocket"));
+        let connection = self.header("connection").is_some_and(|v| {
+            v.split(',').any(|token| token.trim().eq_ignore_ascii_case("upgrade"))
+        });
+        upgrade && connection && self.header("sec-websocket-key").is_some()
+    }
+
+    fn param(&self, name: &str) -> Option<&str> {
+        self.query.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+    }
+
+    /// クエリパラメータ `name` を解析する。省略されていれば `default` を使う
+    fn parse_param<T: FromStr>(&self, name: &str, default: T) -> Result<T, String> {
+        match self.param(name) {
+            None => Ok(default),
+            Some(value) => T::from_str(value)
+                .map_err(|_| format!("invalid value for {}: {}", name, value))
+        }
+    }
+}
+
+#[test]
+fn test_request_parse() {
+    let request = Request::parse("GET /render?cx=-0.5&w=64&flag HTTP/1.1").unwrap();
+    assert_eq!(request.method, "GET");
+    assert_eq!(request.path, "/render");
+    assert_eq!(request.param("cx"), Some("-0.5"));
+    assert_eq!(request.param("flag"), Some(""));
+    assert_eq!(request.param("h"), None);
+    assert_eq!(request.parse_param("w", 256usize), Ok(64));
+    assert_eq!(request.parse_param("h", 256usize), Ok(256));
+    assert!(request.parse_param::<f64>("flag", 0.0).is_err());
+
+    assert_eq!(Request::parse("GET / HTTP/1.0").map(|r| r.query), Some(vec![]));
+    assert_eq!(Request::parse("GET /render"), None);
+
+    let mut request = Request::parse("GET /stream HTTP/1.1").unwrap();
+    assert!(!request.is_websocket_upgrade());
+    request.add_header("Upgrade: websocket\r\n");
+    request.add_header("Connection: keep-alive, Upgrade");
+    request.add_header("Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==");
+    assert_eq!(request.header("upgrade"), Some("websocket"));
+    assert!(request.is_websocket_upgrade());
+
+    assert!(!request.matches_etag("\"abc\""));
+    request.add_header("If-None-Match: \"xyz\", W/\"abc\"");
+    assert!(request.matches_etag("\"abc\""));
+    assert!(request.matches_etag("\"xyz\""));
+    assert!(!request.matches_etag("\"ab\""));
+
+    // 接続から読むのと同じ手順で頭全体を読む
+    assert_eq!(parse_render_request("GET /render?w=64&palette=fire HTTP/1.1\r\nHost: a\r\n\r\n"), Ok(()));
+    assert!(parse_render_request("GET /render?w=0 HTTP/1.1\r\n\r\n").is_err());
+    assert!(parse_render_request("GET /render\r\n\r\n").is_err());
+    let huge = format!("GET /render HTTP/1.1\r\nX: {}\r\n\r\n", "a".repeat(MAX_HEADER_LEN));
+    assert_eq!(parse_render_request(&huge), Err("request header too large".to_string()));
+}
+
+/// クライアントの `Sec-WebSocket-Key` から `Sec-WebSocket-Accept` の値を求める
+fn websocket_accept(key: &str) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(WEBSOCKET_GUID.as_bytes());
+    base64::encode(sha1.digest().bytes())
+}
+
+#[test]
+fn test_websocket_accept() {
+    // RFC 6455 の例
+    assert_eq!(websocket_accept("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+}
+
+/// サーバからクライアントへ送る WebSocket のフレーム (マスクなし) を書き出す
+fn write_websocket_frame<W: Write>(output: &mut W, opcode: u8, payload: &[u8]) -> io::Result<()> {
+    // FIN ビットを立てて1フレームで送る
+    output.write_all(&[0x80 | opcode])?;
+    match payload.len() {
+        len if len < 126 => output.write_all(&[len as u8])?,
+        len if len <= u16::MAX as usize => {
+            output.write_all(&[126])?;
+            output.write_all(&(len as u16).to_be_bytes())?;
+        }
+        len => {
+            output.write_all(&[127])?;
+            output.write_all(&(len as u64).to_be_bytes())?;
+        }
+    }
+    output.write_all(payload)?;
+    output.flush()
+}
+
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+
+#[test]
+fn test_write_websocket_frame() {
+    let mut frame = Vec::new();
+    write_websocket_frame(&mut frame, OPCODE_BINARY, b"abc").unwrap();
+    assert_eq!(frame, b"\x82\x03abc");
+
+    let mut frame = Vec::new();
+    write_websocket_frame(&mut frame, OPCODE_BINARY, &[0; 300]).unwrap();
+    assert_eq!(&frame[..4], &[0x82, 126, 0x01, 0x2c]);
+    assert_eq!(frame.len(), 4 + 300);
+
+    let mut frame = Vec::new();
+    write_websocket_frame(&mut frame, OPCODE_BINARY, &[0; 70000]).unwrap();
+    assert_eq!(&frame[..10], &[0x82, 127, 0, 0, 0, 0, 0, 0x01, 0x11, 0x70]);
+}
+
+/// 返送する HTTP レスポンス
+#[derive(Debug)]
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    /// `Content-Type` と `Content-Length` の他に返すヘッダ
+    headers: Vec<(&'static str, String)>,
+    body: Vec<u8>
+}
+
+impl Response {
+    fn new(status: u16, content_type: &'static str, body: Vec<u8>) -> Response {
+        Response { status, content_type, headers: vec![], body }
+    }
+
+    fn text(status: u16, message: &str) -> Response {
+        Response::new(status, "text/plain; charset=utf-8", format!("{}\n", message).into_bytes())
+    }
+
+    fn with_header(mut self, name: &'static str, value: String) -> Response {
+        self.headers.push((name, value));
+        self
+    }
+
+    /// 描画条件の鍵 `key` から決まる画像として、`ETag` と長い `Cache-Control` を付ける
+    fn cacheable(self, key: &str) -> Response {
+        self.with_header("ETag", format!("\"{}\"", key))
+            .with_header("Cache-Control", format!("public, max-age={}, immutable", CACHE_MAX_AGE))
+    }
+
+    #[cfg(test)]
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(key, _)| *key == name).map(|(_, value)| value.as_str())
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            101 => "Switching Protocols",
+            200 => "OK",
+            304 => "Not Modified",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            422 => "Unprocessable Entity",
+            429 => "Too Many Requests",
+            503 => "Service Unavailable",
+            504 => "Gateway Timeout",
+            _ => "Internal Server Error"
+        }
+    }
+
+    fn write_to<W: Write>(&self, output: &mut W) -> io::Result<()> {
+        write!(output, "HTTP/1.1 {} {}\r\n", self.status, self.reason())?;
+        write!(output, "Content-Type: {}\r\n", self.content_type)?;
+        write!(output, "Content-Length: {}\r\n", self.body.len())?;
+        for (name, value) in &self.headers {
+            write!(output, "{}: {}\r\n", name, value)?;
+        }
+        write!(output, "Connection: close\r\n\r\n")?;
+        output.write_all(&self.body)?;
+        output.flush()
+    }
+}
+
+/// `/render` のクエリから解析した描画範囲
+#[derive(Debug, PartialEq)]
+struct RenderRequest {
+    center: Complex<f64>,
+    zoom: f64,
+    bounds: (usize, usize),
+    iters: u32,
+    palette: String
+}
+
+impl RenderRequest {
+    fn from_request(request: &Request, options: &ServerOptions) -> Result<RenderRequest, String> {
+        let render = RenderRequest {
+            center: Complex {
+                re: request.parse_param("cx", -0.5)?,
+                im: request.parse_param("cy", 0.0)?
+            },
+            zoom: request.parse_param("zoom", 1.0)?,
+            bounds: (request.parse_param("w", 256)?, request.parse_param("h", 256)?),
+            iters: request.parse_param("iters", 255)?,
+            palette: request.parse_param("palette", "gray".to_string())?
+        };
+
+        if !(render.center.re.is_finite() && render.center.im.is_finite()) {
+            return Err("cx and cy must be finite".to_string());
+        }
+        if !(render.zoom > 0.0 && render.zoom.is_finite()) {
+            return Err("zoom must be a positive number".to_string());
+        }
+        let (width, height) = render.bounds;
+        if width == 0 || height == 0 || width > options.max_size || height > options.max_size {
+            return Err(format!("w and h must be between 1 and {}", options.max_size));
+        }
+        if render.iters == 0 || render.iters > options.max_iters {
+            return Err(format!("iters must be between 1 and {}", options.max_iters));
+        }
+        if Palette::builtin(&render.palette).is_none() {
+            return Err(format!("unknown palette {}, expected one of {}", render.palette, palette_names().join(", ")));
+        }
+
+        Ok(render)
+    }
+
+    /// キャッシュの鍵に使う、描画結果を決める全ての条件を並べた文字列
+    fn describe(&self) -> String {
+        format!("{} {} {} {},{} {} {}x{} {} {}",
+                env!("CARGO_PKG_VERSION"), env!("GIT_REVISION"), RENDER_FORMAT, self.center.re, self.center.im, self.zoom,
+                self.bounds.0, self.bounds.1, self.iters, self.palette)
+    }
+
+    /// 期限 `deadline` と反復の上限 `budget` で描画するパラメータ
+    fn params(&self, deadline: Instant, budget: &Arc<IterationBudget>) -> RenderParams {
+        RenderParams {
+            limits: vec![self.iters],
+            deadline: Some(deadline),
+            budget: Some(budget.clone()),
+            ..RenderParams::default()
+        }
+    }
+}
+
+/// `/stream` で粗い段から描き足していく画像
+struct Progressive {
+    pixels: Vec<u8>,
+    bounds: (usize, usize),
+    upper_left: Complex<f64>,
+    lower_right: Complex<f64>,
+    /// 描き終えた段の縮小率
+    scale: Option<usize>
+}
+
+impl Progressive {
+    fn new(render: &RenderRequest) -> Progressive {
+        let (upper_left, lower_right) = region_from_center(render.center, render.zoom, render.bounds);
+        Progressive {
+            pixels: vec![0; render.bounds.0 * render.bounds.1],
+            bounds: render.bounds,
+            upper_left,
+            lower_right,
+            scale: None
+        }
+    }
+
+    /// 縮小率 `scale` の段を描く。前の段の縮小率は `scale` の2倍で、そのピクセルは描き直さない。
+    /// 描画しきれなかったら `false` を返す
+    fn refine(&mut self, scale: usize, params: &RenderParams) -> bool {
+        let complete = match self.scale {
+            None => self.render_sampled((0, 0), (scale, scale), params),
+            Some(coarser) => {
+                assert_eq!(coarser, 2 * scale);
+                // 前の段の行の間の行と、前の段の行のうち列の間のピクセル
+                self.render_sampled((0, scale), (scale, coarser), params)
+                    && self.render_sampled((scale, 0), (coarser, coarser), params)
+            }
+        };
+        self.scale = Some(scale);
+        complete
+    }
+
+    /// `origin` から `step` おきのピクセルを描く。描くピクセルを並べた小さな画像を描いて書き戻す
+    fn render_sampled(&mut self, origin: (usize, usize), step: (usize, usize), params: &RenderParams) -> bool {
+        if origin.0 >= self.bounds.0 || origin.1 >= self.bounds.1 {
+            return true;
+        }
+        let bounds = ((self.bounds.0 - origin.0).div_ceil(step.0), (self.bounds.1 - origin.1).div_ceil(step.1));
+        let upper_left = pixel_to_point(self.bounds, origin, self.upper_left, self.lower_right);
+        let lower_right = pixel_to_point(self.bounds, (origin.0 + bounds.0 * step.0, origin.1 + bounds.1 * step.1),
+                                         self.upper_left, self.lower_right);
+        let mut samples = vec![0; bounds.0 * bounds.1];
+        let complete = render_parallel(&mut samples, bounds, upper_left, lower_right, params);
+        for (row, line) in samples.chunks(bounds.0).enumerate() {
+            let start = (origin.1 + row * step.1) * self.bounds.0 + origin.0;
+            for (column, &value) in line.iter().enumerate() {
+                self.pixels[start + column * step.0] = value;
+            }
+        }
+        complete
+    }
+
+    /// `scale` おきのピクセルを抜き出した縮小画像とその大きさ
+    fn sample(&self, scale: usize) -> (Vec<u8>, (usize, usize)) {
+        let bounds = (self.bounds.0.div_ceil(scale), self.bounds.1.div_ceil(scale));
+        let pixels = self.pixels.chunks(self.bounds.0).step_by(scale)
+            .flat_map(|line| line.iter().step_by(scale).copied())
+            .collect();
+        (pixels, bounds)
+    }
+}
+
+#[test]
+fn test_progressive() {
+    let render = RenderRequest {
+        center: Complex { re: -0.5, im: 0.0 },
+        zoom: 1.5,
+        bounds: (37, 21),
+        iters: 64,
+        palette: "gray".to_string()
+    };
+    let deadline = Instant::now() + Duration::from_secs(60);
+    let budget = Arc::new(IterationBudget::new(u64::MAX));
+    let params = render.params(deadline, &budget);
+    let mut progressive = Progressive::new(&render);
+    let mut previews = vec![];
+    for &scale in &PREVIEW_SCALES {
+        assert!(progressive.refine(scale, &params));
+        previews.push(progressive.sample(scale));
+    }
+    assert_eq!(previews.iter().map(|(_, bounds)| *bounds).collect::<Vec<_>>(),
+               vec![(5, 3), (10, 6), (19, 11), (37, 21)]);
+
+    // 描き足した画像は1回で描いた画像と同じで、反復の回数も1回分
+    let whole_budget = Arc::new(IterationBudget::new(u64::MAX));
+    let mut whole = vec![0; 37 * 21];
+    let (upper_left, lower_right) = region_from_center(render.center, render.zoom, render.bounds);
+    assert!(render_parallel(&mut whole, render.bounds, upper_left, lower_right, &render.params(deadline, &whole_budget)));
+    assert_eq!(progressive.pixels, whole);
+    assert_eq!(*budget, *whole_budget);
+    // 粗い段のプレビューは細かい段の画像から抜き出したものと同じ
+    assert_eq!(previews[0].0, progressive.sample(8).0);
+}
+
+struct Server {
+    options: ServerOptions,
+    queue: RenderQueue,
+    rate_limiter: Option<RateLimiter>,
+    metrics: Metrics,
+    cache: Option<TileCache>
+}
+
+impl Server {
+    fn new(options: ServerOptions) -> Server {
+        Server {
+            queue: RenderQueue::new(options.max_concurrent, options.max_queue),
+            rate_limiter: options.rate_limit.map(RateLimiter::new),
+            options,
+            metrics: Metrics::default(),
+            cache: None
+        }
+    }
+
+    fn serve_connection(&self, mut stream: TcpStream) -> io::Result<()> {
+        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        // 上限を1バイト超えて読めたら大き過ぎるとわかる
+        let deadline = Instant::now() + self.options.header_timeout;
+        let mut reader = BufReader::new(DeadlineReader { stream: stream.try_clone()?, deadline }
+                                        .take(MAX_HEADER_LEN as u64 + 1));
+        let request = match read_head(&mut reader)? {
+            Ok(request) => request,
+            Err(message) => return Response::text(400, message).write_to(&mut stream)
+        };
+        let request = Request { client: stream.peer_addr().ok().map(|addr| addr.ip()), ..request };
+        if request.method == "GET" && request.path == "/stream" {
+            return self.handle_stream(&request, &mut stream);
+        }
+        self.handle(&request).write_to(&mut stream)
+    }
+
+    fn handle(&self, request: &Request) -> Response {
+        let response = if request.method != "GET" {
+            Response::text(405, "only GET is supported")
+        } else {
+            match request.path.as_str() {
+                "/render" => self.handle_render(request),
+                "/metrics" => self.handle_metrics(),
+                _ => Response::text(404, "not found")
+            }
+        };
+        self.metrics.count_response(response.status);
+        response
+    }
+
+    fn handle_metrics(&self) -> Response {
+        let (active, queued) = self.queue.load();
+        Response::new(200, "text/plain; version=0.0.4", self.metrics.render(active, queued).into_bytes())
+    }
+
+    /// 描画範囲の解析、クライアントの頻度の確認と描画の枠の確保をまとめて行い、描画の期限と一緒に返す。
+    /// 枠の空きを待つ時間も期限に含める
+    fn prepare(&self, request: &Request) -> Result<(RenderRequest, Slot, Instant), Response> {
+        let render = RenderRequest::from_request(request, &self.options)
+            .map_err(|message| Response::text(400, &message))?;
+        let now = Instant::now();
+        let deadline = now + self.options.timeout;
+        let admitted = match (&self.rate_limiter, request.client) {
+            (Some(limiter), Some(client)) => limiter.check(client, now),
+            _ => Ok(())
+        };
+        let slot = admitted.and_then(|_| self.queue.acquire(deadline)).map_err(|rejection| match rejection {
+            Rejection::QueueFull => Response::text(429, "render queue is full").with_header("Retry-After", "1".to_string()),
+            Rejection::TimedOut => Response::text(503, "timed out waiting for a render slot"),
+            Rejection::RateLimited(wait) => Response::text(429, "rate limit exceeded")
+                .with_header("Retry-After", wait.as_secs_f64().ceil().to_string())
+        })?;
+        Ok((render, slot, deadline))
+    }
+
+    /// `render` の範囲を PNG に描画する。期限 `deadline` と反復の上限 `budget` は同じリクエストの描画で共有する
+    fn render_png(&self, render: &RenderRequest, deadline: Instant, budget: &Arc<IterationBudget>)
+        -> Result<Vec<u8>, Response>
+    {
+        let (upper_left, lower_right) = region_from_center(render.center, render.zoom, render.bounds);
+        let mut pixels = vec![0; render.bounds.0 * render.bounds.1];
+        let started = Instant::now();
+        if !render_parallel(&mut pixels, render.bounds, upper_left, lower_right, &render.params(deadline, budget)) {
+            return Err(self.incomplete(budget));
+        }
+        self.metrics.observe_render(started.elapsed());
+        self.encode(render, &pixels, render.bounds)
+    }
+
+    /// 期限を過ぎたか反復の上限 `budget` を使い切って描画しきれなかったときの応答
+    fn incomplete(&self, budget: &IterationBudget) -> Response {
+        if budget.exhausted() {
+            self.metrics.count_budget_exceeded();
+            return Response::text(422, &format!("render exceeded the iteration budget of {}",
+                                                self.options.iteration_budget));
+        }
+        self.metrics.count_timeout();
+        Response::text(504, "render timed out")
+    }
+
+    /// 描画したピクセル `pixels` を `render` の配色で PNG にする
+    fn encode(&self, render: &RenderRequest, pixels: &[u8], bounds: (usize, usize)) -> Result<Vec<u8>, Response> {
+        let colors = Colors::resolve(Some(&render.palette), None, None)
+            .map_err(|e| Response::text(500, &format!("error resolving palette: {}", e)))?;
+        let mut png = Vec::new();
+        if colors.is_plain() {
+            encode_png(&mut png, pixels, bounds)
+        } else {
+            encode_colored(&mut png, pixels, bounds, &colors, &PngOptions::default())
+        }.map_err(|e| Response::text(500, &format!("error encoding PNG: {}", e)))?;
+        Ok(png)
+    }
+
+    fn handle_render(&self, request: &Request) -> Response {
+        let render = match RenderRequest::from_request(request, &self.options) {
+            Ok(render) => render,
+            Err(message) => return Response::text(400, &message)
+        };
+        let key = TileCache::key(&render.describe());
+        if request.matches_etag(&format!("\"{}\"", key)) {
+            return Response::new(304, "image/png", vec![]).cacheable(&key);
+        }
+        if let Some(cache) = &self.cache {
+            let cached = cache.get(&key);
+            self.metrics.count_cache(cached.is_some());
+            if let Some(body) = cached {
+                return Response::new(200, "image/png", body).cacheable(&key);
+            }
+        }
+
+        let (render, _slot, deadline) = match self.prepare(request) {
+            Ok(prepared) => prepared,
+            Err(response) => return response
+        };
+        let budget = Arc::new(IterationBudget::new(self.options.iteration_budget));
+        match self.render_png(&render, deadline, &budget) {
+            Ok(body) => {
+                if let Some(cache) = &self.cache {
+                    if let Err(e) = cache.put(&key, &body) {
+                        eprintln!("serve-api: cannot write cache: {}", e);
+                    }
+                }
+                Response::new(200, "image/png", body).cacheable(&key)
+            }
+            Err(response) => response
+        }
+    }
+
+    /// WebSocket に切り替え、縮小率 `PREVIEW_SCALES` の順にプレビューの PNG を送る
+    ///
+    /// プレビューは1枚の画像を描き足して作る。最初の段は `PREVIEW_SCALES[0]` おきのピクセルだけを描き、
+    /// 次の段からは前の段で描いたピクセルの間だけを描くので、全部の段を合わせても画像1枚分しか反復しない。
+    /// 各段のプレビューは、その段で描いたピクセルを縮小率おきに抜き出したもの
+    fn handle_stream<W: Write>(&self, request: &Request, output: &mut W) -> io::Result<()> {
+        if !request.is_websocket_upgrade() {
+            self.metrics.count_response(400);
+            return Response::text(400, "expected a WebSocket upgrade").write_to(output);
+        }
+        let (render, _slot, deadline) = match self.prepare(request) {
+            Ok(prepared) => prepared,
+            Err(response) => {
+                self.metrics.count_response(response.status);
+                return response.write_to(output);
+            }
+        };
+        self.metrics.count_response(101);
+
+        let key = request.header("sec-websocket-key").unwrap_or_default();
+        write!(output, "HTTP/1.1 101 Switching Protocols\r\n")?;
+        write!(output, "Upgrade: websocket\r\nConnection: Upgrade\r\n")?;
+        write!(output, "Sec-WebSocket-Accept: {}\r\n\r\n", websocket_accept(key))?;
+
+        let budget = Arc::new(IterationBudget::new(self.options.iteration_budget));
+        let params = render.params(deadline, &budget);
+        let mut progressive = Progressive::new(&render);
+        let started = Instant::now();
+        for &scale in &PREVIEW_SCALES {
+            let preview = if progressive.refine(scale, &params) {
+                let (pixels, bounds) = progressive.sample(scale);
+                if scale == 1 {
+                    self.metrics.observe_render(started.elapsed());
+                }
+                self.encode(&render, &pixels, bounds)
+            } else {
+                Err(self.incomplete(&budget))
+            };
+            match preview {
+                Ok(png) => write_websocket_frame(output, OPCODE_BINARY, &png)?,
+                Err(response) => {
+                    // 1011: サーバ側の都合で続けられない
+                    let mut reason = 1011u16.to_be_bytes().to_vec();
+                    reason.extend_from_slice(&response.body[..response.body.len().min(100)]);
+                    return write_websocket_frame(output, OPCODE_CLOSE, &reason);
+                }
+            }
+        }
+        write_websocket_frame(output, OPCODE_CLOSE, &1000u16.to_be_bytes())
+    }
+}
+
+#[test]
+fn test_serve_connection_header_limits() {
+    let server = Server::new(ServerOptions { header_timeout: Duration::from_millis(300), ..ServerOptions::default() });
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let client = |request: Vec<u8>| thread::spawn(move || {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        // 送り切る前にサーバが閉じることもある
+        let _ = stream.write_all(&request);
+        let mut response = Vec::new();
+        let _ = stream.read_to_end(&mut response);
+        response
+    });
+
+    // 改行の無い行は上限まで読んだところで断る。上限より先は読まないので、読み残しの無い長さで確かめる
+    let huge = client(vec![b'a'; MAX_HEADER_LEN + 1]);
+    server.serve_connection(listener.accept().unwrap().0).unwrap();
+    assert!(huge.join().unwrap().starts_with(b"HTTP/1.1 400 "));
+
+    // 何も送らない接続は期限で閉じる
+    let idle = client(vec![]);
+    let started = Instant::now();
+    assert!(server.serve_connection(listener.accept().unwrap().0).is_err());
+    assert!(started.elapsed() < Duration::from_secs(5));
+    drop(idle);
+
+    let ok = client(b"GET /render?w=8&h=8 HTTP/1.1\r\nHost: x\r\n\r\n".to_vec());
+    server.serve_connection(listener.accept().unwrap().0).unwrap();
+    assert!(ok.join().unwrap().starts_with(b"HTTP/1.1 200 "));
+}
+
+#[test]
+fn test_handle_render() {
+    let server = Server::new(ServerOptions::default());
+    let get = |target: &str| {
+        server.handle(&Request::parse(&format!("GET {} HTTP/1.1", target)).unwrap())
+    };
+
+    let response = get("/render?cx=-0.5&cy=0&zoom=2&w=32&h=24&iters=64");
+    assert_eq!(response.status, 200);
+    assert_eq!(response.content_type, "image/png");
+    assert_eq!(&response.body[..8], b"\x89PNG\r\n\x1a\n");
+
+    assert_eq!(get("/render?w=0").status, 400);
+    assert_eq!(get("/render?w=5000").status, 400);
+    assert_eq!(get("/render?zoom=-1").status, 400);
+    assert_eq!(get("/render?iters=1000000").status, 400);
+    assert_eq!(get("/render?palette=rainbow").status, 400);
+    assert_eq!(get("/render?cx=abc").status, 400);
+    assert_eq!(get("/").status, 404);
+    assert_eq!(server.handle(&Request::parse("POST /render HTTP/1.1").unwrap()).status, 405);
+
+    let metrics = get("/metrics");
+    assert_eq!(metrics.status, 200);
+    let metrics = String::from_utf8(metrics.body).unwrap();
+    assert!(metrics.contains("mandelbrot_renders_total 1\n"));
+    assert!(metrics.contains("mandelbrot_http_responses_total{status=\"400\"} 6\n"));
+
+    // パレットのファイルは受け付けない
+    assert_eq!(get("/render?palette=/etc/palette.toml").status, 400);
+    // 組み込みのパレットは RGB の PNG になり、灰色とは別の ETag になる
+    let gray = get("/render?w=8&h=8");
+    let fire = get("/render?w=8&h=8&palette=fire");
+    assert_eq!(fire.status, 200);
+    let decoded = image::load_from_memory(&fire.body).unwrap();
+    assert_eq!(decoded.color(), image::ColorType::RGB(8));
+    assert_ne!(fire.header("ETag"), gray.header("ETag"));
+}
+
+#[test]
+fn test_handle_render_limits() {
+    let server = Server::new(ServerOptions { max_concurrent: 1, max_queue: 0, ..ServerOptions::default() });
+    let request = Request::parse("GET /render?w=8&h=8 HTTP/1.1").unwrap();
+
+    let slot = server.queue.acquire(Instant::now());
+    assert!(slot.is_ok());
+    let response = server.handle(&request);
+    assert_eq!((response.status, response.header("Retry-After")), (429, Some("1")));
+    drop(slot);
+    assert_eq!(server.handle(&request).status, 200);
+
+    // 列に並べても期限までに枠が空かなければ諦める
+    let server = Server::new(ServerOptions { max_concurrent: 1, timeout: Duration::from_secs(0),
+                                             ..ServerOptions::default() });
+    let _slot = server.queue.acquire(Instant::now());
+    assert_eq!(server.handle(&request).status, 503);
+
+    let server = Server::new(ServerOptions { rate_limit: Some(1), ..ServerOptions::default() });
+    let client = Request { client: Some([192, 0, 2, 1].into()), ..Request::parse("GET /render?w=8&h=8 HTTP/1.1").unwrap() };
+    assert_eq!(server.handle(&client).status, 200);
+    let response = server.handle(&client);
+    assert_eq!((response.status, response.header("Retry-After")), (429, Some("60")));
+    assert_eq!(server.handle(&request).status, 200);
+
+    let server = Server::new(ServerOptions { timeout: Duration::from_secs(0), ..ServerOptions::default() });
+    assert_eq!(server.handle(&request).status, 504);
+
+    // 内部ばかりの範囲は 1 ピクセルで iters 回反復するので、64x64 ピクセルの描画は上限の 10000 回に収まらない
+    let server = Server::new(ServerOptions { iteration_budget: 10_000, ..ServerOptions::default() });
+    let inside = Request::parse("GET /render?cx=-0.1&cy=0&zoom=20&w=64&h=64&iters=1000 HTTP/1.1").unwrap();
+    let response = server.handle(&inside);
+    assert_eq!(response.status, 422);
+    assert!(server.metrics.render(0, 0).contains("mandelbrot_render_budget_exceeded_total 1\n"));
+    assert_eq!(server.handle(&request).status, 200);
+}
+
+#[test]
+fn test_handle_render_etag() {
+    let server = Server::new(ServerOptions::default());
+    let target = "/render?cx=-0.5&zoom=2&w=16&h=16&iters=32";
+    let response = server.handle(&Request::parse(&format!("GET {} HTTP/1.1", target)).unwrap());
+    assert_eq!(response.status, 200);
+    let etag = response.header("ETag").unwrap().to_string();
+    assert!(etag.starts_with('"') && etag.ends_with('"'));
+    assert_eq!(response.header("Cache-Control"), Some("public, max-age=31536000, immutable"));
+
+    // 別のサーバでも同じ条件なら同じ ETag になり、一致すれば描画せずに 304 を返す
+    let other = Server::new(ServerOptions::default());
+    let mut conditional = Request::parse(&format!("GET {} HTTP/1.1", target)).unwrap();
+    conditional.add_header(&format!("If-None-Match: {}", etag));
+    let response = other.handle(&conditional);
+    assert_eq!((response.status, response.body.len()), (304, 0));
+    assert_eq!(response.header("ETag"), Some(etag.as_str()));
+    assert!(other.metrics.render(0, 0).contains("mandelbrot_renders_total 0\n"));
+
+    let mut changed = Request::parse("GET /render?cx=-0.5&zoom=2&w=16&h=16&iters=33 HTTP/1.1").unwrap();
+    changed.add_header(&format!("If-None-Match: {}", etag));
+    let response = other.handle(&changed);
+    assert_eq!(response.status, 200);
+    assert_ne!(response.header("ETag"), Some(etag.as_str()));
+
+    // 鍵にはビルドしたリビジョンと描画結果の形式も入る
+    let render = RenderRequest::from_request(&Request::parse(&format!("GET {} HTTP/1.1", target)).unwrap(),
+                                             &ServerOptions::default()).unwrap();
+    assert!(render.describe().starts_with(&format!("{} {} {} ", env!("CARGO_PKG_VERSION"), env!("GIT_REVISION"),
+                                                    RENDER_FORMAT)));
+}
+
+#[test]
+fn test_handle_render_cache() {
+    let dir = std::env::temp_dir().join(format!("mandelbrot-server-cache-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let mut server = Server::new(ServerOptions::default());
+    server.cache = Some(TileCache::open(&dir, 1 << 20).unwrap());
+    let request = Request::parse("GET /render?w=16&h=16&iters=32 HTTP/1.1").unwrap();
+
+    let first = server.handle(&request);
+    let second = server.handle(&request);
+    assert_eq!(first.status, 200);
+    assert_eq!(first.body, second.body);
+    let metrics = server.metrics.render(0, 0);
+    assert!(metrics.contains("mandelbrot_renders_total 1\n"));
+    assert!(metrics.contains("mandelbrot_cache_hits_total 1\n"));
+    assert!(metrics.contains("mandelbrot_cache_misses_total 1\n"));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_handle_stream() {
+    let server = Server::new(ServerOptions::default());
+    let mut request = Request::parse("GET /stream?w=32&h=24 HTTP/1.1").unwrap();
+
+    let mut output = Vec::new();
+    server.handle_stream(&request, &mut output).unwrap();
+    assert!(output.starts_with(b"HTTP/1.1 400 "));
+
+    request.add_header("Upgrade: websocket");
+    request.add_header("Connection: Upgrade");
+    request.add_header("Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==");
+    let mut output = Vec::new();
+    server.handle_stream(&request, &mut output).unwrap();
+
+    let header_end = output.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+    let header = String::from_utf8_lossy(&output[..header_end]);
+    assert!(header.starts_with("HTTP/1.1 101 "));
+    assert!(header.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+
+    // プレビューの枚数分のバイナリフレームの後に close フレームが続く
+    let mut frames = vec![];
+    let mut rest = &output[header_end..];
+    while !rest.is_empty() {
+        let opcode = rest[0] & 0x0f;
+        let (len, offset) = match rest[1] {
+            126 => (u16::from_be_bytes([rest[2], rest[3]]) as usize, 4),
+            len => (len as usize, 2)
+        };
+        frames.push((opcode, rest[offset..offset + len].to_vec()));
+        rest = &rest[offset + len..];
+    }
+    assert_eq!(frames.len(), PREVIEW_SCALES.len() + 1);
+    assert!(frames[..PREVIEW_SCALES.len()].iter()
+            .all(|(opcode, png)| *opcode == OPCODE_BINARY && png.starts_with(b"\x89PNG")));
+    assert_eq!(frames.last(), Some(&(OPCODE_CLOSE, vec![0x03, 0xe8])));
+}
@@ -0,0 +1,115 @@
+//! 画像と並べて書く JSON のメタデータ `--sidecar`
+//!
+//! `image.png` に対して `image.json` を書き、どの範囲をどのパラメータで描いたかと、描画に掛かった時間、
+//! バックエンド、スレッド数、クレートのバージョンを残す。`options` は描画のコマンドにそのまま渡せるので、
+//! データセットの出所を辿ったり、スクリプトから描き直したりできる。
+
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use num::Complex;
+use serde::Serialize;
+
+use super::RenderParams;
+
+/// 解析し終えたパラメータ。列挙型は `Debug` の表記で書く
+#[derive(Debug, Serialize)]
+struct ResolvedParams {
+    limits: Vec<u32>,
+    fractal: String,
+    bailout: f64,
+    bailout_norm: String,
+    interior_check: bool,
+    coloring: String,
+    layers: Vec<String>,
+    exterior_texture: bool,
+    texture_mode: String,
+    projection: String,
+    mobius: Option<String>
+}
+
+#[derive(Debug, Serialize)]
+pub struct Sidecar {
+    image: String,
+    version: &'static str,
+    revision: &'static str,
+    pixels: (usize, usize),
+    upper_left: (f64, f64),
+    lower_right: (f64, f64),
+    /// 範囲の後に渡したオプション
+    options: Vec<String>,
+    params: ResolvedParams,
+    render_secs: f64,
+    backend: &'static str,
+    threads: usize
+}
+
+impl Sidecar {
+    pub fn new(image: &str,
+               bounds: (usize, usize),
+               upper_left: Complex<f64>,
+               lower_right: Complex<f64>,
+               options: &[String],
+               params: &RenderParams,
+               elapsed: Duration)
+        -> Sidecar
+    {
+        let backend = params.scheduling.backend;
+        Sidecar {
+            image: image.to_string(),
+            version: env!("CARGO_PKG_VERSION"),
+            revision: env!("GIT_REVISION"),
+            pixels: bounds,
+            upper_left: (upper_left.re, upper_left.im),
+            lower_right: (lower_right.re, lower_right.im),
+            options: options.to_vec(),
+            params: ResolvedParams {
+                limits: params.limits.clone(),
+                fractal: format!("{:?}", params.fractal),
+                bailout: params.termination.radius,
+                bailout_norm: format!("{:?}", params.termination.norm),
+                interior_check: params.termination.detect_interior,
+                coloring: format!("{:?}", params.coloring),
+                layers: params.layers.iter().map(|layer| format!("{:?}", layer)).collect(),
+                exterior_texture: params.exterior_texture.is_some(),
+                texture_mode: format!("{:?}", params.texture_mode),
+                projection: format!("{:?}", params.projection),
+                mobius: params.mobius.as_ref().map(|mobius| format!("{:?}", mobius))
+            },
+            render_secs: elapsed.as_secs_f64(),
+            backend: backend.name(),
+            threads: backend.workers(&params.scheduling)
+        }
+    }
+
+    /// 画像 `image` の拡張子を `.json` に替えたパス
+    pub fn path_for(image: &str) -> String {
+        Path::new(image).with_extension("json").to_string_lossy().into_owned()
+    }
+
+    pub fn write(&self, path: &str) -> Result<(), String> {
+        let json = serde_json::to_string_pretty(self).map_err(|e| e.to_string())?;
+        fs::write(path, json + "\n").map_err(|e| format!("cannot write {}: {}", path, e))
+    }
+}
+
+#[test]
+fn test_sidecar() {
+    assert_eq!(Sidecar::path_for("out/mandel.png"), "out/mandel.json");
+    assert_eq!(Sidecar::path_for("mandel"), "mandel.json");
+
+    let options = vec!["--passes".to_string(), "64,256".to_string()];
+    let params = super::parse_params(&options).unwrap();
+    let sidecar = Sidecar::new("mandel.png", (100, 75), Complex { re: -1.2, im: 0.35 }, Complex { re: -1.0, im: 0.2 },
+                               &options, &params, Duration::from_millis(1500));
+    let json: serde_json::Value = serde_json::from_str(&serde_json::to_string(&sidecar).unwrap()).unwrap();
+    assert_eq!(json["pixels"], serde_json::json!([100, 75]));
+    assert_eq!(json["upper_left"], serde_json::json!([-1.2, 0.35]));
+    assert_eq!(json["options"], serde_json::json!(["--passes", "64,256"]));
+    assert_eq!(json["params"]["limits"], serde_json::json!([64, 256]));
+    assert_eq!(json["params"]["fractal"], "Mandelbrot");
+    assert_eq!(json["render_secs"], 1.5);
+    assert_eq!(json["backend"], "rayon");
+    assert_eq!(json["version"], env!("CARGO_PKG_VERSION"));
+}
@@ -0,0 +1,161 @@
+//! 拡大して眺められるフラクタルの静的なサイトを書き出す `export-site DIR` サブコマンド
+//!
+//! `--center` と `--zoom` の範囲を1辺とする正方形をレベル 0 のタイル1枚とし、レベルが1つ上がる毎に
+//! 縦横2つずつに分けた XYZ 形式のタイル `DIR/tiles/{z}/{x}/{y}.png` を `--levels N` 段描く。
+//! 一緒に書く `DIR/index.html` はタイルを読む JavaScript のビューアで、外部のファイルを使わないので
+//! ディレクトリごと静的なホスティングに置けば共有できる。ドラッグで移動、ホイールとダブルクリックで拡大し、
+//! 表示中の位置は URL の `#RE,IM,SCALE` に残る。
+//!
+//! ```bash
+//! $ mandelbrot export-site /tmp/site --center -0.75,0 --zoom 1 --levels 6 --passes 1024
+//! ```
+
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use num::Complex;
+
+use super::{parse_complex, render_file, Failure};
+
+/// タイルを読むビューア。`/*SITE*/` をサイトの設定に置き換えて書き出す
+const VIEWER: &str = include_str!("viewer.html");
+
+/// 書き出すレベル数の上限。レベル `z` は 4^z 枚のタイルになる
+const MAX_LEVELS: u32 = 12;
+
+#[derive(Debug, PartialEq)]
+struct SiteArgs {
+    dir: String,
+    center: Complex<f64>,
+    /// レベル 0 のタイル1枚の幅が 4 / zoom
+    zoom: f64,
+    levels: u32,
+    tile_size: usize,
+    /// 描画パラメータとして `parse_params` に渡す残り
+    options: Vec<String>
+}
+
+fn parse_site_args(args: &[String]) -> Result<SiteArgs, String> {
+    let (dir, rest) = args.split_first().ok_or("export-site expects a directory")?;
+    let mut site = SiteArgs {
+        dir: dir.clone(),
+        center: Complex { re: -0.5, im: 0.0 },
+        zoom: 1.0,
+        levels: 5,
+        tile_size: 256,
+        options: vec![]
+    };
+    let mut args = rest.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().map(String::as_str).ok_or(format!("{} expects a value", arg));
+        match arg.as_str() {
+            "--center" => site.center = parse_complex(value()?).ok_or("--center expects RE,IM")?,
+            "--zoom" => {
+                site.zoom = f64::from_str(value()?).ok().filter(|zoom| *zoom > 0.0 && zoom.is_finite())
+                    .ok_or("--zoom expects a positive number")?;
+            }
+            "--levels" => {
+                site.levels = u32::from_str(value()?).ok().filter(|n| (1 ..= MAX_LEVELS).contains(n))
+                    .ok_or(format!("--levels expects a number from 1 to {}", MAX_LEVELS))?;
+            }
+            "--tile-size" => {
+                site.tile_size = usize::from_str(value()?).ok().filter(|n| *n > 0)
+                    .ok_or("--tile-size expects a positive integer")?;
+            }
+            _ => site.options.push(arg.clone())
+        }
+    }
+    Ok(site)
+}
+
+impl SiteArgs {
+    /// レベル 0 のタイル1枚の幅
+    fn width(&self) -> f64 {
+        4.0 / self.zoom
+    }
+
+    /// レベル `level` の左から `x` 番目、上から `y` 番目のタイルの左上と右下の点
+    fn tile_region(&self, level: u32, x: usize, y: usize) -> (Complex<f64>, Complex<f64>) {
+        let extent = self.width() / (1u64 << level) as f64;
+        let left = self.center.re - self.width() / 2.0 + x as f64 * extent;
+        let top = self.center.im + self.width() / 2.0 - y as f64 * extent;
+        (Complex { re: left, im: top }, Complex { re: left + extent, im: top - extent })
+    }
+
+    /// ビューアに埋め込む設定
+    fn viewer(&self) -> String {
+        let config = format!("{{ \"tileSize\": {}, \"levels\": {}, \"center\": [{:?}, {:?}], \"width\": {:?} }}",
+                             self.tile_size, self.levels, self.center.re, self.center.im, self.width());
+        VIEWER.replace("/*SITE*/", &config)
+    }
+}
+
+#[test]
+fn test_parse_site_args() {
+    let args = |s: &str| -> Vec<String> { s.split_whitespace().map(String::from).collect() };
+    let site = parse_site_args(&args("out --center -0.75,0.1 --zoom 2 --levels 3 --passes 64 --tile-size 128")).unwrap();
+    assert_eq!(site, SiteArgs {
+        dir: "out".to_string(),
+        center: Complex { re: -0.75, im: 0.1 },
+        zoom: 2.0,
+        levels: 3,
+        tile_size: 128,
+        options: args("--passes 64")
+    });
+    assert_eq!(parse_site_args(&args("out")).map(|site| (site.levels, site.tile_size)), Ok((5, 256)));
+    assert!(parse_site_args(&[]).is_err());
+    assert!(parse_site_args(&args("out --levels 0")).is_err());
+    assert!(parse_site_args(&args("out --levels 13")).is_err());
+    assert!(parse_site_args(&args("out --zoom 0")).is_err());
+    assert!(parse_site_args(&args("out --center")).is_err());
+}
+
+#[test]
+fn test_tile_region() {
+    let site = parse_site_args(&["out".to_string()]).unwrap();
+    assert_eq!(site.tile_region(0, 0, 0), (Complex { re: -2.5, im: 2.0 }, Complex { re: 1.5, im: -2.0 }));
+    // レベル 1 の右下のタイル
+    assert_eq!(site.tile_region(1, 1, 1), (Complex { re: -0.5, im: 0.0 }, Complex { re: 1.5, im: -2.0 }));
+    assert_eq!(site.tile_region(2, 1, 2).0, Complex { re: -1.5, im: 0.0 });
+
+    let html = site.viewer();
+    assert!(html.contains(r#"const site = { "tileSize": 256, "levels": 5, "center": [-0.5, 0.0], "width": 4.0 };"#));
+    assert!(!html.contains("/*SITE*/"));
+}
+
+/// `export-site DIR [--center RE,IM] [--zoom Z] [--levels N] [--tile-size N] [OPTIONS]` サブコマンド
+pub fn run_export_site(args: &[String]) -> Result<(), Failure> {
+    let site = parse_site_args(args).map_err(Failure::Usage)?;
+    if site.options.iter().any(|option| option == "--normalize") {
+        return Err(Failure::Usage("--normalize stretches each tile differently and cannot be used with export-site"
+                                  .to_string()));
+    }
+    // タイル毎にパスを減らすと明るさの段がタイル毎に変わって継ぎ目が見えるので、全てのパスを使う
+    let options: Vec<String> = ["--pass-stop", "0"].iter().map(|s| s.to_string()).chain(site.options.clone()).collect();
+    let dir = Path::new(&site.dir);
+    let bounds = (site.tile_size, site.tile_size);
+    for level in 0 .. site.levels {
+        let count = 1usize << level;
+        eprintln!("level {}: {} tiles", level, count * count);
+        for x in 0 .. count {
+            let column = dir.join("tiles").join(level.to_string()).join(x.to_string());
+            fs::create_dir_all(&column)
+                .map_err(|e| Failure::Runtime(format!("cannot create {}: {}", column.display(), e)))?;
+            for y in 0 .. count {
+                let (upper_left, lower_right) = site.tile_region(level, x, y);
+                let path = column.join(format!("{}.png", y));
+                // 描画のオプションの誤りは最初のタイルで分かるので、使い方も表示する
+                render_file(&path.to_string_lossy(), bounds, upper_left, lower_right, &options, false)
+                    .map_err(|failure| match failure {
+                        Failure::Usage(message) => Failure::Usage(message),
+                        Failure::Runtime(message) => Failure::Runtime(format!("tile {}: {}", path.display(), message))
+                    })?;
+            }
+        }
+    }
+    let index = dir.join("index.html");
+    fs::write(&index, site.viewer()).map_err(|e| Failure::Runtime(format!("cannot write {}: {}", index.display(), e)))?;
+    eprintln!("wrote {}", index.display());
+    Ok(())
+}
@@ -0,0 +1,91 @@
+//! 集合の外側に画像を貼る `--exterior-texture`
+//!
+//! 発散した点の脱出角 (`arg z` を `0.0 ..= 1.0` にしたもの) を横、連続化した反復回数を縦の座標にして
+//! テクスチャを引く。縦は反復の帯1本でテクスチャ1枚分進むので、`--texture-mode` で範囲外の扱いを選ぶ。
+//! `wrap` は同じ向きで繰り返し、`mirror` は帯ごとに上下を反転して帯の境目の継ぎ目をなくす。
+
+use std::str::FromStr;
+
+/// テクスチャ座標が `0.0 .. 1.0` の外に出たときの扱い
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TextureMode {
+    Wrap,
+    Mirror
+}
+
+impl FromStr for TextureMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<TextureMode, String> {
+        match s {
+            "wrap" => Ok(TextureMode::Wrap),
+            "mirror" => Ok(TextureMode::Mirror),
+            _ => Err(format!("unknown texture mode '{}', expected wrap or mirror", s))
+        }
+    }
+}
+
+impl TextureMode {
+    /// 座標 `t` を `0.0 .. 1.0` に折り込む
+    fn fold(self, t: f64) -> f64 {
+        match self {
+            TextureMode::Wrap => t - t.floor(),
+            TextureMode::Mirror => {
+                let t = t.rem_euclid(2.0);
+                if t < 1.0 { t } else { 2.0 - t }
+            }
+        }
+    }
+}
+
+/// グレースケールにして読み込んだテクスチャ
+#[derive(Debug, PartialEq)]
+pub struct Texture {
+    width: usize,
+    height: usize,
+    pixels: Vec<u8>
+}
+
+impl Texture {
+    pub fn new(width: usize, height: usize, pixels: Vec<u8>) -> Texture {
+        assert!(width > 0 && height > 0 && pixels.len() == width * height);
+        Texture { width, height, pixels }
+    }
+
+    /// `image` が読める形式の画像を読み込む
+    pub fn open(path: &str) -> Result<Texture, String> {
+        let image = image::open(path)
+            .map_err(|e| format!("cannot read texture {}: {}", path, e))?
+            .to_luma();
+        let (width, height) = image.dimensions();
+        if width == 0 || height == 0 {
+            return Err(format!("texture {} is empty", path));
+        }
+        Ok(Texture::new(width as usize, height as usize, image.into_raw()))
+    }
+
+    /// 座標 `(u, v)` に最も近いピクセルの値。`v` は上から下に増える
+    pub fn sample(&self, (u, v): (f64, f64), mode: TextureMode) -> u8 {
+        let x = ((mode.fold(u) * self.width as f64) as usize).min(self.width - 1);
+        let y = ((mode.fold(v) * self.height as f64) as usize).min(self.height - 1);
+        self.pixels[y * self.width + x]
+    }
+}
+
+#[test]
+fn test_texture_sample() {
+    // 2x2 の市松模様
+    let texture = Texture::new(2, 2, vec![0, 10, 20, 30]);
+    assert_eq!(texture.sample((0.25, 0.25), TextureMode::Wrap), 0);
+    assert_eq!(texture.sample((0.75, 0.75), TextureMode::Wrap), 30);
+    // wrap は1進むごとに同じ向きで繰り返し、mirror は反転する
+    assert_eq!(texture.sample((0.25, 1.25), TextureMode::Wrap), 0);
+    assert_eq!(texture.sample((0.25, 1.25), TextureMode::Mirror), 20);
+    assert_eq!(texture.sample((-0.25, 0.25), TextureMode::Wrap), 10);
+    assert_eq!(texture.sample((-0.25, 0.25), TextureMode::Mirror), 0);
+    assert_eq!(texture.sample((1.0, 1.0), TextureMode::Mirror), 30);
+
+    assert_eq!(TextureMode::from_str("mirror"), Ok(TextureMode::Mirror));
+    assert!(TextureMode::from_str("clamp").is_err());
+    assert!(Texture::open("/nonexistent/texture.png").is_err());
+}
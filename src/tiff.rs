@@ -0,0 +1,475 @@
+//! 数ギガピクセルの画像のためのタイル分割した BigTIFF
+//!
+//! 拡張子が `.tif` か `.tiff` の出力はこの形式で書く。画像を `--tile-size N` (既定値: 256) の正方形のタイルに分け、
+//! タイル毎に deflate で圧縮する。タイル1段分の行を描き終える度に書き出すので、画像全体のバッファを確保しない。
+//! `--pyramid` を付けると、幅と高さを半分ずつにした縮小画像を1つのタイルに収まるまで続く IFD に加える。
+//! GIS や顕微鏡画像のビューアはこれを縮小表示 (overview) として読み、表示する範囲のタイルだけを読み込む。
+//!
+//! 縮小画像は上の段の 2x2 ピクセルの平均で、上の段の行が2行揃う度に作るので、どの段も1段分の行しか持たない。
+//! IFD はタイルを全て書いた後にファイルの末尾にまとめて書き、ヘッダーの最初の IFD の位置を後から書き換える。
+//! パレットで色を付けたり透明にする点を抜いたりするときは、縮小も灰色のまま行い、タイルにするときに置き換える。
+//! ICC プロファイルは各段の IFD の InterColorProfile タグが、IFD の前に1つだけ書いたプロファイルを指す。
+
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use num::Complex;
+use rayon::prelude::*;
+
+use super::backend::{self, WorkLog};
+use super::color::Colors;
+use super::webformat::Encoding;
+use super::RenderParams;
+
+/// タイルの分け方
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Tiling {
+    /// タイルの一辺。TIFF の決まりで 16 の倍数
+    pub tile_size: usize,
+    /// 縮小画像を加える
+    pub pyramid: bool
+}
+
+impl Default for Tiling {
+    fn default() -> Tiling {
+        Tiling { tile_size: 256, pyramid: false }
+    }
+}
+
+impl Tiling {
+    pub fn check(&self) -> Result<(), String> {
+        if self.tile_size == 0 || self.tile_size % 16 != 0 {
+            return Err(format!("--tile-size must be a positive multiple of 16: {}", self.tile_size));
+        }
+        Ok(())
+    }
+
+    /// 各段の画像の大きさ。最初が元の画像
+    fn levels(&self, bounds: (usize, usize)) -> Vec<(usize, usize)> {
+        let mut levels = vec![bounds];
+        while let Some(&(width, height)) = levels.last().filter(|_| self.pyramid) {
+            if width <= self.tile_size && height <= self.tile_size {
+                break;
+            }
+            levels.push(((width + 1) / 2, (height + 1) / 2));
+        }
+        levels
+    }
+}
+
+/// TIFF のフィールドの型
+const BYTE: u16 = 1;
+const SHORT: u16 = 3;
+const LONG: u16 = 4;
+const UNDEFINED: u16 = 7;
+const LONG8: u16 = 16;
+
+/// 1つの段。タイル1段分の行と、縮小画像に使う対になる前の行を持つ
+struct Level {
+    bounds: (usize, usize),
+    band: Vec<u8>,
+    unpaired: Option<Vec<u8>>,
+    offsets: Vec<u64>,
+    byte_counts: Vec<u64>
+}
+
+/// 行を上から順に受け取り、タイルにして書き出す
+pub struct TiledWriter {
+    output: BufWriter<File>,
+    position: u64,
+    tile_size: usize,
+    levels: Vec<Level>,
+    colors: Colors,
+    xmp: Option<String>
+}
+
+impl TiledWriter {
+    pub fn create(path: &str, bounds: (usize, usize), tiling: Tiling) -> io::Result<TiledWriter> {
+        let mut output = BufWriter::new(File::create(path)?);
+        // 最初の IFD の位置は `finish` で書き換える
+        output.write_all(b"II")?;
+        output.write_all(&43u16.to_le_bytes())?;
+        output.write_all(&8u16.to_le_bytes())?;
+        output.write_all(&0u16.to_le_bytes())?;
+        output.write_all(&0u64.to_le_bytes())?;
+        let levels = tiling.levels(bounds).into_iter()
+            .map(|bounds| Level { bounds, band: vec![], unpaired: None, offsets: vec![], byte_counts: vec![] })
+            .collect();
+        Ok(TiledWriter { output, position: 16, tile_size: tiling.tile_size, levels, colors: Colors::default(),
+                         xmp: None })
+    }
+
+    /// `colors` のパレットで色を付け、ICC プロファイルを埋め込む
+    pub fn colored(mut self, colors: Colors) -> TiledWriter {
+        self.colors = colors;
+        self
+    }
+
+    /// XMP のパケット `xmp` を埋め込む
+    pub fn with_xmp(mut self, xmp: Option<String>) -> TiledWriter {
+        self.xmp = xmp;
+        self
+    }
+
+    /// 元の画像の続きの行 `rows` を書く
+    pub fn write_rows(&mut self, rows: &[u8]) -> io::Result<()> {
+        self.push(0, rows)
+    }
+
+    fn push(&mut self, index: usize, rows: &[u8]) -> io::Result<()> {
+        let width = self.levels[index].bounds.0;
+        let has_next = index + 1 < self.levels.len();
+        let mut reduced = vec![];
+        for row in rows.chunks(width) {
+            let level = &mut self.levels[index];
+            level.band.extend_from_slice(row);
+            if has_next {
+                match level.unpaired.take() {
+                    Some(above) => reduced.extend(halve(&above, row)),
+                    None => level.unpaired = Some(row.to_vec())
+                }
+            }
+            if self.levels[index].band.len() == width * self.tile_size {
+                self.flush_band(index)?;
+            }
+        }
+        if !reduced.is_empty() {
+            self.push(index + 1, &reduced)?;
+        }
+        Ok(())
+    }
+
+    /// 段 `index` の溜まった行をタイルにして書く。足りない行と列は 0 で埋める
+    fn flush_band(&mut self, index: usize) -> io::Result<()> {
+        let tile_size = self.tile_size;
+        let colors = &self.colors;
+        let level = &mut self.levels[index];
+        let width = level.bounds.0;
+        let band = std::mem::take(&mut level.band);
+        let rows = band.len() / width;
+        let tiles: Vec<Vec<u8>> = (0 .. width.div_ceil(tile_size)).into_par_iter().map(|column| {
+            let left = column * tile_size;
+            let columns = tile_size.min(width - left);
+            let mut tile = vec![0; tile_size * tile_size];
+            for y in 0 .. rows {
+                tile[y * tile_size .. y * tile_size + columns]
+                    .copy_from_slice(&band[y * width + left .. y * width + left + columns]);
+            }
+            let tile = colors.samples(&tile);
+            let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+            encoder.write_all(&tile).and_then(|_| encoder.finish()).expect("compressing to memory")
+        }).collect();
+        for tile in tiles {
+            level.offsets.push(self.position);
+            level.byte_counts.push(tile.len() as u64);
+            self.output.write_all(&tile)?;
+            self.position += tile.len() as u64;
+        }
+        Ok(())
+    }
+
+    /// 残りの行を書き、各段の IFD を書いて閉じる
+    pub fn finish(mut self) -> io::Result<()> {
+        for index in 0 .. self.levels.len() {
+            // 高さが奇数の段の最後の行は、その行だけで縮小する
+            if let Some(row) = self.levels[index].unpaired.take() {
+                self.push(index + 1, &halve(&row, &row))?;
+            }
+            if !self.levels[index].band.is_empty() {
+                self.flush_band(index)?;
+            }
+        }
+
+        // タイルが2つ以上ある段は位置と長さの配列を IFD の外に置く
+        let mut arrays = vec![];
+        for index in 0 .. self.levels.len() {
+            let (offsets, byte_counts) = (self.levels[index].offsets.clone(), self.levels[index].byte_counts.clone());
+            let (offsets, byte_counts) = if offsets.len() > 1 {
+                let start = self.position;
+                let byte_counts_start = self.write_u64s(&offsets)?;
+                self.write_u64s(&byte_counts)?;
+                (start, byte_counts_start)
+            } else {
+                (offsets[0], byte_counts[0])
+            };
+            arrays.push((offsets, byte_counts));
+        }
+
+        let profile = self.colors.icc.clone().map(|profile| self.write_bytes(&profile)).transpose()?;
+        let xmp = self.xmp.clone().map(|packet| self.write_bytes(packet.as_bytes())).transpose()?;
+        // 色を付けたら RGB、そうでなければ 0 が黒の灰色。透明にする点があれば不透明度の成分が続く。
+        // BitsPerSample は成分の数だけの 8 を値の欄に詰める
+        let samples = self.colors.channels() as u64;
+        let photometric = if self.colors.lut.is_some() { 2 } else { 1 };
+        let bits = (0 .. samples).map(|i| 8 << (16 * i)).sum();
+
+        // IFD は語の境界から始める
+        if self.position % 2 == 1 {
+            self.write_bytes(&[0])?;
+        }
+        let first = self.position;
+        for (index, (level, &(offsets, byte_counts))) in self.levels.iter().zip(&arrays).enumerate() {
+            let tiles = level.offsets.len() as u64;
+            let mut entries: Vec<(u16, u16, u64, u64)> = vec![
+                // 縮小画像は NewSubfileType を 1 にする
+                (254, LONG, 1, (index > 0) as u64),
+                (256, LONG, 1, level.bounds.0 as u64),
+                (257, LONG, 1, level.bounds.1 as u64),
+                (258, SHORT, samples, bits),
+                // Adobe deflate
+                (259, SHORT, 1, 8),
+                (262, SHORT, 1, photometric),
+                (277, SHORT, 1, samples),
+                (284, SHORT, 1, 1),
+                (322, LONG, 1, self.tile_size as u64),
+                (323, LONG, 1, self.tile_size as u64),
+                (324, LONG8, tiles, offsets),
+                (325, LONG8, tiles, byte_counts)
+            ];
+            if self.colors.alpha.is_some() {
+                // 乗算していない不透明度
+                entries.push((338, SHORT, 1, 2));
+            }
+            if let Some((start, len)) = xmp {
+                entries.push((700, BYTE, len, start));
+            }
+            if let Some((start, len)) = profile {
+                entries.push((34675, UNDEFINED, len, start));
+            }
+            let ifd_len = 8 + 20 * entries.len() as u64 + 8;
+            let next = if index + 1 < self.levels.len() { self.position + ifd_len } else { 0 };
+            self.output.write_all(&(entries.len() as u64).to_le_bytes())?;
+            for &(tag, kind, count, value) in &entries {
+                self.output.write_all(&tag.to_le_bytes())?;
+                self.output.write_all(&kind.to_le_bytes())?;
+                self.output.write_all(&count.to_le_bytes())?;
+                self.output.write_all(&value.to_le_bytes())?;
+            }
+            self.output.write_all(&next.to_le_bytes())?;
+            self.position += ifd_len;
+        }
+
+        self.output.seek(SeekFrom::Start(8))?;
+        self.output.write_all(&first.to_le_bytes())?;
+        self.output.flush()
+    }
+
+    /// `values` を書き、書いた後の位置を返す
+    fn write_u64s(&mut self, values: &[u64]) -> io::Result<u64> {
+        for value in values {
+            self.output.write_all(&value.to_le_bytes())?;
+        }
+        self.position += 8 * values.len() as u64;
+        Ok(self.position)
+    }
+
+    /// `data` を書き、その位置と長さを返す
+    fn write_bytes(&mut self, data: &[u8]) -> io::Result<(u64, u64)> {
+        let start = self.position;
+        self.output.write_all(data)?;
+        self.position += data.len() as u64;
+        Ok((start, data.len() as u64))
+    }
+}
+
+/// 隣り合う2行を 2x2 ピクセルの平均で半分の幅の1行にする。幅が奇数なら最後の列はその列だけで平均する
+fn halve(above: &[u8], below: &[u8]) -> Vec<u8> {
+    let width = above.len();
+    (0 .. (width + 1) / 2).map(|x| {
+        let (left, right) = (2 * x, (2 * x + 1).min(width - 1));
+        let sum = above[left] as u32 + above[right] as u32 + below[left] as u32 + below[right] as u32;
+        ((sum + 2) / 4) as u8
+    }).collect()
+}
+
+/// 範囲をタイル1段分の行ずつ描画しながら `path` に書き出す。
+/// 期限を過ぎて描画しきれなかった行があれば `false` を返す
+pub fn render_tiled(path: &str,
+                    bounds: (usize, usize),
+                    upper_left: Complex<f64>,
+                    lower_right: Complex<f64>,
+                    params: &RenderParams,
+                    encoding: &Encoding,
+                    work: Option<&WorkLog>)
+    -> io::Result<bool>
+{
+    let tiling = encoding.tiling;
+    let mut writer = TiledWriter::create(path, bounds, tiling)?
+        .colored(encoding.colors.clone())
+        .with_xmp(encoding.xmp.clone());
+    let mut band = vec![0; bounds.0 * tiling.tile_size];
+    let mut complete = true;
+    for top in (0 .. bounds.1).step_by(tiling.tile_size) {
+        let rows = &mut band[.. bounds.0 * tiling.tile_size.min(bounds.1 - top)];
+        complete &= backend::render_rows_logged(rows, bounds, top, upper_left, lower_right, params, work);
+        writer.write_rows(rows)?;
+    }
+    writer.finish()?;
+    Ok(complete)
+}
+
+/// テスト用に、`TiledWriter` が書いた BigTIFF の各段を読み戻す
+#[cfg(test)]
+fn read_levels(data: &[u8]) -> Vec<((usize, usize), Vec<u8>)> {
+    use flate2::read::ZlibDecoder;
+    use std::io::Read;
+
+    let u16_at = |at: usize| u16::from_le_bytes([data[at], data[at + 1]]);
+    let u64_at = |at: usize| {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&data[at .. at + 8]);
+        u64::from_le_bytes(bytes)
+    };
+    assert_eq!((&data[.. 2], u16_at(2), u16_at(4)), (&b"II"[..], 43, 8));
+
+    let mut levels = vec![];
+    let mut ifd = u64_at(8) as usize;
+    while ifd != 0 {
+        let mut fields = std::collections::HashMap::new();
+        for entry in 0 .. u64_at(ifd) as usize {
+            let at = ifd + 8 + 20 * entry;
+            fields.insert(u16_at(at), (u64_at(at + 4), u64_at(at + 12)));
+        }
+        let (width, height, tile) = (fields[&256].1 as usize, fields[&257].1 as usize, fields[&322].1 as usize);
+        let samples = fields[&277].1 as usize;
+        let (count, offsets) = fields[&324];
+        let byte_counts = fields[&325].1;
+        let array = |start: u64, i: usize| if count == 1 { start } else { u64_at(start as usize + 8 * i) };
+        let across = width.div_ceil(tile);
+        let mut pixels = vec![0; width * height * samples];
+        for i in 0 .. count as usize {
+            let (offset, len) = (array(offsets, i) as usize, array(byte_counts, i) as usize);
+            let mut tile_pixels = vec![];
+            ZlibDecoder::new(&data[offset .. offset + len]).read_to_end(&mut tile_pixels).unwrap();
+            assert_eq!(tile_pixels.len(), tile * tile * samples);
+            let (left, top) = (i % across * tile, i / across * tile);
+            for y in top .. (top + tile).min(height) {
+                for x in left .. (left + tile).min(width) {
+                    let (to, from) = ((y * width + x) * samples, ((y - top) * tile + x - left) * samples);
+                    pixels[to .. to + samples].copy_from_slice(&tile_pixels[from .. from + samples]);
+                }
+            }
+        }
+        levels.push(((width, height), pixels));
+        ifd = u64_at(ifd + 8 + 20 * u64_at(ifd) as usize) as usize;
+    }
+    levels
+}
+
+#[test]
+fn test_tiled_writer() {
+    let bounds = (70, 37);
+    let pixels: Vec<u8> = (0 .. bounds.0 * bounds.1).map(|i| (i * 13 % 251) as u8).collect();
+    let path = std::env::temp_dir().join(format!("mandelbrot-test-{}.tif", std::process::id()));
+    let path = path.to_str().unwrap();
+
+    let tiling = Tiling { tile_size: 16, pyramid: true };
+    assert_eq!(tiling.levels(bounds), vec![(70, 37), (35, 19), (18, 10), (9, 5)]);
+    let mut writer = TiledWriter::create(path, bounds, tiling).unwrap();
+    // 行をタイルの境目と揃わない単位で渡す
+    for rows in pixels.chunks(bounds.0 * 5) {
+        writer.write_rows(rows).unwrap();
+    }
+    writer.finish().unwrap();
+    let levels = read_levels(&std::fs::read(path).unwrap());
+    std::fs::remove_file(path).unwrap();
+
+    assert_eq!(levels.len(), 4);
+    assert_eq!(levels[0], (bounds, pixels.clone()));
+    let (reduced_bounds, reduced) = &levels[1];
+    assert_eq!(*reduced_bounds, (35, 19));
+    assert_eq!(reduced[0], ((pixels[0] as u32 + pixels[1] as u32 + pixels[70] as u32 + pixels[71] as u32 + 2) / 4) as u8);
+    // 最後の行は元の画像の最後の行だけから作る
+    assert_eq!(reduced[18 * 35], ((2 * pixels[36 * 70] as u32 + 2 * pixels[36 * 70 + 1] as u32 + 2) / 4) as u8);
+    assert_eq!(levels[3].0, (9, 5));
+}
+
+#[test]
+fn test_tiled_writer_colored() {
+    let bounds = (40, 20);
+    let pixels: Vec<u8> = (0 .. bounds.0 * bounds.1).map(|i| (i * 7 % 256) as u8).collect();
+    let path = std::env::temp_dir().join(format!("mandelbrot-test-colored-{}.tif", std::process::id()));
+    let path = path.to_str().unwrap();
+    let colors = Colors::resolve(Some("fire"), None, None).unwrap();
+    let mut writer = TiledWriter::create(path, bounds, Tiling { tile_size: 16, pyramid: true }).unwrap()
+        .colored(colors.clone());
+    writer.write_rows(&pixels).unwrap();
+    writer.finish().unwrap();
+    let data = std::fs::read(path).unwrap();
+    let levels = read_levels(&data);
+    std::fs::remove_file(path).unwrap();
+
+    assert_eq!(levels[0], (bounds, colors.samples(&pixels).into_owned()));
+    // 最初の IFD の最後のタグがプロファイルを指す
+    let u64_at = |at: usize| {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&data[at .. at + 8]);
+        u64::from_le_bytes(bytes) as usize
+    };
+    let last = u64_at(8) + 8 + 20 * 12;
+    assert_eq!(u16::from_le_bytes([data[last], data[last + 1]]), 34675);
+    let profile = colors.icc.clone().unwrap();
+    let offset = u64_at(last + 12);
+    assert_eq!(&data[offset .. offset + profile.len()], &profile[..]);
+
+    // XMP のパケットはプロファイルの前のタグ 700。長さが奇数でも IFD は語の境界から始まる
+    let packet = "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\"/>";
+    let mut writer = TiledWriter::create(path, bounds, Tiling { tile_size: 16, pyramid: false }).unwrap()
+        .colored(colors.clone())
+        .with_xmp(Some(packet.to_string()));
+    writer.write_rows(&pixels).unwrap();
+    writer.finish().unwrap();
+    let data = std::fs::read(path).unwrap();
+    std::fs::remove_file(path).unwrap();
+    assert_eq!(read_levels(&data), vec![(bounds, colors.samples(&pixels).into_owned())]);
+    let u64_at = |at: usize| {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&data[at .. at + 8]);
+        u64::from_le_bytes(bytes) as usize
+    };
+    assert_eq!(u64_at(8) % 2, 0);
+    let entry = u64_at(8) + 8 + 20 * 12;
+    assert_eq!(u16::from_le_bytes([data[entry], data[entry + 1]]), 700);
+    let offset = u64_at(entry + 12);
+    assert_eq!(&data[offset .. offset + packet.len()], packet.as_bytes());
+
+    // 透明にする点があれば灰色と不透明度の2成分になる
+    let colors = Colors { alpha: Some(super::color::Alpha::Exterior), ..Colors::default() };
+    let mut writer = TiledWriter::create(path, bounds, Tiling { tile_size: 16, pyramid: false }).unwrap()
+        .colored(colors.clone());
+    writer.write_rows(&pixels).unwrap();
+    writer.finish().unwrap();
+    let levels = read_levels(&std::fs::read(path).unwrap());
+    std::fs::remove_file(path).unwrap();
+    assert_eq!(levels, vec![(bounds, colors.samples(&pixels).into_owned())]);
+}
+
+#[test]
+fn test_tiling() {
+    assert_eq!(halve(&[0, 10, 20], &[40, 50, 60]), vec![25, 40]);
+    assert_eq!(Tiling::default().levels((1000, 500)), vec![(1000, 500)]);
+    assert_eq!(Tiling { pyramid: true, ..Tiling::default() }.levels((1000, 500)),
+               vec![(1000, 500), (500, 250), (250, 125)]);
+    assert!(Tiling::default().check().is_ok());
+    assert!(Tiling { tile_size: 100, pyramid: false }.check().is_err());
+    assert!(Tiling { tile_size: 0, pyramid: false }.check().is_err());
+}
+
+#[test]
+fn test_render_tiled() {
+    let bounds = (100, 40);
+    let (upper_left, lower_right) = (Complex { re: -2.0, im: 1.0 }, Complex { re: 1.0, im: -1.0 });
+    let params = RenderParams::default();
+    let mut expected = vec![0; bounds.0 * bounds.1];
+    backend::render_rows(&mut expected, bounds, 0, upper_left, lower_right, &params);
+
+    let path = std::env::temp_dir().join(format!("mandelbrot-test-render-{}.tif", std::process::id()));
+    let path = path.to_str().unwrap();
+    let encoding = Encoding { tiling: Tiling { tile_size: 32, pyramid: false }, ..Encoding::default() };
+    assert!(render_tiled(path, bounds, upper_left, lower_right, &params, &encoding, None).unwrap());
+    let levels = read_levels(&std::fs::read(path).unwrap());
+    std::fs::remove_file(path).unwrap();
+    assert_eq!(levels, vec![(bounds, expected)]);
+}
@@ -0,0 +1,261 @@
+//! 端末の中で範囲を動かしながら眺める `explore-tui` サブコマンド
+//!
+//! 上半分のブロック文字 `▀` の前景色と背景色に上下2つのピクセルを割り当て、端末の1文字で縦2ピクセルを描く。
+//! 文字の縦横比はおよそ 2:1 なので、これでピクセルがほぼ正方形になる。GUI のない SSH 越しでも使える。
+//!
+//! 矢印キーで移動、`+` / `-` で拡大・縮小、`s` で今の範囲を `--save-size` の大きさの PNG に保存、`q` で終了する。
+//! 移動はピクセルの整数倍に丸め、前の画面をずらして新しく見えた帯だけを描き直す。
+
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind};
+use crossterm::style::{Color, Print, SetBackgroundColor, SetForegroundColor, ResetColor};
+use crossterm::{cursor, execute, queue, terminal};
+use mandelbrot::{scroll, Region};
+use num::Complex;
+
+use super::{failed, parse_complex, parse_pair, parse_params, region_from_center, render_parallel,
+            write_image, Failure, RenderParams};
+
+/// 1回の矢印キーで動かす量 (表示している幅に対する割合)
+const PAN_STEP: f64 = 0.1;
+
+/// 1回の `+` / `-` で変える倍率
+const ZOOM_STEP: f64 = 1.5;
+
+/// 表示している範囲
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct View {
+    center: Complex<f64>,
+    zoom: f64
+}
+
+impl View {
+    /// 表示している幅の `dx`、高さの `dy` の割合だけ中心を動かす。`dy` は上向きが正。
+    /// 前の画面をずらして使えるよう、動かす量はピクセルの整数倍に丸める
+    fn pan(&mut self, dx: f64, dy: f64, bounds: (usize, usize)) {
+        let pixel = 4.0 / self.zoom / bounds.0 as f64;
+        self.center.re += (dx * bounds.0 as f64).round() * pixel;
+        self.center.im += (dy * bounds.1 as f64).round() * pixel;
+    }
+
+    fn region(&self, bounds: (usize, usize)) -> (Complex<f64>, Complex<f64>) {
+        region_from_center(self.center, self.zoom, bounds)
+    }
+}
+
+/// キー入力に対する操作
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Action {
+    Pan(f64, f64),
+    Zoom(f64),
+    Save,
+    Quit
+}
+
+fn action_for(key: KeyEvent) -> Option<Action> {
+    match key.code {
+        KeyCode::Left => Some(Action::Pan(-PAN_STEP, 0.0)),
+        KeyCode::Right => Some(Action::Pan(PAN_STEP, 0.0)),
+        KeyCode::Up => Some(Action::Pan(0.0, PAN_STEP)),
+        KeyCode::Down => Some(Action::Pan(0.0, -PAN_STEP)),
+        KeyCode::Char('+') | KeyCode::Char('=') => Some(Action::Zoom(ZOOM_STEP)),
+        KeyCode::Char('-') => Some(Action::Zoom(1.0 / ZOOM_STEP)),
+        KeyCode::Char('s') => Some(Action::Save),
+        KeyCode::Char('q') | KeyCode::Esc => Some(Action::Quit),
+        _ => None
+    }
+}
+
+/// `explore-tui` のオプション
+#[derive(Debug, PartialEq)]
+struct ExploreOptions {
+    view: View,
+    /// `s` で保存する画像の大きさ
+    save_size: (usize, usize),
+    params: RenderParams
+}
+
+fn parse_explore_args(args: &[String]) -> Result<ExploreOptions, String> {
+    let mut view = View { center: Complex { re: -0.5, im: 0.0 }, zoom: 1.0 };
+    let mut save_size = (1920, 1080);
+    let mut rest = vec![];
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().map(String::as_str).ok_or(format!("{} expects a value", arg));
+        match arg.as_str() {
+            "--center" => view.center = parse_complex(value()?).ok_or("--center expects RE,IM")?,
+            "--zoom" => {
+                view.zoom = f64::from_str(value()?).ok().filter(|zoom| *zoom > 0.0 && zoom.is_finite())
+                    .ok_or("--zoom expects a positive number")?;
+            }
+            "--save-size" => save_size = parse_pair(value()?, 'x').ok_or("--save-size expects WxH")?,
+            _ => rest.push(arg.clone())
+        }
+    }
+    Ok(ExploreOptions { view, save_size, params: parse_params(&rest)? })
+}
+
+#[test]
+fn test_parse_explore_args() {
+    let args = |s: &str| -> Vec<String> { s.split_whitespace().map(String::from).collect() };
+    let options = parse_explore_args(&args("--center -0.75,0.1 --zoom 8 --save-size 640x480 --passes 64,256")).unwrap();
+    assert_eq!(options.view, View { center: Complex { re: -0.75, im: 0.1 }, zoom: 8.0 });
+    assert_eq!(options.save_size, (640, 480));
+    assert_eq!(options.params.limits, vec![64, 256]);
+    assert_eq!(parse_explore_args(&[]).map(|options| options.save_size), Ok((1920, 1080)));
+    assert!(parse_explore_args(&args("--zoom 0")).is_err());
+    assert!(parse_explore_args(&args("--center")).is_err());
+}
+
+/// `explore-tui [--center RE,IM] [--zoom Z] [--save-size WxH] [OPTIONS]` サブコマンド
+pub fn run_explore(args: &[String]) -> Result<(), Failure> {
+    let options = parse_explore_args(args).map_err(Failure::Usage)?;
+    let error = |e: io::Error| format!("terminal error: {}", e);
+
+    terminal::enable_raw_mode().map_err(failed("terminal error"))?;
+    let mut stdout = io::stdout();
+    let result = execute!(stdout, terminal::EnterAlternateScreen, cursor::Hide)
+        .map_err(error)
+        .and_then(|_| explore(&mut stdout, options));
+    // 途中で失敗しても端末は元に戻す
+    let _ = execute!(stdout, ResetColor, cursor::Show, terminal::LeaveAlternateScreen);
+    let _ = terminal::disable_raw_mode();
+    result.map_err(Failure::Runtime)
+}
+
+fn explore<W: Write>(output: &mut W, mut options: ExploreOptions) -> Result<(), String> {
+    let error = |e: io::Error| format!("terminal error: {}", e);
+    let mut status = "arrows: move  +/-: zoom  s: save  q: quit".to_string();
+    let mut previous: Option<(Region, Vec<u8>)> = None;
+    loop {
+        let (columns, lines) = terminal::size().map_err(error)?;
+        // 最後の行は状態の表示に使う
+        let bounds = (columns.max(1) as usize, (lines.max(2) as usize - 1) * 2);
+        let (upper_left, lower_right) = options.view.region(bounds);
+        let region = Region::new(bounds, upper_left, lower_right);
+        let pixels = match previous.take() {
+            Some((before, pixels)) if before.diff(&region).is_some() => pan(pixels, &before, &region, &options.params)?,
+            _ => {
+                let mut pixels = vec![0; bounds.0 * bounds.1];
+                render_parallel(&mut pixels, bounds, upper_left, lower_right, &options.params);
+                pixels
+            }
+        };
+        draw(output, &pixels, bounds, &status, &options.view).map_err(error)?;
+        previous = Some((region, pixels));
+
+        let key = match event::read().map_err(error)? {
+            Event::Key(key) if key.kind != KeyEventKind::Release => key,
+            _ => continue
+        };
+        match action_for(key) {
+            Some(Action::Pan(dx, dy)) => options.view.pan(dx, dy, bounds),
+            Some(Action::Zoom(factor)) => options.view.zoom *= factor,
+            Some(Action::Save) => status = save(&options),
+            Some(Action::Quit) => return Ok(()),
+            None => {}
+        }
+    }
+}
+
+/// `before` を描いた `pixels` をずらして `region` にし、新しく見えた帯だけを描画する
+fn pan(mut pixels: Vec<u8>, before: &Region, region: &Region, params: &RenderParams) -> Result<Vec<u8>, String> {
+    let shift = before.diff(region).ok_or("the view cannot be shifted")?;
+    let width = region.bounds.0;
+    scroll(&mut pixels, width, region.bounds, shift)?;
+    for (origin, bounds) in region.exposed(shift) {
+        let strip = region.sub_region(origin, bounds);
+        let mut drawn = vec![0; bounds.0 * bounds.1];
+        render_parallel(&mut drawn, bounds, strip.upper_left, strip.lower_right, params);
+        for (row, line) in drawn.chunks(bounds.0).enumerate() {
+            let start = (origin.1 + row) * width + origin.0;
+            pixels[start .. start + bounds.0].copy_from_slice(line);
+        }
+    }
+    Ok(pixels)
+}
+
+/// 今の範囲を `save_size` の大きさで描画して保存し、結果を状態の行に表示する文にする
+fn save(options: &ExploreOptions) -> String {
+    let bounds = options.save_size;
+    let (upper_left, lower_right) = options.view.region(bounds);
+    let mut pixels = vec![0; bounds.0 * bounds.1];
+    render_parallel(&mut pixels, bounds, upper_left, lower_right, &options.params);
+    let seconds = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let filename = format!("explore-{}.png", seconds);
+    match write_image(&filename, &pixels, bounds) {
+        Ok(()) => format!("saved {} ({}x{})", filename, bounds.0, bounds.1),
+        Err(e) => format!("cannot save {}: {}", filename, e)
+    }
+}
+
+fn gray(value: u8) -> Color {
+    Color::Rgb { r: value, g: value, b: value }
+}
+
+/// 2行ずつのピクセルを半ブロック文字にして描き、最後の行に状態を表示する
+fn draw<W: Write>(output: &mut W, pixels: &[u8], bounds: (usize, usize), status: &str, view: &View)
+    -> io::Result<()>
+{
+    queue!(output, cursor::MoveTo(0, 0))?;
+    for line in 0 .. bounds.1 / 2 {
+        if line > 0 {
+            queue!(output, cursor::MoveToNextLine(1))?;
+        }
+        let upper = &pixels[line * 2 * bounds.0 .. (line * 2 + 1) * bounds.0];
+        let lower = &pixels[(line * 2 + 1) * bounds.0 .. (line * 2 + 2) * bounds.0];
+        // 色が変わるときだけエスケープシーケンスを出して、送る量を減らす
+        let mut previous = None;
+        for (&top, &bottom) in upper.iter().zip(lower) {
+            if previous != Some((top, bottom)) {
+                queue!(output, SetForegroundColor(gray(top)), SetBackgroundColor(gray(bottom)))?;
+                previous = Some((top, bottom));
+            }
+            queue!(output, Print('▀'))?;
+        }
+    }
+    queue!(output, ResetColor, cursor::MoveToNextLine(1),
+           terminal::Clear(terminal::ClearType::CurrentLine),
+           Print(format!("{:+.10},{:+.10} zoom {:.3e}  {}", view.center.re, view.center.im,
+                         view.zoom, status)))?;
+    output.flush()
+}
+
+#[test]
+fn test_view_navigation() {
+    let mut view = View { center: Complex { re: -0.5, im: 0.0 }, zoom: 1.0 };
+    let bounds = (80, 40);
+    view.pan(0.25, 0.5, bounds);
+    assert_eq!(view.center, Complex { re: 0.5, im: 1.0 });
+
+    // 動かした後の画面は、前の画面をずらして見えた帯を描き足したものと全体を描き直したものが一致する
+    let params = RenderParams::default();
+    let render = |view: &View| {
+        let (upper_left, lower_right) = view.region(bounds);
+        let mut pixels = vec![0; bounds.0 * bounds.1];
+        render_parallel(&mut pixels, bounds, upper_left, lower_right, &params);
+        (Region::new(bounds, upper_left, lower_right), pixels)
+    };
+    let (before, pixels) = render(&view);
+    view.pan(-PAN_STEP, PAN_STEP, bounds);
+    let (region, expected) = render(&view);
+    assert_eq!(before.diff(&region), Some((-8, -4)));
+    let panned = pan(pixels, &before, &region, &params).unwrap();
+    let differing = panned.iter().zip(&expected).filter(|(a, b)| a != b).count();
+    assert!(differing * 100 < expected.len(), "{} pixels differ", differing);
+
+    let key = |code| KeyEvent::new(code, event::KeyModifiers::NONE);
+    assert_eq!(action_for(key(KeyCode::Left)), Some(Action::Pan(-PAN_STEP, 0.0)));
+    assert_eq!(action_for(key(KeyCode::Char('+'))), Some(Action::Zoom(ZOOM_STEP)));
+    assert_eq!(action_for(key(KeyCode::Char('q'))), Some(Action::Quit));
+    assert_eq!(action_for(key(KeyCode::Char('x'))), None);
+
+    let mut screen = vec![];
+    draw(&mut screen, &[0, 255, 255, 0], (2, 2), "ready", &view).unwrap();
+    let screen = String::from_utf8(screen).unwrap();
+    assert_eq!(screen.matches('▀').count(), 2);
+    assert!(screen.ends_with("ready"));
+}
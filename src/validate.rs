@@ -0,0 +1,111 @@
+//! 全てのバックエンドで同じ画像になるかを確かめる `validate-backends` サブコマンド
+//!
+//! 全体像、谷の拡大、小さなコピーの深い拡大などの場面を、`Backend::ALL` の全てのバックエンドと
+//! ライブラリの `render_gray` で描き、`rayon` の画像とピクセル毎に比べて違うピクセルの数、差の最大と平均、
+//! PSNR を表にする。並列化の仕方や丸めの違いで結果がずれていれば見つかる。差の最大が `--tolerance N`
+//! (既定値: 0) を超えた組があれば失敗で終わるので、CI でそのまま使える。
+//!
+//! `render_gray` は既定の描画だけを実装しているので、描画のオプションを変えたときは比べない。
+//! GPU のバックエンドはまだ無く、加えるときは `Backend::ALL` に並べればここでも比べられる。
+
+use std::str::FromStr;
+
+use mandelbrot::Region;
+use num::Complex;
+
+use super::backend::{render_rows, Backend};
+use super::compare::{compare, Comparison};
+use super::{parse_pair, parse_params, Failure, RenderParams};
+
+/// 比べる場面の名前と、左上の実部と虚部、右下の実部と虚部
+const SCENES: &[(&str, [f64; 4])] = &[
+    ("full", [-2.5, 1.25, 1.0, -1.25]),
+    ("seahorse", [-0.7600, 0.1300, -0.7300, 0.1075]),
+    ("elephant", [0.2500, 0.0200, 0.3000, -0.0175]),
+    ("spiral", [-0.74364, 0.13187, -0.74362, 0.131855]),
+    ("minibrot", [-1.7690, 0.0050, -1.7650, -0.0020])
+];
+
+/// 1つの場面を1つのバックエンドで描いた画像と、`rayon` の画像の比較
+struct Divergence {
+    scene: &'static str,
+    backend: String,
+    comparison: Comparison
+}
+
+/// `params` の全てのバックエンドと、既定の描画ならライブラリで、大きさ `bounds` の各場面を描いて比べる
+fn validate(bounds: (usize, usize), params: &RenderParams) -> Vec<Divergence> {
+    let library = *params == RenderParams::default();
+    let mut divergences = vec![];
+    for &(scene, [left, top, right, bottom]) in SCENES {
+        let (upper_left, lower_right) = (Complex::new(left, top), Complex::new(right, bottom));
+        let render = |backend: Backend| {
+            let mut params = params.clone();
+            params.scheduling.backend = backend;
+            let mut pixels = vec![0; bounds.0 * bounds.1];
+            render_rows(&mut pixels, bounds, 0, upper_left, lower_right, &params);
+            pixels
+        };
+        let reference = render(Backend::Rayon);
+        for &backend in &Backend::ALL[1 ..] {
+            let comparison = compare(&reference, &render(backend), bounds);
+            divergences.push(Divergence { scene, backend: backend.name().to_string(), comparison });
+        }
+        if library {
+            let mut pixels = vec![0; bounds.0 * bounds.1];
+            mandelbrot::render_gray(&mut pixels, bounds.0, &Region::new(bounds, upper_left, lower_right), params.limit())
+                .expect("the buffer fits the image");
+            divergences.push(Divergence { scene, backend: "library".to_string(), comparison: compare(&reference, &pixels, bounds) });
+        }
+    }
+    divergences
+}
+
+#[test]
+fn test_validate_backends() {
+    // どのバックエンドもライブラリも rayon と同じ画像を描く
+    let divergences = validate((48, 32), &RenderParams::default());
+    assert_eq!(divergences.len(), SCENES.len() * Backend::ALL.len());
+    for divergence in &divergences {
+        assert_eq!(divergence.comparison.differing, 0, "{} on {}", divergence.scene, divergence.backend);
+    }
+    // 描画のオプションを変えるとライブラリは比べない
+    let params = parse_params(&["--passes".to_string(), "64".to_string()]).unwrap();
+    assert!(validate((8, 8), &params).iter().all(|divergence| divergence.backend != "library"));
+}
+
+/// `validate-backends` の引数を画像の大きさ、許す差と描画のパラメータに分ける
+fn parse_validate_args(args: &[String]) -> Result<((usize, usize), u8, RenderParams), String> {
+    let (mut bounds, mut tolerance) = ((320, 240), 0);
+    let mut rest = vec![];
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().map(String::as_str).ok_or(format!("{} expects a value", arg));
+        match arg.as_str() {
+            "--size" => bounds = parse_pair(value()?, 'x').filter(|&(w, h)| w > 0 && h > 0).ok_or("--size expects WxH")?,
+            "--tolerance" => tolerance = u8::from_str(value()?).map_err(|_| "--tolerance expects 0 to 255")?,
+            _ => rest.push(arg.clone())
+        }
+    }
+    Ok((bounds, tolerance, parse_params(&rest)?))
+}
+
+/// `validate-backends [--size WxH] [--tolerance N] [OPTIONS]` サブコマンド
+pub fn run_validate(args: &[String]) -> Result<(), Failure> {
+    let (bounds, tolerance, params) = parse_validate_args(args).map_err(Failure::Usage)?;
+    let divergences = validate(bounds, &params);
+    println!("{:<10} {:<10} {:>10} {:>5} {:>10} {:>10}", "scene", "backend", "differing", "max", "mean", "PSNR");
+    let mut failed = 0;
+    for Divergence { scene, backend, comparison } in &divergences {
+        let over = comparison.max_difference > tolerance;
+        failed += over as usize;
+        println!("{:<10} {:<10} {:>10} {:>5} {:>10.4} {:>8.2}dB{}", scene, backend, comparison.differing,
+                 comparison.max_difference, comparison.mean_difference, comparison.psnr,
+                 if over { "  DIVERGED" } else { "" });
+    }
+    if failed > 0 {
+        return Err(Failure::Runtime(format!("{} of {} renders differ from rayon by more than {}",
+                                            failed, divergences.len(), tolerance)));
+    }
+    Ok(())
+}
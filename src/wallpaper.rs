@@ -0,0 +1,264 @@
+//! 画面の解像度に合わせて有名な場所を描き、デスクトップの壁紙にする `wallpaper` サブコマンド
+//!
+//! 場所は `PRESETS` の一覧から `--preset NAME` で選ぶ。省略すると日付で日替わりに選ぶ。
+//! 画像の大きさは `--size WxH` か、主ディスプレイの解像度を OS のコマンドで調べて決める。
+//! Linux は `xrandr` (無ければ `xdpyinfo`)、macOS は `system_profiler`、Windows は PowerShell に聞く。
+//! `--set` を付けると描いた画像を壁紙に設定する。Linux は GNOME の `gsettings` か `feh`、
+//! macOS は `osascript`、Windows は `SystemParametersInfo` を使う。
+//!
+//! ```bash
+//! $ mandelbrot wallpaper --preset seahorse-valley --output ~/Pictures/mandelbrot.png --set
+//! ```
+
+use std::path::Path;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use num::Complex;
+
+use super::webformat::{self, Encoding, OutputFormat};
+use super::{parse_pair, parse_params, region_from_center, render_parallel, Failure};
+
+/// 壁紙に向く場所
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Preset {
+    name: &'static str,
+    center: Complex<f64>,
+    /// 画像の幅が 4 / zoom
+    zoom: f64,
+    iterations: u32
+}
+
+const fn preset(name: &'static str, re: f64, im: f64, zoom: f64, iterations: u32) -> Preset {
+    Preset { name, center: Complex { re, im }, zoom, iterations }
+}
+
+const PRESETS: &[Preset] = &[
+    preset("whole", -0.65, 0.0, 1.3, 256),
+    preset("seahorse-valley", -0.7453, 0.1127, 150.0, 1000),
+    preset("elephant-valley", 0.285, 0.012, 100.0, 1000),
+    preset("double-spiral", -0.7436447, 0.1318252, 5000.0, 2000),
+    preset("minibrot", -1.7549, 0.0, 90.0, 1000),
+    preset("period-3-neck", -0.1002, 0.8383, 60.0, 1000)
+];
+
+/// `--preset` に指定できる名前
+pub fn preset_names() -> Vec<&'static str> {
+    PRESETS.iter().map(|preset| preset.name).collect()
+}
+
+fn find_preset(name: &str) -> Result<Preset, String> {
+    PRESETS.iter().copied().find(|preset| preset.name == name).ok_or_else(|| {
+        format!("unknown preset {} (expected one of {})", name, preset_names().join(", "))
+    })
+}
+
+/// 日付 `days` (1970-01-01 からの日数) の日替わりの場所
+fn preset_of_day(days: u64) -> Preset {
+    PRESETS[(days % PRESETS.len() as u64) as usize]
+}
+
+#[test]
+fn test_presets() {
+    assert_eq!(find_preset("double-spiral").map(|preset| preset.iterations), Ok(2000));
+    assert!(find_preset("nowhere").unwrap_err().contains("seahorse-valley"));
+    assert_eq!(preset_of_day(0), PRESETS[0]);
+    assert_eq!(preset_of_day(PRESETS.len() as u64 + 1), PRESETS[1]);
+}
+
+/// `xrandr --current` の出力から主ディスプレイの解像度を読む。主ディスプレイが無ければ最初に繋がっているもの
+fn parse_xrandr(output: &str) -> Option<(usize, usize)> {
+    let connected: Vec<&str> = output.lines().filter(|line| line.contains(" connected")).collect();
+    let line = connected.iter().find(|line| line.contains(" primary ")).or_else(|| connected.first())?;
+    // `HDMI-1 connected primary 1920x1080+0+0 (normal ...` の `1920x1080+0+0`
+    line.split_whitespace().find_map(|word| parse_pair(word.split('+').next()?, 'x'))
+}
+
+/// `xdpyinfo` の `dimensions:    1920x1080 pixels` を読む
+fn parse_xdpyinfo(output: &str) -> Option<(usize, usize)> {
+    let line = output.lines().find(|line| line.trim_start().starts_with("dimensions:"))?;
+    parse_pair(line.split_whitespace().nth(1)?, 'x')
+}
+
+/// `system_profiler SPDisplaysDataType` の最初の `Resolution: 2560 x 1600 ...` を読む
+fn parse_system_profiler(output: &str) -> Option<(usize, usize)> {
+    let line = output.lines().find(|line| line.trim_start().starts_with("Resolution:"))?;
+    let mut words = line.split_whitespace().skip(1);
+    let width = words.next()?.parse().ok()?;
+    if words.next()? != "x" {
+        return None;
+    }
+    Some((width, words.next()?.parse().ok()?))
+}
+
+/// PowerShell で書いた `1920x1080` を読む
+fn parse_windows(output: &str) -> Option<(usize, usize)> {
+    parse_pair(output.trim(), 'x')
+}
+
+#[test]
+fn test_parse_resolution() {
+    let xrandr = "Screen 0: minimum 320 x 200, current 4480 x 1440, maximum 16384 x 16384\n\
+                  DP-1 connected 2560x1440+1920+0 (normal left inverted right x axis y axis) 597mm x 336mm\n\
+                     2560x1440     59.95*+\n\
+                  HDMI-1 connected primary 1920x1080+0+0 (normal left inverted right x axis y axis) 527mm x 296mm\n\
+                  DP-2 disconnected (normal left inverted right x axis y axis)\n";
+    assert_eq!(parse_xrandr(xrandr), Some((1920, 1080)));
+    assert_eq!(parse_xrandr(&xrandr.replace(" primary", "")), Some((2560, 1440)));
+    assert_eq!(parse_xrandr("Screen 0: minimum 320 x 200\n"), None);
+    assert_eq!(parse_xdpyinfo("screen #0:\n  dimensions:    3840x2160 pixels (1016x571 millimeters)\n"),
+               Some((3840, 2160)));
+    assert_eq!(parse_system_profiler("Graphics/Displays:\n    Displays:\n      Color LCD:\n\
+                                      \x20       Resolution: 2560 x 1600 Retina\n"),
+               Some((2560, 1600)));
+    assert_eq!(parse_windows("1920x1200\r\n"), Some((1920, 1200)));
+}
+
+/// コマンドを実行して標準出力を返す。実行できなかったか失敗したら `None`
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    String::from_utf8(output.stdout).ok()
+}
+
+/// 主ディスプレイの解像度
+fn detect_resolution() -> Option<(usize, usize)> {
+    if cfg!(target_os = "macos") {
+        return parse_system_profiler(&command_output("system_profiler", &["SPDisplaysDataType"])?);
+    }
+    if cfg!(windows) {
+        let script = "Add-Type -AssemblyName System.Windows.Forms; \
+                      $b = [System.Windows.Forms.Screen]::PrimaryScreen.Bounds; \"$($b.Width)x$($b.Height)\"";
+        return parse_windows(&command_output("powershell", &["-NoProfile", "-Command", script])?);
+    }
+    command_output("xrandr", &["--current"]).and_then(|output| parse_xrandr(&output))
+        .or_else(|| parse_xdpyinfo(&command_output("xdpyinfo", &[])?))
+}
+
+/// `path` (絶対パス) の画像を壁紙にする
+fn set_wallpaper(path: &str) -> Result<(), String> {
+    let run = |program: &str, args: &[&str]| {
+        Command::new(program).args(args).status().ok().filter(|status| status.success()).is_some()
+    };
+    let done = if cfg!(target_os = "macos") {
+        let script = format!("tell application \"System Events\" to tell every desktop to set picture to {:?}", path);
+        run("osascript", &["-e", &script])
+    } else if cfg!(windows) {
+        // SPI_SETDESKWALLPAPER = 20、SPIF_UPDATEINIFILE | SPIF_SENDCHANGE = 3
+        let script = format!("Add-Type -Namespace W -Name D -MemberDefinition \
+                              '[DllImport(\"user32.dll\", CharSet = CharSet.Unicode)] \
+                              public static extern int SystemParametersInfo(int a, int b, string c, int d);'; \
+                              [W.D]::SystemParametersInfo(20, 0, '{}', 3)", path.replace('\'', "''"));
+        run("powershell", &["-NoProfile", "-Command", &script])
+    } else {
+        let uri = format!("file://{}", path);
+        let gnome = ["picture-uri", "picture-uri-dark"].iter().all(|key| {
+            run("gsettings", &["set", "org.gnome.desktop.background", key, &uri])
+        });
+        gnome || run("feh", &["--bg-fill", path])
+    };
+    if done {
+        Ok(())
+    } else {
+        Err(format!("cannot set the desktop background; the image is at {}", path))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct WallpaperArgs {
+    output: String,
+    preset: Option<Preset>,
+    size: Option<(usize, usize)>,
+    set: bool,
+    list: bool,
+    /// 描画パラメータとして `parse_params` に渡す残り
+    options: Vec<String>
+}
+
+fn parse_wallpaper_args(args: &[String]) -> Result<WallpaperArgs, String> {
+    let mut wallpaper = WallpaperArgs {
+        output: "mandelbrot-wallpaper.png".to_string(),
+        preset: None,
+        size: None,
+        set: false,
+        list: false,
+        options: vec![]
+    };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().map(String::as_str).ok_or(format!("{} expects a value", arg));
+        match arg.as_str() {
+            "--output" => wallpaper.output = value()?.to_string(),
+            "--preset" => wallpaper.preset = Some(find_preset(value()?)?),
+            "--size" => {
+                wallpaper.size = Some(parse_pair(value()?, 'x').filter(|&(w, h)| w > 0 && h > 0)
+                    .ok_or("--size expects WxH with positive sizes")?);
+            }
+            "--set" => wallpaper.set = true,
+            "--list" => wallpaper.list = true,
+            _ => wallpaper.options.push(arg.clone())
+        }
+    }
+    Ok(wallpaper)
+}
+
+#[test]
+fn test_parse_wallpaper_args() {
+    let args = |s: &str| -> Vec<String> { s.split_whitespace().map(String::from).collect() };
+    assert_eq!(parse_wallpaper_args(&args("--preset whole --size 1920x1080 --set --output a.png --backend atomic")),
+               Ok(WallpaperArgs {
+                   output: "a.png".to_string(),
+                   preset: find_preset("whole").ok(),
+                   size: Some((1920, 1080)),
+                   set: true,
+                   list: false,
+                   options: args("--backend atomic")
+               }));
+    assert_eq!(parse_wallpaper_args(&[]).map(|args| (args.output, args.preset)),
+               Ok(("mandelbrot-wallpaper.png".to_string(), None)));
+    assert!(parse_wallpaper_args(&args("--preset nowhere")).is_err());
+    assert!(parse_wallpaper_args(&args("--size 1920")).is_err());
+    assert!(parse_wallpaper_args(&args("--size 0x8")).is_err());
+}
+
+/// `wallpaper [--preset NAME] [--size WxH] [--output FILE] [--set] [--list] [OPTIONS]` サブコマンド
+pub fn run_wallpaper(args: &[String]) -> Result<(), Failure> {
+    let wallpaper = parse_wallpaper_args(args).map_err(Failure::Usage)?;
+    if wallpaper.list {
+        for preset in PRESETS {
+            println!("{:<16} {},{} zoom {} passes {}",
+                     preset.name, preset.center.re, preset.center.im, preset.zoom, preset.iterations);
+        }
+        return Ok(());
+    }
+    let preset = wallpaper.preset.unwrap_or_else(|| {
+        let days = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs() / 86400);
+        preset_of_day(days)
+    });
+    let bounds = match wallpaper.size {
+        Some(size) => size,
+        None => detect_resolution().filter(|&(w, h)| w > 0 && h > 0)
+            .ok_or_else(|| Failure::Runtime("cannot detect the screen resolution; give --size WxH".to_string()))?
+    };
+    // 場所の反復回数は後の --passes で上書きできる
+    let mut options = vec!["--passes".to_string(), preset.iterations.to_string()];
+    options.extend_from_slice(&wallpaper.options);
+    let params = parse_params(&options).map_err(Failure::Usage)?;
+    let encoding = Encoding::default();
+    OutputFormat::of(&wallpaper.output).and_then(|format| encoding.check(format)).map_err(Failure::Usage)?;
+
+    eprintln!("rendering {} at {}x{}", preset.name, bounds.0, bounds.1);
+    let (upper_left, lower_right) = region_from_center(preset.center, preset.zoom, bounds);
+    let mut pixels = vec![0; bounds.0 * bounds.1];
+    render_parallel(&mut pixels, bounds, upper_left, lower_right, &params);
+    webformat::write_output(&wallpaper.output, &pixels, bounds, &encoding).map_err(Failure::Runtime)?;
+
+    if wallpaper.set {
+        let path = Path::new(&wallpaper.output).canonicalize()
+            .map_err(|e| Failure::Runtime(format!("cannot resolve {}: {}", wallpaper.output, e)))?;
+        set_wallpaper(&path.to_string_lossy()).map_err(Failure::Runtime)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,203 @@
+//! 出力ファイルの拡張子で選ぶ画像の形式。PNG の他に、Web 向けの WebP と AVIF、タイル分割した BigTIFF で書き出せる
+//!
+//! ノイズの多い深い拡大の画像は PNG だと非常に大きくなるので、Web に載せるときは非可逆に圧縮した方が小さい。
+//! WebP は `webp`、AVIF は `avif` の feature を有効にしてビルドしたときだけ使え、
+//! `--quality Q` (1..100、既定値: 90) で画質を、`--lossless` で WebP の可逆圧縮を選ぶ。
+//! どちらも RGB で渡し、灰色の画像は3つの成分を同じ値にする。BigTIFF は `tiff.rs` で書く。
+//! パレットの色 (`color.rs`) はどの形式にも付くが、ICC プロファイルを埋め込むのは PNG と TIFF だけ。
+//! `--interlace` は PNG を Adam7 でインターレースし、途中まで読み込んだところで粗い全体を見せる。
+//! `--xmp` のメタデータ (`xmp.rs`) も PNG と TIFF にだけ埋め込む。
+
+use std::path::Path;
+
+use super::color::Colors;
+use super::tiff::{TiledWriter, Tiling};
+use super::{encode, write_image};
+
+/// 画像の形式
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    Png,
+    WebP,
+    Avif,
+    Tiff
+}
+
+impl OutputFormat {
+    /// `path` の拡張子の形式。このビルドで書き出せない形式なら `Err`
+    pub fn of(path: &str) -> Result<OutputFormat, String> {
+        let extension = Path::new(path).extension().and_then(|e| e.to_str()).map(str::to_ascii_lowercase);
+        let format = match extension.as_deref() {
+            Some("webp") => OutputFormat::WebP,
+            Some("avif") => OutputFormat::Avif,
+            Some("tif") | Some("tiff") => OutputFormat::Tiff,
+            _ => OutputFormat::Png
+        };
+        match format {
+            OutputFormat::WebP if !cfg!(feature = "webp") => {
+                Err("WebP output needs a build with --features webp".to_string())
+            }
+            OutputFormat::Avif if !cfg!(feature = "avif") => {
+                Err("AVIF output needs a build with --features avif".to_string())
+            }
+            _ => Ok(format)
+        }
+    }
+}
+
+/// 形式毎の書き出しの設定
+#[derive(Clone, Debug, PartialEq)]
+pub struct Encoding {
+    /// 非可逆な形式の画質。1 から 100 まで、大きいほど高画質
+    pub quality: f32,
+    /// WebP を可逆圧縮にする
+    pub lossless: bool,
+    /// BigTIFF のタイルの分け方
+    pub tiling: Tiling,
+    /// パレットと ICC プロファイル
+    pub colors: Colors,
+    /// PNG を Adam7 でインターレースする
+    pub interlace: bool,
+    /// PNG と TIFF に埋め込む XMP のパケット
+    pub xmp: Option<String>
+}
+
+impl Default for Encoding {
+    fn default() -> Encoding {
+        Encoding { quality: 90.0, lossless: false, tiling: Tiling::default(), colors: Colors::default(), interlace: false,
+                   xmp: None }
+    }
+}
+
+impl Encoding {
+    /// `format` に使える設定か確かめる
+    pub fn check(&self, format: OutputFormat) -> Result<(), String> {
+        if !(1.0 ..= 100.0).contains(&self.quality) {
+            return Err(format!("--quality must be between 1 and 100: {}", self.quality));
+        }
+        self.tiling.check()?;
+        match format {
+            OutputFormat::Avif if self.lossless => Err("--lossless is only supported for WebP".to_string()),
+            OutputFormat::Png => Ok(()),
+            _ if self.interlace => Err("--interlace is only supported for PNG".to_string()),
+            OutputFormat::Tiff => Ok(()),
+            _ if self.xmp.is_some() => Err("--xmp is only supported for PNG and TIFF".to_string()),
+            _ => Ok(())
+        }
+    }
+
+    /// 色以外の PNG の書き出しの設定
+    pub fn png(&self) -> encode::PngOptions<'_> {
+        encode::PngOptions { interlace: self.interlace, xmp: self.xmp.as_deref() }
+    }
+}
+
+/// 灰色の画像 `pixels` を `path` の拡張子の形式で書き出す
+pub fn write_output(path: &str, pixels: &[u8], bounds: (usize, usize), encoding: &Encoding) -> Result<(), String> {
+    let encoded = match OutputFormat::of(path)? {
+        OutputFormat::Png if encoding.colors.is_plain() && encoding.png() == encode::PngOptions::default() => {
+            return write_image(path, pixels, bounds).map_err(|e| format!("error writing PNG file: {}", e));
+        }
+        OutputFormat::Png => {
+            return std::fs::File::create(path)
+                .and_then(|file| {
+                    encode::encode_colored(std::io::BufWriter::new(file), pixels, bounds, &encoding.colors, &encoding.png())
+                })
+                .map_err(|e| format!("error writing PNG file: {}", e));
+        }
+        OutputFormat::Tiff => {
+            return TiledWriter::create(path, bounds, encoding.tiling)
+                .map(|writer| writer.colored(encoding.colors.clone()).with_xmp(encoding.xmp.clone()))
+                .and_then(|mut writer| writer.write_rows(pixels).and_then(|_| writer.finish()))
+                .map_err(|e| format!("error writing TIFF file: {}", e));
+        }
+        OutputFormat::WebP => encode_webp(pixels, bounds, encoding)?,
+        OutputFormat::Avif => encode_avif(pixels, bounds, encoding)?
+    };
+    std::fs::write(path, encoded).map_err(|e| format!("cannot write {}: {}", path, e))
+}
+
+/// RGB か、透明にする点があれば RGBA のサンプル
+#[cfg(any(feature = "webp", feature = "avif"))]
+fn to_rgb(pixels: &[u8], colors: &Colors) -> Vec<u8> {
+    let lut = Some(colors.lut.clone().unwrap_or_else(super::color::Lut::gray));
+    Colors { lut, icc: None, alpha: colors.alpha }.samples(pixels).into_owned()
+}
+
+#[cfg(feature = "webp")]
+fn encode_webp(pixels: &[u8], bounds: (usize, usize), encoding: &Encoding) -> Result<Vec<u8>, String> {
+    let rgb = to_rgb(pixels, &encoding.colors);
+    let (width, height) = (bounds.0 as u32, bounds.1 as u32);
+    let encoder = if encoding.colors.alpha.is_some() {
+        libwebp::Encoder::from_rgba(&rgb, width, height)
+    } else {
+        libwebp::Encoder::from_rgb(&rgb, width, height)
+    };
+    let memory = if encoding.lossless { encoder.encode_lossless() } else { encoder.encode(encoding.quality) };
+    Ok(memory.to_vec())
+}
+
+#[cfg(not(feature = "webp"))]
+fn encode_webp(_: &[u8], _: (usize, usize), _: &Encoding) -> Result<Vec<u8>, String> {
+    unreachable!("OutputFormat::of rejects WebP without the webp feature")
+}
+
+#[cfg(feature = "avif")]
+fn encode_avif(pixels: &[u8], bounds: (usize, usize), encoding: &Encoding) -> Result<Vec<u8>, String> {
+    let samples = to_rgb(pixels, &encoding.colors);
+    let encoder = ravif::Encoder::new().with_quality(encoding.quality);
+    let image = if encoding.colors.alpha.is_some() {
+        let rgba: Vec<ravif::RGBA8> = samples.chunks(4).map(|p| ravif::RGBA8::new(p[0], p[1], p[2], p[3])).collect();
+        encoder.encode_rgba(ravif::Img::new(&rgba[..], bounds.0, bounds.1))
+    } else {
+        let rgb: Vec<ravif::RGB8> = samples.chunks(3).map(|p| ravif::RGB8::new(p[0], p[1], p[2])).collect();
+        encoder.encode_rgb(ravif::Img::new(&rgb[..], bounds.0, bounds.1))
+    }.map_err(|e| format!("cannot encode AVIF: {}", e))?;
+    Ok(image.avif_file)
+}
+
+#[cfg(not(feature = "avif"))]
+fn encode_avif(_: &[u8], _: (usize, usize), _: &Encoding) -> Result<Vec<u8>, String> {
+    unreachable!("OutputFormat::of rejects AVIF without the avif feature")
+}
+
+#[test]
+fn test_output_format() {
+    assert_eq!(OutputFormat::of("mandel.png"), Ok(OutputFormat::Png));
+    assert_eq!(OutputFormat::of("mandel"), Ok(OutputFormat::Png));
+    assert_eq!(OutputFormat::of("mandel.tif"), Ok(OutputFormat::Tiff));
+    assert_eq!(OutputFormat::of("mandel.WEBP").is_ok(), cfg!(feature = "webp"));
+    assert_eq!(OutputFormat::of("mandel.avif").is_ok(), cfg!(feature = "avif"));
+
+    assert!(Encoding::default().check(OutputFormat::Avif).is_ok());
+    assert!(Encoding { quality: 0.0, ..Encoding::default() }.check(OutputFormat::WebP).is_err());
+    assert!(Encoding { quality: 101.0, ..Encoding::default() }.check(OutputFormat::Png).is_err());
+    assert!(Encoding { lossless: true, ..Encoding::default() }.check(OutputFormat::WebP).is_ok());
+    assert!(Encoding { lossless: true, ..Encoding::default() }.check(OutputFormat::Avif).is_err());
+    assert!(Encoding { interlace: true, ..Encoding::default() }.check(OutputFormat::Png).is_ok());
+    assert!(Encoding { interlace: true, ..Encoding::default() }.check(OutputFormat::Tiff).is_err());
+    let xmp = Encoding { xmp: Some(String::new()), ..Encoding::default() };
+    assert!(xmp.check(OutputFormat::Tiff).is_ok() && xmp.check(OutputFormat::Avif).is_err());
+    assert!(Encoding { tiling: Tiling { tile_size: 100, pyramid: false }, ..Encoding::default() }
+            .check(OutputFormat::Tiff).is_err());
+}
+
+#[cfg(feature = "webp")]
+#[test]
+fn test_encode_webp() {
+    let pixels: Vec<u8> = (0 .. 64 * 48).map(|i| (i % 256) as u8).collect();
+    let lossless = encode_webp(&pixels, (64, 48), &Encoding { lossless: true, ..Encoding::default() }).unwrap();
+    assert_eq!(&lossless[.. 4], b"RIFF");
+    assert_eq!(&lossless[8 .. 12], b"WEBP");
+    let decoded = libwebp::Decoder::new(&lossless).decode().unwrap();
+    assert_eq!((decoded.width(), decoded.height()), (64, 48));
+    assert!(decoded.iter().step_by(decoded.len() / pixels.len()).zip(&pixels).all(|(a, b)| a == b));
+}
+
+#[cfg(feature = "avif")]
+#[test]
+fn test_encode_avif() {
+    let pixels = vec![128; 32 * 24];
+    let encoded = encode_avif(&pixels, (32, 24), &Encoding::default()).unwrap();
+    assert_eq!(&encoded[4 .. 12], b"ftypavif");
+}
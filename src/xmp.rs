@@ -0,0 +1,80 @@
+//! 画像に埋め込む XMP のメタデータ `--xmp`
+//!
+//! Lightroom などのアセット管理ソフトが書き出した画像と一緒に出所を残せるよう、描いたソフト
+//! (`xmp:CreatorTool`)、説明 (`dc:description`) と、このクレートの名前空間に描いた位置とパラメータを書く。
+//! 位置は地図の座標の代わりに中心 `Center` と倍率 `Zoom` (画像の高さが `4 / Zoom`、`.kfr` と同じ) で残し、
+//! `Options` は範囲の後に渡したオプションをそのまま並べたものなので、描画のコマンドに渡せば描き直せる。
+//! 同じ設定なら同じ内容になるよう、日時は書かない。PNG は iTXt チャンク、TIFF は タグ 700 に埋め込む。
+
+use num::Complex;
+
+use super::RenderParams;
+
+/// このクレートの XMP の名前空間
+const NAMESPACE: &str = "https://github.com/raimon49/rust-parallel-mandelbrot-rewrite-study/ns/xmp/1.0/";
+
+/// XML の属性と文字列に書けるようにする
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// 範囲と `options` を残す XMP のパケット
+pub fn packet(bounds: (usize, usize),
+              upper_left: Complex<f64>,
+              lower_right: Complex<f64>,
+              options: &[String],
+              params: &RenderParams)
+    -> String
+{
+    let center = (upper_left + lower_right) / 2.0;
+    let zoom = 4.0 / (upper_left.im - lower_right.im);
+    let fields = [
+        ("Center", format!("{:?},{:?}", center.re, center.im)),
+        ("Zoom", format!("{:?}", zoom)),
+        ("UpperLeft", format!("{:?},{:?}", upper_left.re, upper_left.im)),
+        ("LowerRight", format!("{:?},{:?}", lower_right.re, lower_right.im)),
+        ("Pixels", format!("{}x{}", bounds.0, bounds.1)),
+        ("Iterations", params.limit().to_string()),
+        ("Fractal", format!("{:?}", params.fractal)),
+        ("Options", options.join(" "))
+    ];
+    let description = format!("{:?} at {:?},{:?} zoom {:?}, {} iterations",
+                              params.fractal, center.re, center.im, zoom, params.limit());
+
+    let mut xml = String::new();
+    xml.push_str("<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>\n");
+    xml.push_str("<x:xmpmeta xmlns:x=\"adobe:ns:meta/\">\n");
+    xml.push_str(" <rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">\n");
+    xml.push_str("  <rdf:Description rdf:about=\"\"\n");
+    xml.push_str("    xmlns:xmp=\"http://ns.adobe.com/xap/1.0/\"\n");
+    xml.push_str("    xmlns:dc=\"http://purl.org/dc/elements/1.1/\"\n");
+    xml.push_str(&format!("    xmlns:mandelbrot=\"{}\"\n", NAMESPACE));
+    xml.push_str(&format!("    xmp:CreatorTool=\"{} {}\"", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")));
+    for (name, value) in &fields {
+        xml.push_str(&format!("\n    mandelbrot:{}=\"{}\"", name, escape(value)));
+    }
+    xml.push_str(">\n");
+    xml.push_str("   <dc:description>\n    <rdf:Alt>\n");
+    xml.push_str(&format!("     <rdf:li xml:lang=\"x-default\">{}</rdf:li>\n", escape(&description)));
+    xml.push_str("    </rdf:Alt>\n   </dc:description>\n");
+    xml.push_str("  </rdf:Description>\n </rdf:RDF>\n</x:xmpmeta>\n");
+    xml.push_str("<?xpacket end=\"w\"?>");
+    xml
+}
+
+#[test]
+fn test_packet() {
+    assert_eq!(escape("a<b & \"c\">"), "a&lt;b &amp; &quot;c&quot;&gt;");
+
+    let options = vec!["--passes".to_string(), "64,256".to_string(), "--annotations".to_string(), "a&b.toml".to_string()];
+    let params = super::parse_params(&options[.. 2]).unwrap();
+    let packet = packet((100, 50), Complex { re: -2.0, im: 1.0 }, Complex { re: 2.0, im: -1.0 }, &options, &params);
+    assert!(packet.starts_with("<?xpacket begin=\"\u{feff}\""));
+    assert!(packet.ends_with("<?xpacket end=\"w\"?>"));
+    assert!(packet.contains(&format!("xmp:CreatorTool=\"mandelbrot-rewrite {}\"", env!("CARGO_PKG_VERSION"))));
+    assert!(packet.contains("mandelbrot:Center=\"0.0,0.0\""));
+    assert!(packet.contains("mandelbrot:Zoom=\"2.0\""));
+    assert!(packet.contains("mandelbrot:Iterations=\"256\""));
+    assert!(packet.contains("mandelbrot:Options=\"--passes 64,256 --annotations a&amp;b.toml\""));
+    assert!(packet.contains(">Mandelbrot at 0.0,0.0 zoom 2.0, 256 iterations</rdf:li>"));
+}
@@ -0,0 +1,146 @@
+//! バイナリを実際に起動して、終了コード、標準エラー出力のメッセージ、書き出したファイルを確かめる
+//!
+//! メッセージは `tests/snapshots/` の同じ名前のファイルと比べる。メッセージを変えたときは
+//! `UPDATE_SNAPSHOTS=1 cargo test --test cli` で書き直し、差分を確かめてからコミットする。
+//! 使い方の全文は機能を足す度に変わるので、スナップショットには最初の行だけを残す。
+
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+/// 引数 `args` でバイナリを起動した結果
+fn run(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_mandelbrot-rewrite")).args(args).output().expect("error starting binary")
+}
+
+/// テスト `name` だけが使う空の作業ディレクトリ
+fn work_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("mandelbrot-cli-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).expect("error creating work directory");
+    dir
+}
+
+/// `text` の最初の `lines` 行を `tests/snapshots/NAME.txt` と比べる。`UPDATE_SNAPSHOTS` があれば書き直す
+fn assert_snapshot(name: &str, text: &[u8], lines: usize) {
+    let text = String::from_utf8_lossy(text);
+    let actual: String = text.lines().take(lines).flat_map(|line| [line, "\n"]).collect();
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/snapshots").join(format!("{}.txt", name));
+    if std::env::var_os("UPDATE_SNAPSHOTS").is_some() {
+        fs::write(&path, &actual).expect("error writing snapshot");
+        return;
+    }
+    let expected = fs::read_to_string(&path).unwrap_or_else(|_| panic!("missing snapshot {}", path.display()));
+    assert_eq!(actual, expected, "snapshot {} differs, rerun with UPDATE_SNAPSHOTS=1 to accept", name);
+}
+
+#[test]
+fn test_usage() {
+    // 引数が足りなければ使い方を表示して失敗し、--help なら成功する
+    let output = run(&[]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_snapshot("usage", &output.stderr, 1);
+    assert_snapshot("usage", &run(&["x.png", "8x6", "-2,1"]).stderr, 1);
+    let output = run(&["--help"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert!(String::from_utf8_lossy(&output.stdout).starts_with("Usage: "));
+}
+
+#[test]
+fn test_bad_arguments() {
+    // 読めない引数はパニックせずにメッセージと使い方を表示して 1 で終わり、ファイルは作らない
+    let dir = work_dir("bad-arguments");
+    let file = dir.join("x.png");
+    let file = file.to_str().unwrap();
+    for (name, args) in [
+        ("bad_dimensions", vec![file, "8by6", "-2,1", "1,-1"]),
+        ("zero_dimensions", vec![file, "0x6", "-2,1", "1,-1"]),
+        ("bad_upper_left", vec![file, "8x6", "-2;1", "1,-1"]),
+        ("bad_lower_right", vec![file, "8x6", "-2,1", "1"]),
+        ("unknown_option", vec![file, "8x6", "-2,1", "1,-1", "--bogus"]),
+        ("bad_passes", vec![file, "8x6", "-2,1", "1,-1", "--passes", "0"]),
+        ("bad_subcommand_option", vec!["fuzz-parsers", "--iterations", "many"])
+    ] {
+        let output = run(&args);
+        assert_eq!(output.status.code(), Some(1), "{}", name);
+        assert_snapshot(name, &output.stderr, 1);
+    }
+    assert!(!dir.join("x.png").exists());
+}
+
+#[test]
+fn test_tiny_png() {
+    let dir = work_dir("tiny-png");
+    let file = dir.join("tiny.png");
+    let output = run(&[file.to_str().unwrap(), "8x6", "-2,1", "1,-1"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert!(output.stderr.is_empty());
+    let image = image::open(&file).unwrap().to_luma();
+    assert_eq!(image.dimensions(), (8, 6));
+    // 集合の中は黒、左上の角は集合から遠いので白
+    assert_eq!(image.get_pixel(5, 3).data, [0]);
+    assert_eq!(image.get_pixel(0, 0).data, [255]);
+}
+
+#[test]
+fn test_output_formats() {
+    let dir = work_dir("output-formats");
+    let render = |name: &str| {
+        let file = dir.join(name);
+        (run(&[file.to_str().unwrap(), "8x6", "-2,1", "1,-1"]), file)
+    };
+    let (output, file) = render("tiny.tif");
+    assert_eq!(output.status.code(), Some(0));
+    // 大きな画像も書けるよう BigTIFF で書き出す
+    assert!(fs::read(file).unwrap().starts_with(b"II+\0"));
+
+    // WebP と AVIF は機能を有効にしたビルドだけで書き出せる
+    let (output, file) = render("tiny.webp");
+    if cfg!(feature = "webp") {
+        assert_eq!(output.status.code(), Some(0));
+        let bytes = fs::read(file).unwrap();
+        assert!(bytes.starts_with(b"RIFF") && &bytes[8 .. 12] == b"WEBP");
+    } else {
+        assert_eq!(output.status.code(), Some(1));
+        assert_snapshot("webp_disabled", &output.stderr, 1);
+        assert!(!file.exists());
+    }
+    let (output, file) = render("tiny.avif");
+    if cfg!(feature = "avif") {
+        assert_eq!(output.status.code(), Some(0));
+        assert_eq!(&fs::read(file).unwrap()[4 .. 8], b"ftyp");
+    } else {
+        assert_eq!(output.status.code(), Some(1));
+        assert!(!file.exists());
+    }
+}
+
+#[test]
+fn test_dry_run() {
+    // 見積もりの時間やスレッドの数は環境で変わるので、決まった値の行だけを比べる
+    let dir = work_dir("dry-run");
+    let file = dir.join("big.png");
+    let output = run(&[file.to_str().unwrap(), "2000x1500", "-2.5,1.5", "1.5,-1.5", "--dry-run"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_snapshot("dry_run", &output.stdout, 5);
+    assert!(!file.exists());
+}
+
+#[test]
+fn test_write_error() {
+    // 書き出せない場所ではパニックせずに理由を表示して 1 で終わる
+    let dir = work_dir("write-error");
+    fs::write(dir.join("file"), b"").unwrap();
+    let file = dir.join("file").join("x.png");
+    let output = run(&[file.to_str().unwrap(), "8x6", "-2,1", "1,-1"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).starts_with("error writing image: "));
+
+    // サブコマンドでも、引数が正しければ失敗の理由だけを表示して使い方は表示しない
+    let missing = dir.join("missing.png");
+    let missing = missing.to_str().unwrap();
+    let output = run(&["diff", missing, missing]);
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.starts_with("error: ") && !stderr.contains("Usage: "), "{}", stderr);
+}